@@ -1,27 +1,126 @@
+// typetag registers its trait-object impls from inside generated const blocks
+#![allow(non_local_definitions)]
+
 extern crate bam;
 extern crate serde;
 extern crate typetag;
 
-use bam::record::tags::TagName;
+use bam::record::cigar::Operation;
+use bam::record::tags::{TagName, TagValue};
 use bam::Record;
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
 
 use utils::BoolOperator;
 
+use crate::script;
+use crate::streaming::{MatePairStream, ReadGroupRateStream, RecordIndexStream, StreamingFilter};
 use crate::utils;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 #[typetag::serde(tag = "type")]
-pub trait Filtering: CloneFilter {
+pub trait Filtering: CloneFilter + Send + Sync {
     fn apply_to(&self, record: &Record) -> bool;
 
     fn repr(&self) -> String;
 
     fn name(&self) -> &str;
+
+    /// Filters whose decision depends on other records (e.g. the read's mate) return a stateful
+    /// stream that the record loop feeds instead of calling `apply_to`.
+    fn to_streaming(&self) -> Option<Box<dyn StreamingFilter>> {
+        None
+    }
+
+    /// Whether every unmapped read fails the filter, so that the unmapped reads at the end of
+    /// a coordinate-sorted BAM file can be skipped without being read. False if it can't be told for sure.
+    fn rejects_unmapped(&self) -> bool {
+        false
+    }
+
+    /// Command line arguments (following the executable) that recreate this filter, assuming that the filters
+    /// it is built from (see `inputs`) already exist. None if the filter can't be created from the command line.
+    fn command_args(&self) -> Option<Vec<String>> {
+        None
+    }
+
+    /// The filters that this filter is built from
+    fn inputs(&self) -> Vec<&dyn Filtering> {
+        Vec::new()
+    }
+
+    /// Called with the header of an input file before the filter is applied to its records,
+    /// for filters that need to look up reference sequences by name.
+    fn prepare(&mut self, _header: &bam::Header) {}
+
+    /// Whether the filter modifies the reads it keeps (see `transform`), rather than only deciding on them.
+    /// Filters built from other filters transform reads if any of their inputs do
+    fn transforms(&self) -> bool {
+        self.inputs().iter().any(|input| input.transforms())
+    }
+
+    /// Modify a read that is kept, before it is written (e.g. trim it). Filters built from other filters
+    /// apply the transformations of their inputs in order
+    fn transform(&self, record: &mut Record) {
+        for input in self.inputs() {
+            input.transform(record);
+        }
+    }
+
+    /// Mutable access to the filters that this filter is built from (see `inputs`)
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Filtering>> {
+        Vec::new()
+    }
+
+    /// Like `repr`, but filters that refer to reference sequences by id also show their names if a header is given.
+    fn describe(&self, _header: Option<&bam::Header>) -> String {
+        self.repr()
+    }
+
+    /// Problems with the definition of this filter (not the filters it is built from), e.g. an empty range.
+    /// Filters created from the command line are checked when they are created, so these usually come from
+    /// hand-edited configs
+    fn problems(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+fn range_problems<T: utils::Bounded>(range: utils::RangeBound<T>) -> Vec<String> {
+    match range.is_empty() {
+        true => vec![format!("empty range: the minimum ({}) is greater than the maximum ({})", range.min_str(), range.max_str())],
+        false => Vec::new(),
+    }
+}
+
+// The counterpart of `range_problems` for filters with a range of f64 values (e.g. fractions), where `quantity` names
+// the values in the message
+fn f64_range_problems(quantity: &str, min: f64, max: f64) -> Vec<String> {
+    match min > max {
+        true => vec![format!("empty range: the minimum {} ({}) is greater than the maximum ({})", quantity, min, max)],
+        false => Vec::new(),
+    }
+}
+
+// `apply_to` of the filters that decide on reads using other records: these are only evaluated by feeding every
+// record to their stream (see `Filtering::to_streaming`), and can't be combined with other filters
+fn needs_stream(name: &str) -> ! {
+    panic!("Filter {} depends on other records and cannot be combined with other filters!", name)
+}
+
+fn tag_name_problems(tag_name: &TagName) -> Vec<String> {
+    match utils::is_valid_tag_name(tag_name) {
+        true => Vec::new(),
+        false => vec![format!("tag name {:?} is not a letter followed by a letter or digit", String::from_utf8_lossy(tag_name))],
+    }
 }
 
 pub trait CloneFilter {
-    fn box_clone<'a>(&self) -> Box<dyn Filtering>;
+    fn box_clone(&self) -> Box<dyn Filtering>;
 }
 
 impl<T> CloneFilter for T
@@ -39,15 +138,85 @@ impl Clone for Box<dyn Filtering> {
     }
 }
 
+// Arguments of the `create` command for a filter, given its subcommand and the subcommand's arguments
+fn create_args(name: &str, opposite: bool, subcommand: &[String]) -> Vec<String> {
+    let mut args = vec!["create".to_string(), "--name".to_string(), name.to_string()];
+    if opposite {
+        args.push("--opposite".to_string());
+    }
+    args.extend_from_slice(subcommand);
+    args
+}
+
+// Whether the aligned span of a mapped read overlaps any of the sorted, non-overlapping intervals of its reference
+fn overlaps_intervals(intervals: &HashMap<i32, Vec<(i32, i32)>>, record: &Record) -> bool {
+    if !record.flag().is_mapped() {
+        return false;
+    }
+    let intervals = match intervals.get(&record.ref_id()) {
+        Some(intervals) => intervals,
+        None => return false,
+    };
+    let (start, end) = (record.start(), record.calculate_end().max(record.start() + 1));
+    let first_after = intervals.partition_point(|&(_, interval_end)| interval_end <= start);
+    first_after < intervals.len() && intervals[first_after].0 < end
+}
+
+// Sort the intervals of each reference and merge the overlapping ones, as `overlaps_intervals` expects
+fn merge_intervals(intervals: &mut HashMap<i32, Vec<(i32, i32)>>) {
+    for ref_intervals in intervals.values_mut() {
+        ref_intervals.sort_unstable();
+        let mut merged: Vec<(i32, i32)> = Vec::with_capacity(ref_intervals.len());
+        for &(start, end) in ref_intervals.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        *ref_intervals = merged;
+    }
+}
+
+// Filters don't expose their name field, so a renamed copy is made through their serialized form
+fn renamed(filter: &dyn Filtering, name: &str) -> Box<dyn Filtering> {
+    let mut value = serde_json::to_value(filter).unwrap();
+    value["name"] = serde_json::Value::String(name.to_string());
+    serde_json::from_value(value).unwrap()
+}
+
+/// Short name of a filter's type, matching its `create` subcommand (e.g. "ref-name" for RefNameFilter)
+pub fn filter_type(filter: &dyn Filtering) -> String {
+    let type_name = filter.typetag_name();
+    let type_name = type_name.strip_suffix("Filter").unwrap_or(type_name);
+    let mut short_name = String::new();
+    for (i, c) in type_name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            short_name.push('-');
+        }
+        short_name.push(c.to_ascii_lowercase());
+    }
+    short_name
+}
+
+// Filters are kept in the order they were added, which is also the order they are saved in.
+// Each filter also gets an immutable id, so that it can be referred to as '@<id>' even after it is renamed.
+// The ids are ordered as well (rather than kept in a HashMap, whose iteration order changes from run to run),
+// so that saving the same config always gives the same file.
 #[derive(Serialize, Deserialize)]
 pub struct Config {
-    filters: HashMap<String, Box<dyn Filtering>>,
+    filters: IndexMap<String, Box<dyn Filtering>>,
+    #[serde(default)]
+    ids: IndexMap<String, u64>,
+    #[serde(default)]
+    next_id: u64,
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
-            filters: HashMap::new(),
+            filters: IndexMap::new(),
+            ids: IndexMap::new(),
+            next_id: 1,
         }
     }
 
@@ -56,15 +225,93 @@ impl Config {
     }
     pub fn push(&mut self, key: &str, value: Box<dyn Filtering>) {
         self.filters.insert(key.to_string(), value);
+        self.assign_ids();
+    }
+
+    // give ids to filters that don't have one yet (e.g. those of a config saved before ids were added)
+    pub fn assign_ids(&mut self) {
+        self.next_id = self.next_id.max(self.ids.values().max().map_or(1, |id| id + 1));
+        for name in self.filters.keys() {
+            if !self.ids.contains_key(name) {
+                self.ids.insert(name.clone(), self.next_id);
+                self.next_id += 1;
+            }
+        }
+    }
+
+    /// Name of the filter referred to either by its name or by its id (as '@<id>')
+    pub fn resolve(&self, reference: &str) -> Option<String> {
+        if self.filters.contains_key(reference) {
+            return Some(reference.to_string());
+        }
+        let id: u64 = reference.strip_prefix('@')?.parse().ok()?;
+        self.ids
+            .iter()
+            .find(|(_, filter_id)| **filter_id == id)
+            .map(|(name, _)| name.clone())
+    }
+
+    pub fn id(&self, key: &str) -> Option<u64> {
+        self.ids.get(key).copied()
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Box<dyn Filtering>> {
+        let name = self.resolve(key)?;
+        self.ids.shift_remove(&name);
+        self.filters.shift_remove(&name)
     }
 
     pub fn get(&self, key: &str) -> Option<Box<dyn Filtering>> {
-        self.filters.get(&key.to_string()).cloned()
+        self.filters.get(&self.resolve(key)?).cloned()
+    }
+
+    /// Rename a filter (referred to by name or id), keeping its id and position.
+    /// Returns false if there is no such filter.
+    pub fn rename(&mut self, key: &str, new_name: &str) -> bool {
+        let name = match self.resolve(key) {
+            Some(name) => name,
+            None => return false,
+        };
+        assert!(!self.filters.contains_key(new_name), "Filter {} already exists!", new_name);
+        let index = self.filters.get_index_of(&name).unwrap();
+        self.filters.replace_index(index, new_name.to_string()).unwrap();
+        let filter = &mut self.filters[index];
+        *filter = renamed(filter.as_ref(), new_name);
+        let id_index = self.ids.get_index_of(&name).unwrap();
+        self.ids.replace_index(id_index, new_name.to_string()).unwrap();
+        true
+    }
+
+    /// Number of filters of each type, most common first
+    pub fn count_by_type(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for filter in self.filters.values() {
+            *counts.entry(filter_type(filter.as_ref())).or_insert(0) += 1;
+        }
+        let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
     }
 
-    pub fn iter(&self) -> std::collections::hash_map::Iter<String, Box<dyn Filtering>> {
+    pub fn iter(&self) -> indexmap::map::Iter<'_, String, Box<dyn Filtering>> {
         self.filters.iter()
     }
+
+    /// Add the filters of another config after the filters of this one. A filter that is already here
+    /// with the same definition is skipped; a different filter with the same name is an error.
+    pub fn merge(&mut self, other: &Config) {
+        for (name, filter) in other.iter() {
+            if let Some(existing) = self.filters.get(name) {
+                assert!(
+                    serde_json::to_value(existing).unwrap() == serde_json::to_value(filter).unwrap(),
+                    "Filter {} already exists with a different definition!",
+                    name
+                );
+                continue;
+            }
+            self.push(name, filter.clone());
+        }
+    }
 }
 
 // TODO: uniquely aligned
@@ -90,6 +337,8 @@ pub struct TagFilter {
     name: String,
     tag_name: TagName,
     tag_value: utils::MinimalTagValue,
+    #[serde(default)]
+    numeric: bool,
     opposite: bool,
 }
 
@@ -124,370 +373,4547 @@ pub struct FlagFilter {
     opposite: bool,
 }
 
-impl CombinedFilter {
-    pub fn new(
-        name: String,
-        filter1: Box<dyn Filtering>,
-        filter2: Box<dyn Filtering>,
-        operator: BoolOperator,
-    ) -> CombinedFilter {
-        CombinedFilter {
-            name,
-            filter1,
-            filter2,
-            operator,
-        }
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompositionFilter {
+    name: String,
+    bases: String,
+    min_fraction: f64,
+    max_fraction: f64,
+    opposite: bool,
 }
 
-impl LengthFilter {
-    pub fn new(name: String, min_len: u32, max_len: u32, opposite: bool) -> LengthFilter {
-        LengthFilter {
-            name,
-            min_len,
-            max_len,
-            opposite,
-        }
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClipContentFilter {
+    name: String,
+    motif: String,
+    side: utils::ClipSide,
+    #[serde(default)]
+    max_mismatch: u32,
+    #[serde(default)]
+    both_strands: bool,
+    opposite: bool,
 }
 
-impl TagFilter {
-    pub fn new(
-        name: String,
-        tag_name: TagName,
-        tag_value: utils::MinimalTagValue,
-        opposite: bool,
-    ) -> TagFilter {
-        TagFilter {
-            name,
-            tag_name,
-            tag_value,
-            opposite,
-        }
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MdMismatchFilter {
+    name: String,
+    min_mismatches: u32,
+    max_mismatches: u32,
+    opposite: bool,
 }
 
-impl MapqFilter {
-    pub fn new(name: String, min_mapq: u8, max_mapq: u8, opposite: bool) -> MapqFilter {
-        MapqFilter {
-            name,
-            min_mapq,
-            max_mapq,
-            opposite,
-        }
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PairConcordantFilter {
+    name: String,
+    filter: Box<dyn Filtering>,
+    opposite: bool,
 }
 
-impl RefNameFilter {
-    pub fn new(name: String, ref_id: i32, opposite: bool) -> RefNameFilter {
-        RefNameFilter {
-            name,
-            ref_id,
-            opposite,
-        }
-    }
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "ScriptFilterSpec", into = "ScriptFilterSpec")]
+pub struct ScriptFilter {
+    name: String,
+    expression: String,
+    compiled: script::Expr,
+    opposite: bool,
 }
 
-impl NthNucleotideFilter {
-    pub fn new(
-        name: String,
-        position: i64,
-        nucleotide: char,
-        n_is_wildcard: bool,
-        opposite: bool,
-    ) -> NthNucleotideFilter {
-        assert!(
-            matches!(nucleotide, 'A' | 'C' | 'G' | 'T' | 'N'),
-            "Nucleotide must be one of A, C, G, T, or N!"
-        );
-        NthNucleotideFilter {
-            name,
-            position,
-            nucleotide,
-            n_is_wildcard,
-            opposite,
-        }
-    }
+// Only the expression is stored; it is compiled again when the config is loaded
+#[derive(Serialize, Deserialize)]
+struct ScriptFilterSpec {
+    name: String,
+    expression: String,
+    opposite: bool,
 }
 
-impl FlagFilter {
-    pub fn new(name: String, remove_flags: u16, opposite: bool) -> FlagFilter {
-        FlagFilter {
-            name,
-            remove_flags,
-            opposite,
-        }
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JunctionsFilter {
+    name: String,
+    // the junctions file the filter was created from
+    path: PathBuf,
+    // 0-based inclusive (first, last) intron positions per reference name, sorted
+    junctions: BTreeMap<String, Vec<(u32, u32)>>,
+    tolerance: u32,
+    opposite: bool,
+    // the junctions of each reference id of the input, set by `prepare`
+    #[serde(skip)]
+    ref_junctions: Option<HashMap<i32, Vec<(u32, u32)>>>,
 }
 
-#[typetag::serde]
-impl Filtering for CombinedFilter {
-    fn apply_to(&self, record: &Record) -> bool {
-        let result1 = self.filter1.apply_to(record);
-        let result2 = self.filter2.apply_to(record);
-        return match self.operator {
-            BoolOperator::AND => result1 && result2,
-            BoolOperator::OR => result1 || result2,
-            BoolOperator::XOR => result1 ^ result2,
-            BoolOperator::XNOR => !(result1 ^ result2),
-            BoolOperator::NAND => !(result1 && result2),
-            BoolOperator::NOR => !(result1 || result2),
-            BoolOperator::IMPLIES => !result1 || result2,
-        };
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FlagMaskFilter {
+    name: String,
+    require_mask: u16,
+    exclude_mask: u16,
+    opposite: bool,
+}
 
-    fn repr(&self) -> String {
-        format!(
-            "CombinedFilter(name={}, filter1={}, filter2={}, operator={:?})",
-            self.name,
-            self.filter1.name(),
-            self.filter2.name(),
-            self.operator
-        )
-    }
-    fn name(&self) -> &str {
-        &self.name
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SanityFilter {
+    name: String,
+    opposite: bool,
 }
 
-#[typetag::serde]
-impl Filtering for FlagFilter {
-    fn apply_to(&self, record: &Record) -> bool {
-        let flags = record.flag();
-        return if flags.no_bits(self.remove_flags) {
-            utils::_opposite(true, self.opposite)
-        } else {
-            utils::_opposite(false, self.opposite)
-        };
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SubtractFilter {
+    name: String,
+    path: PathBuf,
+    opposite: bool,
+    // sorted, non-overlapping intervals for each reference id of the input, loaded from `path` by `prepare`
+    #[serde(skip)]
+    intervals: Option<HashMap<i32, Vec<(i32, i32)>>>,
+}
 
-    fn repr(&self) -> String {
-        format!(
-            "FlagFilter(name={}, remove_flags={}, opposite={})",
-            self.name, self.remove_flags, self.opposite
-        )
-    }
-    fn name(&self) -> &str {
-        &self.name
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MateDistanceFilter {
+    name: String,
+    min_distance: u32,
+    max_distance: u32,
+    opposite: bool,
 }
 
-#[typetag::serde]
-impl Filtering for LengthFilter {
-    fn apply_to(&self, record: &Record) -> bool {
-        let read_len = record.query_len();
-        return if read_len < self.min_len || read_len > self.max_len {
-            utils::_opposite(false, self.opposite)
-        } else {
-            utils::_opposite(true, self.opposite)
-        };
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SpecialContigFilter {
+    name: String,
+    patterns: Vec<String>,
+    opposite: bool,
+    // ids of the input's references whose names match any of the patterns, set by `prepare`
+    #[serde(skip)]
+    ref_ids: Option<Vec<i32>>,
+}
 
-    fn repr(&self) -> String {
-        format!(
-            "LengthFilter(name={}, min_len={}, max_len={}, opposite={})",
-            self.name, self.min_len, self.max_len, self.opposite
-        )
-    }
-    fn name(&self) -> &str {
-        &self.name
-    }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StrandFilter {
+    name: String,
+    strand: utils::Strand,
+    opposite: bool,
 }
 
-#[typetag::serde]
-impl Filtering for TagFilter {
-    fn apply_to(&self, record: &Record) -> bool {
-        return if let Some(tag) = record.tags().get(&self.tag_name) {
-            let expanded_tag_val = utils::_minimal_tag_to_tag(&self.tag_value);
-            if utils::_are_tag_values_equal(&tag, &expanded_tag_val) {
-                utils::_opposite(true, self.opposite)
-            } else {
-                utils::_opposite(false, self.opposite)
-            }
-        } else {
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AdapterFilter {
+    name: String,
+    adapter: String,
+    max_mismatch: u32,
+    min_overlap: u32,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ExternalAnnotationFilter {
+    name: String,
+    path: PathBuf,
+    column: String,
+    comparison: script::CompareOp,
+    threshold: f64,
+    opposite: bool,
+    // the column's value for each read name, loaded from `path` by `prepare`
+    #[serde(skip)]
+    values: Option<Arc<HashMap<Vec<u8>, f64>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SaCountFilter {
+    name: String,
+    min_count: u32,
+    max_count: u32,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RefHomopolymerFilter {
+    name: String,
+    path: PathBuf,
+    min_length: u32,
+    opposite: bool,
+    // sorted homopolymer tracts for each reference id of the input, computed from the FASTA file by `prepare`
+    #[serde(skip)]
+    tracts: Option<HashMap<i32, Vec<(i32, i32)>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadNameFieldFilter {
+    name: String,
+    field: utils::NameField,
+    pattern: utils::NameFieldMatch,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HardClipFilter {
+    name: String,
+    min_clip: u32,
+    max_clip: u32,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrimmedLengthFilter {
+    name: String,
+    quality_threshold: u8,
+    min_len: u32,
+    max_len: u32,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StrandAssignFilter {
+    name: String,
+    library_type: utils::LibraryType,
+    strand: utils::Strand,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SingletonFilter {
+    name: String,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TaggedPrimaryFilter {
+    name: String,
+    tag_name: TagName,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LowQualFractionFilter {
+    name: String,
+    quality_threshold: u8,
+    min_fraction: f64,
+    max_fraction: f64,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ContigMarginFilter {
+    name: String,
+    margin: u32,
+    opposite: bool,
+    // lengths of the input's references, set by `prepare`
+    #[serde(skip)]
+    ref_lengths: Option<Vec<u32>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BreakpointFilter {
+    name: String,
+    reference: String,
+    position: u32,
+    tolerance: u32,
+    opposite: bool,
+    // id of `reference` in the input's header (None if it is not there), set by `prepare`
+    #[serde(skip)]
+    ref_id: Option<Option<i32>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PerfectMatchFilter {
+    name: String,
+    path: PathBuf,
+    opposite: bool,
+    // sequence of each reference id of the input, read from the FASTA file by `prepare`
+    #[serde(skip)]
+    sequences: Option<HashMap<i32, Vec<u8>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LeftmostSplitFilter {
+    name: String,
+    opposite: bool,
+    // id of each reference of the input by name, set by `prepare`
+    #[serde(skip)]
+    ref_ids: Option<HashMap<String, i32>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RgQualityFilter {
+    name: String,
+    max_rate: f64,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegionModeFilter {
+    name: String,
+    region: String,
+    mode: utils::RegionMode,
+    opposite: bool,
+    // reference id and 0-based half-open span of the region in the input (None if its reference is not there),
+    // set by `prepare`
+    #[serde(skip)]
+    span: Option<Option<(i32, i64, i64)>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QualityCliffFilter {
+    name: String,
+    quality_threshold: u8,
+    min_len: u32,
+    window: u32,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CoverageFilter {
+    name: String,
+    path: PathBuf,
+    min_coverage: f64,
+    max_coverage: f64,
+    opposite: bool,
+    // sorted, merged regions of the BedGraph whose coverage is within the bounds, for each reference id of the input,
+    // loaded by `prepare`
+    #[serde(skip)]
+    regions: Option<HashMap<i32, Vec<(i32, i32)>>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrimFilter {
+    name: String,
+    // bases removed from the 5' and 3' ends of the original read
+    leading: u32,
+    trailing: u32,
+    quality_threshold: Option<u8>,
+    min_len: u32,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClipRatioFilter {
+    name: String,
+    min_ratio: f64,
+    max_ratio: f64,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DistinctBasesFilter {
+    name: String,
+    min_distinct: u8,
+    max_distinct: u8,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RecordIndexFilter {
+    name: String,
+    // 0-based positions of the first and last records to keep, inclusive
+    first_index: u64,
+    last_index: u64,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConcordantPairFilter {
+    name: String,
+    min_insert: u32,
+    max_insert: u32,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AlignedGcFilter {
+    name: String,
+    min_fraction: f64,
+    max_fraction: f64,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AsPerBaseFilter {
+    name: String,
+    min_score: f64,
+    max_score: f64,
+    opposite: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InternalClipFilter {
+    name: String,
+    opposite: bool,
+}
+
+impl CombinedFilter {
+    pub fn new(
+        name: String,
+        filter1: Box<dyn Filtering>,
+        filter2: Box<dyn Filtering>,
+        operator: BoolOperator,
+    ) -> CombinedFilter {
+        CombinedFilter {
+            name,
+            filter1,
+            filter2,
+            operator,
+        }
+    }
+}
+
+impl LengthFilter {
+    pub fn new(name: String, min_len: u32, max_len: u32, opposite: bool) -> LengthFilter {
+        LengthFilter {
+            name,
+            min_len,
+            max_len,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u32> {
+        utils::RangeBound::from_values(self.min_len, self.max_len)
+    }
+}
+
+impl TagFilter {
+    pub fn new(
+        name: String,
+        tag_name: TagName,
+        tag_value: utils::MinimalTagValue,
+        numeric: bool,
+        opposite: bool,
+    ) -> TagFilter {
+        TagFilter {
+            name,
+            tag_name,
+            tag_value,
+            numeric,
+            opposite,
+        }
+    }
+}
+
+impl MapqFilter {
+    pub fn new(name: String, min_mapq: u8, max_mapq: u8, opposite: bool) -> MapqFilter {
+        MapqFilter {
+            name,
+            min_mapq,
+            max_mapq,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u8> {
+        utils::RangeBound::from_values(self.min_mapq, self.max_mapq)
+    }
+}
+
+impl RefNameFilter {
+    pub fn new(name: String, ref_id: i32, opposite: bool) -> RefNameFilter {
+        RefNameFilter {
+            name,
+            ref_id,
+            opposite,
+        }
+    }
+}
+
+impl NthNucleotideFilter {
+    pub fn new(
+        name: String,
+        position: i64,
+        nucleotide: char,
+        n_is_wildcard: bool,
+        opposite: bool,
+    ) -> NthNucleotideFilter {
+        assert!(
+            matches!(nucleotide, 'A' | 'C' | 'G' | 'T' | 'N'),
+            "Nucleotide must be one of A, C, G, T, or N!"
+        );
+        NthNucleotideFilter {
+            name,
+            position,
+            nucleotide,
+            n_is_wildcard,
+            opposite,
+        }
+    }
+}
+
+impl FlagFilter {
+    pub fn new(name: String, remove_flags: u16, opposite: bool) -> FlagFilter {
+        FlagFilter {
+            name,
+            remove_flags,
+            opposite,
+        }
+    }
+}
+
+impl CompositionFilter {
+    pub fn new(
+        name: String,
+        bases: &str,
+        min_fraction: f64,
+        max_fraction: f64,
+        opposite: bool,
+    ) -> CompositionFilter {
+        let bases = bases.to_ascii_uppercase();
+        assert!(
+            !bases.is_empty() && bases.chars().all(|c| matches!(c, 'A' | 'C' | 'G' | 'T')),
+            "Base set must consist of the nucleotides A, C, G, and T!"
+        );
+        assert!(
+            min_fraction <= max_fraction,
+            "Minimum fraction must not exceed the maximum fraction!"
+        );
+        CompositionFilter {
+            name,
+            bases,
+            min_fraction,
+            max_fraction,
+            opposite,
+        }
+    }
+}
+
+impl ClipContentFilter {
+    pub fn new(
+        name: String,
+        motif: &str,
+        side: utils::ClipSide,
+        max_mismatch: u32,
+        both_strands: bool,
+        opposite: bool,
+    ) -> ClipContentFilter {
+        let motif = motif.to_ascii_uppercase();
+        assert!(
+            !motif.is_empty() && motif.chars().all(|c| matches!(c, 'A' | 'C' | 'G' | 'T' | 'N')),
+            "Motif must consist of the nucleotides A, C, G, T, or N!"
+        );
+        assert!(
+            (max_mismatch as usize) < motif.len(),
+            "Maximum number of mismatches ({}) must be smaller than the motif length ({})!",
+            max_mismatch,
+            motif.len()
+        );
+        ClipContentFilter {
+            name,
+            motif,
+            side,
+            max_mismatch,
+            both_strands,
+            opposite,
+        }
+    }
+}
+
+impl MdMismatchFilter {
+    pub fn new(name: String, min_mismatches: u32, max_mismatches: u32, opposite: bool) -> MdMismatchFilter {
+        MdMismatchFilter {
+            name,
+            min_mismatches,
+            max_mismatches,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u32> {
+        utils::RangeBound::from_values(self.min_mismatches, self.max_mismatches)
+    }
+}
+
+impl PairConcordantFilter {
+    pub fn new(name: String, filter: Box<dyn Filtering>, opposite: bool) -> PairConcordantFilter {
+        PairConcordantFilter {
+            name,
+            filter,
+            opposite,
+        }
+    }
+}
+
+impl ScriptFilter {
+    pub fn new(name: String, expression: &str, opposite: bool) -> ScriptFilter {
+        let compiled = script::compile(expression)
+            .unwrap_or_else(|e| panic!("Invalid filter expression '{}': {}", expression, e));
+        ScriptFilter {
+            name,
+            expression: expression.to_string(),
+            compiled,
+            opposite,
+        }
+    }
+}
+
+impl TryFrom<ScriptFilterSpec> for ScriptFilter {
+    type Error = String;
+
+    fn try_from(spec: ScriptFilterSpec) -> Result<Self, Self::Error> {
+        let compiled = script::compile(&spec.expression)?;
+        Ok(ScriptFilter {
+            name: spec.name,
+            expression: spec.expression,
+            compiled,
+            opposite: spec.opposite,
+        })
+    }
+}
+
+impl From<ScriptFilter> for ScriptFilterSpec {
+    fn from(filter: ScriptFilter) -> Self {
+        ScriptFilterSpec {
+            name: filter.name,
+            expression: filter.expression,
+            opposite: filter.opposite,
+        }
+    }
+}
+
+impl JunctionsFilter {
+    pub fn new(
+        name: String,
+        path: PathBuf,
+        mut junctions: BTreeMap<String, Vec<(u32, u32)>>,
+        tolerance: u32,
+        opposite: bool,
+    ) -> JunctionsFilter {
+        for introns in junctions.values_mut() {
+            introns.sort_unstable();
+            introns.dedup();
+        }
+        JunctionsFilter {
+            name,
+            path,
+            junctions,
+            tolerance,
+            opposite,
+            ref_junctions: None,
+        }
+    }
+
+    fn is_known(&self, ref_id: i32, (first, last): (u32, u32)) -> bool {
+        let ref_junctions = self
+            .ref_junctions
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        let introns = match ref_junctions.get(&ref_id) {
+            Some(introns) => introns,
+            None => return false,
+        };
+        let lowest = introns.partition_point(|&(known_first, _)| known_first + self.tolerance < first);
+        introns[lowest..]
+            .iter()
+            .take_while(|&&(known_first, _)| known_first <= first + self.tolerance)
+            .any(|&(_, known_last)| known_last.abs_diff(last) <= self.tolerance)
+    }
+}
+
+impl FlagMaskFilter {
+    pub fn new(name: String, require_mask: u16, exclude_mask: u16, opposite: bool) -> FlagMaskFilter {
+        assert!(
+            require_mask & exclude_mask == 0,
+            "Flags cannot be both required and excluded! (overlapping bits: {})",
+            require_mask & exclude_mask
+        );
+        FlagMaskFilter {
+            name,
+            require_mask,
+            exclude_mask,
+            opposite,
+        }
+    }
+}
+
+impl SanityFilter {
+    pub fn new(name: String, opposite: bool) -> SanityFilter {
+        SanityFilter { name, opposite }
+    }
+
+    /// Describe the first contradiction between the fields of a record, if any:
+    /// - an unmapped read with a reference and position, unless it is placed at its mapped mate
+    ///   (which the SAM specification allows);
+    /// - an unmapped read with a non-zero mapping quality;
+    /// - an unmapped read with the reverse strand flag;
+    /// - a mapped read without a reference or position.
+    pub fn contradiction(record: &Record) -> Option<&'static str> {
+        let flag = record.flag();
+        let placed = record.ref_id() >= 0 && record.start() >= 0;
+        if flag.is_mapped() {
+            if !placed {
+                return Some("mapped read without a reference or position");
+            }
+            return None;
+        }
+        if placed && !(flag.is_paired() && flag.mate_is_mapped()) {
+            Some("unmapped read with a reference and position")
+        } else if record.mapq() != 0 {
+            Some("unmapped read with a non-zero mapping quality")
+        } else if flag.is_reverse_strand() {
+            Some("unmapped read on the reverse strand")
+        } else {
+            None
+        }
+    }
+}
+
+impl SubtractFilter {
+    pub fn new(name: String, path: PathBuf, opposite: bool) -> SubtractFilter {
+        SubtractFilter {
+            name,
+            path,
+            opposite,
+            intervals: None,
+        }
+    }
+
+    fn overlaps(&self, record: &Record) -> bool {
+        let intervals = self
+            .intervals
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        overlaps_intervals(intervals, record)
+    }
+}
+
+impl MateDistanceFilter {
+    pub fn new(name: String, min_distance: u32, max_distance: u32, opposite: bool) -> MateDistanceFilter {
+        assert!(
+            min_distance <= max_distance,
+            "Minimum distance must not be greater than the maximum distance!"
+        );
+        MateDistanceFilter {
+            name,
+            min_distance,
+            max_distance,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u32> {
+        utils::RangeBound::from_values(self.min_distance, self.max_distance)
+    }
+}
+
+impl SpecialContigFilter {
+    pub fn new(name: String, patterns: Vec<String>, opposite: bool) -> SpecialContigFilter {
+        assert!(!patterns.is_empty(), "At least one reference name pattern must be given!");
+        for pattern in &patterns {
+            assert!(glob::Pattern::new(pattern).is_ok(), "Invalid reference name pattern '{}'!", pattern);
+        }
+        SpecialContigFilter {
+            name,
+            patterns,
+            opposite,
+            ref_ids: None,
+        }
+    }
+}
+
+impl StrandFilter {
+    pub fn new(name: String, strand: utils::Strand, opposite: bool) -> StrandFilter {
+        StrandFilter {
+            name,
+            strand,
+            opposite,
+        }
+    }
+}
+
+impl AdapterFilter {
+    pub fn new(name: String, adapter: &str, max_mismatch: u32, min_overlap: u32, opposite: bool) -> AdapterFilter {
+        let adapter = adapter.to_ascii_uppercase();
+        assert!(
+            !adapter.is_empty() && adapter.chars().all(|c| matches!(c, 'A' | 'C' | 'G' | 'T' | 'N')),
+            "Adapter must consist of the nucleotides A, C, G, T, or N!"
+        );
+        assert!(min_overlap > 0, "Minimum overlap must be greater than 0!");
+        AdapterFilter {
+            name,
+            adapter,
+            max_mismatch,
+            min_overlap,
+            opposite,
+        }
+    }
+
+    // Whether the 3' end of the read (in its original orientation) starts an adapter copy, which may run past the
+    // end of the read. Overlaps shorter than the adapter allow proportionally fewer mismatches. 'N' matches any base.
+    fn has_adapter(&self, read: &[u8]) -> bool {
+        let adapter = self.adapter.as_bytes();
+        let min_overlap = (self.min_overlap as usize).min(adapter.len());
+        if read.len() < min_overlap {
+            return false;
+        }
+        (0..=read.len() - min_overlap).any(|start| {
+            let overlap = (read.len() - start).min(adapter.len());
+            let allowed = self.max_mismatch as usize * overlap / adapter.len();
+            let mismatches = read[start..start + overlap]
+                .iter()
+                .zip(adapter)
+                .filter(|&(&base, &adapter_base)| base != adapter_base && base != b'N' && adapter_base != b'N')
+                .count();
+            mismatches <= allowed
+        })
+    }
+}
+
+impl ExternalAnnotationFilter {
+    pub fn new(
+        name: String,
+        path: PathBuf,
+        column: String,
+        comparison: script::CompareOp,
+        threshold: f64,
+        opposite: bool,
+    ) -> ExternalAnnotationFilter {
+        ExternalAnnotationFilter {
+            name,
+            path,
+            column,
+            comparison,
+            threshold,
+            opposite,
+            values: None,
+        }
+    }
+}
+
+impl SaCountFilter {
+    pub fn new(name: String, min_count: u32, max_count: u32, opposite: bool) -> SaCountFilter {
+        SaCountFilter {
+            name,
+            min_count,
+            max_count,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u32> {
+        utils::RangeBound::from_values(self.min_count, self.max_count)
+    }
+}
+
+impl RefHomopolymerFilter {
+    pub fn new(name: String, path: PathBuf, min_length: u32, opposite: bool) -> RefHomopolymerFilter {
+        assert!(min_length > 1, "Minimum homopolymer length must be greater than 1!");
+        RefHomopolymerFilter {
+            name,
+            path,
+            min_length,
+            opposite,
+            tracts: None,
+        }
+    }
+}
+
+impl ReadNameFieldFilter {
+    pub fn new(name: String, field: utils::NameField, pattern: utils::NameFieldMatch, opposite: bool) -> ReadNameFieldFilter {
+        ReadNameFieldFilter {
+            name,
+            field,
+            pattern,
+            opposite,
+        }
+    }
+}
+
+impl HardClipFilter {
+    pub fn new(name: String, min_clip: u32, max_clip: u32, opposite: bool) -> HardClipFilter {
+        HardClipFilter {
+            name,
+            min_clip,
+            max_clip,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u32> {
+        utils::RangeBound::from_values(self.min_clip, self.max_clip)
+    }
+}
+
+impl TrimmedLengthFilter {
+    pub fn new(name: String, quality_threshold: u8, min_len: u32, max_len: u32, opposite: bool) -> TrimmedLengthFilter {
+        TrimmedLengthFilter {
+            name,
+            quality_threshold,
+            min_len,
+            max_len,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u32> {
+        utils::RangeBound::from_values(self.min_len, self.max_len)
+    }
+}
+
+impl StrandAssignFilter {
+    pub fn new(name: String, library_type: utils::LibraryType, strand: utils::Strand, opposite: bool) -> StrandAssignFilter {
+        StrandAssignFilter {
+            name,
+            library_type,
+            strand,
+            opposite,
+        }
+    }
+}
+
+impl SingletonFilter {
+    pub fn new(name: String, opposite: bool) -> SingletonFilter {
+        SingletonFilter { name, opposite }
+    }
+}
+
+impl TaggedPrimaryFilter {
+    pub fn new(name: String, tag_name: TagName, opposite: bool) -> TaggedPrimaryFilter {
+        TaggedPrimaryFilter {
+            name,
+            tag_name,
+            opposite,
+        }
+    }
+}
+
+impl LowQualFractionFilter {
+    pub fn new(
+        name: String,
+        quality_threshold: u8,
+        min_fraction: f64,
+        max_fraction: f64,
+        opposite: bool,
+    ) -> LowQualFractionFilter {
+        assert!(
+            min_fraction <= max_fraction,
+            "Minimum fraction must not exceed the maximum fraction!"
+        );
+        LowQualFractionFilter {
+            name,
+            quality_threshold,
+            min_fraction,
+            max_fraction,
+            opposite,
+        }
+    }
+}
+
+impl ContigMarginFilter {
+    pub fn new(name: String, margin: u32, opposite: bool) -> ContigMarginFilter {
+        ContigMarginFilter {
+            name,
+            margin,
+            opposite,
+            ref_lengths: None,
+        }
+    }
+}
+
+impl BreakpointFilter {
+    /// `position` is the 1-based coordinate of the last reference base before the breakpoint
+    pub fn new(name: String, reference: String, position: u32, tolerance: u32, opposite: bool) -> BreakpointFilter {
+        BreakpointFilter {
+            name,
+            reference,
+            position,
+            tolerance,
+            opposite,
+            ref_id: None,
+        }
+    }
+
+    // a discordant pair with one mate starting before the breakpoint and the other at or after it.
+    // Only the start positions are compared, so both mates of a pair get the same outcome
+    fn straddles(&self, record: &Record) -> bool {
+        let flag = record.flag();
+        let same_reference = record.mate_ref_id() == record.ref_id();
+        if !flag.is_paired() || !flag.mate_is_mapped() || flag.all_segments_aligned() || !same_reference {
+            return false;
+        }
+        let junction = self.position as i64;
+        (record.start() as i64 >= junction) != (record.mate_start() as i64 >= junction)
+    }
+
+    // a split read with a soft clip starting or ending within `tolerance` bases of the breakpoint
+    fn clipped_at(&self, record: &Record) -> bool {
+        let cigar = utils::effective_cigar(record);
+        let junction = self.position as i64;
+        let near = |boundary: i64| boundary.abs_diff(junction) <= self.tolerance as u64;
+        (cigar.soft_clipping(true) > 0 && near(record.start() as i64))
+            || (cigar.soft_clipping(false) > 0 && near(record.calculate_end() as i64))
+    }
+}
+
+impl PerfectMatchFilter {
+    pub fn new(name: String, path: PathBuf, opposite: bool) -> PerfectMatchFilter {
+        PerfectMatchFilter {
+            name,
+            path,
+            opposite,
+            sequences: None,
+        }
+    }
+
+    // compares the aligned bases to the reference, ignoring case. Soft-clipped bases are not compared,
+    // and introns ('N') are skipped, but any insertion or deletion makes the alignment imperfect
+    fn matches_reference(record: &Record, reference: &[u8]) -> bool {
+        let sequence = record.sequence();
+        let mut ref_position = record.start() as usize;
+        let mut query_position = 0;
+        for (len, operation) in utils::effective_cigar(record).iter() {
+            let len = len as usize;
+            match operation {
+                Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => {
+                    let Some(ref_bases) = reference.get(ref_position..ref_position + len) else {
+                        return false;
+                    };
+                    let matching = ref_bases
+                        .iter()
+                        .enumerate()
+                        .all(|(i, ref_base)| ref_base.eq_ignore_ascii_case(&sequence.at(query_position + i)));
+                    if !matching {
+                        return false;
+                    }
+                    ref_position += len;
+                    query_position += len;
+                }
+                Operation::Insertion | Operation::Deletion => return false,
+                Operation::Soft => query_position += len,
+                Operation::Skip => ref_position += len,
+                Operation::Hard | Operation::Padding => {}
+            }
+        }
+        true
+    }
+}
+
+impl LeftmostSplitFilter {
+    pub fn new(name: String, opposite: bool) -> LeftmostSplitFilter {
+        LeftmostSplitFilter {
+            name,
+            opposite,
+            ref_ids: None,
+        }
+    }
+}
+
+impl RgQualityFilter {
+    pub fn new(name: String, max_rate: f64, opposite: bool) -> RgQualityFilter {
+        assert!(max_rate.is_finite() && max_rate >= 0.0, "Maximum mismatch rate must be a non-negative number!");
+        RgQualityFilter {
+            name,
+            max_rate,
+            opposite,
+        }
+    }
+}
+
+impl RegionModeFilter {
+    pub fn new(name: String, region: String, mode: utils::RegionMode, opposite: bool) -> RegionModeFilter {
+        // fail on a malformed region before the filter is saved
+        utils::parse_region(&region);
+        RegionModeFilter {
+            name,
+            region,
+            mode,
+            opposite,
+            span: None,
+        }
+    }
+}
+
+impl QualityCliffFilter {
+    pub fn new(name: String, quality_threshold: u8, min_len: u32, window: u32, opposite: bool) -> QualityCliffFilter {
+        assert!(window > 0, "Window size must be greater than 0!");
+        QualityCliffFilter {
+            name,
+            quality_threshold,
+            min_len,
+            window,
+            opposite,
+        }
+    }
+}
+
+impl CoverageFilter {
+    pub fn new(name: String, path: PathBuf, min_coverage: f64, max_coverage: f64, opposite: bool) -> CoverageFilter {
+        assert!(
+            min_coverage <= max_coverage,
+            "Minimum coverage must not exceed the maximum coverage!"
+        );
+        CoverageFilter {
+            name,
+            path,
+            min_coverage,
+            max_coverage,
+            opposite,
+            regions: None,
+        }
+    }
+}
+
+impl TrimFilter {
+    pub fn new(
+        name: String,
+        leading: u32,
+        trailing: u32,
+        quality_threshold: Option<u8>,
+        min_len: u32,
+        opposite: bool,
+    ) -> TrimFilter {
+        TrimFilter {
+            name,
+            leading,
+            trailing,
+            quality_threshold,
+            min_len,
+            opposite,
+        }
+    }
+
+    // the number of bases to trim from the start and the end of the stored sequence. The fixed trimming is done first,
+    // and the quality trimming on what is left of the read
+    fn trimmed(&self, record: &Record) -> (u32, u32) {
+        let len = record.query_len();
+        let (mut start, mut end) = if record.flag().is_reverse_strand() {
+            (self.trailing, self.leading)
+        } else {
+            (self.leading, self.trailing)
+        };
+        start = start.min(len);
+        end = end.min(len - start);
+        let qualities = record.qualities();
+        if let Some(threshold) = self.quality_threshold.filter(|_| qualities.available()) {
+            let (quality_start, quality_end) =
+                utils::quality_trim_counts(&qualities.raw()[start as usize..(len - end) as usize], threshold);
+            start += quality_start;
+            end += quality_end;
+        }
+        (start, end)
+    }
+}
+
+impl ClipRatioFilter {
+    pub fn new(name: String, min_ratio: f64, max_ratio: f64, opposite: bool) -> ClipRatioFilter {
+        assert!(min_ratio <= max_ratio, "Minimum ratio must not exceed the maximum ratio!");
+        ClipRatioFilter {
+            name,
+            min_ratio,
+            max_ratio,
+            opposite,
+        }
+    }
+}
+
+impl DistinctBasesFilter {
+    pub fn new(name: String, min_distinct: u8, max_distinct: u8, opposite: bool) -> DistinctBasesFilter {
+        assert!(
+            min_distinct <= max_distinct && max_distinct <= 4,
+            "Numbers of distinct bases must be between 0 and 4, with the minimum not exceeding the maximum!"
+        );
+        DistinctBasesFilter {
+            name,
+            min_distinct,
+            max_distinct,
+            opposite,
+        }
+    }
+}
+
+impl RecordIndexFilter {
+    pub fn new(name: String, first_index: u64, last_index: u64, opposite: bool) -> RecordIndexFilter {
+        RecordIndexFilter {
+            name,
+            first_index,
+            last_index,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u64> {
+        utils::RangeBound::from_values(self.first_index, self.last_index)
+    }
+}
+
+impl ConcordantPairFilter {
+    pub fn new(name: String, min_insert: u32, max_insert: u32, opposite: bool) -> ConcordantPairFilter {
+        assert!(
+            min_insert <= max_insert,
+            "Minimum insert size must not be greater than the maximum insert size!"
+        );
+        ConcordantPairFilter {
+            name,
+            min_insert,
+            max_insert,
+            opposite,
+        }
+    }
+
+    fn range(&self) -> utils::RangeBound<u32> {
+        utils::RangeBound::from_values(self.min_insert, self.max_insert)
+    }
+}
+
+impl AlignedGcFilter {
+    pub fn new(name: String, min_fraction: f64, max_fraction: f64, opposite: bool) -> AlignedGcFilter {
+        assert!(
+            min_fraction <= max_fraction,
+            "Minimum fraction must not exceed the maximum fraction!"
+        );
+        AlignedGcFilter {
+            name,
+            min_fraction,
+            max_fraction,
+            opposite,
+        }
+    }
+}
+
+impl AsPerBaseFilter {
+    pub fn new(name: String, min_score: f64, max_score: f64, opposite: bool) -> AsPerBaseFilter {
+        assert!(min_score <= max_score, "Minimum score must not exceed the maximum score!");
+        AsPerBaseFilter {
+            name,
+            min_score,
+            max_score,
+            opposite,
+        }
+    }
+}
+
+impl InternalClipFilter {
+    pub fn new(name: String, opposite: bool) -> InternalClipFilter {
+        InternalClipFilter { name, opposite }
+    }
+
+    // a soft clip is terminal if only hard clips are between it and an end of the CIGAR
+    fn has_internal_clip(ops: &[(u32, Operation)]) -> bool {
+        let is_hard = |&(_, operation): &(u32, Operation)| operation == Operation::Hard;
+        ops.iter().enumerate().any(|(i, &(_, operation))| {
+            operation == Operation::Soft && !ops[..i].iter().all(is_hard) && !ops[i + 1..].iter().all(is_hard)
+        })
+    }
+}
+
+#[typetag::serde]
+impl Filtering for CombinedFilter {
+    // filter2 is skipped when filter1 already decides the result, which saves work for expensive filters
+    fn apply_to(&self, record: &Record) -> bool {
+        let result1 = self.filter1.apply_to(record);
+        if let Some(result) = self.operator.short_circuit(result1) {
+            return result;
+        }
+        let result2 = self.filter2.apply_to(record);
+        self.operator.apply(result1, result2)
+    }
+
+    fn prepare(&mut self, header: &bam::Header) {
+        self.filter1.prepare(header);
+        self.filter2.prepare(header);
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "CombinedFilter(name={}, filter1={}, filter2={}, operator={:?})",
+            self.name,
+            self.filter1.name(),
+            self.filter2.name(),
+            self.operator
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    // combining a filter with itself is either redundant or gives a constant result,
+    // and filters that depend on other records can't be evaluated within a combination
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.filter1.name() == self.filter2.name() {
+            problems.push(format!("combines filter {} with itself using {:?}", self.filter1.name(), self.operator));
+        }
+        for input in self.inputs().into_iter().filter(|input| input.to_streaming().is_some()) {
+            problems.push(format!("input {} depends on other records and cannot be combined", input.name()));
+        }
+        problems
+    }
+
+    // an unmapped read fails if the operator fails for every outcome the inputs may have for it
+    fn rejects_unmapped(&self) -> bool {
+        let outcomes = |filter: &dyn Filtering| if filter.rejects_unmapped() { vec![false] } else { vec![false, true] };
+        let outcomes2 = outcomes(self.filter2.as_ref());
+        !outcomes(self.filter1.as_ref())
+            .into_iter()
+            .any(|result1| outcomes2.iter().any(|&result2| self.operator.apply(result1, result2)))
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let mut args = vec![
+            "combine".to_string(),
+            self.filter1.name().to_string(),
+            self.operator.to_possible_value().unwrap().get_name().to_string(),
+            self.filter2.name().to_string(),
+            "--name".to_string(),
+            self.name.clone(),
+        ];
+        if self.name == self.filter1.name() || self.name == self.filter2.name() {
+            args.push("--force".to_string());
+        }
+        Some(args)
+    }
+
+    fn inputs(&self) -> Vec<&dyn Filtering> {
+        vec![self.filter1.as_ref(), self.filter2.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Filtering>> {
+        vec![&mut self.filter1, &mut self.filter2]
+    }
+}
+
+#[typetag::serde]
+impl Filtering for FlagFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        let flags = record.flag();
+        if flags.no_bits(self.remove_flags) {
+            utils::_opposite(true, self.opposite)
+        } else {
+            utils::_opposite(false, self.opposite)
+        }
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "FlagFilter(name={}, remove_flags={}, opposite={})",
+            self.name, self.remove_flags, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite && self.remove_flags & bam::record::RECORD_UNMAPPED != 0
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["flag".to_string(), self.remove_flags.to_string()]))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for LengthFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        utils::_opposite(self.range().contains(record.query_len()), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "LengthFilter(name={}, min_len={}, max_len={}, opposite={})",
+            self.name, self.range().min_str(), self.range().max_str(), self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["length".to_string(), self.range().min_str(), self.range().max_str()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for TagFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        if let Some(tag) = record.tags().get(&self.tag_name) {
+            let expanded_tag_val = utils::_minimal_tag_to_tag(&self.tag_value);
+            let equal = if self.numeric {
+                utils::_are_tag_values_numerically_equal(&tag, &expanded_tag_val)
+            } else {
+                utils::_are_tag_values_equal(&tag, &expanded_tag_val)
+            };
+            if equal {
+                utils::_opposite(true, self.opposite)
+            } else {
+                utils::_opposite(false, self.opposite)
+            }
+        } else {
             utils::_opposite(false, self.opposite)
+        }
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "TagFilter(name={}, tag_name={:#?}, tag_value={}, numeric={}, opposite={})",
+            self.name, self.tag_name, self.tag_value, self.numeric, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        tag_name_problems(&self.tag_name)
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let (tag_type, tag_value) = match &self.tag_value {
+            utils::MinimalTagValue::Char(value) => ("char", value.to_string()),
+            utils::MinimalTagValue::Int(value) => ("int", value.to_string()),
+            utils::MinimalTagValue::Float(value) => ("float", value.to_string()),
+            utils::MinimalTagValue::String(value) => ("string", value.clone()),
+        };
+        let mut subcommand = vec![
+            "tag".to_string(),
+            String::from_utf8_lossy(&self.tag_name).to_string(),
+            tag_type.to_string(),
+        ];
+        if self.numeric {
+            subcommand.push("--numeric".to_string());
+        }
+        // the value may be negative, and must not be mistaken for an option
+        subcommand.extend(["--".to_string(), tag_value]);
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for MapqFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        utils::_opposite(self.range().contains(record.mapq()), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "MapqFilter(name={}, min_mapq={}, max_mapq={}, opposite={})",
+            self.name, self.range().min_str(), self.range().max_str(), self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["mapq".to_string(), self.range().min_str(), self.range().max_str()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for RefNameFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        let this_ref_id = record.ref_id();
+        utils::_opposite(this_ref_id == self.ref_id, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "RefNameFilter(name={}, ref_id={}, opposite={})",
+            self.name, self.ref_id, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn describe(&self, header: Option<&bam::Header>) -> String {
+        let ref_name = match header {
+            Some(_) if self.ref_id < 0 => "*".to_string(),
+            Some(header) => match header.reference_name(self.ref_id as u32) {
+                Some(ref_name) => ref_name.to_string(),
+                None => "not in header".to_string(),
+            },
+            None => return self.repr(),
+        };
+        format!(
+            "RefNameFilter(name={}, ref_id={} ({}), opposite={})",
+            self.name, self.ref_id, ref_name, self.opposite
+        )
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["ref-name".to_string(), "--".to_string(), self.ref_id.to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for NthNucleotideFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        assert_ne!(self.position, 0, "Position must be non-zero!");
+        let len = record.query_len() as i64;
+        if self.position.abs() > len {
+            return utils::_opposite(false, self.opposite);
+        }
+        if !record.sequence().available() {
+            return utils::_opposite(false, self.opposite);
+        }
+
+        let position = if self.position < 0 {
+            len + self.position
+        } else {
+            self.position - 1
+        };
+
+        let this_nuc = if record.flag().is_reverse_strand() {
+            let range = (len - position - 1) as usize..(len - position) as usize;
+            record.sequence().rev_compl(range).next().unwrap() as char
+        } else {
+            record.sequence().at(position as usize) as char
+        };
+
+        if self.n_is_wildcard && (this_nuc == 'N') {
+            utils::_opposite(true, self.opposite)
+        } else {
+            utils::_opposite(this_nuc == self.nucleotide, self.opposite)
+        }
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "NthNucleotideFilter(name={}, position={}, nucleotide={:?}, opposite={})",
+            self.name, self.position, self.nucleotide, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let mut subcommand = vec!["nucleotide".to_string()];
+        if self.n_is_wildcard {
+            subcommand.push("--n-is-wildcard".to_string());
+        }
+        // positions relative to the end of the read are negative, and must not be mistaken for options
+        subcommand.extend(["--".to_string(), self.position.to_string(), self.nucleotide.to_string()]);
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for CompositionFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        if !record.sequence().available() {
+            return utils::_opposite(false, self.opposite);
+        }
+        let len = record.query_len() as usize;
+        // count bases in the orientation of the original read, like NthNucleotideFilter
+        let nucleotides: Vec<u8> = if record.flag().is_reverse_strand() {
+            record.sequence().rev_compl_acgtn_only(0..len).collect()
+        } else {
+            record.sequence().to_vec_acgtn_only()
+        };
+        let mut matched = 0;
+        let mut total = 0;
+        for nuc in nucleotides {
+            if nuc == b'N' {
+                continue;
+            }
+            total += 1;
+            if self.bases.as_bytes().contains(&nuc) {
+                matched += 1;
+            }
+        }
+        if total == 0 {
+            return utils::_opposite(false, self.opposite);
+        }
+        let fraction = matched as f64 / total as f64;
+        utils::_opposite(
+            fraction >= self.min_fraction && fraction <= self.max_fraction,
+            self.opposite,
+        )
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "CompositionFilter(name={}, bases={}, min_fraction={}, max_fraction={}, opposite={})",
+            self.name, self.bases, self.min_fraction, self.max_fraction, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        f64_range_problems("fraction", self.min_fraction, self.max_fraction)
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "composition".to_string(),
+            self.bases.clone(),
+            self.min_fraction.to_string(),
+            self.max_fraction.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for ClipContentFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        if !record.sequence().available() {
+            return utils::_opposite(false, self.opposite);
+        }
+        let len = record.sequence().len();
+        let cigar = utils::effective_cigar(record);
+        let left = cigar.soft_clipping(true) as usize;
+        let right = cigar.soft_clipping(false) as usize;
+        if left + right > len {
+            return utils::_opposite(false, self.opposite);
+        }
+        // orient both clips like the original read, so that the leading clip is always the 5' one
+        let (leading, trailing): (Vec<u8>, Vec<u8>) = if record.flag().is_reverse_strand() {
+            (
+                record.sequence().rev_compl_acgtn_only(len - right..len).collect(),
+                record.sequence().rev_compl_acgtn_only(0..left).collect(),
+            )
+        } else {
+            (
+                record.sequence().subseq_acgtn_only(0..left).collect(),
+                record.sequence().subseq_acgtn_only(len - right..len).collect(),
+            )
+        };
+        let motif = self.motif.as_bytes();
+        let reverse_motif = self.both_strands.then(|| utils::reverse_complement(motif));
+        let contains_motif = |clip: &[u8]| {
+            utils::contains_with_mismatches(clip, motif, self.max_mismatch)
+                || reverse_motif
+                    .as_ref()
+                    .is_some_and(|reverse_motif| utils::contains_with_mismatches(clip, reverse_motif, self.max_mismatch))
+        };
+        let found = match self.side {
+            utils::ClipSide::Leading => contains_motif(&leading),
+            utils::ClipSide::Trailing => contains_motif(&trailing),
+            utils::ClipSide::Either => contains_motif(&leading) || contains_motif(&trailing),
+        };
+        utils::_opposite(found, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "ClipContentFilter(name={}, motif={}, side={:?}, max_mismatch={}, both_strands={}, opposite={})",
+            self.name, self.motif, self.side, self.max_mismatch, self.both_strands, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let mut subcommand = vec![
+            "clip-content".to_string(),
+            self.motif.clone(),
+            "--side".to_string(),
+            self.side.to_possible_value().unwrap().get_name().to_string(),
+        ];
+        if self.max_mismatch > 0 {
+            subcommand.extend(["--max-mismatch".to_string(), self.max_mismatch.to_string()]);
+        }
+        if self.both_strands {
+            subcommand.push("--both-strands".to_string());
+        }
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for MdMismatchFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        let mismatches = match record.tags().get(b"MD") {
+            Some(TagValue::String(md, _)) => std::str::from_utf8(md)
+                .ok()
+                .and_then(utils::count_md_mismatches),
+            _ => None,
+        };
+        match mismatches {
+            Some(n) => utils::_opposite(self.range().contains(n), self.opposite),
+            None => utils::_opposite(false, self.opposite),
+        }
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "MdMismatchFilter(name={}, min_mismatches={}, max_mismatches={}, opposite={})",
+            self.name, self.range().min_str(), self.range().max_str(), self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "md-mismatch".to_string(),
+            self.range().min_str(),
+            self.range().max_str(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for PairConcordantFilter {
+    // decided by the stream, which sees both mates
+    fn apply_to(&self, _record: &Record) -> bool {
+        needs_stream(&self.name)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "PairConcordantFilter(name={}, filter={}, opposite={})",
+            self.name,
+            self.filter.name(),
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["pair-concordant".to_string(), self.filter.name().to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    fn inputs(&self) -> Vec<&dyn Filtering> {
+        vec![self.filter.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Filtering>> {
+        vec![&mut self.filter]
+    }
+
+    fn prepare(&mut self, header: &bam::Header) {
+        self.filter.prepare(header);
+    }
+
+    fn to_streaming(&self) -> Option<Box<dyn StreamingFilter>> {
+        Some(Box::new(MatePairStream::new(
+            self.filter.clone(),
+            BoolOperator::AND,
+            self.opposite,
+        )))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for ScriptFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        utils::_opposite(self.compiled.evaluate(record), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "ScriptFilter(name={}, expression={:?}, opposite={})",
+            self.name, self.expression, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["script".to_string(), "--".to_string(), self.expression.clone()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for JunctionsFilter {
+    // passes spliced reads whose every intron matches a known junction
+    fn apply_to(&self, record: &Record) -> bool {
+        let introns = utils::intron_spans(record);
+        let res = !introns.is_empty() && introns.into_iter().all(|intron| self.is_known(record.ref_id(), intron));
+        utils::_opposite(res, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "JunctionsFilter(name={}, junctions={}, tolerance={}, opposite={})",
+            self.name,
+            self.junctions.values().map(Vec::len).sum::<usize>(),
+            self.tolerance,
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "junctions".to_string(),
+            self.path.display().to_string(),
+            "--tolerance".to_string(),
+            self.tolerance.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    // junctions on references that are not in the input can't match any of its reads, and are skipped
+    fn prepare(&mut self, header: &bam::Header) {
+        let ref_junctions = self
+            .junctions
+            .iter()
+            .filter_map(|(ref_name, introns)| Some((header.reference_id(ref_name)? as i32, introns.clone())))
+            .collect();
+        self.ref_junctions = Some(ref_junctions);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for FlagMaskFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        let flags = record.flag();
+        utils::_opposite(
+            flags.all_bits(self.require_mask) && flags.no_bits(self.exclude_mask),
+            self.opposite,
+        )
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "FlagMaskFilter(name={}, require_mask={}, exclude_mask={}, opposite={})",
+            self.name, self.require_mask, self.exclude_mask, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite && self.exclude_mask & bam::record::RECORD_UNMAPPED != 0
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "flag-mask".to_string(),
+            "--require-mask".to_string(),
+            self.require_mask.to_string(),
+            "--exclude-mask".to_string(),
+            self.exclude_mask.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for SanityFilter {
+    // passes records whose fields are consistent with each other
+    fn apply_to(&self, record: &Record) -> bool {
+        utils::_opposite(SanityFilter::contradiction(record).is_none(), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!("SanityFilter(name={}, opposite={})", self.name, self.opposite)
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["sanity".to_string()]))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for SubtractFilter {
+    // passes reads that don't overlap any interval
+    fn apply_to(&self, record: &Record) -> bool {
+        utils::_opposite(!self.overlaps(record), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "SubtractFilter(name={}, path={}, opposite={})",
+            self.name,
+            self.path.display(),
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["subtract".to_string(), self.path.display().to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    // intervals on references that are not in the input can't overlap any of its reads, and are skipped
+    fn prepare(&mut self, header: &bam::Header) {
+        let mut intervals: HashMap<i32, Vec<(i32, i32)>> = HashMap::new();
+        for (ref_name, start, end) in utils::read_intervals(&self.path) {
+            if let Some(ref_id) = header.reference_id(&ref_name) {
+                intervals.entry(ref_id as i32).or_default().push((start as i32, end as i32));
+            }
+        }
+        merge_intervals(&mut intervals);
+        self.intervals = Some(intervals);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for MateDistanceFilter {
+    // computed from the positions rather than TLEN, which some tools set incorrectly.
+    // Unpaired reads, unmapped reads or mates, and mates on another reference fail.
+    fn apply_to(&self, record: &Record) -> bool {
+        let flag = record.flag();
+        let placed_with_mate = record.ref_id() >= 0 && record.ref_id() == record.mate_ref_id();
+        if !flag.is_paired() || !flag.is_mapped() || !flag.mate_is_mapped() || !placed_with_mate {
+            return utils::_opposite(false, self.opposite);
+        }
+        let distance = record.start().abs_diff(record.mate_start());
+        utils::_opposite(self.range().contains(distance), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "MateDistanceFilter(name={}, min_distance={}, max_distance={}, opposite={})",
+            self.name, self.range().min_str(), self.range().max_str(), self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "mate-distance".to_string(),
+            self.range().min_str(),
+            self.range().max_str(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for SpecialContigFilter {
+    // passes reads mapped to a reference whose name matches a pattern; unplaced reads fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let ref_ids = self
+            .ref_ids
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        utils::_opposite(ref_ids.contains(&record.ref_id()), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "SpecialContigFilter(name={}, patterns={:?}, opposite={})",
+            self.name, self.patterns, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let mut subcommand = vec!["special-contig".to_string(), "--".to_string()];
+        subcommand.extend(self.patterns.iter().cloned());
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    fn prepare(&mut self, header: &bam::Header) {
+        let patterns: Vec<glob::Pattern> = self
+            .patterns
+            .iter()
+            .map(|pattern| glob::Pattern::new(pattern).unwrap())
+            .collect();
+        let ref_ids = header
+            .reference_names()
+            .iter()
+            .enumerate()
+            .filter(|(_, ref_name)| patterns.iter().any(|pattern| pattern.matches(ref_name)))
+            .map(|(ref_id, _)| ref_id as i32)
+            .collect();
+        self.ref_ids = Some(ref_ids);
+    }
+
+    fn describe(&self, header: Option<&bam::Header>) -> String {
+        let Some(header) = header else {
+            return self.repr();
+        };
+        let mut prepared = self.clone();
+        prepared.prepare(header);
+        let ref_names: Vec<&str> = prepared
+            .ref_ids
+            .unwrap()
+            .into_iter()
+            .map(|ref_id| header.reference_name(ref_id as u32).unwrap())
+            .collect();
+        format!(
+            "SpecialContigFilter(name={}, patterns={:?}, matches={:?}, opposite={})",
+            self.name, self.patterns, ref_names, self.opposite
+        )
+    }
+}
+
+#[typetag::serde]
+impl Filtering for StrandFilter {
+    // unmapped reads aren't aligned to either strand, and fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let flags = record.flag();
+        let on_strand = match self.strand {
+            utils::Strand::Forward => !flags.is_reverse_strand(),
+            utils::Strand::Reverse => flags.is_reverse_strand(),
+        };
+        utils::_opposite(flags.is_mapped() && on_strand, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "StrandFilter(name={}, strand={:?}, opposite={})",
+            self.name, self.strand, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let strand = self.strand.to_possible_value().unwrap().get_name().to_string();
+        Some(create_args(&self.name, self.opposite, &["strand".to_string(), strand]))
+    }
+}
+
+/// Number of evaluations of a filter, and the total time they took
+#[derive(Default)]
+pub struct FilterProfile {
+    calls: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl FilterProfile {
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    pub fn micros(&self) -> u64 {
+        self.nanos.load(Ordering::Relaxed) / 1000
+    }
+}
+
+// Times the evaluations of the wrapped filter. Only used while applying filters with --profile-filters, and never saved
+#[derive(Serialize, Deserialize, Clone)]
+struct ProfiledFilter {
+    filter: Box<dyn Filtering>,
+    #[serde(skip)]
+    profile: Arc<FilterProfile>,
+}
+
+#[typetag::serde]
+impl Filtering for ProfiledFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        let start = Instant::now();
+        let res = self.filter.apply_to(record);
+        self.profile.nanos.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        self.profile.calls.fetch_add(1, Ordering::Relaxed);
+        res
+    }
+
+    fn repr(&self) -> String {
+        self.filter.repr()
+    }
+    fn name(&self) -> &str {
+        self.filter.name()
+    }
+
+    fn to_streaming(&self) -> Option<Box<dyn StreamingFilter>> {
+        self.filter.to_streaming()
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        self.filter.rejects_unmapped()
+    }
+
+    fn prepare(&mut self, header: &bam::Header) {
+        self.filter.prepare(header);
+    }
+
+    fn transforms(&self) -> bool {
+        self.filter.transforms()
+    }
+
+    fn transform(&self, record: &mut Record) {
+        self.filter.transform(record);
+    }
+
+    fn describe(&self, header: Option<&bam::Header>) -> String {
+        self.filter.describe(header)
+    }
+}
+
+/// Wrap every leaf filter of a filter tree (the filters not built from other filters) with a timer,
+/// returning the name and profile of each leaf in evaluation order
+pub fn profile_leaves(filter: &mut Box<dyn Filtering>) -> Vec<(String, Arc<FilterProfile>)> {
+    let mut inputs = filter.inputs_mut();
+    if inputs.is_empty() {
+        let profile = Arc::new(FilterProfile::default());
+        let name = filter.name().to_string();
+        *filter = Box::new(ProfiledFilter {
+            filter: filter.clone(),
+            profile: profile.clone(),
+        });
+        return vec![(name, profile)];
+    }
+    inputs.iter_mut().flat_map(|input| profile_leaves(input)).collect()
+}
+
+#[typetag::serde]
+impl Filtering for AdapterFilter {
+    // passes reads with adapter contamination at their 3' end; reads without a sequence fail
+    fn apply_to(&self, record: &Record) -> bool {
+        if !record.sequence().available() {
+            return utils::_opposite(false, self.opposite);
+        }
+        let len = record.query_len() as usize;
+        let read: Vec<u8> = if record.flag().is_reverse_strand() {
+            record.sequence().rev_compl_acgtn_only(0..len).collect()
+        } else {
+            record.sequence().to_vec_acgtn_only()
+        };
+        utils::_opposite(self.has_adapter(&read), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "AdapterFilter(name={}, adapter={}, max_mismatch={}, min_overlap={}, opposite={})",
+            self.name, self.adapter, self.max_mismatch, self.min_overlap, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "adapter".to_string(),
+            self.adapter.clone(),
+            "--max-mismatch".to_string(),
+            self.max_mismatch.to_string(),
+            "--min-overlap".to_string(),
+            self.min_overlap.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for ExternalAnnotationFilter {
+    // reads missing from the annotation file fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let values = self
+            .values
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        let res = match values.get(record.name()) {
+            Some(&value) => self.comparison.apply(value, self.threshold),
+            None => false,
+        };
+        utils::_opposite(res, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "ExternalAnnotationFilter(name={}, path={}, column={}, comparison={:?}, threshold={}, opposite={})",
+            self.name,
+            self.path.display(),
+            self.column,
+            self.comparison,
+            self.threshold,
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "external".to_string(),
+            self.path.display().to_string(),
+            self.column.clone(),
+            self.comparison.to_possible_value().unwrap().get_name().to_string(),
+            "--".to_string(),
+            self.threshold.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    // the annotations don't depend on the header, so they are only loaded once
+    fn prepare(&mut self, _header: &bam::Header) {
+        if self.values.is_none() {
+            self.values = Some(Arc::new(utils::read_annotation_column(&self.path, &self.column)));
+        }
+    }
+}
+
+#[typetag::serde]
+impl Filtering for SaCountFilter {
+    // the SA tag lists one 'rname,pos,strand,CIGAR,mapQ,NM;' entry per supplementary alignment
+    fn apply_to(&self, record: &Record) -> bool {
+        let count = match record.tags().get(b"SA") {
+            Some(TagValue::String(sa, _)) => sa.split(|&c| c == b';').filter(|entry| !entry.is_empty()).count() as u32,
+            _ => 0,
+        };
+        utils::_opposite(self.range().contains(count), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "SaCountFilter(name={}, min_count={}, max_count={}, opposite={})",
+            self.name, self.range().min_str(), self.range().max_str(), self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["sa-count".to_string(), self.range().min_str(), self.range().max_str()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for RefHomopolymerFilter {
+    // passes reads whose aligned span doesn't overlap any homopolymer tract
+    fn apply_to(&self, record: &Record) -> bool {
+        let tracts = self
+            .tracts
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        utils::_opposite(!overlaps_intervals(tracts, record), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "RefHomopolymerFilter(name={}, path={}, min_length={}, opposite={})",
+            self.name,
+            self.path.display(),
+            self.min_length,
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "ref-homopolymer".to_string(),
+            self.path.display().to_string(),
+            "--min-length".to_string(),
+            self.min_length.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    // sequences that are not references of the input can't overlap any of its reads, and are skipped
+    fn prepare(&mut self, header: &bam::Header) {
+        let mut tracts = HashMap::new();
+        for (ref_name, sequence) in utils::read_fasta(&self.path) {
+            if let Some(ref_id) = header.reference_id(&ref_name) {
+                let ref_tracts = utils::homopolymer_tracts(&sequence, self.min_length)
+                    .into_iter()
+                    .map(|(start, end)| (start as i32, end as i32))
+                    .collect();
+                tracts.insert(ref_id as i32, ref_tracts);
+            }
+        }
+        self.tracts = Some(tracts);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for ReadNameFieldFilter {
+    // reads whose name is not an Illumina read name fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let matches = self
+            .field
+            .value_in(record.name())
+            .is_some_and(|value| self.pattern.matches(value));
+        utils::_opposite(matches, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "ReadNameFieldFilter(name={}, field={:?}, pattern={}, opposite={})",
+            self.name, self.field, self.pattern, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let field = self.field.to_possible_value().unwrap().get_name().to_string();
+        Some(create_args(&self.name, self.opposite, &["name-field".to_string(), field, self.pattern.to_string()]))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for HardClipFilter {
+    // total length of the 'H' operations at both ends of the read
+    fn apply_to(&self, record: &Record) -> bool {
+        let clipped: u32 = utils::effective_cigar(record)
+            .iter()
+            .filter(|(_, operation)| *operation == Operation::Hard)
+            .map(|(len, _)| len)
+            .sum();
+        utils::_opposite(self.range().contains(clipped), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "HardClipFilter(name={}, min_clip={}, max_clip={}, opposite={})",
+            self.name, self.range().min_str(), self.range().max_str(), self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["hard-clip".to_string(), self.range().min_str(), self.range().max_str()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for TrimmedLengthFilter {
+    // the record itself is not trimmed
+    fn apply_to(&self, record: &Record) -> bool {
+        let trimmed_len = utils::quality_trimmed_len(record, self.quality_threshold);
+        utils::_opposite(self.range().contains(trimmed_len), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "TrimmedLengthFilter(name={}, quality_threshold={}, min_len={}, max_len={}, opposite={})",
+            self.name,
+            self.quality_threshold,
+            self.range().min_str(),
+            self.range().max_str(),
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "trimmed-length".to_string(),
+            "--quality".to_string(),
+            self.quality_threshold.to_string(),
+            self.range().min_str(),
+            self.range().max_str(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for StrandAssignFilter {
+    // unmapped reads can't be assigned to a strand, and fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let assigned = record.flag().is_mapped() && self.library_type.transcript_strand(record) == self.strand;
+        utils::_opposite(assigned, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "StrandAssignFilter(name={}, library_type={:?}, strand={:?}, opposite={})",
+            self.name, self.library_type, self.strand, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let library_type = self.library_type.to_possible_value().unwrap().get_name().to_string();
+        let strand = self.strand.to_possible_value().unwrap().get_name().to_string();
+        Some(create_args(&self.name, self.opposite, &["strand-assign".to_string(), library_type, strand]))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for SingletonFilter {
+    // decided by the stream, which looks for the mate
+    fn apply_to(&self, _record: &Record) -> bool {
+        needs_stream(&self.name)
+    }
+
+    fn repr(&self) -> String {
+        format!("SingletonFilter(name={}, opposite={})", self.name, self.opposite)
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["singleton".to_string()]))
+    }
+
+    // every read passes on its own, so a pair passes when both mates are found, and a singleton fails
+    // since it is combined as if its missing mate failed
+    fn to_streaming(&self) -> Option<Box<dyn StreamingFilter>> {
+        // removing no flags, every read passes
+        Some(Box::new(MatePairStream::new(
+            Box::new(FlagFilter::new(self.name.clone(), 0, false)),
+            BoolOperator::AND,
+            self.opposite,
+        )))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for TaggedPrimaryFilter {
+    fn apply_to(&self, record: &Record) -> bool {
+        let flags = record.flag();
+        let primary = !flags.is_secondary() && !flags.is_supplementary();
+        utils::_opposite(primary && record.tags().get(&self.tag_name).is_some(), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "TaggedPrimaryFilter(name={}, tag_name={:#?}, opposite={})",
+            self.name, self.tag_name, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        tag_name_problems(&self.tag_name)
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["tagged-primary".to_string(), String::from_utf8_lossy(&self.tag_name).to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for LowQualFractionFilter {
+    // reads without base qualities fail, since their quality is unknown
+    fn apply_to(&self, record: &Record) -> bool {
+        let qualities = record.qualities();
+        if !qualities.available() || qualities.raw().is_empty() {
+            return utils::_opposite(false, self.opposite);
+        }
+        let qualities = qualities.raw();
+        let low = qualities.iter().filter(|&&quality| quality < self.quality_threshold).count();
+        let fraction = low as f64 / qualities.len() as f64;
+        utils::_opposite(
+            fraction >= self.min_fraction && fraction <= self.max_fraction,
+            self.opposite,
+        )
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "LowQualFractionFilter(name={}, quality_threshold={}, min_fraction={}, max_fraction={}, opposite={})",
+            self.name, self.quality_threshold, self.min_fraction, self.max_fraction, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        f64_range_problems("fraction", self.min_fraction, self.max_fraction)
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "low-qual-fraction".to_string(),
+            "--quality".to_string(),
+            self.quality_threshold.to_string(),
+            self.min_fraction.to_string(),
+            self.max_fraction.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for ContigMarginFilter {
+    // passes reads whose aligned span keeps at least `margin` bases away from both ends of its reference;
+    // unmapped reads fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let ref_lengths = self
+            .ref_lengths
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        let ref_length = usize::try_from(record.ref_id()).ok().and_then(|ref_id| ref_lengths.get(ref_id));
+        let within_margin = match ref_length {
+            Some(&ref_length) if record.flag().is_mapped() => {
+                let (start, end) = (record.start() as i64, record.calculate_end() as i64);
+                start >= self.margin as i64 && ref_length as i64 - end >= self.margin as i64
+            }
+            _ => false,
+        };
+        utils::_opposite(within_margin, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "ContigMarginFilter(name={}, margin={}, opposite={})",
+            self.name, self.margin, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["contig-margin".to_string(), self.margin.to_string()]))
+    }
+
+    fn prepare(&mut self, header: &bam::Header) {
+        let ref_lengths = (0..header.n_references() as u32)
+            .map(|ref_id| header.reference_len(ref_id).unwrap())
+            .collect();
+        self.ref_lengths = Some(ref_lengths);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for BreakpointFilter {
+    // passes reads on the breakpoint's reference that either belong to a discordant pair straddling it,
+    // or are soft-clipped within `tolerance` bases of it. Unmapped reads fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let ref_id = self
+            .ref_id
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        let supports = record.flag().is_mapped()
+            && ref_id.is_some_and(|ref_id| ref_id == record.ref_id())
+            && (self.straddles(record) || self.clipped_at(record));
+        utils::_opposite(supports, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "BreakpointFilter(name={}, reference={}, position={}, tolerance={}, opposite={})",
+            self.name, self.reference, self.position, self.tolerance, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let args = [
+            "breakpoint".to_string(),
+            self.reference.clone(),
+            self.position.to_string(),
+            "--tolerance".to_string(),
+            self.tolerance.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &args))
+    }
+
+    fn prepare(&mut self, header: &bam::Header) {
+        self.ref_id = Some(header.reference_id(&self.reference).map(|ref_id| ref_id as i32));
+    }
+}
+
+#[typetag::serde]
+impl Filtering for PerfectMatchFilter {
+    // passes reads whose aligned bases all match the reference (like 'NM == 0', but without relying on the NM tag).
+    // Unmapped reads, reads without a sequence and reads on references missing from the FASTA file fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let sequences = self
+            .sequences
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        let perfect = match sequences.get(&record.ref_id()) {
+            Some(reference) if record.flag().is_mapped() && record.sequence().available() => {
+                PerfectMatchFilter::matches_reference(record, reference)
+            }
+            _ => false,
+        };
+        utils::_opposite(perfect, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "PerfectMatchFilter(name={}, path={}, opposite={})",
+            self.name,
+            self.path.display(),
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["perfect-match".to_string(), self.path.display().to_string()]))
+    }
+
+    // sequences that are not references of the input are skipped
+    fn prepare(&mut self, header: &bam::Header) {
+        let sequences = utils::read_fasta(&self.path)
+            .into_iter()
+            .filter_map(|(ref_name, sequence)| header.reference_id(&ref_name).map(|ref_id| (ref_id as i32, sequence)))
+            .collect();
+        self.sequences = Some(sequences);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for LeftmostSplitFilter {
+    // passes primary alignments of chimeric reads that start at or before every supplementary alignment listed
+    // in their SA tag, ordering references as in the header. SA entries on references missing from the header
+    // are ignored. Reads without an SA tag, unmapped reads, and secondary or supplementary alignments fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let ref_ids = self
+            .ref_ids
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        let flag = record.flag();
+        let segments = match record.tags().get(b"SA") {
+            Some(TagValue::String(sa, _)) => utils::sa_positions(sa),
+            _ => Vec::new(),
+        };
+        let leftmost = flag.is_mapped()
+            && !flag.is_secondary()
+            && !flag.is_supplementary()
+            && !segments.is_empty()
+            && segments
+                .iter()
+                .filter_map(|(ref_name, start)| ref_ids.get(ref_name).map(|&ref_id| (ref_id, *start)))
+                .all(|segment| (record.ref_id(), record.start()) <= segment);
+        utils::_opposite(leftmost, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!("LeftmostSplitFilter(name={}, opposite={})", self.name, self.opposite)
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["leftmost-split".to_string()]))
+    }
+
+    fn prepare(&mut self, header: &bam::Header) {
+        let ref_ids = header
+            .reference_names()
+            .iter()
+            .enumerate()
+            .map(|(ref_id, ref_name)| (ref_name.clone(), ref_id as i32))
+            .collect();
+        self.ref_ids = Some(ref_ids);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for RgQualityFilter {
+    // decided by the stream, which computes the mean rate of each read group
+    fn apply_to(&self, _record: &Record) -> bool {
+        needs_stream(&self.name)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "RgQualityFilter(name={}, max_rate={}, opposite={})",
+            self.name, self.max_rate, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["rg-quality".to_string(), self.max_rate.to_string()]))
+    }
+
+    fn to_streaming(&self) -> Option<Box<dyn StreamingFilter>> {
+        Some(Box::new(ReadGroupRateStream::new(self.max_rate, self.opposite)))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for RegionModeFilter {
+    // compares the aligned span of a read with the region; unmapped reads and reads on other references fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let span = self
+            .span
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        let matches = match span {
+            Some((ref_id, region_start, region_end)) if record.flag().is_mapped() && record.ref_id() == ref_id => {
+                let read_span = (record.start() as i64, record.calculate_end() as i64);
+                self.mode.matches(read_span, (region_start, region_end))
+            }
+            _ => false,
+        };
+        utils::_opposite(matches, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "RegionModeFilter(name={}, region={}, mode={:?}, opposite={})",
+            self.name, self.region, self.mode, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let mode = self.mode.to_possible_value().unwrap().get_name().to_string();
+        Some(create_args(&self.name, self.opposite, &["region-mode".to_string(), self.region.clone(), mode]))
+    }
+
+    // a region without positions covers its whole reference
+    fn prepare(&mut self, header: &bam::Header) {
+        let (ref_name, range) = utils::parse_region(&self.region);
+        let span = header.reference_id(&ref_name).map(|ref_id| {
+            let (start, end) = range.unwrap_or((1, header.reference_len(ref_id).unwrap()));
+            (ref_id as i32, start as i64 - 1, end as i64)
+        });
+        self.span = Some(span);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for QualityCliffFilter {
+    // the qualities of reverse-strand reads are stored reversed, so they are read backwards to start at the 5' end.
+    // Reads without base qualities fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let qualities = record.qualities();
+        let usable = qualities.available() && {
+            let mut qualities = qualities.raw().to_vec();
+            if record.flag().is_reverse_strand() {
+                qualities.reverse();
+            }
+            utils::pre_cliff_len(&qualities, self.window as usize, self.quality_threshold) >= self.min_len
+        };
+        utils::_opposite(usable, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "QualityCliffFilter(name={}, quality_threshold={}, min_len={}, window={}, opposite={})",
+            self.name, self.quality_threshold, self.min_len, self.window, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "quality-cliff".to_string(),
+            "--quality".to_string(),
+            self.quality_threshold.to_string(),
+            "--window".to_string(),
+            self.window.to_string(),
+            self.min_len.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for CoverageFilter {
+    // passes mapped reads whose aligned span overlaps a region with coverage within the bounds.
+    // Reads outside the regions of the BedGraph fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let regions = self
+            .regions
+            .as_ref()
+            .unwrap_or_else(|| panic!("Filter {} was not prepared with the input header!", self.name));
+        utils::_opposite(overlaps_intervals(regions, record), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "CoverageFilter(name={}, path={}, min_coverage={}, max_coverage={}, opposite={})",
+            self.name,
+            self.path.display(),
+            self.min_coverage,
+            self.max_coverage,
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = [
+            "coverage".to_string(),
+            self.path.display().to_string(),
+            "--".to_string(),
+            self.min_coverage.to_string(),
+            self.max_coverage.to_string(),
+        ];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    // regions on references that are not in the input can't overlap any of its reads, and are skipped
+    fn prepare(&mut self, header: &bam::Header) {
+        let mut regions: HashMap<i32, Vec<(i32, i32)>> = HashMap::new();
+        for (ref_name, start, end, coverage) in utils::read_bedgraph(&self.path) {
+            if !(self.min_coverage..=self.max_coverage).contains(&coverage) {
+                continue;
+            }
+            if let Some(ref_id) = header.reference_id(&ref_name) {
+                regions.entry(ref_id as i32).or_default().push((start as i32, end as i32));
+            }
+        }
+        merge_intervals(&mut regions);
+        self.regions = Some(regions);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for TrimFilter {
+    // passes reads with at least `min_len` bases left after trimming. The kept reads are trimmed by `transform`
+    fn apply_to(&self, record: &Record) -> bool {
+        let (start, end) = self.trimmed(record);
+        let trimmed_len = record.query_len().saturating_sub(start + end);
+        utils::_opposite(trimmed_len >= self.min_len, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "TrimFilter(name={}, leading={}, trailing={}, quality_threshold={:?}, min_len={}, opposite={})",
+            self.name,
+            self.leading,
+            self.trailing,
+            self.quality_threshold,
+            self.min_len,
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let mut subcommand = vec![
+            "trim".to_string(),
+            "--leading".to_string(),
+            self.leading.to_string(),
+            "--trailing".to_string(),
+            self.trailing.to_string(),
+            "--min-len".to_string(),
+            self.min_len.to_string(),
+        ];
+        if let Some(quality_threshold) = self.quality_threshold {
+            subcommand.extend(["--quality".to_string(), quality_threshold.to_string()]);
+        }
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    fn transforms(&self) -> bool {
+        true
+    }
+
+    fn transform(&self, record: &mut Record) {
+        let (start, end) = self.trimmed(record);
+        utils::trim_record(record, start, end);
+    }
+}
+
+#[typetag::serde]
+impl Filtering for ClipRatioFilter {
+    // the aligned length counts the M/=/X bases. Unmapped reads, and reads without aligned bases, fail
+    fn apply_to(&self, record: &Record) -> bool {
+        if !record.flag().is_mapped() {
+            return utils::_opposite(false, self.opposite);
+        }
+        let (mut clipped, mut aligned) = (0, 0);
+        for (len, operation) in utils::effective_cigar(record).iter() {
+            match operation {
+                Operation::Soft => clipped += len,
+                Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => aligned += len,
+                _ => {}
+            }
+        }
+        if aligned == 0 {
+            return utils::_opposite(false, self.opposite);
+        }
+        let ratio = clipped as f64 / aligned as f64;
+        utils::_opposite(ratio >= self.min_ratio && ratio <= self.max_ratio, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "ClipRatioFilter(name={}, min_ratio={}, max_ratio={}, opposite={})",
+            self.name, self.min_ratio, self.max_ratio, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        f64_range_problems("ratio", self.min_ratio, self.max_ratio)
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["clip-ratio".to_string(), self.min_ratio.to_string(), self.max_ratio.to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for DistinctBasesFilter {
+    // counts which of A, C, G and T appear in the read, ignoring N. Reads without a sequence fail
+    fn apply_to(&self, record: &Record) -> bool {
+        if !record.sequence().available() {
+            return utils::_opposite(false, self.opposite);
+        }
+        let mut seen = [false; 4];
+        for base in record.sequence().to_vec_acgtn_only() {
+            if let Some(index) = b"ACGT".iter().position(|&nucleotide| nucleotide == base) {
+                seen[index] = true;
+            }
+        }
+        let distinct = seen.iter().filter(|&&seen| seen).count() as u8;
+        utils::_opposite(distinct >= self.min_distinct && distinct <= self.max_distinct, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "DistinctBasesFilter(name={}, min_distinct={}, max_distinct={}, opposite={})",
+            self.name, self.min_distinct, self.max_distinct, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["distinct-bases".to_string(), self.min_distinct.to_string(), self.max_distinct.to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for RecordIndexFilter {
+    // decided by the stream, which counts the records
+    fn apply_to(&self, _record: &Record) -> bool {
+        needs_stream(&self.name)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "RecordIndexFilter(name={}, first_index={}, last_index={}, opposite={})",
+            self.name,
+            self.range().min_str(),
+            self.range().max_str(),
+            self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["record-index".to_string(), self.range().min_str(), self.range().max_str()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+
+    fn to_streaming(&self) -> Option<Box<dyn StreamingFilter>> {
+        Some(Box::new(RecordIndexStream::new(self.range(), self.opposite)))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for ConcordantPairFilter {
+    // passes reads whose mate is on the same reference and the opposite strand, with the forward read starting first
+    // (FR orientation), and whose insert size (the absolute TLEN) is in range. Unpaired reads, unmapped reads or mates,
+    // mates on another reference, and reads without a TLEN fail.
+    fn apply_to(&self, record: &Record) -> bool {
+        let flag = record.flag();
+        let placed_with_mate = record.ref_id() >= 0 && record.ref_id() == record.mate_ref_id();
+        if !flag.is_paired() || !flag.is_mapped() || !flag.mate_is_mapped() || !placed_with_mate {
+            return utils::_opposite(false, self.opposite);
+        }
+        let is_fr = match (flag.is_reverse_strand(), flag.mate_is_reverse_strand()) {
+            (false, true) => record.start() <= record.mate_start(),
+            (true, false) => record.mate_start() <= record.start(),
+            _ => false,
+        };
+        let insert = record.template_len().unsigned_abs();
+        utils::_opposite(is_fr && insert > 0 && self.range().contains(insert), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "ConcordantPairFilter(name={}, min_insert={}, max_insert={}, opposite={})",
+            self.name, self.range().min_str(), self.range().max_str(), self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn problems(&self) -> Vec<String> {
+        range_problems(self.range())
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["concordant".to_string(), self.range().min_str(), self.range().max_str()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for AlignedGcFilter {
+    // only the M/=/X bases are counted, so clips and insertions are left out. 'N' bases are ignored.
+    // Unmapped reads, reads without a sequence, and reads without counted bases fail
+    fn apply_to(&self, record: &Record) -> bool {
+        if !record.flag().is_mapped() || !record.sequence().available() {
+            return utils::_opposite(false, self.opposite);
+        }
+        let sequence = record.sequence();
+        let (mut query_pos, mut gc, mut total) = (0, 0, 0);
+        for (len, operation) in utils::effective_cigar(record).iter() {
+            let len = len as usize;
+            if matches!(operation, Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch) {
+                for base in (query_pos..query_pos + len).map(|i| sequence.at(i)) {
+                    match base {
+                        b'G' | b'C' => {
+                            gc += 1;
+                            total += 1;
+                        }
+                        b'N' => {}
+                        _ => total += 1,
+                    }
+                }
+            }
+            if operation.consumes_query() {
+                query_pos += len;
+            }
+        }
+        if total == 0 {
+            return utils::_opposite(false, self.opposite);
+        }
+        let fraction = gc as f64 / total as f64;
+        utils::_opposite(fraction >= self.min_fraction && fraction <= self.max_fraction, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "AlignedGcFilter(name={}, min_fraction={}, max_fraction={}, opposite={})",
+            self.name, self.min_fraction, self.max_fraction, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        f64_range_problems("fraction", self.min_fraction, self.max_fraction)
+    }
+
+    fn rejects_unmapped(&self) -> bool {
+        !self.opposite
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["aligned-gc".to_string(), self.min_fraction.to_string(), self.max_fraction.to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for AsPerBaseFilter {
+    // the AS tag divided by the query length (which includes soft clips). Reads without an AS tag,
+    // and reads without a sequence, fail
+    fn apply_to(&self, record: &Record) -> bool {
+        let score = match record.tags().get(b"AS") {
+            Some(TagValue::Int(score, _)) => score as f64,
+            Some(TagValue::Float(score)) => score as f64,
+            _ => return utils::_opposite(false, self.opposite),
         };
+        if record.query_len() == 0 {
+            return utils::_opposite(false, self.opposite);
+        }
+        let per_base = score / record.query_len() as f64;
+        utils::_opposite(per_base >= self.min_score && per_base <= self.max_score, self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!(
+            "AsPerBaseFilter(name={}, min_score={}, max_score={}, opposite={})",
+            self.name, self.min_score, self.max_score, self.opposite
+        )
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn problems(&self) -> Vec<String> {
+        f64_range_problems("score", self.min_score, self.max_score)
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        let subcommand = ["as-per-base".to_string(), self.min_score.to_string(), self.max_score.to_string()];
+        Some(create_args(&self.name, self.opposite, &subcommand))
+    }
+}
+
+#[typetag::serde]
+impl Filtering for InternalClipFilter {
+    // passes reads whose soft clips are all at the ends of the CIGAR (outside any hard clips).
+    // Reads without a CIGAR pass
+    fn apply_to(&self, record: &Record) -> bool {
+        let ops: Vec<(u32, Operation)> = utils::effective_cigar(record).iter().collect();
+        utils::_opposite(!InternalClipFilter::has_internal_clip(&ops), self.opposite)
+    }
+
+    fn repr(&self) -> String {
+        format!("InternalClipFilter(name={}, opposite={})", self.name, self.opposite)
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command_args(&self) -> Option<Vec<String>> {
+        Some(create_args(&self.name, self.opposite, &["internal-clip".to_string()]))
+    }
+}
+
+#[cfg(test)]
+// rstest's `#[with(...)]` consumes `#[case]` arguments in a way rustc doesn't see as a use
+#[allow(unused_variables)]
+mod tests {
+    use bam::RecordWriter;
+    use rstest::{fixture, rstest};
+
+    use super::*;
+
+    #[fixture]
+    fn opposite_base() -> bool {
+        false
+    }
+
+    #[fixture]
+    fn length_filter(opposite_base: bool) -> LengthFilter {
+        LengthFilter::new("test 1".to_string(), 18, 24, opposite_base)
+    }
+
+    #[fixture]
+    fn mapq_filter(opposite_base: bool) -> MapqFilter {
+        MapqFilter::new("test 2".to_string(), 4, 20, opposite_base)
+    }
+
+    #[fixture]
+    fn ref_name_filter(opposite_base: bool) -> RefNameFilter {
+        RefNameFilter::new("test 3".to_string(), 0, opposite_base)
+    }
+
+    #[fixture]
+    fn nth_nucleotide_filter(opposite_base: bool) -> NthNucleotideFilter {
+        NthNucleotideFilter::new("test 4".to_string(), 1, 'G', false, opposite_base)
+    }
+
+    #[fixture]
+    fn record_1() -> Record {
+        let mut record = Record::new();
+        record.set_seq_qual("ACGT".bytes(), [10_u8, 20, 30, 10].iter().cloned()).unwrap();
+        record.set_ref_id(0);
+        record.set_mapq(20);
+        record.set_flag(0);
+        // record.tags_mut().push_num(TagName::tryFrom("NM").unwrap(),0);
+        record
+    }
+
+    #[fixture]
+    fn record_2() -> Record {
+        let mut record = Record::new();
+        record.set_seq_qual("GACGTAAGGGCATTACGANN".bytes(),
+                            [10_u8, 20, 30, 10, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30].iter().cloned()).unwrap();
+        record.set_ref_id(0);
+        record.set_mapq(0);
+        record.set_flag(1040);
+        // record.tags_mut().push_num(TagName::tryFrom("NM").unwrap(),0);
+        record
+    }
+
+    #[rstest]
+    #[case(1, 'A', true)]
+    #[case(4, 'T', true)]
+    #[case(-4, 'A', true)]
+    #[case(5, 'A', false)]
+    #[case(-5, 'A', false)]
+    #[case(100, 'T', false)]
+    fn test_nth_nucleotide_filter_position(#[case] position: i64, #[case] nucleotide: char, #[case] expected: bool,
+                                           opposite_base: bool, record_1: Record) {
+        let filter = NthNucleotideFilter::new("nth".to_string(), position, nucleotide, false, opposite_base);
+        assert_eq!(filter.apply_to(&record_1), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(-1)]
+    fn test_nth_nucleotide_filter_unavailable_sequence(#[case] position: i64, opposite_base: bool) {
+        let filter = NthNucleotideFilter::new("nth".to_string(), position, 'A', true, opposite_base);
+        assert_eq!(filter.apply_to(&Record::new()), utils::_opposite(false, opposite_base));
+    }
+
+    #[rstest]
+    #[case(false, false)]
+    #[case(true, true)]
+    fn test_length_filter_below_range(#[case] opposite: bool, #[case]
+    expected: bool, #[with(opposite)] length_filter: LengthFilter, record_1: Record) {
+        assert_eq!(length_filter.apply_to(&record_1), expected);
+    }
+
+    #[rstest]
+    #[case(false, true)]
+    #[case(true, false)]
+    fn test_length_filter_within_range(#[case] opposite: bool, #[case]
+    expected: bool, #[with(opposite)] length_filter: LengthFilter, record_2: Record) {
+        assert_eq!(length_filter.apply_to(&record_2), expected);
+    }
+
+    #[rstest]
+    #[case(false, false)]
+    #[case(true, true)]
+    fn test_mapq_filter_below_range(#[case] opposite: bool, #[case]
+    expected: bool, #[with(opposite)] mapq_filter: MapqFilter, record_2: Record) {
+        assert_eq!(mapq_filter.apply_to(&record_2), expected);
+    }
+
+
+    #[rstest]
+    #[case(false, true)]
+    #[case(true, false)]
+    fn test_mapq_filter_within_range(#[case] opposite: bool, #[case]
+    expected: bool, #[with(opposite)] mapq_filter: MapqFilter, record_1: Record) {
+        assert_eq!(mapq_filter.apply_to(&record_1), expected);
+    }
+
+    #[rstest]
+    #[case(0, 255, 0, true, "min_mapq=*, max_mapq=*")]
+    #[case(30, 255, 255, true, "min_mapq=30, max_mapq=*")]
+    #[case(30, 255, 29, false, "min_mapq=30, max_mapq=*")]
+    #[case(0, 10, 0, true, "min_mapq=*, max_mapq=10")]
+    #[case(0, 10, 11, false, "min_mapq=*, max_mapq=10")]
+    fn test_mapq_filter_open_range(#[case] min_mapq: u8, #[case] max_mapq: u8, #[case] mapq: u8,
+                                   #[case] expected: bool, #[case] repr: &str, mut record_1: Record) {
+        record_1.set_mapq(mapq);
+        let filter = MapqFilter::new("open".to_string(), min_mapq, max_mapq, false);
+        assert_eq!(filter.apply_to(&record_1), expected);
+        assert!(filter.repr().contains(repr));
+    }
+
+    #[rstest]
+    #[case("AG", 0.3, 0.34, true)]
+    #[case("CT", 0.6, 0.7, true)]
+    #[case("AG", 0.6, 0.7, false)]
+    #[case("ct", 0.3, 0.34, false)]
+    fn test_composition_filter(#[case] bases: &str, #[case] min_fraction: f64, #[case]
+    max_fraction: f64, #[case] expected: bool, record_2: Record) {
+        // record_2 is reverse-stranded: the original read has 6 purines and 12 pyrimidines,
+        // plus two N's that are left out of the denominator
+        let filter = CompositionFilter::new("test 5".to_string(), bases, min_fraction, max_fraction, false);
+        assert_eq!(filter.apply_to(&record_2), expected);
+    }
+
+    #[rstest]
+    fn test_composition_filter_forward_strand(mut record_2: Record) {
+        record_2.flag_mut().set_strand(true);
+        let filter = CompositionFilter::new("test 5".to_string(), "AG", 0.6, 0.7, false);
+        assert!(filter.apply_to(&record_2));
+    }
+
+    #[rstest]
+    fn test_composition_filter_unavailable_sequence() {
+        let filter = CompositionFilter::new("test 5".to_string(), "AG", 0.0, 1.0, false);
+        assert!(!filter.apply_to(&Record::new()));
+    }
+
+    fn clipped_record(seq: &str, cigar: &str, reverse: bool) -> Record {
+        let mut record = Record::new();
+        record.set_seq_qual(seq.bytes(), std::iter::repeat_n(30, seq.len())).unwrap();
+        record.set_cigar(cigar.bytes()).unwrap();
+        record.set_ref_id(0);
+        record.set_start(100);
+        record.flag_mut().set_strand(!reverse);
+        record
+    }
+
+    #[rstest]
+    #[case("AGATCGGAAGACGTACGTAC", "10S10M", false, utils::ClipSide::Leading, true)]
+    #[case("AGATCGGAAGACGTACGTAC", "10S10M", false, utils::ClipSide::Trailing, false)]
+    #[case("AGATCGGAAGACGTACGTAC", "10S10M", false, utils::ClipSide::Either, true)]
+    #[case("ACGTACGTACCTTCCGATCT", "10M10S", true, utils::ClipSide::Leading, true)]
+    #[case("ACGTACGTACCTTCCGATCT", "10M10S", true, utils::ClipSide::Trailing, false)]
+    #[case("AGATCGGAAGACGTACGTAC", "20M", false, utils::ClipSide::Either, false)]
+    fn test_clip_content_filter(#[case] seq: &str, #[case] cigar: &str, #[case] reverse: bool,
+                                #[case] side: utils::ClipSide, #[case] expected: bool) {
+        let filter = ClipContentFilter::new("test 6".to_string(), "agatcgg", side, 0, false, false);
+        assert_eq!(filter.apply_to(&clipped_record(seq, cigar, reverse)), expected);
+    }
+
+    #[rstest]
+    // the motif AGATCGG in the leading clip, exact, with one mismatch, with two mismatches,
+    // and as its reverse complement CCGATCT
+    #[case("AGATCGGAAGACGTACGTAC", 1, false, true)]
+    #[case("AGTTCGGAAGACGTACGTAC", 0, false, false)]
+    #[case("AGTTCGGAAGACGTACGTAC", 1, false, true)]
+    #[case("AGTTCGCAAGACGTACGTAC", 1, false, false)]
+    #[case("AGTTCGCAAGACGTACGTAC", 2, false, true)]
+    #[case("CCGATCTAAGACGTACGTAC", 0, false, false)]
+    #[case("CCGATCTAAGACGTACGTAC", 0, true, true)]
+    #[case("CCGTTCTAAGACGTACGTAC", 1, true, true)]
+    fn test_clip_content_filter_mismatches(#[case] seq: &str, #[case] max_mismatch: u32, #[case] both_strands: bool,
+                                           #[case] expected: bool) {
+        let filter = ClipContentFilter::new("test 6".to_string(), "agatcgg", utils::ClipSide::Leading, max_mismatch,
+                                            both_strands, false);
+        assert_eq!(filter.apply_to(&clipped_record(seq, "10S10M", false)), expected);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Maximum number of mismatches (3) must be smaller than the motif length (3)!")]
+    fn test_clip_content_filter_too_many_mismatches() {
+        ClipContentFilter::new("test 6".to_string(), "AGA", utils::ClipSide::Leading, 3, false, false);
+    }
+
+    #[rstest]
+    fn test_clip_content_filter_long_cigar() {
+        // the soft clip is only in the real CIGAR, stored in the CG tag
+        let mut record = clipped_record("ACGTACGTACAGATCGGAAG", "20S10N", false);
+        record.tags_mut().push_array(b"CG", &[10 << 4, 10 << 4 | 4u32]);
+        let filter = ClipContentFilter::new("test 6".to_string(), "agatcgg", utils::ClipSide::Trailing, 0, false, false);
+        assert!(filter.apply_to(&record));
+    }
+
+    #[rstest]
+    #[case(Some("50"), 0, 0, true)]
+    #[case(Some("10A39"), 0, 0, false)]
+    #[case(Some("10A39"), 1, 2, true)]
+    #[case(Some("50"), 1, 2, false)]
+    #[case(None, 0, 10, false)]
+    fn test_md_mismatch_filter(#[case] md: Option<&str>, #[case] min_mismatches: u32, #[case]
+    max_mismatches: u32, #[case] expected: bool, mut record_1: Record) {
+        if let Some(md) = md {
+            record_1.tags_mut().push_string(b"MD", md.as_bytes());
+        }
+        let filter = MdMismatchFilter::new("test 7".to_string(), min_mismatches, max_mismatches, false);
+        assert_eq!(filter.apply_to(&record_1), expected);
+    }
+
+    #[rstest]
+    fn test_config_count_by_type(length_filter: LengthFilter, mapq_filter: MapqFilter,
+                                 nth_nucleotide_filter: NthNucleotideFilter) {
+        let mut config = Config::new();
+        config.push("length", Box::new(length_filter.clone()));
+        config.push("length 2", Box::new(length_filter));
+        config.push("mapq", Box::new(mapq_filter));
+        config.push("nucleotide", Box::new(nth_nucleotide_filter));
+        assert_eq!(config.count(), 4);
+        assert_eq!(
+            config.count_by_type(),
+            vec![
+                ("length".to_string(), 2),
+                ("mapq".to_string(), 1),
+                ("nth-nucleotide".to_string(), 1),
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_script_filter_serialization(record_1: Record, record_2: Record) {
+        let filter: Box<dyn Filtering> =
+            Box::new(ScriptFilter::new("test 8".to_string(), "length >= 18 && mapq < 10", false));
+        let json = serde_json::to_string(&filter).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"ScriptFilter","name":"test 8","expression":"length >= 18 && mapq < 10","opposite":false}"#
+        );
+        let filter: Box<dyn Filtering> = serde_json::from_str(&json).unwrap();
+        assert!(!filter.apply_to(&record_1));
+        assert!(filter.apply_to(&record_2));
+
+        let invalid = json.replace("mapq < 10", "mapq <");
+        assert!(serde_json::from_str::<Box<dyn Filtering>>(&invalid).is_err());
+    }
+
+    fn spliced_record(ref_id: i32, start: i32, cigar: &str) -> Record {
+        let mut record = Record::new();
+        let len = 50;
+        record.set_seq_qual(std::iter::repeat_n(b'A', len), std::iter::repeat_n(30, len)).unwrap();
+        record.set_ref_id(ref_id);
+        record.set_start(start);
+        record.set_cigar(cigar.bytes()).unwrap();
+        record
+    }
+
+    #[rstest]
+    #[case(0, 80, "20M100N30M", 0, true)]
+    #[case(0, 82, "20M100N30M", 0, false)]
+    #[case(0, 82, "20M100N30M", 2, true)]
+    #[case(0, 80, "20M98N30M", 2, true)]
+    #[case(0, 80, "20M97N30M", 2, false)]
+    #[case(1, 80, "20M100N30M", 5, false)]
+    #[case(0, 80, "50M", 0, false)]
+    #[case(0, 80, "20M100N10M280N20M", 0, true)]
+    #[case(0, 80, "20M100N10M100N20M", 0, false)]
+    fn test_junctions_filter(#[case] ref_id: i32, #[case] start: i32, #[case] cigar: &str,
+                             #[case] tolerance: u32, #[case] expected: bool, opposite_base: bool) {
+        // introns at 100-199 and 210-489 (0-based) on chr1 (reference 0), and on a reference that is not in the input
+        let junctions = BTreeMap::from([
+            ("chr1".to_string(), vec![(210, 489), (100, 199)]),
+            ("chr3".to_string(), vec![(100, 199)]),
+        ]);
+        let path = PathBuf::from("junctions.tsv");
+        let mut filter = JunctionsFilter::new("junctions".to_string(), path, junctions, tolerance, opposite_base);
+        filter.prepare(&subtract_header());
+        let record = spliced_record(ref_id, start, cigar);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_junctions_filter_reference_order() {
+        let junctions = BTreeMap::from([("chr1".to_string(), vec![(100, 199)])]);
+        let path = PathBuf::from("junctions.tsv");
+        let mut filter = JunctionsFilter::new("junctions".to_string(), path, junctions, 0, false);
+        let mut header = bam::Header::new();
+        for name in ["chr2", "chr1"] {
+            header.push_entry(bam::header::HeaderEntry::ref_sequence(name.to_string(), 10000)).unwrap();
+        }
+        // the junctions are looked up by the name of the read's reference in the input, whatever its id
+        filter.prepare(&header);
+        assert!(filter.apply_to(&spliced_record(1, 80, "20M100N30M")));
+        assert!(!filter.apply_to(&spliced_record(0, 80, "20M100N30M")));
+        assert_eq!(
+            filter.command_args().unwrap(),
+            ["create", "--name", "junctions", "junctions", "junctions.tsv", "--tolerance", "0"]
+        );
+    }
+
+    #[rstest]
+    // record_2 has flag 1040 (reverse strand + duplicate)
+    #[case(0, 0, true)]
+    #[case(16, 0, true)]
+    #[case(1040, 0, true)]
+    #[case(17, 0, false)]
+    #[case(0, 1024, false)]
+    #[case(0, 4, true)]
+    #[case(16, 4, true)]
+    #[case(16, 1024, false)]
+    #[case(16, 260, true)]
+    fn test_flag_mask_filter(#[case] require_mask: u16, #[case] exclude_mask: u16, #[case] expected: bool,
+                             record_2: Record, opposite_base: bool) {
+        let filter = FlagMaskFilter::new("flag mask".to_string(), require_mask, exclude_mask, opposite_base);
+        assert_eq!(filter.apply_to(&record_2), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Flags cannot be both required and excluded")]
+    fn test_flag_mask_filter_overlapping_masks() {
+        FlagMaskFilter::new("flag mask".to_string(), 18, 16, false);
+    }
+
+    fn unmapped_record() -> Record {
+        let mut record = Record::new();
+        record.set_seq_qual(b"ACGT".iter().copied(), std::iter::repeat_n(30, 4)).unwrap();
+        record.flag_mut().set_mapped(false);
+        record
+    }
+
+    #[rstest]
+    fn test_sanity_filter_consistent_records(opposite_base: bool) {
+        let filter = SanityFilter::new("sanity".to_string(), opposite_base);
+        let mut mapped = unmapped_record();
+        mapped.flag_mut().set_mapped(true);
+        mapped.flag_mut().set_strand(false);
+        mapped.set_ref_id(0);
+        mapped.set_start(100);
+        mapped.set_mapq(30);
+        assert_eq!(filter.apply_to(&mapped), utils::_opposite(true, opposite_base));
+        assert_eq!(filter.apply_to(&unmapped_record()), utils::_opposite(true, opposite_base));
+        // an unmapped read may be placed at the position of its mapped mate
+        let mut placed_at_mate = unmapped_record();
+        placed_at_mate.set_ref_id(0);
+        placed_at_mate.set_start(100);
+        placed_at_mate.flag_mut().set_paired(true);
+        placed_at_mate.flag_mut().set_mate_mapped(true);
+        assert_eq!(filter.apply_to(&placed_at_mate), utils::_opposite(true, opposite_base));
+    }
+
+    #[rstest]
+    #[case(|r: &mut Record| { r.set_ref_id(0); r.set_start(100); }, "unmapped read with a reference and position")]
+    #[case(|r: &mut Record| r.set_mapq(30), "unmapped read with a non-zero mapping quality")]
+    #[case(|r: &mut Record| r.flag_mut().set_strand(false), "unmapped read on the reverse strand")]
+    #[case(|r: &mut Record| r.flag_mut().set_mapped(true), "mapped read without a reference or position")]
+    fn test_sanity_filter_contradictions(#[case] corrupt: fn(&mut Record), #[case] expected: &str,
+                                         opposite_base: bool) {
+        let mut record = unmapped_record();
+        corrupt(&mut record);
+        assert_eq!(SanityFilter::contradiction(&record), Some(expected));
+        let filter = SanityFilter::new("sanity".to_string(), opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(false, opposite_base));
+    }
+
+    fn subtract_header() -> bam::Header {
+        let mut header = bam::Header::new();
+        for name in ["chr1", "chr2"] {
+            header
+                .push_entry(bam::header::HeaderEntry::ref_sequence(name.to_string(), 10000))
+                .unwrap();
+        }
+        header
+    }
+
+    #[rstest]
+    // blacklist: chr1 100-200 and 150-300 (merged), chr2 1000-1010
+    #[case(0, 50, "50M", true)]
+    #[case(0, 50, "51M", false)]
+    #[case(0, 250, "20M", false)]
+    #[case(0, 300, "20M", true)]
+    #[case(0, 20, "30M500N30M", false)]
+    #[case(1, 970, "10M10N10M", true)]
+    #[case(1, 1005, "10M", false)]
+    #[case(1, 150, "50M", true)]
+    fn test_subtract_filter(#[case] ref_id: i32, #[case] start: i32, #[case] cigar: &str, #[case] expected: bool,
+                            opposite_base: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blacklist.bed");
+        std::fs::write(&path, "chr1\t100\t200\nchr1\t150\t300\nchr2\t1000\t1010\nchrX\t0\t5000\n").unwrap();
+        let mut filter = SubtractFilter::new("subtract".to_string(), path, opposite_base);
+        filter.prepare(&subtract_header());
+        let record = spliced_record(ref_id, start, cigar);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_subtract_filter_from_bam(opposite_base: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blacklist.bam");
+        let mut writer = bam::BamWriter::from_path(&path, subtract_header()).unwrap();
+        writer.write(&spliced_record(1, 500, "50M")).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let mut filter = SubtractFilter::new("subtract".to_string(), path, opposite_base);
+        filter.prepare(&subtract_header());
+        assert_eq!(filter.apply_to(&spliced_record(1, 540, "50M")), utils::_opposite(false, opposite_base));
+        assert_eq!(filter.apply_to(&spliced_record(1, 550, "50M")), utils::_opposite(true, opposite_base));
+        assert_eq!(filter.apply_to(&spliced_record(0, 540, "50M")), utils::_opposite(true, opposite_base));
+    }
+
+    #[rstest]
+    fn test_subtract_filter_gzipped_bed(opposite_base: bool) {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blacklist.bed.gz");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder.write_all(b"chr1\t100\t200\nchr2\t1000\t1010\n").unwrap();
+        encoder.finish().unwrap();
+        let mut filter = SubtractFilter::new("subtract".to_string(), path, opposite_base);
+        filter.prepare(&subtract_header());
+        assert_eq!(filter.apply_to(&spliced_record(0, 120, "50M")), utils::_opposite(false, opposite_base));
+        assert_eq!(filter.apply_to(&spliced_record(0, 200, "50M")), utils::_opposite(true, opposite_base));
+        assert_eq!(filter.apply_to(&spliced_record(1, 990, "50M")), utils::_opposite(false, opposite_base));
+    }
+
+    fn mated_record(start: i32, mate_ref_id: i32, mate_start: i32, mate_mapped: bool) -> Record {
+        let mut record = spliced_record(0, start, "50M");
+        record.flag_mut().set_paired(true);
+        record.flag_mut().set_mate_mapped(mate_mapped);
+        record.set_mate_ref_id(mate_ref_id);
+        record.set_mate_start(mate_start);
+        record
+    }
+
+    #[rstest]
+    #[case(mated_record(1000, 0, 1250, true), true)]
+    #[case(mated_record(1250, 0, 1000, true), true)]
+    #[case(mated_record(1000, 0, 1100, true), true)]
+    #[case(mated_record(1000, 0, 1099, true), false)]
+    #[case(mated_record(1000, 0, 1500, true), true)]
+    #[case(mated_record(1000, 0, 1501, true), false)]
+    #[case(mated_record(1000, 1, 1250, true), false)]
+    #[case(mated_record(1000, 0, 1250, false), false)]
+    #[case(spliced_record(0, 1000, "50M"), false)]
+    fn test_mate_distance_filter(#[case] record: Record, #[case] expected: bool, opposite_base: bool) {
+        let filter = MateDistanceFilter::new("mate distance".to_string(), 100, 500, opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    fn contig_header() -> bam::Header {
+        let mut header = bam::Header::new();
+        for name in ["chr1", "chrM", "chr1_KI270706v1_random", "chr6_GL000250v2_alt", "MT"] {
+            header
+                .push_entry(bam::header::HeaderEntry::ref_sequence(name.to_string(), 10000))
+                .unwrap();
+        }
+        header
+    }
+
+    #[rstest]
+    #[case(utils::MITO_CONTIGS, 0, false)]
+    #[case(utils::MITO_CONTIGS, 1, true)]
+    #[case(utils::MITO_CONTIGS, 4, true)]
+    #[case(utils::MITO_CONTIGS, 2, false)]
+    #[case(utils::DECOY_CONTIGS, 2, true)]
+    #[case(utils::DECOY_CONTIGS, 3, true)]
+    #[case(utils::DECOY_CONTIGS, 1, false)]
+    #[case(&["chr?"], 0, true)]
+    #[case(&["chr?"], 2, false)]
+    #[case(&["chr[1M]"], 1, true)]
+    #[case(&["*"], -1, false)]
+    fn test_special_contig_filter(#[case] patterns: &[&str], #[case] ref_id: i32, #[case] expected: bool,
+                                  opposite_base: bool) {
+        let patterns = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        let mut filter = SpecialContigFilter::new("special".to_string(), patterns, opposite_base);
+        filter.prepare(&contig_header());
+        let record = spliced_record(ref_id, 100, "50M");
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_special_contig_filter_describe() {
+        let patterns = utils::MITO_CONTIGS.iter().map(|pattern| pattern.to_string()).collect();
+        let filter = SpecialContigFilter::new("mito".to_string(), patterns, true);
+        assert!(filter.describe(Some(&contig_header())).contains("matches=[\"chrM\", \"MT\"]"));
+    }
+
+    // returns a fixed result and counts how many times it was evaluated
+    #[derive(Serialize, Deserialize, Clone)]
+    struct CountingFilter {
+        name: String,
+        result: bool,
+        #[serde(skip)]
+        evaluations: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl CountingFilter {
+        fn new(result: bool) -> CountingFilter {
+            CountingFilter {
+                name: format!("counting {}", result),
+                result,
+                evaluations: Default::default(),
+            }
+        }
+
+        fn evaluations(&self) -> usize {
+            self.evaluations.load(std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    #[typetag::serde]
+    impl Filtering for CountingFilter {
+        fn apply_to(&self, _record: &Record) -> bool {
+            self.evaluations.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.result
+        }
+
+        fn repr(&self) -> String {
+            format!("CountingFilter(name={})", self.name)
+        }
+        fn name(&self) -> &str {
+            &self.name
+        }
     }
 
-    fn repr(&self) -> String {
-        format!(
-            "TagFilter(name={}, tag_name={:#?}, tag_value={}, opposite={})",
-            self.name, self.tag_name, self.tag_value, self.opposite
-        )
+    #[rstest]
+    fn test_combined_filter_same_results_as_operator(record_1: Record,
+                                                     #[values(BoolOperator::AND, BoolOperator::OR, BoolOperator::XOR,
+                                                     BoolOperator::XNOR, BoolOperator::NAND, BoolOperator::NOR,
+                                                     BoolOperator::IMPLIES)] operator: BoolOperator) {
+        for (a, b) in [(false, false), (false, true), (true, false), (true, true)] {
+            let combined = CombinedFilter::new(
+                "combined".to_string(),
+                Box::new(CountingFilter::new(a)),
+                Box::new(CountingFilter::new(b)),
+                operator.clone(),
+            );
+            assert_eq!(combined.apply_to(&record_1), operator.apply(a, b));
+        }
     }
-    fn name(&self) -> &str {
-        &self.name
+
+    #[rstest]
+    #[case(BoolOperator::AND, false, 0)]
+    #[case(BoolOperator::AND, true, 100)]
+    #[case(BoolOperator::OR, true, 0)]
+    #[case(BoolOperator::OR, false, 100)]
+    #[case(BoolOperator::XOR, true, 100)]
+    #[case(BoolOperator::NAND, false, 100)]
+    fn test_combined_filter_short_circuit(record_1: Record, #[case] operator: BoolOperator, #[case] result1: bool,
+                                          #[case] expected_evaluations: usize) {
+        let filter1 = CountingFilter::new(result1);
+        let filter2 = CountingFilter::new(true);
+        let combined = CombinedFilter::new(
+            "combined".to_string(),
+            Box::new(filter1.clone()),
+            Box::new(filter2.clone()),
+            operator,
+        );
+        for _ in 0..100 {
+            combined.apply_to(&record_1);
+        }
+        assert_eq!(filter1.evaluations(), 100);
+        assert_eq!(filter2.evaluations(), expected_evaluations);
     }
-}
 
-#[typetag::serde]
-impl Filtering for MapqFilter {
-    fn apply_to(&self, record: &Record) -> bool {
-        let mapq = record.mapq();
-        return if mapq < self.min_mapq || mapq > self.max_mapq {
-            utils::_opposite(false, self.opposite)
-        } else {
-            utils::_opposite(true, self.opposite)
-        };
+    #[rstest]
+    #[case(utils::Strand::Forward, false, true, true)]
+    #[case(utils::Strand::Forward, true, true, false)]
+    #[case(utils::Strand::Reverse, true, true, true)]
+    #[case(utils::Strand::Reverse, false, true, false)]
+    #[case(utils::Strand::Forward, false, false, false)]
+    #[case(utils::Strand::Reverse, true, false, false)]
+    fn test_strand_filter(#[case] strand: utils::Strand, #[case] reverse: bool, #[case] mapped: bool,
+                          #[case] expected: bool, opposite_base: bool) {
+        let mut record = spliced_record(0, 100, "50M");
+        record.flag_mut().set_strand(!reverse);
+        record.flag_mut().set_mapped(mapped);
+        let filter = StrandFilter::new("strand".to_string(), strand, opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
     }
 
-    fn repr(&self) -> String {
-        format!(
-            "MapqFilter(name={}, min_mapq={}, max_mapq={}, opposite={})",
-            self.name, self.min_mapq, self.max_mapq, self.opposite
-        )
+    #[rstest]
+    #[case(3u8, true)]
+    #[case(4u8, false)]
+    fn test_tag_filter_integer_subtypes(#[case] nm: u8, #[case] expected: bool, opposite_base: bool) {
+        let filter = TagFilter::new("nm".to_string(), *b"NM", utils::MinimalTagValue::Int(3), false, opposite_base);
+        let mut records = [Record::new(), Record::new(), Record::new()];
+        records[0].tags_mut().push_num(b"NM", nm);
+        records[1].tags_mut().push_num(b"NM", nm as i16);
+        records[2].tags_mut().push_num(b"NM", nm as u32);
+        for record in &records {
+            assert_eq!(filter.apply_to(record), utils::_opposite(expected, opposite_base));
+        }
     }
-    fn name(&self) -> &str {
-        &self.name
+
+    #[rstest]
+    fn test_tag_filter_numeric() {
+        let mut record = Record::new();
+        record.tags_mut().push_num(b"XS", 3.0f32);
+        let tag_value = utils::MinimalTagValue::Int(3);
+        assert!(!TagFilter::new("xs".to_string(), *b"XS", tag_value.clone(), false, false).apply_to(&record));
+        assert!(TagFilter::new("xs".to_string(), *b"XS", tag_value, true, false).apply_to(&record));
     }
-}
 
-#[typetag::serde]
-impl Filtering for RefNameFilter {
-    fn apply_to(&self, record: &Record) -> bool {
-        let this_ref_id = record.ref_id();
-        return utils::_opposite(this_ref_id == self.ref_id, self.opposite);
+    #[rstest]
+    fn test_profile_leaves(record_1: Record, length_filter: LengthFilter, mapq_filter: MapqFilter) {
+        let inner = CombinedFilter::new(
+            "inner".to_string(),
+            Box::new(length_filter),
+            Box::new(mapq_filter),
+            BoolOperator::OR,
+        );
+        let mut filter: Box<dyn Filtering> = Box::new(CombinedFilter::new(
+            "outer".to_string(),
+            Box::new(inner),
+            Box::new(CountingFilter::new(true)),
+            BoolOperator::AND,
+        ));
+        let expected: Vec<bool> = (0..10).map(|_| filter.apply_to(&record_1)).collect();
+        let profiles = profile_leaves(&mut filter);
+        let names: Vec<&str> = profiles.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["test 1", "test 2", "counting true"]);
+        assert_eq!((0..10).map(|_| filter.apply_to(&record_1)).collect::<Vec<bool>>(), expected);
+        for (_, profile) in &profiles {
+            assert!(profile.calls() <= 10);
+        }
+        assert_eq!(profiles[0].1.calls(), 10);
+        assert_eq!(filter.name(), "outer");
     }
 
-    fn repr(&self) -> String {
-        format!(
-            "RefNameFilter(name={}, ref_id={}, opposite={})",
-            self.name, self.ref_id, self.opposite
-        )
+    #[rstest]
+    // adapter AGATCGGAAGAGC
+    #[case("ACGTTGCAACGTTGCAACGT", false, 2, false)]
+    #[case("ACGTTGCAACAGATCGGAAGAGCTT", false, 0, true)]
+    #[case("ACGTTGCAACAGTTCGGAAGTGCTT", false, 1, false)]
+    #[case("ACGTTGCAACAGTTCGGAAGTGCTT", false, 2, true)]
+    #[case("ACGTTGCAACGTTGCAAGATCGG", false, 0, true)]
+    #[case("ACGTTGCAACGTTGCAACGAGAT", false, 0, false)]
+    #[case("AAGCTCTTCCGATCTGTTGCAACGT", true, 0, true)]
+    #[case("ACGTTGCAACAGATCGGAAGAGCTT", true, 2, false)]
+    fn test_adapter_filter(#[case] seq: &str, #[case] reverse: bool, #[case] max_mismatch: u32,
+                           #[case] expected: bool, opposite_base: bool) {
+        let filter = AdapterFilter::new("adapter".to_string(), "agatcggaagagc", max_mismatch, 5, opposite_base);
+        let mut record = Record::new();
+        record.set_seq_qual(seq.bytes(), std::iter::empty()).unwrap();
+        record.flag_mut().set_strand(!reverse);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
     }
-    fn name(&self) -> &str {
-        &self.name
+
+    #[rstest]
+    #[case("read1", script::CompareOp::Ge, 0.5, true)]
+    #[case("read2", script::CompareOp::Ge, 0.5, false)]
+    #[case("read2", script::CompareOp::Lt, 0.5, true)]
+    #[case("read3", script::CompareOp::Eq, 2.0, true)]
+    #[case("unannotated", script::CompareOp::Lt, 0.5, false)]
+    #[case("unannotated", script::CompareOp::Ge, 0.5, false)]
+    fn test_external_annotation_filter(#[case] read_name: &str, #[case] comparison: script::CompareOp,
+                                       #[case] threshold: f64, #[case] expected: bool, opposite_base: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scores.tsv");
+        std::fs::write(&path, "read\tscore\nread1\t0.9\nread2\t0.1\nread3\t2\n").unwrap();
+        let mut filter = ExternalAnnotationFilter::new(
+            "external".to_string(),
+            path,
+            "score".to_string(),
+            comparison,
+            threshold,
+            opposite_base,
+        );
+        filter.prepare(&bam::Header::new());
+        let mut record = Record::new();
+        record.set_name(read_name.bytes());
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
     }
-}
 
-#[typetag::serde]
-impl Filtering for NthNucleotideFilter {
-    fn apply_to(&self, record: &Record) -> bool {
-        assert_ne!(self.position, 0, "Position must be non-zero!");
-        let len = record.query_len() as i64;
-        if self.position.abs() > len {
-            return utils::_opposite(false, self.opposite);
-        }
-        if !record.sequence().available() {
-            return utils::_opposite(false, self.opposite);
+    #[rstest]
+    #[case(None, 0, 0, true)]
+    #[case(None, 1, 5, false)]
+    #[case(Some("chr2,1000,+,50M50S,60,0;"), 1, 1, true)]
+    #[case(Some("chr2,1000,+,50M50S,60,0;"), 2, 5, false)]
+    #[case(Some("chr2,1000,+,50M50S,60,0;chr5,300,-,30S70M,20,1;"), 2, 5, true)]
+    #[case(Some("chr2,1000,+,50M50S,60,0;chr5,300,-,30S70M,20,1"), 2, 2, true)]
+    #[case(Some("chr2,1000,+,50M50S,60,0;chr5,300,-,30S70M,20,1;"), 0, 1, false)]
+    fn test_sa_count_filter(#[case] sa: Option<&str>, #[case] min_count: u32, #[case] max_count: u32,
+                            #[case] expected: bool, mut record_1: Record, opposite_base: bool) {
+        if let Some(sa) = sa {
+            record_1.tags_mut().push_string(b"SA", sa.as_bytes());
         }
+        let filter = SaCountFilter::new("sa".to_string(), min_count, max_count, opposite_base);
+        assert_eq!(filter.apply_to(&record_1), utils::_opposite(expected, opposite_base));
+    }
 
-        let position = if self.position < 0 {
-            len + self.position
-        } else {
-            self.position - 1
-        };
+    #[rstest]
+    // chr1 has a tract at 10-16 (TTTTTT), and a run too short to count at 20-23 (GGG); chrM has a lowercase tract at 2-8
+    #[case(0, 0, "10M", true)]
+    #[case(0, 5, "6M", false)]
+    #[case(0, 15, "5M", false)]
+    #[case(0, 16, "10M", true)]
+    #[case(0, 2, "5M10N5M", false)]
+    #[case(1, 0, "2M", true)]
+    #[case(1, 7, "2M", false)]
+    #[case(2, 0, "50M", true)]
+    fn test_ref_homopolymer_filter(#[case] ref_id: i32, #[case] start: i32, #[case] cigar: &str,
+                                   #[case] expected: bool, opposite_base: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ref.fa");
+        std::fs::write(&path, ">chr1\nACGTACGTAC\nTTTTTTACGTGGGACGTACGT\n>chrM\nACaaaaaaCG\n>chrX\nAAAAAAAAAA\n").unwrap();
+        let mut filter = RefHomopolymerFilter::new("homopolymer".to_string(), path, 4, opposite_base);
+        filter.prepare(&contig_header());
+        let record = spliced_record(ref_id, start, cigar);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
 
-        let this_nuc = if record.flag().is_reverse_strand() {
-            let range = (len - position - 1) as usize..(len - position) as usize;
-            record.sequence().rev_compl(range).next().unwrap() as char
-        } else {
-            record.sequence().at(position as usize) as char
+    #[rstest]
+    #[case(utils::NameField::Lane, "2", "A00123:8:H7KJ2DSXY:2:1101:15474:1000", true)]
+    #[case(utils::NameField::Lane, "1", "A00123:8:H7KJ2DSXY:2:1101:15474:1000", false)]
+    #[case(utils::NameField::Tile, "1101-1120", "A00123:8:H7KJ2DSXY:2:1101:15474:1000", true)]
+    #[case(utils::NameField::Tile, "1102-*", "A00123:8:H7KJ2DSXY:2:1101:15474:1000", false)]
+    #[case(utils::NameField::Tile, "1101-1120", "A00123:8:H7KJ2DSXY:2:1101:15474:1000:ACGTACGT", true)]
+    #[case(utils::NameField::Flowcell, "H7KJ2DSXY", "A00123:8:H7KJ2DSXY:2:1101:15474:1000", true)]
+    #[case(utils::NameField::Flowcell, "0-*", "A00123:8:H7KJ2DSXY:2:1101:15474:1000", false)]
+    #[case(utils::NameField::Lane, "2", "SRR001.2", false)]
+    #[case(utils::NameField::Lane, "2", "A00123:8:H7KJ2DSXY:2:1101", false)]
+    fn test_read_name_field_filter(#[case] field: utils::NameField, #[case] pattern: &str, #[case] read_name: &str,
+                                   #[case] expected: bool, opposite_base: bool, mut record_1: Record) {
+        record_1.set_name(read_name.bytes());
+        let filter = ReadNameFieldFilter::new("lane".to_string(), field, utils::NameFieldMatch::parse(pattern), opposite_base);
+        assert_eq!(filter.apply_to(&record_1), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    #[case("10H90M", 1, u32::MAX, true)]
+    #[case("100M", 1, u32::MAX, false)]
+    #[case("100M", 0, 0, true)]
+    #[case("10H90M", 0, 0, false)]
+    #[case("10H80M10H", 15, 20, true)]
+    #[case("10H90M", 15, 20, false)]
+    #[case("5H10S85M", 5, 5, true)]
+    fn test_hard_clip_filter(#[case] cigar: &str, #[case] min_clip: u32, #[case] max_clip: u32, #[case] expected: bool,
+                             opposite_base: bool) {
+        let filter = HardClipFilter::new("hard clip".to_string(), min_clip, max_clip, opposite_base);
+        let record = spliced_record(0, 100, cigar);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    // 20 bases with a low-quality tail of 12 bases
+    #[case(&[[35; 8].as_slice(), &[3; 12]].concat(), 20, 18, u32::MAX, false)]
+    #[case(&[[35; 8].as_slice(), &[3; 12]].concat(), 20, 5, 10, true)]
+    #[case(&[[35; 8].as_slice(), &[3; 12]].concat(), 2, 18, u32::MAX, true)]
+    #[case(&[35; 20], 20, 18, u32::MAX, true)]
+    #[case(&[35; 20], 20, 0, 19, false)]
+    fn test_trimmed_length_filter(#[case] qualities: &[u8], #[case] quality_threshold: u8, #[case] min_len: u32,
+                                  #[case] max_len: u32, #[case] expected: bool, opposite_base: bool) {
+        let mut record = Record::new();
+        record.set_seq_qual(std::iter::repeat_n(b'A', qualities.len()), qualities.iter().copied()).unwrap();
+        let filter = TrimmedLengthFilter::new("trimmed".to_string(), quality_threshold, min_len, max_len, opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    // (library type, paired, first in pair, read on reverse strand) -> transcript on the forward strand
+    #[case(utils::LibraryType::FrFirststrand, true, true, true, true)]
+    #[case(utils::LibraryType::FrFirststrand, true, true, false, false)]
+    #[case(utils::LibraryType::FrFirststrand, true, false, false, true)]
+    #[case(utils::LibraryType::FrFirststrand, true, false, true, false)]
+    #[case(utils::LibraryType::FrFirststrand, false, false, true, true)]
+    #[case(utils::LibraryType::FrSecondstrand, true, true, false, true)]
+    #[case(utils::LibraryType::FrSecondstrand, true, true, true, false)]
+    #[case(utils::LibraryType::FrSecondstrand, true, false, true, true)]
+    #[case(utils::LibraryType::FrSecondstrand, false, false, false, true)]
+    fn test_strand_assign_filter(#[case] library_type: utils::LibraryType, #[case] paired: bool, #[case] first: bool,
+                                 #[case] reverse: bool, #[case] expected_forward: bool, opposite_base: bool) {
+        let mut record = spliced_record(0, 100, "50M");
+        record.flag_mut().set_paired(paired);
+        record.flag_mut().set_first_in_pair(paired && first);
+        record.flag_mut().set_last_in_pair(paired && !first);
+        record.flag_mut().set_strand(!reverse);
+        let forward = StrandAssignFilter::new("forward".to_string(), library_type, utils::Strand::Forward, opposite_base);
+        let reverse = StrandAssignFilter::new("reverse".to_string(), library_type, utils::Strand::Reverse, opposite_base);
+        assert_eq!(forward.apply_to(&record), utils::_opposite(expected_forward, opposite_base));
+        assert_eq!(reverse.apply_to(&record), utils::_opposite(!expected_forward, opposite_base));
+
+        record.flag_mut().set_mapped(false);
+        assert_eq!(forward.apply_to(&record), opposite_base);
+        assert_eq!(reverse.apply_to(&record), opposite_base);
+    }
+
+    #[rstest]
+    fn test_singleton_filter(opposite_base: bool) {
+        let read = |name: &str, paired: bool, first: bool| {
+            let mut record = spliced_record(0, 100, "50M");
+            record.set_name(name.bytes());
+            record.flag_mut().set_paired(paired);
+            record.flag_mut().set_first_in_pair(paired && first);
+            record.flag_mut().set_last_in_pair(paired && !first);
+            record
         };
+        let records = vec![
+            read("pair", true, true),
+            read("orphan", true, false),
+            read("unpaired", false, false),
+            read("pair", true, false),
+        ];
+        let filter = SingletonFilter::new("singleton".to_string(), opposite_base);
+        let mut stream = filter.to_streaming().unwrap();
+        let mut decided = Vec::new();
+        for record in records {
+            stream.push(record, &mut decided);
+        }
+        stream.finish(&mut decided);
+        let decided: Vec<(String, bool)> = decided
+            .into_iter()
+            .map(|(record, keep)| (String::from_utf8(record.name().to_vec()).unwrap(), keep))
+            .collect();
+        let expected = [("pair", true), ("orphan", false), ("unpaired", true), ("pair", true)];
+        let expected: Vec<(String, bool)> = expected
+            .iter()
+            .map(|(name, keep)| (name.to_string(), utils::_opposite(*keep, opposite_base)))
+            .collect();
+        assert_eq!(decided, expected);
+    }
 
-        return if self.n_is_wildcard && (this_nuc == 'N') {
-            utils::_opposite(true, self.opposite)
-        } else {
-            utils::_opposite(this_nuc == self.nucleotide, self.opposite)
+    #[rstest]
+    #[case(false, false, true, true)]
+    #[case(false, false, false, false)]
+    #[case(true, false, true, false)]
+    #[case(false, true, true, false)]
+    fn test_tagged_primary_filter(#[case] secondary: bool, #[case] supplementary: bool, #[case] tagged: bool,
+                                  #[case] expected: bool, opposite_base: bool) {
+        let mut record = spliced_record(0, 100, "50M");
+        record.flag_mut().set_secondary(secondary);
+        record.flag_mut().set_supplementary(supplementary);
+        if tagged {
+            record.tags_mut().push_string(b"CB", b"ACGTACGT");
+        }
+        let filter = TaggedPrimaryFilter::new("barcoded".to_string(), *b"CB", opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    // 20 bases with a low-quality tail of 8 bases: 40% below Q20
+    #[case(&[[35; 12].as_slice(), &[3; 8]].concat(), 20, 0.0, 0.1, false)]
+    #[case(&[[35; 12].as_slice(), &[3; 8]].concat(), 20, 0.4, 0.5, true)]
+    #[case(&[[35; 12].as_slice(), &[3; 8]].concat(), 2, 0.0, 0.1, true)]
+    #[case(&[35; 20], 20, 0.0, 0.1, true)]
+    #[case(&[35; 20], 36, 0.0, 0.1, false)]
+    fn test_low_qual_fraction_filter(#[case] qualities: &[u8], #[case] quality_threshold: u8, #[case] min_fraction: f64,
+                                     #[case] max_fraction: f64, #[case] expected: bool, opposite_base: bool) {
+        let mut record = Record::new();
+        record.set_seq_qual(std::iter::repeat_n(b'A', qualities.len()), qualities.iter().copied()).unwrap();
+        let filter = LowQualFractionFilter::new("low quality".to_string(), quality_threshold, min_fraction, max_fraction, opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_low_qual_fraction_filter_without_qualities() {
+        let mut record = Record::new();
+        record.set_seq_qual(b"ACGT".iter().copied(), std::iter::empty()).unwrap();
+        let filter = LowQualFractionFilter::new("low quality".to_string(), 20, 0.0, 1.0, false);
+        assert!(!filter.apply_to(&record));
+    }
+
+    #[rstest]
+    #[case(BoolOperator::AND, true, false, true)]
+    #[case(BoolOperator::AND, false, false, false)]
+    #[case(BoolOperator::OR, true, false, false)]
+    #[case(BoolOperator::OR, true, true, true)]
+    #[case(BoolOperator::NOR, false, false, false)]
+    #[case(BoolOperator::IMPLIES, false, true, false)]
+    #[case(BoolOperator::IMPLIES, true, false, false)]
+    fn test_combined_rejects_unmapped(#[case] operator: BoolOperator, #[case] mapped1: bool, #[case] mapped2: bool,
+                                      #[case] expected: bool) {
+        // an input that removes unmapped reads, or one that can't tell
+        let input = |mapped: bool| match mapped {
+            true => Box::new(FlagFilter::new("mapped".to_string(), 4, false)) as Box<dyn Filtering>,
+            false => Box::new(MapqFilter::new("mapq".to_string(), 30, 255, false)),
         };
+        let filter = CombinedFilter::new("combined".to_string(), input(mapped1), input(mapped2), operator);
+        assert_eq!(filter.rejects_unmapped(), expected);
     }
 
-    fn repr(&self) -> String {
-        format!(
-            "NthNucleotideFilter(name={}, position={}, nucleotide={:?}, opposite={})",
-            self.name, self.position, self.nucleotide, self.opposite
-        )
+    #[rstest]
+    fn test_rejects_unmapped() {
+        assert!(FlagFilter::new("mapped".to_string(), 4 | 256, false).rejects_unmapped());
+        assert!(!FlagFilter::new("unmapped".to_string(), 4, true).rejects_unmapped());
+        assert!(!FlagFilter::new("primary".to_string(), 256, false).rejects_unmapped());
+        assert!(FlagMaskFilter::new("mask".to_string(), 1, 4, false).rejects_unmapped());
+        assert!(StrandFilter::new("plus".to_string(), utils::Strand::Forward, false).rejects_unmapped());
+        assert!(!StrandFilter::new("plus".to_string(), utils::Strand::Forward, true).rejects_unmapped());
     }
-    fn name(&self) -> &str {
-        &self.name
+
+    #[rstest]
+    // the references of contig_header() are 10000 bases long, and the reads span 50 bases
+    #[case(0, 100, "50M", true)]
+    #[case(0, 99, "50M", false)]
+    #[case(0, 5000, "50M", true)]
+    #[case(0, 9850, "50M", true)]
+    #[case(0, 9851, "50M", false)]
+    #[case(0, 9800, "10M100N40M", false)]
+    #[case(4, 5000, "50M", true)]
+    #[case(5, 5000, "50M", false)]
+    #[case(-1, 5000, "50M", false)]
+    fn test_contig_margin_filter(#[case] ref_id: i32, #[case] start: i32, #[case] cigar: &str, #[case] expected: bool,
+                                 opposite_base: bool) {
+        let mut filter = ContigMarginFilter::new("margin".to_string(), 100, opposite_base);
+        filter.prepare(&contig_header());
+        let record = spliced_record(ref_id, start, cigar);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rstest::{fixture, rstest};
+    #[rstest]
+    #[should_panic(expected = "Filter margin was not prepared with the input header!")]
+    fn test_contig_margin_filter_unprepared() {
+        let filter = ContigMarginFilter::new("margin".to_string(), 100, false);
+        filter.apply_to(&spliced_record(0, 5000, "50M"));
+    }
 
-    use super::*;
+    fn pair_record(start: i32, mate_start: i32, proper: bool) -> Record {
+        let mut record = spliced_record(0, start, "50M");
+        record.flag_mut().set_paired(true);
+        record.flag_mut().set_mate_mapped(true);
+        record.flag_mut().set_all_segments_aligned(proper);
+        record.set_mate_ref_id(0);
+        record.set_mate_start(mate_start);
+        record
+    }
 
-    #[fixture]
-    fn opposite_base() -> bool { false }
+    #[rstest]
+    // the breakpoint is after base 5000 (1-based) of chr1, i.e. between 0-based positions 4999 and 5000
+    #[case(pair_record(4000, 6000, false), true)]
+    #[case(pair_record(6000, 4000, false), true)]
+    #[case(pair_record(4999, 5000, false), true)]
+    #[case(pair_record(4000, 4500, false), false)]
+    #[case(pair_record(5000, 6000, false), false)]
+    #[case(pair_record(4000, 6000, true), false)]
+    #[case(spliced_record(0, 5000, "10S40M"), true)]
+    #[case(spliced_record(0, 5005, "10S40M"), true)]
+    #[case(spliced_record(0, 5006, "10S40M"), false)]
+    #[case(spliced_record(0, 4960, "40M10S"), true)]
+    #[case(spliced_record(0, 4900, "40M10S"), false)]
+    #[case(spliced_record(0, 5000, "50M"), false)]
+    #[case(spliced_record(1, 5000, "10S40M"), false)]
+    fn test_breakpoint_filter(#[case] record: Record, #[case] expected: bool, opposite_base: bool) {
+        let mut filter = BreakpointFilter::new("sv".to_string(), "chr1".to_string(), 5000, 5, opposite_base);
+        filter.prepare(&contig_header());
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
 
-    #[fixture]
-    fn length_filter(opposite_base: bool) -> LengthFilter {
-        LengthFilter::new("test 1".to_string(), 18, 24, opposite_base)
+    #[rstest]
+    fn test_breakpoint_filter_missing_reference() {
+        let mut filter = BreakpointFilter::new("sv".to_string(), "chr2".to_string(), 5000, 5, false);
+        filter.prepare(&contig_header());
+        assert!(!filter.apply_to(&pair_record(4000, 6000, false)));
     }
 
-    #[fixture]
-    fn mapq_filter(opposite_base: bool) -> MapqFilter {
-        MapqFilter::new("test 2".to_string(), 4, 20, opposite_base)
+    #[rstest]
+    #[should_panic(expected = "Filter pairs depends on other records and cannot be combined with other filters!")]
+    fn test_pair_concordant_filter_nested() {
+        let mapq = Box::new(MapqFilter::new("mapq".to_string(), 0, 255, false));
+        let pairs = Box::new(PairConcordantFilter::new("pairs".to_string(), mapq.clone(), false));
+        let combined = CombinedFilter::new("combined".to_string(), pairs, mapq, BoolOperator::OR);
+        combined.apply_to(&pair_record(4000, 6000, false));
     }
 
-    #[fixture]
-    fn ref_name_filter(opposite_base: bool) -> RefNameFilter {
-        RefNameFilter::new("test 3".to_string(), 0, opposite_base)
+    #[rstest]
+    #[should_panic(expected = "Filter sv was not prepared with the input header!")]
+    fn test_breakpoint_filter_unprepared() {
+        let filter = BreakpointFilter::new("sv".to_string(), "chr1".to_string(), 5000, 5, false);
+        filter.apply_to(&pair_record(4000, 6000, false));
     }
 
-    #[fixture]
-    fn nth_nucleotide_filter(opposite_base: bool) -> NthNucleotideFilter {
-        NthNucleotideFilter::new("test 4".to_string(), 1, 'G', false, opposite_base)
+    fn sequenced_record(ref_id: i32, start: i32, seq: &str, cigar: &str) -> Record {
+        let mut record = Record::new();
+        record.set_seq_qual(seq.bytes(), std::iter::repeat_n(30, seq.len())).unwrap();
+        record.set_ref_id(ref_id);
+        record.set_start(start);
+        record.set_cigar(cigar.bytes()).unwrap();
+        record
     }
 
-    #[fixture]
-    fn record_1() -> Record {
+    #[rstest]
+    // chr1 is ACGTACGTAC TTGCAGGCAT (0-based positions 0-19); chrM is lowercase
+    #[case(sequenced_record(0, 0, "ACGTACGTAC", "10M"), true)]
+    #[case(sequenced_record(0, 0, "ACGTACCTAC", "10M"), false)]
+    #[case(sequenced_record(0, 10, "TTGCA", "5="), true)]
+    #[case(sequenced_record(0, 4, "GGGGACGTAC", "4S6M"), true)]
+    #[case(sequenced_record(0, 0, "ACGTAACGTA", "5M1I4M"), false)]
+    #[case(sequenced_record(0, 0, "ACGTCGTAC", "4M1D5M"), false)]
+    #[case(sequenced_record(0, 0, "ACGTCAT", "4M13N3M"), true)]
+    #[case(sequenced_record(0, 15, "GGCATA", "6M"), false)]
+    #[case(sequenced_record(1, 2, "GTAC", "4M"), true)]
+    #[case(sequenced_record(2, 0, "ACGT", "4M"), false)]
+    fn test_perfect_match_filter(#[case] record: Record, #[case] expected: bool, opposite_base: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ref.fa");
+        std::fs::write(&path, ">chr1 assembled\nACGTACGTAC\nTTGCAGGCAT\n>chrM\nacgtac\n").unwrap();
+        let mut filter = PerfectMatchFilter::new("perfect".to_string(), path, opposite_base);
+        filter.prepare(&contig_header());
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_perfect_match_filter_unmapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ref.fa");
+        std::fs::write(&path, ">chr1\nACGTACGTAC\n").unwrap();
+        let mut filter = PerfectMatchFilter::new("perfect".to_string(), path, false);
+        filter.prepare(&contig_header());
+        let mut record = sequenced_record(0, 0, "ACGT", "4M");
+        record.flag_mut().set_mapped(false);
+        assert!(!filter.apply_to(&record));
+    }
+
+    fn chimeric_record(ref_id: i32, start: i32, sa: &str, supplementary: bool) -> Record {
+        let mut record = spliced_record(ref_id, start, "30M20S");
+        if !sa.is_empty() {
+            record.tags_mut().push_string(b"SA", sa.as_bytes());
+        }
+        record.flag_mut().set_supplementary(supplementary);
+        record
+    }
+
+    #[rstest]
+    // SA positions are 1-based, so 'chr1,1001' starts at the same position as a record starting at 1000
+    #[case(chimeric_record(0, 1000, "chr1,5001,+,30S20M,60,0;", false), true)]
+    #[case(chimeric_record(0, 5000, "chr1,1001,+,20S30M,60,0;", false), false)]
+    #[case(chimeric_record(0, 1000, "chr1,1001,-,30S20M,60,0;", false), true)]
+    #[case(chimeric_record(0, 1000, "chr1,1000,-,30S20M,60,0;", false), false)]
+    #[case(chimeric_record(0, 9000, "chrM,101,+,30S20M,60,0;", false), true)]
+    #[case(chimeric_record(1, 100, "chr1,9001,+,30S20M,60,0;", false), false)]
+    #[case(chimeric_record(0, 1000, "chr1,5001,+,30S20M,60,0;chrM,11,+,20M30S,60,0;", false), true)]
+    #[case(chimeric_record(1, 100, "chrM,5001,+,30S20M,60,0;chr1,11,+,20M30S,60,0;", false), false)]
+    #[case(chimeric_record(1, 100, "chr2,11,+,30S20M,60,0;", false), true)]
+    #[case(chimeric_record(0, 1000, "chr1,5001,+,30S20M,60,0;", true), false)]
+    #[case(chimeric_record(0, 1000, "", false), false)]
+    fn test_leftmost_split_filter(#[case] record: Record, #[case] expected: bool, opposite_base: bool) {
+        let mut filter = LeftmostSplitFilter::new("leftmost".to_string(), opposite_base);
+        filter.prepare(&contig_header());
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Filter rg depends on other records and cannot be combined with other filters!")]
+    fn test_rg_quality_filter_nested() {
+        let rg = Box::new(RgQualityFilter::new("rg".to_string(), 0.03, false));
+        let mapq = Box::new(MapqFilter::new("mapq".to_string(), 0, 255, false));
+        let combined = CombinedFilter::new("combined".to_string(), rg, mapq, BoolOperator::AND);
+        combined.apply_to(&spliced_record(0, 100, "50M"));
+    }
+
+    #[rstest]
+    // the region covers the 0-based positions 1000-1999, and the reads span 50 bases
+    #[case(utils::RegionMode::Contained, 0, 1000, true)]
+    #[case(utils::RegionMode::Contained, 0, 1950, true)]
+    #[case(utils::RegionMode::Contained, 0, 980, false)]
+    #[case(utils::RegionMode::Contained, 0, 1980, false)]
+    #[case(utils::RegionMode::Contained, 0, 3000, false)]
+    #[case(utils::RegionMode::Spanning, 0, 980, true)]
+    #[case(utils::RegionMode::Spanning, 0, 1980, true)]
+    #[case(utils::RegionMode::Spanning, 0, 1500, false)]
+    #[case(utils::RegionMode::Spanning, 0, 950, false)]
+    #[case(utils::RegionMode::Spanning, 0, 2000, false)]
+    #[case(utils::RegionMode::Overlapping, 0, 951, true)]
+    #[case(utils::RegionMode::Overlapping, 0, 1500, true)]
+    #[case(utils::RegionMode::Overlapping, 0, 1999, true)]
+    #[case(utils::RegionMode::Overlapping, 0, 950, false)]
+    #[case(utils::RegionMode::Overlapping, 1, 1500, false)]
+    #[case(utils::RegionMode::Overlapping, -1, 1500, false)]
+    fn test_region_mode_filter(#[case] mode: utils::RegionMode, #[case] ref_id: i32, #[case] start: i32,
+                               #[case] expected: bool, opposite_base: bool) {
+        let region = "chr1:1,001-2,000".to_string();
+        let mut filter = RegionModeFilter::new("edges".to_string(), region, mode, opposite_base);
+        filter.prepare(&contig_header());
+        let record = spliced_record(ref_id, start, "50M");
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    #[case("chrM", 9950, true)]
+    #[case("chrM", 9951, false)]
+    #[case("chr2", 100, false)]
+    fn test_region_mode_filter_whole_reference(#[case] region: &str, #[case] start: i32, #[case] expected: bool) {
+        let mode = utils::RegionMode::Contained;
+        let mut filter = RegionModeFilter::new("mito".to_string(), region.to_string(), mode, false);
+        filter.prepare(&contig_header());
+        assert_eq!(filter.apply_to(&spliced_record(1, start, "50M")), expected);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Invalid region 'chr1:200-100'")]
+    fn test_region_mode_filter_invalid_region() {
+        RegionModeFilter::new("edges".to_string(), "chr1:200-100".to_string(), utils::RegionMode::Contained, false);
+    }
+
+    fn qualities_record(qualities: &[u8], reverse: bool) -> Record {
         let mut record = Record::new();
-        record.set_seq_qual("ACGT".bytes(), [10_u8, 20, 30, 10].iter().cloned()).unwrap();
-        record.set_ref_id(0);
-        record.set_mapq(20);
-        record.set_flag(0);
-        // record.tags_mut().push_num(TagName::tryFrom("NM").unwrap(),0);
+        record.set_seq_qual(std::iter::repeat_n(b'A', qualities.len()), qualities.iter().copied()).unwrap();
+        record.flag_mut().set_strand(!reverse);
         record
     }
 
-    #[fixture]
-    fn record_2() -> Record {
+    #[rstest]
+    // a sharp cliff after 6 good bases, stored reversed for reverse-strand reads
+    #[case(&[35, 35, 35, 35, 35, 35, 8, 6, 4, 2], false, 6, true)]
+    #[case(&[35, 35, 35, 35, 35, 35, 8, 6, 4, 2], false, 7, false)]
+    #[case(&[2, 4, 6, 8, 35, 35, 35, 35, 35, 35], true, 6, true)]
+    #[case(&[2, 4, 6, 8, 35, 35, 35, 35, 35, 35], false, 6, false)]
+    #[case(&[25; 10], false, 10, true)]
+    #[case(&[25; 10], true, 10, true)]
+    #[case(&[15; 10], false, 1, false)]
+    fn test_quality_cliff_filter(#[case] qualities: &[u8], #[case] reverse: bool, #[case] min_len: u32,
+                                 #[case] expected: bool, opposite_base: bool) {
+        let filter = QualityCliffFilter::new("cliff".to_string(), 20, min_len, 2, opposite_base);
+        assert_eq!(filter.apply_to(&qualities_record(qualities, reverse)), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_quality_cliff_filter_without_qualities() {
+        let filter = QualityCliffFilter::new("cliff".to_string(), 20, 0, 4, false);
         let mut record = Record::new();
-        record.set_seq_qual("GACGTAAGGGCATTACGANN".bytes(),
-                            [10_u8, 20, 30, 10, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30].iter().cloned()).unwrap();
-        record.set_ref_id(0);
-        record.set_mapq(0);
-        record.set_flag(1040);
-        // record.tags_mut().push_num(TagName::tryFrom("NM").unwrap(),0);
+        record.set_seq_qual(b"ACGT".iter().copied(), std::iter::repeat_n(0xff, 4)).unwrap();
+        assert!(!filter.apply_to(&record));
+    }
+
+    #[rstest]
+    // coverage of chr1: 0-100 at 30x, 100-200 at 5000x (an artifact), 200-300 at 0x; chr2: 1000-1100 at 40x
+    #[case(0, 20, "50M", true)]
+    #[case(0, 120, "50M", false)]
+    #[case(0, 80, "50M", true)]
+    #[case(0, 150, "20M100N10M", false)]
+    #[case(0, 400, "50M", false)]
+    #[case(1, 1050, "10M", true)]
+    #[case(1, 500, "10M", false)]
+    fn test_coverage_filter(#[case] ref_id: i32, #[case] start: i32, #[case] cigar: &str, #[case] expected: bool,
+                            opposite_base: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coverage.bedgraph");
+        let bedgraph = "track type=bedGraph\nchr1\t0\t100\t30\nchr1\t100\t200\t5000\nchr1\t200\t300\t0\n\
+                        chr2\t1000\t1100\t40\nchrX\t0\t5000\t30\n";
+        std::fs::write(&path, bedgraph).unwrap();
+        let mut filter = CoverageFilter::new("coverage".to_string(), path, 10.0, 1000.0, opposite_base);
+        filter.prepare(&subtract_header());
+        let record = spliced_record(ref_id, start, cigar);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    // the read is ACGTACGTACGTACGTACGT with qualities of 30, except for the last 4 bases, of 5
+    #[case(false, 2, 3, None, "GTACGTACGTACGTA", 102)]
+    #[case(true, 2, 3, None, "TACGTACGTACGTAC", 103)]
+    #[case(false, 2, 0, Some(20), "GTACGTACGTACGT", 102)]
+    #[case(true, 0, 2, Some(20), "GTACGTACGTACGT", 102)]
+    fn test_trim_filter_transform(#[case] reverse: bool, #[case] leading: u32, #[case] trailing: u32,
+                                  #[case] quality_threshold: Option<u8>, #[case] expected_seq: &str,
+                                  #[case] expected_start: i32) {
+        let mut record = spliced_record(0, 100, "20M");
+        let qualities: Vec<u8> = (0..20).map(|i| if i < 16 { 30 } else { 5 }).collect();
+        record.set_seq_qual(b"ACGTACGTACGTACGTACGT".iter().copied(), qualities.iter().copied()).unwrap();
+        record.flag_mut().set_strand(!reverse);
+        let filter = TrimFilter::new("trim".to_string(), leading, trailing, quality_threshold, 10, false);
+        assert!(filter.transforms());
+        assert!(filter.apply_to(&record));
+        filter.transform(&mut record);
+        assert_eq!(String::from_utf8(record.sequence().to_vec()).unwrap(), expected_seq);
+        assert_eq!(record.qualities().raw().len(), expected_seq.len());
+        assert_eq!(record.start(), expected_start);
+        assert_eq!(record.calculate_end() - record.start(), expected_seq.len() as i32);
+    }
+
+    #[rstest]
+    #[case(5, 35, true)]
+    #[case(5, 36, false)]
+    #[case(60, 0, false)]
+    fn test_trim_filter_min_len(#[case] leading: u32, #[case] trailing: u32, #[case] expected: bool,
+                                opposite_base: bool) {
+        let record = spliced_record(0, 100, "20M");
+        let filter = TrimFilter::new("trim".to_string(), leading, trailing, None, 10, opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_combined_filter_transforms_with_inputs() {
+        let trim = TrimFilter::new("trim".to_string(), 5, 0, None, 1, false);
+        let length = LengthFilter::new("length".to_string(), 10, 100, false);
+        let combined = CombinedFilter::new("both".to_string(), Box::new(length), Box::new(trim), BoolOperator::AND);
+        assert!(combined.transforms());
+        let mut record = spliced_record(0, 100, "20M");
+        combined.transform(&mut record);
+        assert_eq!(record.query_len(), 45);
+        assert!(!LengthFilter::new("length".to_string(), 10, 100, false).transforms());
+    }
+
+    #[rstest]
+    #[case("20S80M", 0.0, 0.1, false)]
+    #[case("2S98M", 0.0, 0.1, true)]
+    #[case("20S80M", 0.2, 0.3, true)]
+    #[case("2S98M", 0.2, 0.3, false)]
+    #[case("10S40M10N40M10S", 0.2, 0.3, true)]
+    #[case("5H100M", 0.0, 0.0, true)]
+    #[case("100S", 0.0, 100.0, false)]
+    fn test_clip_ratio_filter(#[case] cigar: &str, #[case] min_ratio: f64, #[case] max_ratio: f64,
+                              #[case] expected: bool, opposite_base: bool) {
+        let filter = ClipRatioFilter::new("clip ratio".to_string(), min_ratio, max_ratio, opposite_base);
+        assert_eq!(filter.apply_to(&spliced_record(0, 100, cigar)), utils::_opposite(expected, opposite_base));
+        let mut unmapped = spliced_record(0, 100, cigar);
+        unmapped.flag_mut().set_mapped(false);
+        assert_eq!(filter.apply_to(&unmapped), utils::_opposite(false, opposite_base));
+    }
+
+    #[rstest]
+    #[case("AAAAAAAAAA", 1, 1, true)]
+    #[case("AAAAAAAAAA", 2, 4, false)]
+    #[case("CACACACACA", 2, 2, true)]
+    #[case("CACACACACA", 3, 4, false)]
+    #[case("ACGTTGCAAC", 4, 4, true)]
+    #[case("ACGTTGCAAC", 1, 3, false)]
+    #[case("NNANNNNTNN", 2, 2, true)]
+    #[case("NNNNNNNNNN", 0, 0, true)]
+    fn test_distinct_bases_filter(#[case] seq: &str, #[case] min_distinct: u8, #[case] max_distinct: u8,
+                                  #[case] expected: bool, opposite_base: bool) {
+        let filter = DistinctBasesFilter::new("complexity".to_string(), min_distinct, max_distinct, opposite_base);
+        let mut record = Record::new();
+        record.set_seq_qual(seq.bytes(), std::iter::empty()).unwrap();
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Numbers of distinct bases must be between 0 and 4")]
+    fn test_distinct_bases_filter_too_many() {
+        DistinctBasesFilter::new("complexity".to_string(), 1, 5, false);
+    }
+
+    #[rstest]
+    #[case(0, 9, 10)]
+    #[case(5, 5, 1)]
+    #[case(18, u64::MAX, 2)]
+    fn test_record_index_filter(#[case] first_index: u64, #[case] last_index: u64, #[case] expected_kept: usize,
+                                opposite_base: bool) {
+        let filter = RecordIndexFilter::new("index".to_string(), first_index, last_index, opposite_base);
+        let mut stream = filter.to_streaming().unwrap();
+        let mut decided = Vec::new();
+        for i in 0..20 {
+            stream.push(spliced_record(0, i, "50M"), &mut decided);
+        }
+        stream.finish(&mut decided);
+        let kept: Vec<i32> = decided.iter().filter(|(_, keep)| *keep).map(|(record, _)| record.start()).collect();
+        let expected: Vec<i32> = (0..20)
+            .filter(|&i| utils::_opposite((first_index..=last_index).contains(&(i as u64)), opposite_base))
+            .collect();
+        assert_eq!(kept, expected);
+        assert_eq!(kept.len(), if opposite_base { 20 - expected_kept } else { expected_kept });
+    }
+
+    // a read with a mapped mate on the same reference, on the given strands, with the given TLEN
+    fn oriented_pair(start: i32, reverse: bool, mate_start: i32, mate_reverse: bool, template_len: i32) -> Record {
+        let mut record = mated_record(start, 0, mate_start, true);
+        record.flag_mut().set_strand(!reverse);
+        record.flag_mut().set_mate_strand(!mate_reverse);
+        record.set_template_len(template_len);
         record
     }
 
     #[rstest]
-    #[case(false, false)]
-    #[case(true, true)]
-    fn test_length_filter_below_range(#[case] opposite: bool, #[case]
-    expected: bool, #[with(opposite)] length_filter: LengthFilter, record_1: Record) {
-        assert_eq!(length_filter.apply_to(&record_1), expected);
+    #[case(oriented_pair(1000, false, 1200, true, 250), true)]
+    #[case(oriented_pair(1200, true, 1000, false, -250), true)]
+    #[case(oriented_pair(1000, false, 1000, true, 50), true)]
+    #[case(oriented_pair(1000, false, 1800, true, 850), false)]
+    #[case(oriented_pair(1000, false, 1000, true, 40), false)]
+    #[case(oriented_pair(1000, true, 1200, false, 250), false)]
+    #[case(oriented_pair(1000, false, 1200, false, 250), false)]
+    #[case(oriented_pair(1000, true, 1200, true, 250), false)]
+    #[case(oriented_pair(1000, false, 1200, true, 0), false)]
+    #[case(mated_record(1000, 1, 1200, true), false)]
+    #[case(mated_record(1000, 0, 1200, false), false)]
+    #[case(spliced_record(0, 1000, "50M"), false)]
+    fn test_concordant_pair_filter(#[case] record: Record, #[case] expected: bool, opposite_base: bool) {
+        let filter = ConcordantPairFilter::new("concordant".to_string(), 50, 500, opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
     }
 
     #[rstest]
-    #[case(false, true)]
-    #[case(true, false)]
-    fn test_length_filter_within_range(#[case] opposite: bool, #[case]
-    expected: bool, #[with(opposite)] length_filter: LengthFilter, record_2: Record) {
-        assert_eq!(length_filter.apply_to(&record_2), expected);
+    #[case("GGGGGATATATATAGGGGG", "5S9M5S", 0.0, 0.2, true)]
+    #[case("GGGGGATATATATAGGGGG", "5S9M5S", 0.5, 1.0, false)]
+    #[case("ATATAGCGCGCGCGATATA", "5S9M5S", 0.8, 1.0, true)]
+    #[case("ATATAGCGCGCGCGATATA", "19M", 0.8, 1.0, false)]
+    #[case("ATATGGGGATATATAT", "4M4I8M", 0.0, 0.1, true)]
+    #[case("GCNNNNNNAT", "10M", 0.5, 0.5, true)]
+    #[case("GGGGGNNNNNGGGGG", "5S5M5S", 0.0, 1.0, false)]
+    fn test_aligned_gc_filter(#[case] seq: &str, #[case] cigar: &str, #[case] min_fraction: f64,
+                              #[case] max_fraction: f64, #[case] expected: bool, opposite_base: bool) {
+        let filter = AlignedGcFilter::new("aligned gc".to_string(), min_fraction, max_fraction, opposite_base);
+        assert_eq!(filter.apply_to(&clipped_record(seq, cigar, false)), utils::_opposite(expected, opposite_base));
+        assert_eq!(filter.apply_to(&clipped_record(seq, cigar, true)), utils::_opposite(expected, opposite_base));
     }
 
     #[rstest]
-    #[case(false, false)]
-    #[case(true, true)]
-    fn test_mapq_filter_below_range(#[case] opposite: bool, #[case]
-    expected: bool, #[with(opposite)] mapq_filter: MapqFilter, record_2: Record) {
-        assert_eq!(mapq_filter.apply_to(&record_2), expected);
+    fn test_aligned_gc_filter_unmapped(opposite_base: bool) {
+        let filter = AlignedGcFilter::new("aligned gc".to_string(), 0.0, 1.0, opposite_base);
+        assert_eq!(filter.apply_to(&unmapped_record()), utils::_opposite(false, opposite_base));
+    }
+
+    fn scored_record(len: usize, score: Option<i32>) -> Record {
+        let cigar = if len > 0 { format!("{}M", len) } else { String::new() };
+        let mut record = clipped_record(&"A".repeat(len), &cigar, false);
+        if let Some(score) = score {
+            record.tags_mut().push_num(b"AS", score);
+        }
+        record
     }
 
+    #[rstest]
+    #[case(scored_record(50, Some(60)), true)]
+    #[case(scored_record(100, Some(60)), false)]
+    #[case(scored_record(60, Some(60)), true)]
+    #[case(scored_record(50, Some(-10)), false)]
+    #[case(scored_record(50, None), false)]
+    #[case(scored_record(0, Some(60)), false)]
+    fn test_as_per_base_filter(#[case] record: Record, #[case] expected: bool, opposite_base: bool) {
+        let filter = AsPerBaseFilter::new("as per base".to_string(), 1.0, 2.0, opposite_base);
+        assert_eq!(filter.apply_to(&record), utils::_opposite(expected, opposite_base));
+    }
 
     #[rstest]
-    #[case(false, true)]
-    #[case(true, false)]
-    fn test_mapq_filter_within_range(#[case] opposite: bool, #[case]
-    expected: bool, #[with(opposite)] mapq_filter: MapqFilter, record_1: Record) {
-        assert_eq!(mapq_filter.apply_to(&record_1), expected);
+    fn test_as_per_base_filter_float_score() {
+        let mut record = scored_record(40, None);
+        record.tags_mut().push_num(b"AS", 30.0f32);
+        let filter = AsPerBaseFilter::new("as per base".to_string(), 0.7, 0.8, false);
+        assert!(filter.apply_to(&record));
+    }
+
+    #[rstest]
+    #[case("50M", true)]
+    #[case("5S45M", true)]
+    #[case("5S40M5S", true)]
+    #[case("5H5S35M5S5H", true)]
+    #[case("20M10N5S25M", false)]
+    #[case("20M5S100N5S20M", false)]
+    #[case("20M5S25M", false)]
+    #[case("5H20M5S20M5H", false)]
+    fn test_internal_clip_filter(#[case] cigar: &str, #[case] expected: bool, opposite_base: bool) {
+        let filter = InternalClipFilter::new("internal clip".to_string(), opposite_base);
+        assert_eq!(filter.apply_to(&spliced_record(0, 100, cigar)), utils::_opposite(expected, opposite_base));
+    }
+
+    #[rstest]
+    fn test_internal_clip_filter_unmapped(opposite_base: bool) {
+        let filter = InternalClipFilter::new("internal clip".to_string(), opposite_base);
+        assert_eq!(filter.apply_to(&unmapped_record()), utils::_opposite(true, opposite_base));
     }
 }
 
 // #[cfg(test)]
-
@@ -3,29 +3,57 @@ extern crate clap;
 extern crate serde;
 extern crate serde_json;
 
+use std::collections::VecDeque;
 use std::fs::OpenOptions;
-use std::io::{BufRead, Read, Write};
+use std::io::{IsTerminal, Read, Write};
 use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use bam::{RecordReader, RecordWriter};
 use bam::record::Record;
-use clap::{command, Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use indexmap::IndexMap;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::filters::Filtering;
-use crate::utils::BoolOperator;
+use crate::pairing::MateBuffer;
+use crate::streaming::{MatePairStream, StreamingFilter};
+use crate::utils::{BoolOperator, ErrorKind};
 
 mod filters;
+mod pairing;
+#[cfg(feature = "remote")]
+mod remote;
+mod script;
+mod streaming;
 mod utils;
 
+// Number of records evaluated together when filtering in parallel
+const PARALLEL_BATCH_SIZE: usize = 4096;
+
+// Maximum number of kept records held in memory by --shuffle
+const MAX_SHUFFLE_RECORDS: usize = 10_000_000;
+
+// Maximum number of read groups (and so of open output files) written by --split-by-rg
+const MAX_READ_GROUP_OUTPUTS: usize = 256;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short = 'p', long, env)]
+    #[arg(short = 'p', long, env)]
     bametric_path: Option<PathBuf>,
 
+    /// Print errors to stderr as JSON objects with 'error_kind', 'message' and 'path' fields, instead of text.
+    /// Errors of no particular kind ('error') are also printed as text, with where they were raised
+    #[arg(long, global = true)]
+    json_errors: bool,
+
     #[command(subcommand)]
     cmd: Commands,
 }
@@ -34,10 +62,105 @@ struct Args {
 enum CreateCommands {
     /// Create a filter based on read length
     Length {
-        /// Minimum read length (inclusive)
+        /// Minimum read length (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_len: utils::Limit<u32>,
+        /// Maximum read length (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_len: utils::Limit<u32>,
+    },
+    /// Create a filter that trims the reads it keeps: a fixed number of bases from each end of the original read,
+    /// and optionally the low-quality bases left at its ends (BWA-style). The trimmed bases are hard-clipped in the
+    /// CIGAR of mapped reads, whose start position moves accordingly, and their MD and NM tags are removed.
+    /// Reads too short after trimming are removed. Modifies the reads, so it is only applied with --transform
+    Trim {
+        /// Number of bases to trim from the 5' end of the original read
+        #[arg(long, default_value = "0")]
+        leading: u32,
+        /// Number of bases to trim from the 3' end of the original read
+        #[arg(long, default_value = "0")]
+        trailing: u32,
+        /// Also trim low-quality bases from both ends, with this Phred quality threshold
+        #[arg(short = 'q', long = "quality")]
+        quality_threshold: Option<u8>,
+        /// Minimum length of a read after trimming (inclusive)
+        #[arg(long, default_value = "1")]
+        min_len: u32,
+    },
+    /// Create a filter based on the read length left after BWA-style quality trimming from both ends.
+    /// The reads themselves are not trimmed
+    TrimmedLength {
+        /// Phred quality threshold of the trimming
+        #[arg(short = 'q', long = "quality", default_value = "20")]
+        quality_threshold: u8,
+        /// Minimum trimmed length (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_len: utils::Limit<u32>,
+        /// Maximum trimmed length (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_len: utils::Limit<u32>,
+    },
+    /// Create a filter that keeps reads whose usable length, before their 3' quality cliff, is long enough.
+    /// The cliff is the start of the first window of bases (from the 5' end of the original read) whose mean quality
+    /// is below the threshold. Reads without base qualities are removed
+    QualityCliff {
+        /// Phred quality threshold of the mean quality of a window
+        #[arg(short = 'q', long = "quality", default_value = "20")]
+        quality_threshold: u8,
+        /// Number of bases whose mean quality is compared to the threshold
+        #[arg(short = 'w', long, default_value = "4")]
+        window: u32,
+        /// Minimum number of bases before the cliff (inclusive)
         min_len: u32,
-        /// Maximum read length (inclusive)
-        max_len: u32,
+    },
+    /// Create a filter based on the fraction of bases with a quality below a threshold.
+    /// Unlike the mean quality, this catches reads with a good start and a bad tail. Reads without base qualities are removed
+    LowQualFraction {
+        /// Phred quality threshold. Bases of a lower quality are counted as low-quality
+        #[arg(short = 'q', long = "quality", default_value = "20")]
+        quality_threshold: u8,
+        /// Minimum fraction of low-quality bases (inclusive)
+        min_fraction: f64,
+        /// Maximum fraction of low-quality bases (inclusive)
+        max_fraction: f64,
+    },
+    /// Create a filter that keeps records by their 0-based position in the input file (e.g. to isolate a problematic
+    /// record by its ordinal). Depends on the order of the input: the positions count every record read,
+    /// so they shift with --region or when the unmapped reads at the end of an indexed BAM file are skipped.
+    /// It cannot be combined with other filters
+    RecordIndex {
+        /// Position of the first record to keep (inclusive). '*' or omitted for the start of the file
+        #[arg(default_value = "*")]
+        first_index: utils::Limit<u64>,
+        /// Position of the last record to keep (inclusive). '*' or omitted for the end of the file
+        #[arg(default_value = "*")]
+        last_index: utils::Limit<u64>,
+    },
+    /// Create a filter based on how many of the bases A, C, G and T appear in the read, as a cheap complexity gate
+    /// (1 for a homopolymer, 4 if all bases are present). N bases are not counted
+    DistinctBases {
+        /// Minimum number of distinct bases (inclusive)
+        min_distinct: u8,
+        /// Maximum number of distinct bases (inclusive)
+        max_distinct: u8,
+    },
+    /// Create a filter based on the number of soft-clipped bases per aligned (M/=/X) base,
+    /// e.g. 0.25 for '20S80M'. Unmapped reads are removed
+    ClipRatio {
+        /// Minimum ratio of soft-clipped to aligned bases (inclusive)
+        min_ratio: f64,
+        /// Maximum ratio of soft-clipped to aligned bases (inclusive)
+        max_ratio: f64,
+    },
+    /// Create a filter based on the alignment score (AS tag) per base of the read, so that short and long reads
+    /// are compared fairly. The length includes soft-clipped bases. Reads without an AS tag are removed
+    AsPerBase {
+        /// Minimum alignment score per base (inclusive)
+        #[arg(allow_negative_numbers = true)]
+        min_score: f64,
+        /// Maximum alignment score per base (inclusive)
+        #[arg(allow_negative_numbers = true)]
+        max_score: f64,
     },
     /// Create a filter based on a tag:value pair
     Tag {
@@ -47,13 +170,19 @@ enum CreateCommands {
         tag_type: utils::CliTagType,
         /// Tag value
         tag_value: String,
+        /// Also compare integer and float values numerically (e.g. an int filter value 3 matches a float tag value 3.0).
+        /// Integer values always match regardless of the integer type they are stored as
+        #[arg(long)]
+        numeric: bool,
     },
     /// Create a filter based on mapping quality
     Mapq {
-        /// Minimum mapping quality (inclusive)
-        min_mapq: u8,
-        /// Maximum mapping quality (inclusive)
-        max_mapq: u8,
+        /// Minimum mapping quality (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_mapq: utils::Limit<u8>,
+        /// Maximum mapping quality (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_mapq: utils::Limit<u8>,
     },
     /// Create a filter based on the reference name
     RefName { ref_id: i32 },
@@ -66,7 +195,7 @@ enum CreateCommands {
         /// Nucleotide to be matched
         nucleotide: char,
         /// Treat 'N' nucleotides as a wildcard, matching them to any other nucleotide.
-        #[clap(short = 'w', long, action = clap::ArgAction::SetTrue)]
+        #[arg(short = 'w', long, action = clap::ArgAction::SetTrue)]
         n_is_wildcard: bool,
     },
     /// Create a filter based on the bitwise SAM flags
@@ -75,6 +204,417 @@ enum CreateCommands {
         /// Any read that matches at least one of the specified flags will be removed.
         remove_flags: u16,
     },
+    /// Create a filter that keeps reads with all the required flag bits set and none of the excluded ones
+    /// (like `samtools view -f/-F`)
+    FlagMask {
+        /// Flag bits that must all be set
+        #[arg(short = 'f', long, default_value = "0")]
+        require_mask: u16,
+        /// Flag bits that must all be unset
+        #[arg(short = 'F', long, default_value = "0")]
+        exclude_mask: u16,
+    },
+    /// Create a filter that keeps reads aligned to the forward or the reverse strand. Unmapped reads are removed
+    Strand {
+        /// The strand that reads must be aligned to
+        #[arg(value_enum)]
+        strand: utils::Strand,
+    },
+    /// Create a filter based on a field of Illumina read names (instrument:run:flowcell:lane:tile:x:y),
+    /// e.g. to keep the reads of one lane or a range of tiles. Reads whose name has fewer fields are removed
+    NameField {
+        /// The field to match, by name or 0-based index
+        #[arg(value_enum)]
+        field: utils::NameField,
+        /// The value of the field, or an inclusive numeric range as 'min-max' (either end may be '*')
+        pattern: String,
+    },
+    /// Create a filter that keeps the reads of stranded RNA-seq assigned to the transcripts of one strand,
+    /// based on the library type and the strand and mate of each read. Unmapped reads are removed
+    StrandAssign {
+        /// Library type, which tells which read of a pair is aligned to the strand of the transcript.
+        /// Unpaired reads are treated as first reads
+        #[arg(value_enum)]
+        library_type: utils::LibraryType,
+        /// The strand of the transcripts whose reads are kept
+        #[arg(value_enum)]
+        strand: utils::Strand,
+    },
+    /// Create a filter based on the fraction of a set of bases in the read (e.g. AG for purines)
+    Composition {
+        /// Set of bases to be counted (e.g. AG for purines, CT for pyrimidines)
+        bases: String,
+        /// Minimum fraction of the read made up of the base set (inclusive). 'N' bases are ignored.
+        min_fraction: f64,
+        /// Maximum fraction of the read made up of the base set (inclusive). 'N' bases are ignored.
+        max_fraction: f64,
+    },
+    /// Create a filter based on the GC content of the aligned (M/=/X) bases of a read, leaving out soft clips
+    /// (e.g. adapter sequence) and insertions. Unmapped reads are removed
+    AlignedGc {
+        /// Minimum fraction of G and C among the aligned bases (inclusive). 'N' bases are ignored.
+        min_fraction: f64,
+        /// Maximum fraction of G and C among the aligned bases (inclusive). 'N' bases are ignored.
+        max_fraction: f64,
+    },
+    /// Create a filter based on the presence of a motif (e.g. adapter sequence) in the soft-clipped bases of a read
+    ClipContent {
+        /// Motif to search for in the soft-clipped bases
+        motif: String,
+        /// Which clip to search, relative to the original read orientation (leading = 5', trailing = 3')
+        #[arg(short = 's', long, value_enum, default_value_t = utils::ClipSide::Either)]
+        side: utils::ClipSide,
+        /// Maximum number of mismatched bases between the motif and the clip (no insertions or deletions)
+        #[arg(short = 'm', long, default_value = "0")]
+        max_mismatch: u32,
+        /// Also search for the reverse complement of the motif
+        #[arg(long)]
+        both_strands: bool,
+    },
+    /// Create a filter that keeps reads with an adapter sequence at their 3' end (relative to the original read orientation),
+    /// possibly running past the end of the read. Use '--opposite' to keep reads without adapter contamination instead
+    Adapter {
+        /// Adapter sequence
+        adapter: String,
+        /// Maximum number of mismatches with the full adapter. Shorter overlaps at the end of the read allow proportionally fewer
+        #[arg(short = 'm', long, default_value = "1")]
+        max_mismatch: u32,
+        /// Minimum number of adapter bases at the end of the read
+        #[arg(long, default_value = "5")]
+        min_overlap: u32,
+    },
+    /// Create a filter based on the number of mismatches described by the MD tag
+    MdMismatch {
+        /// Minimum number of mismatches (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_mismatches: utils::Limit<u32>,
+        /// Maximum number of mismatches (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_mismatches: utils::Limit<u32>,
+    },
+    /// Create a filter based on the number of supplementary alignments listed in the SA tag (e.g. to find chimeric reads).
+    /// Reads without an SA tag have none
+    SaCount {
+        /// Minimum number of supplementary alignments (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_count: utils::Limit<u32>,
+        /// Maximum number of supplementary alignments (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_count: utils::Limit<u32>,
+    },
+    /// Create a filter that keeps the primary alignment of a chimeric read only if it is the leftmost of its segments,
+    /// i.e. it starts at or before every supplementary alignment in its SA tag (references are ordered as in the header).
+    /// Reads without an SA tag, and secondary or supplementary alignments, are removed
+    LeftmostSplit {},
+    /// Create a filter based on the total number of hard-clipped bases ('H' in the CIGAR) at both ends of the read.
+    /// Hard clips usually mark supplementary alignments of a chimeric read
+    HardClip {
+        /// Minimum number of hard-clipped bases (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_clip: utils::Limit<u32>,
+        /// Maximum number of hard-clipped bases (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_clip: utils::Limit<u32>,
+    },
+    /// Create a filter that keeps the reads of read groups whose mean mismatch rate (NM divided by the read length)
+    /// is below a threshold. Reads without an RG tag form a group of their own, and reads without an NM tag are not
+    /// counted. This takes two passes: a group's mean is only known at the end of the input, so all records are held
+    /// in memory until then, and the filter can't be evaluated in parallel
+    RgQuality {
+        /// Maximum mean mismatch rate of a read group (exclusive), e.g. 0.02 for 2 mismatches per 100 bases
+        max_rate: f64,
+    },
+    /// Create a filter that keeps a paired read only if both it and its mate pass an existing filter.
+    /// Reads are held back until their mate is seen, so name-sorted input is recommended:
+    /// on coordinate-sorted input, many reads may be buffered in memory.
+    PairConcordant {
+        /// Name of the existing filter that both mates must pass
+        filter_name: String,
+    },
+    /// Create a filter that removes singletons: paired reads whose mate is not in the file (e.g. after subsetting).
+    /// Use --opposite to keep only the singletons. Unpaired, secondary and supplementary reads pass.
+    /// A read is held back until its mate is seen, and so are all reads after it, so name-sorted input is recommended:
+    /// on coordinate-sorted input, the reads between distant mates are buffered in memory.
+    /// Reads still waiting for their mate at the end of the file are singletons
+    Singleton {},
+    /// Create a filter that keeps primary alignments (neither secondary nor supplementary) that carry a given tag,
+    /// with any value
+    TaggedPrimary {
+        /// Name of the tag that must be present (e.g. 'CB')
+        tag_name: String,
+    },
+    /// Create a filter from an expression over record fields,
+    /// e.g. 'length >= 18 && (mapq > 20 || tag("NM") < 3)'.
+    /// Available fields are length, mapq, flag, pos, ref_id, and tag("XX") for numeric tags.
+    Script {
+        /// The filter expression
+        expression: String,
+    },
+    /// Create a filter that removes records with contradictory fields, which usually indicate an aligner bug:
+    /// unmapped reads with a reference and position (unless placed at their mapped mate), a non-zero mapping quality
+    /// or the reverse strand flag, and mapped reads without a reference or position
+    Sanity {},
+    /// Create a filter that removes reads with soft clips inside the CIGAR rather than at its ends (e.g. '20M5S25M',
+    /// or clips flanking an intron), which the SAM specification does not allow and which usually indicate a corrupt
+    /// record. Hard clips may surround the terminal soft clips. Use '--opposite' to keep only these reads,
+    /// e.g. for inspection
+    InternalClip {},
+    /// Create a filter that keeps reads mapped to references whose names match a pattern, resolved against the header
+    /// of each input (e.g. '--mito' for the mitochondrial genome). Use '--opposite' to remove these reads instead
+    SpecialContig {
+        /// Reference name patterns, with '*', '?' and '[...]' wildcards (e.g. 'chrM' or '*_random')
+        patterns: Vec<String>,
+        /// Also match the common names of the mitochondrial genome (chrM, chrMT, MT, M)
+        #[arg(long)]
+        mito: bool,
+        /// Also match the common names of unplaced, alternate and decoy contigs (*_random, *_alt, *_decoy, chrUn_*, chrEBV, hs37d5)
+        #[arg(long)]
+        decoy: bool,
+    },
+    /// Create a filter that compares the aligned span of each read with a region, keeping the reads contained in it,
+    /// the reads spanning one of its ends, or any overlapping read.
+    /// Unmapped reads and reads on other references are removed
+    RegionMode {
+        /// Region such as 'chr1:1,000-2,000' (1-based, inclusive), or a reference name for the whole reference
+        region: String,
+        /// How the read must relate to the region
+        #[arg(value_enum)]
+        mode: utils::RegionMode,
+    },
+    /// Create a filter that keeps reads aligned at least a given number of bases away from both ends of their reference,
+    /// whose length is read from the header of each input. Unmapped reads are removed
+    ContigMargin {
+        /// Minimum number of bases between the aligned span of a read and either end of its reference
+        margin: u32,
+    },
+    /// Create a filter based on per-read values from a tab-separated annotation file (e.g. scores from an external tool).
+    /// Reads missing from the file are removed. The file is loaded when the filter is applied, so it must still exist then
+    External {
+        /// Tab-separated file with a header line, and a read name in the first column of each line
+        path: PathBuf,
+        /// Name of the column with the values to compare
+        column: String,
+        /// How the read's value is compared to the threshold
+        #[arg(value_enum)]
+        comparison: script::CompareOp,
+        /// The value that the read's value is compared to
+        threshold: f64,
+    },
+    /// Create a filter that removes reads whose aligned span overlaps any interval of a BED file, or any alignment of a BAM/SAM file
+    /// (e.g. a blacklist). The intervals are loaded when the filter is applied, so the file must still exist then
+    Subtract {
+        /// BED (optionally gzipped, e.g. 'blacklist.bed.gz'), BAM or SAM file with the intervals to subtract
+        path: PathBuf,
+    },
+    /// Create a filter that keeps mapped reads overlapping a region whose coverage in a BedGraph file is within bounds
+    /// (e.g. to remove reads in ultra-high-coverage artifact regions). Reads overlapping no region of the BedGraph
+    /// are removed. The BedGraph is read when the filter is applied, so it must still exist then
+    Coverage {
+        /// BedGraph (optionally gzipped) file with the coverage of each region
+        bedgraph: PathBuf,
+        /// Minimum coverage of a region (inclusive)
+        min_coverage: f64,
+        /// Maximum coverage of a region (inclusive)
+        max_coverage: f64,
+    },
+    /// Create a filter that removes reads whose aligned span overlaps a homopolymer tract of the reference
+    /// (e.g. for error-model work). The tracts are found when the filter is applied, so the FASTA file must still exist then
+    RefHomopolymer {
+        /// FASTA file of the reference the reads are aligned to
+        fasta: PathBuf,
+        /// Minimum length of a run of a single base to count as a homopolymer tract
+        #[arg(long, default_value = "4")]
+        min_length: u32,
+    },
+    /// Create a filter that keeps reads whose aligned bases all match the reference, compared directly rather than through
+    /// the NM tag. Soft-clipped bases are not compared, and reads with insertions or deletions are removed.
+    /// The reference is read when the filter is applied, so the FASTA file must still exist then
+    PerfectMatch {
+        /// FASTA file of the reference the reads are aligned to
+        fasta: PathBuf,
+    },
+    /// Create a filter based on the distance between the start positions of a read and its mate,
+    /// regardless of the TLEN field. Reads with an unmapped mate, or a mate on another reference, are removed
+    MateDistance {
+        /// Minimum distance (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_distance: utils::Limit<u32>,
+        /// Maximum distance (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_distance: utils::Limit<u32>,
+    },
+    /// Create a filter that keeps reads in a concordant pair: the mate is mapped to the same reference and the opposite
+    /// strand, the pair is in FR orientation (the forward read starts first), and the insert size (the absolute TLEN)
+    /// is within range. Unpaired reads, reads with an unmapped mate, and reads without a TLEN are removed
+    Concordant {
+        /// Minimum insert size (inclusive). '*' or omitted for no lower
+        #[arg(default_value = "*")]
+        min_insert: utils::Limit<u32>,
+        /// Maximum insert size (inclusive). '*' or omitted for no upper
+        #[arg(default_value = "*")]
+        max_insert: utils::Limit<u32>,
+    },
+    /// Create a filter that keeps spliced reads whose every intron ('N' in the CIGAR) is a known splice junction
+    Junctions {
+        /// Tab-separated file (optionally gzipped) with a reference name and the first and last intron base
+        /// (1-based, inclusive) on each line
+        junctions_file: PathBuf,
+        /// Maximum distance (in bases) between the intron ends of a read and a known junction
+        #[arg(short = 't', long, default_value = "0")]
+        tolerance: u32,
+    },
+    /// Create a filter that keeps reads supporting a structural-variant breakpoint: discordant pairs (not flagged as
+    /// properly paired) with one mate starting before the breakpoint and the other at or after it, and split reads
+    /// whose soft clip starts or ends within the tolerance window around it. Unmapped reads are removed
+    Breakpoint {
+        /// Name of the reference the breakpoint is on
+        reference: String,
+        /// Position of the last reference base before the breakpoint (1-based)
+        position: u32,
+        /// Maximum distance (in bases) between a soft-clip boundary and the breakpoint.
+        /// Applies to split reads only; discordant pairs must straddle the breakpoint exactly
+        #[arg(short = 't', long, default_value = "5")]
+        tolerance: u32,
+    },
+}
+
+#[derive(clap::Args, Clone)]
+#[command(group(clap::ArgGroup::new("seeded").args(["shuffle", "subsample"]).multiple(true)))]
+struct ApplyArgs {
+    /// Input BAM/SAM files. Wildcard patterns (e.g. 'data/*.bam') are expanded
+    input: Vec<PathBuf>,
+    /// Output file, or the output directory with --name-template
+    #[arg(short = 'o', long, required_unless_present = "count_passes")]
+    output: Option<PathBuf>,
+    /// Total number of threads to use. Defaults to the number of logical CPUs; use 1 to run on a single thread.
+    /// When input files can be processed in parallel (see below), the threads are split between them: as many files
+    /// as there are threads (up to the number of inputs) are processed at once, and the remaining threads are
+    /// divided evenly between those files for decompression (supported for BAM files only).
+    /// Files are processed in parallel only if each writes its own output, i.e. with --name-template and without
+    /// --rejected, --stats-by-ref, --stats-json, --error-report, --unmapped-fastq or --progress-json
+    #[arg(short = 'p', long)]
+    threads: Option<u16>,
+    /// Toggle verbose output
+    #[arg(short = 'v', long, required = false)]
+    verbose: bool,
+    /// Evaluate the filter on batches of records in parallel. The output preserves the input order.
+    #[arg(long)]
+    parallel_filter: bool,
+    /// Keep both mates of a pair if either of them passes the filter.
+    /// Works best on name-sorted input, where mates are adjacent.
+    #[arg(long)]
+    keep_pairs: bool,
+    /// Maximum number of reads buffered while waiting for their mates with --keep-pairs
+    #[arg(long, default_value = "100000")]
+    pair_buffer_size: usize,
+    /// Write the records that the filter rejects, instead of those it accepts
+    #[arg(long)]
+    invert_output: bool,
+    /// Write the records that are not kept to this file, with the same header as the output
+    #[arg(long)]
+    rejected: Option<PathBuf>,
+    /// Write the number of kept and removed records for each reference sequence to this file, as TSV
+    #[arg(long)]
+    stats_by_ref: Option<PathBuf>,
+    /// Write the number of records that passed and failed the filter, and that were kept and removed, to this file as JSON
+    #[arg(long)]
+    stats_json: Option<PathBuf>,
+    /// Also print a single line per input file for scripts to parse, e.g.
+    /// 'file=in.bam total=100 kept=90 removed=10 filter=mapq'. The filter name comes last, since it may contain spaces.
+    /// Inputs skipped by --resume get the counts that the manifest lists for them
+    #[arg(long)]
+    summary_line: bool,
+    /// Skip records that cannot be read, instead of stopping with an error
+    #[arg(long)]
+    skip_errors: bool,
+    /// Write the number of each record that could not be read, and the reason, to this file
+    #[arg(long)]
+    error_report: Option<PathBuf>,
+    /// Only scan the reads overlapping this region (e.g. 'chr1' or 'chr1:1000-2000', 1-based and inclusive).
+    /// Requires an indexed BAM input, with the index at '<input>.bai'
+    #[arg(long)]
+    region: Option<String>,
+    /// Fail before processing an input whose header doesn't declare this sort order
+    #[arg(long, value_enum)]
+    assert_sort: Option<utils::SortOrder>,
+    /// Stop after this many records, e.g. to try a filter on the head of a large file
+    #[arg(long)]
+    max_records: Option<u64>,
+    /// Whether --max-records counts the records written to the output, or the records read from the input
+    #[arg(long, value_enum, default_value_t = utils::RecordLimit::Output)]
+    max_records_of: utils::RecordLimit,
+    /// Write the kept records in a pseudo-random order. All kept records are held in memory until the end of the input
+    /// (up to 10 million records, after which they are shuffled and written in chunks of that size)
+    #[arg(long)]
+    shuffle: bool,
+    /// Never overwrite an existing output file. By default, you are asked before overwriting an output file
+    /// if running interactively, and the run fails otherwise
+    #[arg(long, conflicts_with = "force")]
+    no_clobber: bool,
+    /// Overwrite existing output files without asking
+    #[arg(short = 'f', long)]
+    force: bool,
+    /// Time the evaluations of each filter that the applied filter is built from, and print a breakdown at the end
+    #[arg(long)]
+    profile_filters: bool,
+    /// Seed of the --shuffle order and the --subsample selection. The same seed and input always give the same output
+    #[arg(long, default_value = "0", requires = "seeded")]
+    seed: u64,
+    /// Keep only this fraction of the records that pass the filter (e.g. 0.1 for 10%), chosen by hashing the read
+    /// names so that both mates of a pair are kept or removed together. The same --seed keeps the same reads.
+    /// The records left out count as failing the filter, so it cannot be combined with --invert-output
+    /// (or '--default keep'), nor with --count-passes
+    #[arg(long, conflicts_with_all = ["invert_output", "count_passes"])]
+    subsample: Option<f64>,
+    /// Write the output of each input file into the --output directory, named by this template
+    /// (e.g. '{stem}.{filter}.filtered.bam'). Placeholders: {stem} (the input file name without its extension),
+    /// {filter} (the filter name) and {ext} (the extension of the input file)
+    #[arg(long)]
+    name_template: Option<String>,
+    /// Write a manifest of the processed input files to this file, with the output path, filter name,
+    /// completion time (seconds since the Unix epoch) and record counts of each input.
+    /// Written as JSON if the file name ends with '.json', and as TSV otherwise. It is updated after each input
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    /// Skip the input files that the --manifest file of a previous run lists as processed by the same filter into
+    /// the same output, if that output still exists. Outputs only appear once they are complete, so a run that
+    /// was interrupted can be restarted without redoing the finished inputs
+    #[arg(long, requires = "manifest")]
+    resume: bool,
+    /// Write the kept reads of each read group to its own BAM file in the --output directory, named by the
+    /// read group ID (e.g. 'lane1.bam'). Reads without an RG tag are written to 'no_read_group.bam'.
+    /// At most 256 read groups are supported
+    #[arg(long, conflicts_with = "name_template")]
+    split_by_rg: bool,
+    /// Also write the unmapped reads that the filter removes to this FASTQ file (e.g. for realignment), in their
+    /// original orientation. Give two files to write the first and second mates of pairs to separate files;
+    /// then only pairs whose mates are both written are kept, so that the files stay in sync
+    #[arg(long, num_args = 1..=2)]
+    unmapped_fastq: Vec<PathBuf>,
+    /// Periodically write the progress of the run to this file as JSON ('processed', 'kept', 'removed' and
+    /// 'elapsed_secs'), replacing its contents each time. Meant for monitoring runs without a terminal
+    #[arg(long)]
+    progress_json: Option<PathBuf>,
+    /// Number of seconds between the updates of the --progress-json file
+    #[arg(long, default_value = "10", requires = "progress_json")]
+    progress_interval: f64,
+    /// Instead of filtering, count how many of the applied filters each read passes, and print how many reads
+    /// pass 0, 1, ... all of them (e.g. to tell whether the filters are redundant or complementary).
+    /// Nothing is written, so no --output is given
+    #[arg(long, conflicts_with = "output")]
+    count_passes: bool,
+    /// Allow filters that modify the reads they keep (e.g. 'trim'), and write the kept reads as modified.
+    /// Applying such a filter without it is an error, so that reads are never changed by accident.
+    /// The --rejected reads are written unmodified
+    #[arg(long)]
+    transform: bool,
+}
+
+impl ApplyArgs {
+    // the output is only left out with --count-passes, which writes nothing
+    fn output_path(&self) -> &Path {
+        self.output.as_deref().expect("An output file is required!")
+    }
 }
 
 #[derive(Subcommand)]
@@ -85,46 +625,123 @@ enum Commands {
     /// Create a new filter
     Create {
         ///  Optionally set a name for the filter. If not specified, a name will be generated automatically
-        #[clap(short = 'n', long)]
+        #[arg(short = 'n', long)]
         name: Option<String>,
         ///  Optionally invert the filter logic
-        #[clap(short = 'o', long)]
+        #[arg(short = 'o', long)]
         opposite: bool,
         #[command(subcommand)]
         cmd: CreateCommands,
     },
 
-    /// Combine two existing filters using a boolean operator
+    /// Combine two existing filters using a boolean operator,
+    /// given either as 'FILTER1 OPERATOR FILTER2' or as 'OPERATOR FILTER1 FILTER2'.
+    /// Filters that decide on reads using other records (e.g. pair-concordant) cannot be combined
     Combine {
-        /// Name of the first filter to be combined
-        #[clap(index = 1)]
-        filter1: String,
-        /// The boolean operator to be used for combining the two filters
-        #[clap(index = 2)]
-        operator: utils::BoolOperator,
+        /// Name of the first filter to be combined, or the operator if it is given first
+        #[arg(index = 1)]
+        first: String,
+        /// The boolean operator to be used for combining the two filters (and, or, xor, xnor, nand, nor, implies),
+        /// or the name of the first filter if the operator is given first
+        #[arg(index = 2)]
+        second: String,
         /// Name of the second filter to be combined
-        #[clap(index = 3)]
+        #[arg(index = 3)]
         filter2: String,
         /// Optionally set a name for the combined filter. If not specified, a name will be generated automatically
-        #[clap(short = 'n', long)]
+        #[arg(short = 'n', long)]
         name: Option<String>,
+        /// File with additional newline-separated filter names, combined left to right with the same operator
+        #[arg(long)]
+        names_file: Option<PathBuf>,
+        /// Allow the combined filter to replace one of the filters it is made from
+        #[arg(short = 'f', long)]
+        force: bool,
     },
 
     /// Apply a filter to BAM/SAM files
     Apply {
-        ///  Name of the filter to be applied
-        filter_name: String,
-        /// Input BAM/SAM files
-        input: Vec<PathBuf>,
-        /// Output directory
-        #[clap(short = 'o', long)]
+        ///  Name of the filter to be applied. Not given with --filter-file, in which case all the positional arguments are input files
+        #[arg(required_unless_present = "filter_file")]
+        filter_name: Option<String>,
+        /// File with additional newline-separated filter names. By default, reads match only if they pass all filters
+        #[arg(long, conflicts_with = "filter_file")]
+        names_file: Option<PathBuf>,
+        /// Reads match if they pass all the filters (the default)
+        #[arg(long, conflicts_with = "any")]
+        all: bool,
+        /// Reads match if they pass any of the filters
+        #[arg(long)]
+        any: bool,
+        /// What happens to the reads that don't match: with 'drop' (the default) only matching reads are kept,
+        /// and with 'keep' every read is kept except the matching ones.
+        /// For example, with filters A and B:
+        /// --all --default drop keeps 'A and B';
+        /// --any --default drop keeps 'A or B';
+        /// --all --default keep keeps 'not (A and B)';
+        /// --any --default keep keeps 'neither A nor B'
+        #[arg(long, value_enum, default_value_t = utils::DefaultAction::Drop)]
+        default: utils::DefaultAction,
+        /// Apply a filter from a JSON file (a single exported filter, or a config file) instead of the session
+        #[arg(long)]
+        filter_file: Option<PathBuf>,
+        /// Name of the filter to apply from a --filter-file with more than one filter
+        #[arg(long, requires = "filter_file")]
+        filter_file_name: Option<String>,
+        #[command(flatten)]
+        args: ApplyArgs,
+    },
+
+    /// Rename an existing filter. Its id stays the same
+    Rename {
+        /// Name (or '@id') of the filter to be renamed
+        name: String,
+        /// New name for the filter
+        new_name: String,
+    },
+
+    /// Delete existing filters
+    Delete {
+        /// Names (or '@id's) of the filters to be deleted
+        names: Vec<String>,
+        /// File with additional newline-separated filter names to be deleted
+        #[arg(long)]
+        names_file: Option<PathBuf>,
+    },
+
+    /// Apply a filter defined inline to BAM/SAM files, without saving it to the session
+    #[command(subcommand_precedence_over_arg = true)]
+    Run {
+        #[command(flatten)]
+        args: ApplyArgs,
+        ///  Optionally invert the filter logic
+        #[arg(long)]
+        opposite: bool,
+        #[command(subcommand)]
+        cmd: CreateCommands,
+    },
+
+    /// Apply filters one after another to a BAM/SAM file, each to the reads kept by the previous ones,
+    /// and report how many reads each stage kept and removed. Keeps the same reads as combining the filters with AND,
+    /// but shows the attrition at each stage. Filters that depend on other records (e.g. the read's mate) or
+    /// modify reads can't be used
+    Pipeline {
+        /// Names (or '@id's) of the filters, in the order they are applied
+        #[arg(required = true)]
+        filters: Vec<String>,
+        /// Input BAM/SAM file
+        #[arg(short = 'i', long)]
+        input: PathBuf,
+        /// Output file, with the reads that pass every stage
+        #[arg(short = 'o', long)]
         output: PathBuf,
-        /// Number of threads to use (supported for BAM files only)
-        #[clap(short = 'p', long, default_value = "1")]
-        threads: u16,
-        /// Toggle verbose output
-        #[clap(short = 'v', long, required = false)]
-        verbose: bool,
+        /// Never overwrite an existing output file. By default, you are asked before overwriting the output file
+        /// if running interactively, and the run fails otherwise
+        #[arg(long, conflicts_with = "force")]
+        no_clobber: bool,
+        /// Overwrite an existing output file without asking
+        #[arg(short = 'f', long)]
+        force: bool,
     },
 
     /// Import filters from a JSON file
@@ -137,17 +754,82 @@ enum Commands {
     Export {
         ///  Path to the JSON file to which the filters will be exported. If not specified, the filters will be printed to stdout.
         export_path: Option<PathBuf>,
+        /// Export a shell script with the commands that recreate the filters, instead of JSON
+        #[arg(long)]
+        as_script: bool,
+        /// Add the filters to an existing export file instead of overwriting it. Filters that are already in the file
+        /// are skipped, and a different filter with the same name is an error
+        #[arg(long, requires = "export_path", conflicts_with = "as_script")]
+        append: bool,
+        /// Indent the exported JSON so it is easy to read and edit by hand. JSON printed to stdout is always indented
+        #[arg(long, conflicts_with = "as_script")]
+        pretty: bool,
     },
     /// View the list of defined filters
-    View {},
+    View {
+        /// Only print the total number of filters
+        #[arg(short = 'c', long)]
+        count_only: bool,
+        /// Output format of the filter list
+        #[arg(long, value_enum, default_value_t = utils::ViewFormat::Human)]
+        format: utils::ViewFormat,
+    },
+
+    /// Describe a filter and the filters it is built from
+    Describe {
+        /// Name (or '@id') of the filter to be described
+        name: String,
+        /// A BAM/SAM file whose header is used to show reference sequence names instead of ids
+        #[arg(long)]
+        header: Option<PathBuf>,
+    },
+
+    /// Run every filter in the session on the first records of a BAM/SAM file, and report how many passed each one.
+    /// Filters that pass none or all of the sample are flagged, since they are likely misconfigured
+    Check {
+        /// Path to the BAM/SAM file to sample
+        input: PathBuf,
+        /// Number of records to sample from the start of the file
+        #[arg(short = 's', long, default_value = "1000")]
+        sample_size: usize,
+    },
+
+    /// Compare the filters defined in two config files
+    Diff {
+        /// Path to the first (original) config file
+        a: PathBuf,
+        /// Path to the second (modified) config file
+        b: PathBuf,
+        /// Output the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check the filter definitions in a config file for problems, such as empty ranges, invalid tag names,
+    /// filters combined with themselves and names that only differ in case. Exits with an error if any are found
+    Verify {
+        /// Path to the config file. Defaults to the session config
+        path: Option<PathBuf>,
+    },
+
+    /// List the filter types that can be created, with their parameters and a short description
+    Types {
+        /// Output the filter types as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 fn deserialize_from_json(s: &str) -> Result<filters::Config, serde_json::Error> {
     serde_json::from_str(s)
 }
 
-fn serialize_to_json(config: &filters::Config) -> Result<String, serde_json::Error> {
-    serde_json::to_string(config)
+fn serialize_to_json(config: &filters::Config, pretty: bool) -> Result<String, serde_json::Error> {
+    if pretty {
+        serde_json::to_string_pretty(config)
+    } else {
+        serde_json::to_string(config)
+    }
 }
 
 // Define filter creation and combining logic
@@ -157,59 +839,308 @@ fn create_filter(
     args: CreateCommands,
     config_path: &Path,
 ) {
-    // Implement filter creation logic based on args
     let name = match filter_name {
         Some(s) => s,
         None => "combined filter 1".to_string(), //TODO: name generator
     };
-    let filter: Box<dyn Filtering> = match args {
-        CreateCommands::Length { min_len, max_len } => Box::new(filters::LengthFilter::new(
-            name.clone(),
+    let filter = build_filter(name.clone(), opposite, args, config_path);
+    store_filter(filter, &name, config_path);
+}
+
+fn build_filter(
+    name: String,
+    opposite: bool,
+    args: CreateCommands,
+    config_path: &Path,
+) -> Box<dyn Filtering> {
+    match args {
+        CreateCommands::Length { min_len, max_len } => {
+            let range = utils::RangeBound::new(min_len, max_len);
+            Box::new(filters::LengthFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
+        CreateCommands::QualityCliff {
+            quality_threshold,
+            window,
+            min_len,
+        } => Box::new(filters::QualityCliffFilter::new(name, quality_threshold, min_len, window, opposite)),
+        CreateCommands::Trim {
+            leading,
+            trailing,
+            quality_threshold,
+            min_len,
+        } => Box::new(filters::TrimFilter::new(name, leading, trailing, quality_threshold, min_len, opposite)),
+        CreateCommands::TrimmedLength {
+            quality_threshold,
             min_len,
             max_len,
-            opposite,
-        )),
+        } => {
+            let range = utils::RangeBound::new(min_len, max_len);
+            Box::new(filters::TrimmedLengthFilter::new(
+                name,
+                quality_threshold,
+                range.min_value(),
+                range.max_value(),
+                opposite,
+            ))
+        }
         CreateCommands::Tag {
             tag_name,
             tag_type,
             tag_value,
+            numeric,
         } => {
             let tag_value = utils::convert_to_minimal_tag_value(tag_type, &tag_value).unwrap();
             let tag_name = utils::str_to_tag_name(&tag_name);
             Box::new(filters::TagFilter::new(
-                name.clone(),
+                name,
                 tag_name,
                 tag_value,
+                numeric,
                 opposite,
             ))
         }
-        CreateCommands::Mapq { min_mapq, max_mapq } => Box::new(filters::MapqFilter::new(
-            name.clone(),
-            min_mapq,
-            max_mapq,
-            opposite,
-        )),
+        CreateCommands::Mapq { min_mapq, max_mapq } => {
+            let range = utils::RangeBound::new(min_mapq, max_mapq);
+            Box::new(filters::MapqFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
         CreateCommands::RefName { ref_id } => {
-            Box::new(filters::RefNameFilter::new(name.clone(), ref_id, opposite))
+            Box::new(filters::RefNameFilter::new(name, ref_id, opposite))
         }
         CreateCommands::Nucleotide {
             position,
             nucleotide,
             n_is_wildcard,
         } => Box::new(filters::NthNucleotideFilter::new(
-            name.clone(),
+            name,
             position,
             nucleotide,
             n_is_wildcard,
             opposite,
         )),
         CreateCommands::Flag { remove_flags } => Box::new(filters::FlagFilter::new(
-            name.clone(),
+            name,
             remove_flags,
             opposite,
         )),
-    };
-    store_filter(filter, &name, config_path);
+        CreateCommands::FlagMask {
+            require_mask,
+            exclude_mask,
+        } => Box::new(filters::FlagMaskFilter::new(
+            name,
+            require_mask,
+            exclude_mask,
+            opposite,
+        )),
+        CreateCommands::NameField { field, pattern } => Box::new(filters::ReadNameFieldFilter::new(
+            name,
+            field,
+            utils::NameFieldMatch::parse(&pattern),
+            opposite,
+        )),
+        CreateCommands::StrandAssign { library_type, strand } => {
+            Box::new(filters::StrandAssignFilter::new(name, library_type, strand, opposite))
+        }
+        CreateCommands::Strand { strand } => Box::new(filters::StrandFilter::new(name, strand, opposite)),
+        CreateCommands::LowQualFraction {
+            quality_threshold,
+            min_fraction,
+            max_fraction,
+        } => Box::new(filters::LowQualFractionFilter::new(
+            name,
+            quality_threshold,
+            min_fraction,
+            max_fraction,
+            opposite,
+        )),
+        CreateCommands::RecordIndex { first_index, last_index } => {
+            let range = utils::RangeBound::new(first_index, last_index);
+            Box::new(filters::RecordIndexFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
+        CreateCommands::DistinctBases { min_distinct, max_distinct } => {
+            Box::new(filters::DistinctBasesFilter::new(name, min_distinct, max_distinct, opposite))
+        }
+        CreateCommands::AsPerBase { min_score, max_score } => {
+            Box::new(filters::AsPerBaseFilter::new(name, min_score, max_score, opposite))
+        }
+        CreateCommands::ClipRatio { min_ratio, max_ratio } => {
+            Box::new(filters::ClipRatioFilter::new(name, min_ratio, max_ratio, opposite))
+        }
+        CreateCommands::Composition {
+            bases,
+            min_fraction,
+            max_fraction,
+        } => Box::new(filters::CompositionFilter::new(
+            name,
+            &bases,
+            min_fraction,
+            max_fraction,
+            opposite,
+        )),
+        CreateCommands::AlignedGc { min_fraction, max_fraction } => {
+            Box::new(filters::AlignedGcFilter::new(name, min_fraction, max_fraction, opposite))
+        }
+        CreateCommands::ClipContent {
+            motif,
+            side,
+            max_mismatch,
+            both_strands,
+        } => Box::new(filters::ClipContentFilter::new(
+            name,
+            &motif,
+            side,
+            max_mismatch,
+            both_strands,
+            opposite,
+        )),
+        CreateCommands::Adapter {
+            adapter,
+            max_mismatch,
+            min_overlap,
+        } => Box::new(filters::AdapterFilter::new(
+            name,
+            &adapter,
+            max_mismatch,
+            min_overlap,
+            opposite,
+        )),
+        CreateCommands::SaCount { min_count, max_count } => {
+            let range = utils::RangeBound::new(min_count, max_count);
+            Box::new(filters::SaCountFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
+        CreateCommands::LeftmostSplit {} => Box::new(filters::LeftmostSplitFilter::new(name, opposite)),
+        CreateCommands::HardClip { min_clip, max_clip } => {
+            let range = utils::RangeBound::new(min_clip, max_clip);
+            Box::new(filters::HardClipFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
+        CreateCommands::MdMismatch {
+            min_mismatches,
+            max_mismatches,
+        } => {
+            let range = utils::RangeBound::new(min_mismatches, max_mismatches);
+            Box::new(filters::MdMismatchFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
+        CreateCommands::RgQuality { max_rate } => Box::new(filters::RgQualityFilter::new(name, max_rate, opposite)),
+        CreateCommands::PairConcordant { filter_name } => {
+            let filter = get_filters(vec![&filter_name], config_path).pop().unwrap();
+            assert!(
+                filter.to_streaming().is_none(),
+                "Filter {} depends on other records and cannot be checked on both mates!",
+                filter.name()
+            );
+            Box::new(filters::PairConcordantFilter::new(name, filter, opposite))
+        }
+        CreateCommands::Script { expression } => {
+            Box::new(filters::ScriptFilter::new(name, &expression, opposite))
+        }
+        CreateCommands::SpecialContig { mut patterns, mito, decoy } => {
+            if mito {
+                patterns.extend(utils::MITO_CONTIGS.iter().map(|pattern| pattern.to_string()));
+            }
+            if decoy {
+                patterns.extend(utils::DECOY_CONTIGS.iter().map(|pattern| pattern.to_string()));
+            }
+            Box::new(filters::SpecialContigFilter::new(name, patterns, opposite))
+        }
+        CreateCommands::RegionMode { region, mode } => {
+            Box::new(filters::RegionModeFilter::new(name, region, mode, opposite))
+        }
+        CreateCommands::ContigMargin { margin } => Box::new(filters::ContigMarginFilter::new(name, margin, opposite)),
+        CreateCommands::External {
+            path,
+            column,
+            comparison,
+            threshold,
+        } => {
+            let path = std::fs::canonicalize(&path)
+                .unwrap_or_else(|e| utils::fail_io(&path, "Could not find annotation file", e));
+            Box::new(filters::ExternalAnnotationFilter::new(
+                name,
+                path,
+                column,
+                comparison,
+                threshold,
+                opposite,
+            ))
+        }
+        CreateCommands::Subtract { path } => {
+            let path = std::fs::canonicalize(&path)
+                .unwrap_or_else(|e| utils::fail_io(&path, "Could not find interval file", e));
+            Box::new(filters::SubtractFilter::new(name, path, opposite))
+        }
+        CreateCommands::Coverage {
+            bedgraph,
+            min_coverage,
+            max_coverage,
+        } => {
+            let bedgraph = std::fs::canonicalize(&bedgraph)
+                .unwrap_or_else(|e| utils::fail_io(&bedgraph, "Could not find BedGraph file", e));
+            Box::new(filters::CoverageFilter::new(name, bedgraph, min_coverage, max_coverage, opposite))
+        }
+        CreateCommands::RefHomopolymer { fasta, min_length } => {
+            let fasta = std::fs::canonicalize(&fasta)
+                .unwrap_or_else(|e| utils::fail_io(&fasta, "Could not find FASTA file", e));
+            Box::new(filters::RefHomopolymerFilter::new(name, fasta, min_length, opposite))
+        }
+        CreateCommands::PerfectMatch { fasta } => {
+            let fasta = std::fs::canonicalize(&fasta)
+                .unwrap_or_else(|e| utils::fail_io(&fasta, "Could not find FASTA file", e));
+            Box::new(filters::PerfectMatchFilter::new(name, fasta, opposite))
+        }
+        CreateCommands::MateDistance {
+            min_distance,
+            max_distance,
+        } => {
+            let range = utils::RangeBound::new(min_distance, max_distance);
+            Box::new(filters::MateDistanceFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
+        CreateCommands::Concordant { min_insert, max_insert } => {
+            let range = utils::RangeBound::new(min_insert, max_insert);
+            Box::new(filters::ConcordantPairFilter::new(name, range.min_value(), range.max_value(), opposite))
+        }
+        CreateCommands::Singleton {} => Box::new(filters::SingletonFilter::new(name, opposite)),
+        CreateCommands::TaggedPrimary { tag_name } => {
+            Box::new(filters::TaggedPrimaryFilter::new(name, utils::str_to_tag_name(&tag_name), opposite))
+        }
+        CreateCommands::Sanity {} => Box::new(filters::SanityFilter::new(name, opposite)),
+        CreateCommands::InternalClip {} => Box::new(filters::InternalClipFilter::new(name, opposite)),
+        CreateCommands::Junctions {
+            junctions_file,
+            tolerance,
+        } => Box::new(filters::JunctionsFilter::new(
+            name,
+            junctions_file.clone(),
+            utils::read_junctions_file(&junctions_file),
+            tolerance,
+            opposite,
+        )),
+        CreateCommands::Breakpoint {
+            reference,
+            position,
+            tolerance,
+        } => Box::new(filters::BreakpointFilter::new(name, reference, position, tolerance, opposite)),
+    }
+}
+
+// The first filter and the operator of a combine command, whose operator may come before or after the first filter.
+// The 'FILTER1 OPERATOR FILTER2' order wins when both readings are possible (e.g. for a filter named 'or')
+fn combine_operands(first: String, second: String) -> (String, BoolOperator) {
+    let parse = |arg: &str| <BoolOperator as ValueEnum>::from_str(arg, true).ok();
+    if let Some(operator) = parse(&second) {
+        return (first, operator);
+    }
+    if let Some(operator) = parse(&first) {
+        return (second, operator);
+    }
+    let operators: Vec<String> = BoolOperator::value_variants()
+        .iter()
+        .map(|operator| operator.to_possible_value().unwrap().get_name().to_string())
+        .collect();
+    panic!(
+        "Neither '{}' nor '{}' is a boolean operator! Expected one of: {}",
+        first,
+        second,
+        operators.join(", ")
+    );
 }
 
 fn combine_filters(
@@ -217,28 +1148,136 @@ fn combine_filters(
     filter1: &str,
     operator: BoolOperator,
     filter2: &str,
+    more_filters: &[String],
+    force: bool,
     config_path: &Path,
 ) {
-    // Implement filter combination logic using specified operator
-    let mut objs = get_filters(vec![filter1, filter2], config_path);
-    let f2_obj = objs.pop().unwrap();
-    let f1_obj = objs.pop().unwrap();
+    // the operands keep their order: `filter1` becomes the left input of the combined filter, which matters for
+    // operators that are not commutative (e.g. IMPLIES)
     let name = match combined_name {
         Some(s) => s,
         None => "combined filter 1".to_string(), //TODO: name generator
     };
-    let combined = filters::CombinedFilter::new(name.clone(), f1_obj, f2_obj, operator);
-    store_filter(Box::new(combined), &name, config_path);
+    let mut all_filters = get_filters(vec![filter1, filter2], config_path);
+    all_filters.extend(get_filters(more_filters.iter().map(String::as_str).collect(), config_path));
+    // the combined filter embeds copies of its inputs, so overwriting an input is easy to miss
+    let is_input_name = all_filters.iter().any(|filter| filter.name() == name);
+    assert!(
+        force || !is_input_name,
+        "The combined filter would replace its input filter {}! Choose a different name or use --force.",
+        name
+    );
+    let combined = chain_filters(name.clone(), all_filters, &operator);
+    store_filter(combined, &name, config_path);
 }
 
-fn get_filters(filter_names: Vec<&str>, config_path: &Path) -> Vec<Box<dyn Filtering>> {
+// Combine filters from left to right, e.g. ((f1 OP f2) OP f3). Only the outermost filter gets `name`.
+fn chain_filters(
+    name: String,
+    filters: Vec<Box<dyn Filtering>>,
+    operator: &BoolOperator,
+) -> Box<dyn Filtering> {
+    let n_filters = filters.len();
+    // such filters are only evaluated by feeding every record to their stream, which a combination can't do
+    for filter in filters.iter().filter(|_| n_filters > 1) {
+        assert!(
+            filter.to_streaming().is_none(),
+            "Filter {} depends on other records and cannot be combined with other filters!",
+            filter.name()
+        );
+    }
+    let mut filters = filters.into_iter();
+    let mut chained = filters.next().expect("At least one filter is required!");
+    for (i, filter) in filters.enumerate() {
+        let step_name = if i + 2 == n_filters {
+            name.clone()
+        } else {
+            format!("({} {:?} {})", chained.name(), operator, filter.name())
+        };
+        chained = Box::new(filters::CombinedFilter::new(step_name, chained, filter, operator.clone()));
+    }
+    chained
+}
+
+fn with_names_file(mut names: Vec<String>, names_file: Option<&Path>) -> Vec<String> {
+    if let Some(path) = names_file {
+        names.extend(utils::read_names_file(path));
+    }
+    names
+}
+
+// Combine the filters given to apply into one, named after all of them (e.g. 'f1 AND f2')
+fn chain_applied_filters(filters: Vec<Box<dyn Filtering>>, operator: &BoolOperator) -> Box<dyn Filtering> {
+    let name = filters
+        .iter()
+        .map(|filter| filter.name())
+        .collect::<Vec<_>>()
+        .join(&format!(" {:?} ", operator));
+    chain_filters(name, filters, operator)
+}
+
+// A filter file holds either a single filter, or a config with any number of filters
+fn load_filter_file(path: &Path, name: Option<&str>) -> Box<dyn Filtering> {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| utils::fail_io(path, "Could not read filter file", e));
+    if let Ok(config) = deserialize_from_json(&contents) {
+        return match name {
+            Some(name) => config
+                .get(name)
+                .unwrap_or_else(|| missing_filter(name, path)),
+            None => {
+                assert_eq!(
+                    config.count(),
+                    1,
+                    "Filter file {} has {} filters. Choose one with --filter-file-name",
+                    path.display(),
+                    config.count()
+                );
+                config.iter().next().unwrap().1.clone()
+            }
+        };
+    }
+    let filter: Box<dyn Filtering> = serde_json::from_str(&contents)
+        .unwrap_or_else(|e| utils::fail_io(path, "Could not parse filter file", e));
+    if let Some(name) = name.filter(|name| *name != filter.name()) {
+        missing_filter(name, path);
+    }
+    filter
+}
+
+fn rename_filter(name: &str, new_name: &str, config_path: &Path) {
+    let mut config = load_config(config_path);
+    if !config.rename(name, new_name) {
+        missing_filter(name, config_path);
+    }
+    save_config(&config, config_path);
+}
+
+fn delete_filters(names: &[String], config_path: &Path) {
     let mut config = load_config(config_path);
+    for name in names {
+        if config.remove(name).is_none() {
+            missing_filter(name, config_path);
+        }
+    }
+    save_config(&config, config_path);
+}
+
+fn missing_filter(name: &str, config_path: &Path) -> ! {
+    utils::fail(ErrorKind::MissingFilter, config_path, format!("Filter {} does not exist!", name))
+}
+
+// Look up filters by name (or '@id'), in the order they are named
+fn get_filters(filter_names: Vec<&str>, config_path: &Path) -> Vec<Box<dyn Filtering>> {
+    let config = load_config(config_path);
     let mut filters = Vec::new();
     for name in filter_names {
-        let filter = config.get(name).unwrap();
+        let filter = config
+            .get(name)
+            .unwrap_or_else(|| missing_filter(name, config_path));
         filters.push(filter);
     }
-    return filters;
+    filters
 }
 
 fn store_filter(filter: Box<dyn Filtering>, name: &str, config_path: &Path) {
@@ -256,11 +1295,17 @@ fn load_config(config_path: &Path) -> filters::Config {
         .unwrap();
     let mut config_str = String::new();
     config_file.read_to_string(&mut config_str).unwrap();
-    let config = deserialize_from_json(&config_str).unwrap();
-    return config;
+    let mut config: filters::Config = deserialize_from_json(&config_str).unwrap();
+    config.assign_ids();
+    config
 }
 
 fn save_config(config: &filters::Config, config_path: &Path) {
+    write_config(config, config_path, false);
+}
+
+// The session config is kept compact; exports can be indented for hand-editing
+fn write_config(config: &filters::Config, config_path: &Path, pretty: bool) {
     let mut config_file = OpenOptions::new()
         .read(false)
         .write(true)
@@ -268,7 +1313,7 @@ fn save_config(config: &filters::Config, config_path: &Path) {
         .truncate(true)
         .open(config_path)
         .unwrap();
-    let json_str = serialize_to_json(config).unwrap();
+    let json_str = serialize_to_json(config, pretty).unwrap();
     config_file.write_all(json_str.as_bytes()).unwrap();
 }
 
@@ -277,13 +1322,75 @@ fn import_filters(import_path: &Path, config_path: &Path) {
     save_config(&config, config_path);
 }
 
-fn export_filters(export_path: Option<&Path>, config_path: &Path) -> Option<String> {
+fn export_filters(
+    export_path: Option<&Path>,
+    as_script: bool,
+    append: bool,
+    pretty: bool,
+    config_path: &Path,
+) -> Option<String> {
     let config = load_config(config_path);
-    if export_path.is_none() {
-        return Some(serialize_to_json(&config).unwrap());
+    if as_script {
+        let script = export_script(&config);
+        return match export_path {
+            Some(path) => {
+                std::fs::write(path, script).unwrap();
+                None
+            }
+            None => Some(script),
+        };
+    }
+    let export_path = match export_path {
+        Some(path) => path,
+        None => return Some(serialize_to_json(&config, true).unwrap()),
+    };
+    if append && export_path.exists() {
+        let mut exported = load_config(export_path);
+        exported.merge(&config);
+        write_config(&exported, export_path, pretty);
+    } else {
+        write_config(&config, export_path, pretty);
+    }
+    None
+}
+
+// A shell script with the commands that recreate the filters of a config. Filters are created after the filters
+// they are built from; those that are not in the config themselves (e.g. the intermediate steps of a chained combine)
+// are deleted at the end. Filters that were built from a filter which was later replaced (using --force)
+// get the replacement instead.
+fn export_script(config: &filters::Config) -> String {
+    let mut lines = vec![
+        "#!/bin/sh".to_string(),
+        "# Recreates a BAMetrics session. Set BAMETRIC_PATH to choose the session file".to_string(),
+        "set -e".to_string(),
+        "BAMETRICS=${BAMETRICS:-BAMetrics}".to_string(),
+        "\"$BAMETRICS\" init".to_string(),
+    ];
+    let mut created = Vec::new();
+    for (_, filter) in config.iter() {
+        push_filter_commands(filter.as_ref(), &mut created, &mut lines);
+    }
+    for name in created.iter().filter(|name| config.get(name).is_none()) {
+        lines.push(format!("\"$BAMETRICS\" delete {}", utils::shell_quote(name)));
+    }
+    lines.join("\n") + "\n"
+}
+
+fn push_filter_commands(filter: &dyn Filtering, created: &mut Vec<String>, lines: &mut Vec<String>) {
+    if created.iter().any(|name| name == filter.name()) {
+        return;
+    }
+    for input in filter.inputs() {
+        push_filter_commands(input, created, lines);
+    }
+    match filter.command_args() {
+        Some(args) => {
+            let args: Vec<String> = args.iter().map(|arg| utils::shell_quote(arg)).collect();
+            lines.push(format!("\"$BAMETRICS\" {}", args.join(" ")));
+        }
+        None => lines.push(format!("# {} cannot be created from the command line: {}", filter.name(), filter.repr())),
     }
-    save_config(&config, export_path.unwrap());
-    return None;
+    created.push(filter.name().to_string());
 }
 
 fn init(config_path: &Path) {
@@ -294,119 +1401,4448 @@ fn init(config_path: &Path) {
 }
 
 // Define filter application logic
-fn apply_filter(
-    filter: &str,
-    input_file: &Path,
-    output_file: &Path,
-    threads: u16,
-    config_path: &Path,
-) {
-    println!("Applying filter {} to file {}", filter, input_file.display());
+fn apply_filter(filter: &dyn Filtering, input_file: &Path, args: &ApplyArgs) -> FilterSummary {
+    let output_file = args.output_path();
+    let threads = args.threads.unwrap_or_else(|| default_threads(std::thread::available_parallelism().ok()));
+    println!("Applying filter {} to file {}", filter.name(), input_file.display());
     println!("Output will be written to {}", output_file.display());
-    let suffix = input_file.extension().unwrap();
-    // Implement the logic to apply the filter to the input BAM file
-    assert!(suffix == "sam" || suffix == "bam", "Input file must be a BAM or SAM file!");
+    let format = input_format(input_file);
+    if let Some(named_format) = extension_format(input_file).filter(|named_format| *named_format != format) {
+        eprintln!(
+            "Warning: input file {} is named as {:?} but its contents are {:?}, and it is read as {:?}",
+            input_file.display(),
+            named_format,
+            format,
+            format
+        );
+    }
     assert!(threads > 0, "Number of threads must be greater than 0!");
+    assert!(
+        args.transform || !filter.transforms(),
+        "Filter {} modifies the reads it keeps. Use --transform to apply it!",
+        filter.name()
+    );
 
-    let filter = get_filters(vec![filter], config_path).pop().unwrap();
-
-    let reader: Box<dyn RecordReader<Item=Result<Record, std::io::Error>>> =
-        if suffix == "bam" {
-            Box::new(bam::BamReader::from_path(input_file, threads - 1).unwrap())
-        } else {
-            Box::new(bam::SamReader::from_path(input_file).unwrap())
-        };
+    if !args.split_by_rg {
+        check_clobber(output_file, args.force, args.no_clobber);
+    }
+    if let Some(rejected_file) = &args.rejected {
+        check_clobber(rejected_file, args.force, args.no_clobber);
+    }
+    for fastq_file in &args.unmapped_fastq {
+        check_clobber(fastq_file, args.force, args.no_clobber);
+    }
 
-    let reader_header: bam::Header = if suffix == "bam" {
-        bam::BamReader::from_path(input_file, threads - 1)
-            .unwrap()
-            .header()
-            .clone()
+    // a URL is read from as it is opened for its header, rather than requested again for the records
+    let (reader_header, url_reader) = if utils::is_url(input_file) {
+        let (header, reader) = open_input(input_file, threads);
+        (header, Some(reader))
     } else {
-        bam::SamReader::from_path(input_file)
-            .unwrap()
-            .header()
-            .clone()
+        (read_header(input_file, threads), None)
     };
+    if let Some(sort_order) = args.assert_sort {
+        let declared = utils::header_sort_order(&reader_header);
+        if declared != Some(sort_order.header_value()) {
+            let message = format!(
+                "Input file {} is declared to be sorted by '{}', not '{}'!",
+                input_file.display(),
+                declared.unwrap_or("unknown"),
+                sort_order.header_value()
+            );
+            utils::fail(ErrorKind::BadInput, input_file, message);
+        }
+    }
 
-    let mut writer: Box<dyn RecordWriter> = if output_file.extension().unwrap() == "bam" {
-        Box::new(bam::BamWriter::from_path(output_file, reader_header).unwrap())
+    let mut filter = filter.box_clone();
+    let profiles = if args.profile_filters {
+        filters::profile_leaves(&mut filter)
     } else {
-        Box::new(bam::SamWriter::from_path(output_file, reader_header).unwrap())
+        Vec::new()
+    };
+    filter.prepare(&reader_header);
+    let filter = filter.as_ref();
+    if args.verbose {
+        eprintln!("{}", filter.describe(Some(&reader_header)));
+    }
+
+    let mut indexed_reader;
+    let mut skipped_unmapped = 0;
+    let reader: Box<dyn RecordReader<Item=Result<Record, std::io::Error>> + '_> =
+        if let Some(region) = &args.region {
+            assert!(
+                format == utils::SupportedFormats::BAM && !utils::is_url(input_file),
+                "--region requires an indexed local BAM input file!"
+            );
+            let region = resolve_region(region, &reader_header);
+            indexed_reader = open_indexed_reader(input_file, threads);
+            Box::new(indexed_reader.fetch(&region).unwrap())
+        } else if let Some(n_unmapped) = unmapped_to_skip(filter, format, input_file, args) {
+            skipped_unmapped = n_unmapped;
+            indexed_reader = open_indexed_reader(input_file, threads);
+            let index = indexed_reader.index();
+            let mapped = index.start_offset().zip(index.end_offset());
+            let chunks = mapped.map(|(start, end)| bam::index::Chunk::new(start, end));
+            Box::new(indexed_reader.fetch_chunks(chunks))
+        } else {
+            url_reader.unwrap_or_else(|| open_reader(input_file, threads))
+        };
+
+    // the outputs are written under temporary names, and only moved into place once they are complete
+    let output = (!args.split_by_rg).then(|| AtomicOutput::new(output_file));
+    let rejected_output = args.rejected.as_deref().map(AtomicOutput::new);
+    let rejected_writer = rejected_output
+        .as_ref()
+        .map(|rejected_output| open_writer(&rejected_output.temp_path, reader_header.clone()));
+    let fastq_outputs: Vec<AtomicOutput> = args.unmapped_fastq.iter().map(|path| AtomicOutput::new(path)).collect();
+    let unmapped_fastq = (!fastq_outputs.is_empty()).then(|| {
+        let temp_paths: Vec<&Path> = fastq_outputs.iter().map(|output| output.temp_path.as_path()).collect();
+        UnmappedFastq::new(&temp_paths)
+    });
+    let writer = match &output {
+        Some(output) => open_writer(&output.temp_path, reader_header.clone()),
+        None => Box::new(ReadGroupWriter::new(output_file, reader_header.clone(), args.clone())),
+    };
+    let rejected = RejectedOutputs {
+        writer: rejected_writer,
+        unmapped_fastq,
     };
 
-    for record in reader {
-        let record: Record = record.unwrap();
-        let res = filter.apply_to(&record);
-        if res {
-            writer.write(&record).unwrap()
-        }
+    let mut summary = utils::with_error_path(input_file, || {
+        run_filter(Boxed(reader), Boxed(writer), rejected, filter, &reader_header, args)
+    });
+    if let Some(output) = output {
+        output.commit();
     }
-    writer.finish().unwrap();
+    for fastq_output in fastq_outputs {
+        fastq_output.commit();
+    }
+    if let Some(rejected_output) = rejected_output {
+        rejected_output.commit();
+    }
+    summary.add_removed_unmapped(skipped_unmapped);
+    println!("{}", summary);
+    if let Some(stats_file) = &args.stats_by_ref {
+        std::fs::write(stats_file, summary.ref_tsv()).unwrap();
+    }
+    if let Some(stats_file) = &args.stats_json {
+        let stats = summary.to_stats(args.invert_output);
+        std::fs::write(stats_file, serde_json::to_string_pretty(&stats).unwrap()).unwrap();
+    }
+    if args.profile_filters {
+        println!("{}", format_profile(&profiles));
+    }
+    summary
 }
 
-fn view_filters(config_path: &Path) {
-    let config = load_config(config_path);
-    for (name, filter) in config.iter() {
-        println!("{}: {}", name, filter.repr());
+// Readers and writers chosen at runtime (e.g. by the format of the file), passed to `run_filter`
+struct Boxed<T: ?Sized>(Box<T>);
+
+impl Iterator for Boxed<dyn RecordReader<Item=Result<Record, std::io::Error>> + '_> {
+    type Item = Result<Record, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
     }
 }
 
-fn read_files(input_file: &str, output_file: &str) {
-    // Implement the logic to read the input BAM file and write the output BAM file
+impl RecordReader for Boxed<dyn RecordReader<Item=Result<Record, std::io::Error>> + '_> {
+    fn read_into(&mut self, record: &mut Record) -> std::io::Result<bool> {
+        self.0.read_into(record)
+    }
+
+    fn pause(&mut self) {
+        self.0.pause()
+    }
 }
 
-fn main() {
-    let args = Args::parse();
-    let config_path = match args.bametric_path {
-        Some(s) => s,
-        None => PathBuf::from_str("bametric.json").unwrap(),
+impl RecordWriter for Boxed<dyn RecordWriter> {
+    fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        self.0.write(record)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.0.finish()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Where the records that are not kept go, besides being left out of the main output
+#[derive(Default)]
+struct RejectedOutputs {
+    writer: Option<Box<dyn RecordWriter>>,
+    unmapped_fastq: Option<UnmappedFastq>,
+}
+
+// The filtering loop of `apply_filter`, independent of where the records come from and where they are written.
+// The filter should already be prepared for the header. The outputs are finished, but not committed.
+fn run_filter<R: RecordReader, W: RecordWriter + 'static>(
+    reader: R,
+    writer: W,
+    rejected: RejectedOutputs,
+    filter: &dyn Filtering,
+    header: &bam::Header,
+    args: &ApplyArgs,
+) -> FilterSummary {
+    let mut sink = OutputSink {
+        ref_stats: RefStats::new(header.reference_names()),
+        writer: Box::new(writer),
+        rejected_writer: rejected.writer,
+        unmapped_fastq: rejected.unmapped_fastq,
+        invert: args.invert_output,
+        max_kept: args.max_records.filter(|_| args.max_records_of == utils::RecordLimit::Output),
+        kept: 0,
+        shuffle: args.shuffle.then(|| ShuffleBuffer::new(args.seed, MAX_SHUFFLE_RECORDS)),
+        heartbeat: args.progress_json.as_deref().map(|path| Heartbeat::new(path, args.progress_interval)),
+        transformer: filter.transforms().then(|| filter.box_clone()),
     };
-    match args.cmd {
-        Commands::Init {} => init(&config_path),
-        Commands::Create {
-            name,
-            opposite,
-            cmd,
-        } => create_filter(name, opposite, cmd, &config_path),
-        Commands::Combine {
-            filter1,
-            operator,
-            filter2,
-            name,
-        } => combine_filters(name, &filter1, operator, &filter2, &config_path),
-        Commands::Apply {
-            filter_name,
-            input,
-            output,
-            threads,
-            verbose,
-        } => {
-            for this_input in input {
-                if verbose {
-                    eprintln!("Processing file {}", this_input.display());
+
+    let mut records = CheckedRecords {
+        reader: Box::new(reader),
+        record_number: 0,
+        skip_errors: args.skip_errors,
+        max_records: args.max_records.filter(|_| args.max_records_of == utils::RecordLimit::Input),
+        error_report: args.error_report.as_deref().map(|report_file| {
+            let mut report = std::fs::File::create(report_file).unwrap();
+            writeln!(report, "record\treason").unwrap();
+            report
+        }),
+        errors: 0,
+    };
+
+    let summary = apply_filter_to_reader(filter, records.by_ref(), &mut sink, args);
+    if records.errors > 0 {
+        println!("Skipped {} records that could not be read", records.errors);
+    }
+    summary
+}
+
+// Run the filter over the records, passing each decision to the sink, and summarize the decisions
+fn apply_filter_to_reader(
+    filter: &dyn Filtering,
+    mut records: impl Iterator<Item = Record>,
+    sink: &mut OutputSink,
+    args: &ApplyArgs,
+) -> FilterSummary {
+    let stream = if args.keep_pairs {
+        assert!(
+            filter.to_streaming().is_none(),
+            "Filter {} already decides on reads using other records and cannot be used with --keep-pairs!",
+            filter.name()
+        );
+        let pairing = MatePairStream::new(filter.box_clone(), BoolOperator::OR, false)
+            .with_max_buffer(args.pair_buffer_size);
+        Some(Box::new(pairing) as Box<dyn StreamingFilter>)
+    } else {
+        filter.to_streaming()
+    };
+
+    let subsample = args.subsample.map(|fraction| utils::Subsample::new(fraction, args.seed));
+    let tally = FilterTally::default();
+    if let Some(mut stream) = stream {
+        assert!(
+            !args.parallel_filter,
+            "Filter {} depends on other records and cannot be evaluated in parallel!",
+            filter.name()
+        );
+        let mut decided = Vec::new();
+        for record in records.by_ref() {
+            stream.push(record, &mut decided);
+            write_decided(&mut decided, subsample, &tally, sink);
+            if sink.is_full() {
+                break;
+            }
+        }
+        stream.finish(&mut decided);
+        write_decided(&mut decided, subsample, &tally, sink);
+    } else if args.parallel_filter {
+        let mut batch = Vec::with_capacity(PARALLEL_BATCH_SIZE);
+        for record in records.by_ref() {
+            batch.push(record);
+            if batch.len() == PARALLEL_BATCH_SIZE {
+                let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(PARALLEL_BATCH_SIZE));
+                write_passing_batch(filter, full_batch, subsample, &tally, sink);
+                if sink.is_full() {
+                    break;
                 }
-                apply_filter(&filter_name, &this_input, &output, threads, &config_path);
             }
         }
-        Commands::Import { import_path } => import_filters(&import_path, &config_path),
-        Commands::Export { export_path } => {
-            let out = export_filters(export_path.as_deref(), &config_path);
-            match out {
-                Some(s) => {
-                    println!("{}", s);
+        write_passing_batch(filter, batch, subsample, &tally, sink);
+    } else {
+        for record in records.by_ref() {
+            let res = in_subsample(filter.apply_to(&record), &record, subsample);
+            tally.add(res);
+            sink.handle(&record, res);
+            if sink.is_full() {
+                break;
+            }
+        }
+    }
+    sink.finish();
+    tally.summarize(sink.invert, &sink.ref_stats)
+}
+
+fn format_profile(profiles: &[(String, std::sync::Arc<filters::FilterProfile>)]) -> String {
+    let mut lines = vec!["Filter evaluation profile:".to_string()];
+    let (mut total_calls, mut total_micros) = (0, 0);
+    for (name, profile) in profiles {
+        lines.push(format!("  {}: {} calls, {} us", name, profile.calls(), profile.micros()));
+        total_calls += profile.calls();
+        total_micros += profile.micros();
+    }
+    lines.push(format!("  total: {} calls, {} us", total_calls, total_micros));
+    lines.join("\n")
+}
+
+// Split a thread budget between the input files: the number of files processed at once,
+// and the number of (decompression) threads given to each of them
+fn thread_allocation(budget: u16, parallel_inputs: usize) -> (usize, u16) {
+    assert!(budget > 0, "Number of threads must be greater than 0!");
+    let files = parallel_inputs.clamp(1, budget as usize);
+    (files, budget / files as u16)
+}
+
+// Whether every input file of an apply run writes only its own outputs, so that the files can be processed in parallel
+fn outputs_are_per_file(args: &ApplyArgs) -> bool {
+    args.name_template.is_some()
+        && args.rejected.is_none()
+        && args.stats_by_ref.is_none()
+        && args.stats_json.is_none()
+        && args.error_report.is_none()
+        && args.unmapped_fastq.is_empty()
+        && args.progress_json.is_none()
+}
+
+// The number of logical CPUs, or 1 if it cannot be determined
+fn default_threads(available: Option<std::num::NonZeroUsize>) -> u16 {
+    available.map_or(1, |cpus| u16::try_from(cpus.get()).unwrap_or(u16::MAX))
+}
+
+// With a filter that removes every unmapped read, the unplaced unmapped reads at the end of an indexed BAM file
+// are skipped instead of read. Returns their number as recorded in the index, so they can still be counted as removed.
+// They are read as usual when they are written (--rejected, --invert-output), paired (--keep-pairs),
+// or counted towards --max-records, and if the index doesn't record their number
+fn unmapped_to_skip(filter: &dyn Filtering, format: utils::SupportedFormats, input_file: &Path, args: &ApplyArgs) -> Option<u64> {
+    let skippable = filter.rejects_unmapped()
+        && filter.to_streaming().is_none()
+        && format == utils::SupportedFormats::BAM
+        && !utils::is_url(input_file)
+        && !args.keep_pairs
+        && !args.invert_output
+        && args.rejected.is_none()
+        && args.max_records.is_none()
+        && args.unmapped_fastq.is_empty();
+    let index_file = PathBuf::from(format!("{}.bai", input_file.display()));
+    if !skippable || !index_file.exists() {
+        return None;
+    }
+    bam::index::Index::from_path(&index_file).ok()?.n_unmapped()
+}
+
+fn open_indexed_reader(input_file: &Path, threads: u16) -> bam::IndexedReader<std::fs::File> {
+    let index_file = PathBuf::from(format!("{}.bai", input_file.display()));
+    assert!(
+        index_file.exists(),
+        "--region requires a BAM index, but {} was not found. Create one with 'samtools index {}'",
+        index_file.display(),
+        input_file.display()
+    );
+    bam::IndexedReader::build()
+        .additional_threads(threads - 1)
+        .from_path(input_file)
+        .unwrap_or_else(|e| utils::fail_io(input_file, "Could not open the index of", e))
+}
+
+fn extension_format(input_file: &Path) -> Option<utils::SupportedFormats> {
+    match input_file.extension()?.to_str()? {
+        "bam" => Some(utils::SupportedFormats::BAM),
+        "sam" => Some(utils::SupportedFormats::SAM),
+        _ => None,
+    }
+}
+
+// The format of a local input file is detected from its contents, which take precedence over a misleading extension.
+// URLs, and files whose contents are not recognized (or can't be read), go by their extension
+fn input_format(input_file: &Path) -> utils::SupportedFormats {
+    let sniffed = if utils::is_url(input_file) { None } else { utils::sniff_format(input_file) };
+    sniffed
+        .or_else(|| extension_format(input_file))
+        .unwrap_or_else(|| {
+            let message = format!("Input file {} must be a BAM or SAM file!", input_file.display());
+            utils::fail(ErrorKind::BadInput, input_file, message)
+        })
+}
+
+fn read_header(input_file: &Path, threads: u16) -> bam::Header {
+    open_input(input_file, threads).0
+}
+
+// Convert a region string (e.g. 'chr1:1000-2000', 1-based and inclusive) to a 0-based half-open region.
+// The region is clipped to the length of the reference.
+fn resolve_region(region: &str, header: &bam::Header) -> bam::Region {
+    // reference names may contain ':' themselves (e.g. 'HLA-A*01:01')
+    let (ref_name, range) = match header.reference_id(region) {
+        Some(_) => (region.to_string(), None),
+        None => utils::parse_region(region),
+    };
+    let ref_id = header
+        .reference_id(&ref_name)
+        .unwrap_or_else(|| panic!("Reference '{}' of region '{}' is not in the input header!", ref_name, region));
+    let ref_len = header.reference_len(ref_id).unwrap();
+    let (start, end) = range.unwrap_or((1, ref_len));
+    assert!(start <= ref_len, "Region '{}' starts after the end of reference '{}'!", region, ref_name);
+    bam::Region::new(ref_id, start - 1, end.min(ref_len))
+}
+
+// Reads records from the input, reporting (and optionally skipping) those that cannot be read
+struct CheckedRecords<'a> {
+    reader: Box<dyn RecordReader<Item=Result<Record, std::io::Error>> + 'a>,
+    record_number: usize,
+    max_records: Option<u64>,
+    skip_errors: bool,
+    error_report: Option<std::fs::File>,
+    errors: usize,
+}
+
+impl Iterator for CheckedRecords<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        if self.max_records.is_some_and(|max_records| self.record_number as u64 >= max_records) {
+            return None;
+        }
+        loop {
+            let error = match self.reader.next()? {
+                Ok(record) => {
+                    self.record_number += 1;
+                    return Some(record);
                 }
-                None => {}
+                Err(error) => error,
+            };
+            self.record_number += 1;
+            self.errors += 1;
+            if let Some(report) = self.error_report.as_mut() {
+                writeln!(report, "{}\t{}", self.record_number, error).unwrap();
+            }
+            if !self.skip_errors {
+                // the input file is added by `utils::with_error_path`
+                std::panic::panic_any(utils::CommandError {
+                    kind: ErrorKind::BadInput,
+                    message: format!(
+                        "Could not read record {}: {}. Use --skip-errors to skip such records.",
+                        self.record_number,
+                        error
+                    ),
+                    path: None,
+                });
+            }
+            // a malformed record can be skipped, but a broken stream (e.g. a truncated BAM file) cannot be read past
+            if error.kind() != std::io::ErrorKind::InvalidData {
+                eprintln!("Warning: stopped reading the input after record {}", self.record_number);
+                return None;
             }
         }
-        Commands::View {} => view_filters(&config_path),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use rstest::*;
+// Inputs given as URLs are streamed (see `remote::open_url`) if built with the 'remote' feature
+#[cfg(feature = "remote")]
+fn open_url(input_file: &Path) -> Box<dyn Read + Send> {
+    remote::open_url(input_file)
+}
 
-    #[fixture]
-    fn bam_record() {}
+#[cfg(not(feature = "remote"))]
+fn open_url(input_file: &Path) -> Box<dyn Read + Send> {
+    let message = format!(
+        "Could not read {}: reading inputs from URLs requires building BAMetrics with the 'remote' feature!",
+        input_file.display()
+    );
+    utils::fail(ErrorKind::Io, input_file, message)
+}
+
+fn open_reader(input_file: &Path, threads: u16) -> Box<dyn RecordReader<Item=Result<Record, std::io::Error>>> {
+    open_input(input_file, threads).1
+}
+
+// The header of an input file, and a reader of its records. Both come from a single opening of the input, so that a
+// URL is only requested once
+fn open_input(
+    input_file: &Path,
+    threads: u16,
+) -> (bam::Header, Box<dyn RecordReader<Item=Result<Record, std::io::Error>>>) {
+    let is_bam = input_format(input_file) == utils::SupportedFormats::BAM;
+    if utils::is_url(input_file) {
+        return if is_bam {
+            let reader = bam::BamReader::from_stream(open_url(input_file), threads - 1).unwrap();
+            (reader.header().clone(), Box::new(reader))
+        } else {
+            let reader = bam::SamReader::from_stream(std::io::BufReader::new(open_url(input_file))).unwrap();
+            (reader.header().clone(), Box::new(reader))
+        };
+    }
+    if is_bam {
+        let reader = bam::BamReader::from_path(input_file, threads - 1).unwrap();
+        (reader.header().clone(), Box::new(reader))
+    } else {
+        let reader = bam::SamReader::from_path(input_file).unwrap();
+        (reader.header().clone(), Box::new(reader))
+    }
+}
+
+// `force` and `no_clobber` are the --force and --no-clobber flags of the command
+fn check_clobber(output_file: &Path, force: bool, no_clobber: bool) {
+    if force || !output_file.exists() {
+        return;
+    }
+    let confirmed = !no_clobber && std::io::stdin().is_terminal() && {
+        eprint!("Output file {} already exists. Overwrite it? [y/N] ", output_file.display());
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).unwrap();
+        matches!(answer.trim(), "y" | "Y" | "yes")
+    };
+    assert!(confirmed, "Output file {} already exists! Use --force to overwrite it.", output_file.display());
+}
+
+// An output file that is written under a temporary name in the same directory, and renamed to its final path
+// by `commit`, so that the output is either complete or absent. If it is dropped without being committed
+// (e.g. when the run fails midway), the temporary file is removed.
+struct AtomicOutput {
+    path: PathBuf,
+    temp_path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicOutput {
+    fn new(path: &Path) -> AtomicOutput {
+        // the temporary name keeps the extension, which decides the output format
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.{}.tmp.{}", file_name, std::process::id(), extension));
+        AtomicOutput {
+            path: path.to_path_buf(),
+            temp_path,
+            committed: false,
+        }
+    }
+
+    fn commit(mut self) {
+        std::fs::rename(&self.temp_path, &self.path)
+            .unwrap_or_else(|e| utils::fail_io(&self.path, "Could not move the output to", e));
+        self.committed = true;
+    }
+}
+
+impl Drop for AtomicOutput {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+fn open_writer(output_file: &Path, header: bam::Header) -> Box<dyn RecordWriter> {
+    if output_file.extension().unwrap() == "bam" {
+        Box::new(bam::BamWriter::from_path(output_file, header).unwrap())
+    } else {
+        Box::new(bam::SamWriter::from_path(output_file, header).unwrap())
+    }
+}
+
+// Writes each record to the BAM file of its read group in an output directory. The files are opened as their
+// read groups are first seen, and are moved into place when the writer is finished
+struct ReadGroupWriter {
+    directory: PathBuf,
+    header: bam::Header,
+    // used to check each output file before it is overwritten
+    args: ApplyArgs,
+    outputs: IndexMap<Vec<u8>, (AtomicOutput, Box<dyn RecordWriter>)>,
+}
+
+impl ReadGroupWriter {
+    fn new(directory: &Path, header: bam::Header, args: ApplyArgs) -> ReadGroupWriter {
+        std::fs::create_dir_all(directory).unwrap();
+        ReadGroupWriter {
+            directory: directory.to_path_buf(),
+            header,
+            args,
+            outputs: IndexMap::new(),
+        }
+    }
+
+    fn output_path(&self, read_group: &[u8]) -> PathBuf {
+        if read_group.is_empty() {
+            return self.directory.join("no_read_group.bam");
+        }
+        // read group IDs may contain path separators
+        let name = String::from_utf8_lossy(read_group).replace(std::path::MAIN_SEPARATOR, "_");
+        self.directory.join(format!("{}.bam", name))
+    }
+}
+
+impl RecordWriter for ReadGroupWriter {
+    fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        let read_group = utils::read_group(record).unwrap_or_default().to_vec();
+        if !self.outputs.contains_key(&read_group) {
+            assert!(
+                self.outputs.len() < MAX_READ_GROUP_OUTPUTS,
+                "The input has more than {} read groups, too many to write to separate files with --split-by-rg!",
+                MAX_READ_GROUP_OUTPUTS
+            );
+            let path = self.output_path(&read_group);
+            check_clobber(&path, self.args.force, self.args.no_clobber);
+            let output = AtomicOutput::new(&path);
+            let writer = open_writer(&output.temp_path, self.header.clone());
+            self.outputs.insert(read_group.clone(), (output, writer));
+        }
+        self.outputs[&read_group].1.write(record)
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        for (_, (output, mut writer)) in self.outputs.drain(..) {
+            writer.finish()?;
+            drop(writer);
+            output.commit();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for (_, writer) in self.outputs.values_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Writes unmapped reads as FASTQ, reverse-complementing reads stored on the reverse strand.
+// With two files, first and second mates go to separate files, and a read is held back until its mate arrives;
+// reads whose mate never arrives (and unpaired reads) are not written
+struct UnmappedFastq {
+    first: std::io::BufWriter<std::fs::File>,
+    second: Option<std::io::BufWriter<std::fs::File>>,
+    // mates waiting for their pair when writing to two files
+    waiting: MateBuffer<Record>,
+}
+
+impl UnmappedFastq {
+    fn new(paths: &[&Path]) -> UnmappedFastq {
+        let create = |path: &Path| {
+            let file = std::fs::File::create(path)
+                .unwrap_or_else(|e| utils::fail_io(path, "Could not create FASTQ file", e));
+            std::io::BufWriter::new(file)
+        };
+        UnmappedFastq {
+            first: create(paths[0]),
+            second: paths.get(1).map(|&path| create(path)),
+            waiting: MateBuffer::new(),
+        }
+    }
+
+    fn write(&mut self, record: &Record) {
+        let Some(second) = self.second.as_mut() else {
+            write_fastq_record(&mut self.first, record);
+            return;
+        };
+        if !record.flag().is_paired() {
+            return;
+        }
+        if let Some((mate, record)) = self.waiting.push(record.name(), record.clone()) {
+            let (first_mate, second_mate) = if mate.flag().first_in_pair() { (mate, record) } else { (record, mate) };
+            write_fastq_record(&mut self.first, &first_mate);
+            write_fastq_record(second, &second_mate);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.first.flush().unwrap();
+        if let Some(second) = self.second.as_mut() {
+            second.flush().unwrap();
+        }
+        let unpaired = self.waiting.buffered();
+        if unpaired > 0 {
+            println!("Skipped {} unmapped reads whose mate was not written to the FASTQ files", unpaired);
+        }
+    }
+}
+
+fn write_fastq_record(writer: &mut impl Write, record: &Record) {
+    let mut sequence = record.sequence().to_vec();
+    let qualities = record.qualities();
+    let mut qualities = if qualities.available() {
+        qualities.to_readable()
+    } else {
+        vec![b'!'; sequence.len()]
+    };
+    if record.flag().is_reverse_strand() {
+        sequence = utils::reverse_complement(&sequence);
+        qualities.reverse();
+    }
+    writer.write_all(b"@").unwrap();
+    writer.write_all(record.name()).unwrap();
+    writer.write_all(b"\n").unwrap();
+    writer.write_all(&sequence).unwrap();
+    writer.write_all(b"\n+\n").unwrap();
+    writer.write_all(&qualities).unwrap();
+    writer.write_all(b"\n").unwrap();
+}
+
+// Receives every record along with the filter's decision, in input order, and writes the records to keep
+// (and optionally the rejected records to a separate file)
+struct OutputSink {
+    writer: Box<dyn RecordWriter>,
+    rejected_writer: Option<Box<dyn RecordWriter>>,
+    unmapped_fastq: Option<UnmappedFastq>,
+    ref_stats: RefStats,
+    invert: bool,
+    // records beyond this number of kept records are ignored
+    max_kept: Option<u64>,
+    kept: u64,
+    shuffle: Option<ShuffleBuffer>,
+    heartbeat: Option<Heartbeat>,
+    // modifies the kept records before they are written (see `Filtering::transform`)
+    transformer: Option<Box<dyn Filtering>>,
+}
+
+impl OutputSink {
+    fn is_full(&self) -> bool {
+        self.max_kept.is_some_and(|max_kept| self.kept >= max_kept)
+    }
+
+    fn handle(&mut self, record: &Record, passed: bool) {
+        if self.is_full() {
+            return;
+        }
+        let keep = passed != self.invert;
+        if keep {
+            self.kept += 1;
+        }
+        self.ref_stats.add(record, keep);
+        if let Some(heartbeat) = self.heartbeat.as_mut() {
+            heartbeat.add(keep);
+        }
+        if keep {
+            let transformed = self.transformer.as_ref().map(|transformer| {
+                let mut record = record.clone();
+                transformer.transform(&mut record);
+                record
+            });
+            let record = transformed.as_ref().unwrap_or(record);
+            match self.shuffle.as_mut() {
+                Some(shuffle) => shuffle.push(record.clone(), self.writer.as_mut()),
+                None => self.writer.write(record).unwrap(),
+            }
+        } else {
+            if let Some(rejected_writer) = self.rejected_writer.as_mut() {
+                rejected_writer.write(record).unwrap()
+            }
+            if let Some(unmapped_fastq) = self.unmapped_fastq.as_mut().filter(|_| !record.flag().is_mapped()) {
+                unmapped_fastq.write(record);
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(shuffle) = self.shuffle.as_mut() {
+            shuffle.flush(self.writer.as_mut());
+        }
+        self.writer.finish().unwrap();
+        if let Some(rejected_writer) = self.rejected_writer.as_mut() {
+            rejected_writer.finish().unwrap();
+        }
+        if let Some(unmapped_fastq) = self.unmapped_fastq.as_mut() {
+            unmapped_fastq.finish();
+        }
+        if let Some(heartbeat) = self.heartbeat.as_mut() {
+            heartbeat.write();
+        }
+    }
+}
+
+// Kept records waiting to be written in a shuffled order.
+// Once the buffer is full, it is shuffled and written, so larger inputs are only shuffled within chunks.
+struct ShuffleBuffer {
+    rng: ChaCha8Rng,
+    records: Vec<Record>,
+    max_records: usize,
+    warned: bool,
+}
+
+impl ShuffleBuffer {
+    fn new(seed: u64, max_records: usize) -> ShuffleBuffer {
+        ShuffleBuffer {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            records: Vec::new(),
+            max_records,
+            warned: false,
+        }
+    }
+
+    fn push(&mut self, record: Record, writer: &mut dyn RecordWriter) {
+        self.records.push(record);
+        if self.records.len() >= self.max_records {
+            if !self.warned {
+                eprintln!(
+                    "Warning: more than {} records are kept. They are shuffled in chunks of this size to limit memory use",
+                    self.max_records
+                );
+                self.warned = true;
+            }
+            self.flush(writer);
+        }
+    }
+
+    fn flush(&mut self, writer: &mut dyn RecordWriter) {
+        self.records.shuffle(&mut self.rng);
+        for record in self.records.drain(..) {
+            writer.write(&record).unwrap();
+        }
+    }
+}
+
+// The progress of a run, as written to the --progress-json file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Progress {
+    processed: u64,
+    kept: u64,
+    removed: u64,
+    elapsed_secs: f64,
+}
+
+// Counts the decisions of a run, and writes them to a file every `interval`.
+// The file is replaced rather than rewritten in place, so that readers never see a partial update
+struct Heartbeat {
+    path: PathBuf,
+    interval: std::time::Duration,
+    start: std::time::Instant,
+    last_write: std::time::Instant,
+    kept: u64,
+    removed: u64,
+}
+
+impl Heartbeat {
+    fn new(path: &Path, interval_secs: f64) -> Heartbeat {
+        assert!(
+            interval_secs.is_finite() && interval_secs >= 0.0,
+            "Progress interval must be a non-negative number of seconds!"
+        );
+        let now = std::time::Instant::now();
+        Heartbeat {
+            path: path.to_path_buf(),
+            interval: std::time::Duration::from_secs_f64(interval_secs),
+            start: now,
+            last_write: now,
+            kept: 0,
+            removed: 0,
+        }
+    }
+
+    fn add(&mut self, keep: bool) {
+        if keep {
+            self.kept += 1;
+        } else {
+            self.removed += 1;
+        }
+        if self.last_write.elapsed() >= self.interval {
+            self.write();
+        }
+    }
+
+    fn write(&mut self) {
+        let progress = Progress {
+            processed: self.kept + self.removed,
+            kept: self.kept,
+            removed: self.removed,
+            elapsed_secs: self.start.elapsed().as_secs_f64(),
+        };
+        let output = AtomicOutput::new(&self.path);
+        std::fs::write(&output.temp_path, serde_json::to_string(&progress).unwrap()).unwrap();
+        output.commit();
+        self.last_write = std::time::Instant::now();
+    }
+}
+
+// Kept/removed record counts per reference sequence, in header order. Unmapped records without a reference are counted as '*'.
+struct RefStats {
+    names: Vec<String>,
+    kept: Vec<u64>,
+    removed: Vec<u64>,
+}
+
+impl RefStats {
+    fn new(reference_names: &[String]) -> RefStats {
+        let mut names = reference_names.to_vec();
+        names.push("*".to_string());
+        RefStats {
+            kept: vec![0; names.len()],
+            removed: vec![0; names.len()],
+            names,
+        }
+    }
+
+    fn add(&mut self, record: &Record, keep: bool) {
+        let unmapped = self.names.len() - 1;
+        let index = usize::try_from(record.ref_id()).map_or(unmapped, |ref_id| ref_id.min(unmapped));
+        if keep {
+            self.kept[index] += 1;
+        } else {
+            self.removed[index] += 1;
+        }
+    }
+
+    // References without any records are left out
+    fn to_summary(&self) -> IndexMap<String, RefSummary> {
+        self.names
+            .iter()
+            .zip(&self.kept)
+            .zip(&self.removed)
+            .filter(|((_, kept), removed)| *kept + *removed > 0)
+            .map(|((name, &kept), &removed)| (name.clone(), RefSummary { kept, removed }))
+            .collect()
+    }
+}
+
+/// The outcome of applying a filter to an input file
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct FilterSummary {
+    /// Number of records the filter was evaluated on
+    total: u64,
+    kept: u64,
+    removed: u64,
+    /// Kept/removed records per reference sequence, in header order. Unmapped records are counted under '*',
+    /// and references without any records are left out
+    per_ref: IndexMap<String, RefSummary>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RefSummary {
+    kept: u64,
+    removed: u64,
+}
+
+impl FilterSummary {
+    // Count unmapped records that were removed without being read
+    fn add_removed_unmapped(&mut self, n_unmapped: u64) {
+        if n_unmapped == 0 {
+            return;
+        }
+        self.total += n_unmapped;
+        self.removed += n_unmapped;
+        self.per_ref
+            .entry("*".to_string())
+            .or_insert(RefSummary { kept: 0, removed: 0 })
+            .removed += n_unmapped;
+    }
+
+    fn ref_tsv(&self) -> String {
+        let mut tsv = "reference\tkept\tremoved\n".to_string();
+        for (name, counts) in &self.per_ref {
+            tsv.push_str(&format!("{}\t{}\t{}\n", name, counts.kept, counts.removed));
+        }
+        tsv
+    }
+
+    // With an inverted output the records that pass the filter are the removed ones
+    fn to_stats(&self, invert: bool) -> ApplyStats {
+        let (passed, failed) = if invert { (self.removed, self.kept) } else { (self.kept, self.removed) };
+        ApplyStats {
+            records: self.total,
+            passed,
+            failed,
+            kept: self.kept,
+            removed: self.removed,
+        }
+    }
+}
+
+impl std::fmt::Display for FilterSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Kept {} of {} records ({} removed)", self.kept, self.total, self.removed)
+    }
+}
+
+// Counts the filter's decisions. It is updated from the worker threads with --parallel-filter, hence the atomics
+#[derive(Default)]
+struct FilterTally {
+    records: AtomicU64,
+    passed: AtomicU64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ApplyStats {
+    records: u64,
+    passed: u64,
+    failed: u64,
+    kept: u64,
+    removed: u64,
+}
+
+impl FilterTally {
+    fn add(&self, passed: bool) {
+        self.records.fetch_add(1, Ordering::Relaxed);
+        if passed {
+            self.passed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn summarize(&self, invert: bool, ref_stats: &RefStats) -> FilterSummary {
+        let total = self.records.load(Ordering::Relaxed);
+        let passed = self.passed.load(Ordering::Relaxed);
+        let (kept, removed) = if invert { (total - passed, passed) } else { (passed, total - passed) };
+        FilterSummary {
+            total,
+            kept,
+            removed,
+            per_ref: ref_stats.to_summary(),
+        }
+    }
+}
+
+// With --subsample, a record passes only if it also is in the subsample
+fn in_subsample(passed: bool, record: &Record, subsample: Option<utils::Subsample>) -> bool {
+    passed && subsample.is_none_or(|subsample| subsample.contains(record.name()))
+}
+
+// Evaluate the filter on a batch of records in parallel, then write the survivors in input order.
+// Records are moved into the thread pool since they are not Sync.
+fn write_passing_batch(
+    filter: &dyn Filtering,
+    batch: Vec<Record>,
+    subsample: Option<utils::Subsample>,
+    tally: &FilterTally,
+    sink: &mut OutputSink,
+) {
+    let results: Vec<(Record, bool)> = batch
+        .into_par_iter()
+        .map(|record| {
+            let res = in_subsample(filter.apply_to(&record), &record, subsample);
+            (record, res)
+        })
+        .collect();
+    for (record, res) in results {
+        // records after the sink is full are not written, so they aren't counted either
+        if sink.is_full() {
+            break;
+        }
+        tally.add(res);
+        sink.handle(&record, res);
+    }
+}
+
+fn write_decided(
+    decided: &mut Vec<(Record, bool)>,
+    subsample: Option<utils::Subsample>,
+    tally: &FilterTally,
+    sink: &mut OutputSink,
+) {
+    for (record, res) in decided.drain(..) {
+        if sink.is_full() {
+            break;
+        }
+        let res = in_subsample(res, &record, subsample);
+        tally.add(res);
+        sink.handle(&record, res);
+    }
+}
+
+fn apply_filter_to_files(filter: &dyn Filtering, args: &ApplyArgs) {
+    if let Some(template) = &args.name_template {
+        // fail on a malformed template before any input is processed
+        if let Err(e) = utils::render_name_template(template, "", "", "") {
+            panic!("Invalid name template '{}': {}", template, e);
+        }
+        std::fs::create_dir_all(args.output_path()).unwrap();
+    }
+    let inputs = utils::expand_input_paths(&args.input);
+    // '--default keep' inverts the output after the arguments are parsed
+    assert!(
+        args.subsample.is_none() || !args.invert_output,
+        "--subsample cannot be combined with --invert-output or '--default keep'!"
+    );
+    // the read group outputs of several inputs would overwrite each other
+    assert!(!args.split_by_rg || inputs.len() == 1, "--split-by-rg requires a single input file!");
+    let budget = args.threads.unwrap_or_else(|| default_threads(std::thread::available_parallelism().ok()));
+    let parallel_inputs = if outputs_are_per_file(args) { inputs.len() } else { 1 };
+    let (parallel_files, file_threads) = thread_allocation(budget, parallel_inputs);
+    if args.verbose {
+        eprintln!("Processing {} files at a time, with {} threads each", parallel_files, file_threads);
+    }
+    // manifest entries are kept in input order, whatever order the files finish in
+    let manifest: std::sync::Mutex<Vec<Option<ManifestEntry>>> =
+        std::sync::Mutex::new(inputs.iter().map(|_| None).collect());
+    let completed = match &args.manifest {
+        Some(manifest_file) if args.resume && manifest_file.exists() => read_manifest(manifest_file),
+        _ => Vec::new(),
+    };
+    let process = |index: usize, this_input: &PathBuf| {
+        if args.verbose {
+            eprintln!("Processing file {}", this_input.display());
+        }
+        let mut file_args = args.clone();
+        file_args.threads = Some(file_threads);
+        if let Some(template) = &args.name_template {
+            let stem = this_input.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = this_input.extension().unwrap_or_default().to_string_lossy();
+            // filter names may contain path separators
+            let filter_name = filter.name().replace(std::path::MAIN_SEPARATOR, "_");
+            let file_name = utils::render_name_template(template, &stem, &filter_name, &ext).unwrap();
+            file_args.output = Some(args.output_path().join(file_name));
+        }
+        let output = file_args.output_path();
+        let previous = completed.iter().find(|entry| {
+            entry.input == *this_input && entry.output == output && entry.filter == filter.name() && output.exists()
+        });
+        let entry = match previous {
+            Some(previous) => {
+                println!("Skipping file {}: its output {} is complete", this_input.display(), output.display());
+                previous.clone()
+            }
+            None => {
+                let summary = apply_filter(filter, this_input, &file_args);
+                ManifestEntry {
+                    input: this_input.clone(),
+                    output: output.to_path_buf(),
+                    filter: filter.name().to_string(),
+                    timestamp: std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                    total: summary.total,
+                    kept: summary.kept,
+                    removed: summary.removed,
+                }
+            }
+        };
+        // an input skipped by --resume is reported with the counts of the run that produced its output
+        if args.summary_line {
+            println!("{}", format_summary_line(&entry));
+        }
+        if let Some(manifest_file) = &args.manifest {
+            let mut manifest = manifest.lock().unwrap();
+            manifest[index] = Some(entry);
+            let entries: Vec<&ManifestEntry> = manifest.iter().flatten().collect();
+            write_manifest(&entries, manifest_file);
+        }
+    };
+    if parallel_files == 1 {
+        for (index, this_input) in inputs.iter().enumerate() {
+            process(index, this_input);
+        }
+    } else {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(parallel_files).build().unwrap();
+        pool.install(|| inputs.par_iter().enumerate().for_each(|(index, this_input)| process(index, this_input)));
+    }
+}
+
+// A processed input file, as listed in the --manifest file
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct ManifestEntry {
+    input: PathBuf,
+    output: PathBuf,
+    filter: String,
+    timestamp: u64,
+    total: u64,
+    kept: u64,
+    removed: u64,
+}
+
+fn write_manifest(manifest: &[&ManifestEntry], manifest_file: &Path) {
+    let contents = if manifest_file.extension().is_some_and(|ext| ext == "json") {
+        serde_json::to_string_pretty(manifest).unwrap()
+    } else {
+        let mut tsv = "input\toutput\tfilter\ttimestamp\ttotal\tkept\tremoved\n".to_string();
+        for entry in manifest {
+            tsv.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                entry.input.display(),
+                entry.output.display(),
+                entry.filter,
+                entry.timestamp,
+                entry.total,
+                entry.kept,
+                entry.removed
+            ));
+        }
+        tsv
+    };
+    std::fs::write(manifest_file, contents).unwrap();
+}
+
+// Read a manifest written by `write_manifest`, in either format
+fn read_manifest(manifest_file: &Path) -> Vec<ManifestEntry> {
+    let contents = std::fs::read_to_string(manifest_file)
+        .unwrap_or_else(|e| utils::fail_io(manifest_file, "Could not read manifest file", e));
+    if manifest_file.extension().is_some_and(|ext| ext == "json") {
+        return serde_json::from_str(&contents)
+            .unwrap_or_else(|e| panic!("Invalid manifest file {}: {}", manifest_file.display(), e));
+    }
+    contents
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            assert_eq!(fields.len(), 7, "Invalid line in manifest file {}: '{}'", manifest_file.display(), line);
+            let count = |i: usize| {
+                let invalid = |_| panic!("Invalid count '{}' in manifest file {}", fields[i], manifest_file.display());
+                fields[i].parse().unwrap_or_else(invalid)
+            };
+            ManifestEntry {
+                input: fields[0].into(),
+                output: fields[1].into(),
+                filter: fields[2].to_string(),
+                timestamp: count(3),
+                total: count(4),
+                kept: count(5),
+                removed: count(6),
+            }
+        })
+        .collect()
+}
+
+// Apply a filter defined on the command line, without saving it to the session config.
+// The config is only read if the inline filter wraps existing filters.
+fn run_inline_filter(opposite: bool, cmd: CreateCommands, args: &ApplyArgs, config_path: &Path) {
+    let filter = build_filter("inline filter".to_string(), opposite, cmd, config_path);
+    if args.count_passes {
+        println!("{}", format_pass_counts(&count_passes(&[filter], args)));
+        return;
+    }
+    apply_filter_to_files(filter.as_ref(), args);
+}
+
+// A stage of a pipeline: the filter, the number of reads that reached it, and how many of them it kept
+#[derive(Debug, PartialEq)]
+struct PipelineStage {
+    name: String,
+    input: u64,
+    kept: u64,
+}
+
+// Apply the filters to the reads of the input in a single pass. A read is only evaluated by the next filter if it
+// passes the previous ones
+fn run_pipeline(
+    filter_names: &[String],
+    input_file: &Path,
+    output_file: &Path,
+    force: bool,
+    no_clobber: bool,
+    config_path: &Path,
+) -> Vec<PipelineStage> {
+    let filters = get_filters(filter_names.iter().map(String::as_str).collect(), config_path);
+    for filter in &filters {
+        assert!(
+            filter.to_streaming().is_none() && !filter.transforms(),
+            "Filter {} depends on other records or modifies reads, and cannot be used in a pipeline!",
+            filter.name()
+        );
+    }
+    check_clobber(output_file, force, no_clobber);
+    let threads = default_threads(std::thread::available_parallelism().ok());
+    let (header, reader) = open_input(input_file, threads);
+    let filters: Vec<Box<dyn Filtering>> = filters
+        .into_iter()
+        .map(|mut filter| {
+            filter.prepare(&header);
+            filter
+        })
+        .collect();
+    let mut stages: Vec<PipelineStage> = filters
+        .iter()
+        .map(|filter| PipelineStage { name: filter.name().to_string(), input: 0, kept: 0 })
+        .collect();
+
+    let output = AtomicOutput::new(output_file);
+    let mut writer = open_writer(&output.temp_path, header);
+    let records = CheckedRecords {
+        reader,
+        record_number: 0,
+        max_records: None,
+        skip_errors: false,
+        error_report: None,
+        errors: 0,
+    };
+    utils::with_error_path(input_file, || {
+        for record in records {
+            let mut kept = true;
+            for (filter, stage) in filters.iter().zip(stages.iter_mut()) {
+                stage.input += 1;
+                if !filter.apply_to(&record) {
+                    kept = false;
+                    break;
+                }
+                stage.kept += 1;
+            }
+            if kept {
+                writer.write(&record).unwrap();
+            }
+        }
+    });
+    writer.finish().unwrap();
+    drop(writer);
+    output.commit();
+    stages
+}
+
+fn format_pipeline(stages: &[PipelineStage]) -> String {
+    let mut lines: Vec<String> = stages
+        .iter()
+        .enumerate()
+        .map(|(i, stage)| {
+            format!(
+                "Stage {} ({}): {} in, {} kept, {} removed",
+                i + 1,
+                stage.name,
+                stage.input,
+                stage.kept,
+                stage.input - stage.kept
+            )
+        })
+        .collect();
+    if let (Some(first), Some(last)) = (stages.first(), stages.last()) {
+        let percent = if first.input > 0 { 100.0 * last.kept as f64 / first.input as f64 } else { 0.0 };
+        lines.push(format!("Kept {} of {} records ({:.1}%)", last.kept, first.input, percent));
+    }
+    lines.join("\n")
+}
+
+fn view_filters(config_path: &Path, count_only: bool, format: utils::ViewFormat) {
+    let config = load_config(config_path);
+    if count_only {
+        println!("{}", config.count());
+        return;
+    }
+    match format {
+        utils::ViewFormat::Human => {
+            println!("{}", format_view_summary(&config));
+            for (name, filter) in config.iter() {
+                println!("{} (@{}): {}", name, config.id(name).unwrap(), filter.repr());
+            }
+        }
+        utils::ViewFormat::Json => println!("{}", serde_json::to_string(&filter_rows(&config)).unwrap()),
+        utils::ViewFormat::Tsv => println!("{}", format_view_tsv(&filter_rows(&config))),
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct FilterRow {
+    name: String,
+    id: u64,
+    #[serde(rename = "type")]
+    filter_type: String,
+    opposite: bool,
+    // the serialized fields of the filter other than its name and opposite flag
+    params: serde_json::Map<String, serde_json::Value>,
+}
+
+fn filter_rows(config: &filters::Config) -> Vec<FilterRow> {
+    config
+        .iter()
+        .map(|(name, filter)| {
+            let mut params = match serde_json::to_value(filter).unwrap() {
+                serde_json::Value::Object(fields) => fields,
+                _ => serde_json::Map::new(),
+            };
+            // the serialized type tag
+            params.remove("type");
+            params.remove("name");
+            let opposite = params.remove("opposite").and_then(|opposite| opposite.as_bool()).unwrap_or(false);
+            FilterRow {
+                name: name.clone(),
+                id: config.id(name).unwrap(),
+                filter_type: filters::filter_type(filter.as_ref()),
+                opposite,
+                params,
+            }
+        })
+        .collect()
+}
+
+// Parameters are written as 'key=value' pairs. Filters that a filter is built from are written by their name
+fn format_view_tsv(rows: &[FilterRow]) -> String {
+    fn compact(value: &serde_json::Value) -> String {
+        match value {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Object(fields) => match fields.get("name").and_then(|name| name.as_str()) {
+                Some(name) => name.to_string(),
+                None => value.to_string(),
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    let mut lines = vec!["name\ttype\topposite\tparams".to_string()];
+    for row in rows {
+        let params: Vec<String> = row
+            .params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, compact(value)))
+            .collect();
+        let line = format!("{}\t{}\t{}\t{}", row.name, row.filter_type, row.opposite, params.join(","));
+        lines.push(line.replace(['\n', '\r'], " "));
+    }
+    lines.join("\n")
+}
+
+// One line per filter, with the filters it is built from indented below it
+fn describe_filter(name: &str, header_path: Option<&Path>, config_path: &Path) -> String {
+    fn describe_lines(filter: &dyn Filtering, header: Option<&bam::Header>, depth: usize, lines: &mut Vec<String>) {
+        lines.push(format!("{}{}", "  ".repeat(depth), filter.describe(header)));
+        for input in filter.inputs() {
+            describe_lines(input, header, depth + 1, lines);
+        }
+    }
+
+    let config = load_config(config_path);
+    let filter = config
+        .get(name)
+        .unwrap_or_else(|| missing_filter(name, config_path));
+    let header = header_path.map(|header_path| read_header(header_path, 1));
+    let mut lines = Vec::new();
+    describe_lines(filter.as_ref(), header.as_ref(), 0, &mut lines);
+    lines.join("\n")
+}
+
+// The number of sampled records, and how many of them passed each filter
+fn check_filters(input_file: &Path, sample_size: usize, config_path: &Path) -> (usize, Vec<(String, usize)>) {
+    let config = load_config(config_path);
+    let (header, reader) = open_input(input_file, 1);
+    let records = CheckedRecords {
+        reader,
+        record_number: 0,
+        max_records: Some(sample_size as u64),
+        skip_errors: false,
+        error_report: None,
+        errors: 0,
+    };
+    let sample: Vec<Record> = utils::with_error_path(input_file, || records.collect());
+
+    let passed = config
+        .iter()
+        .map(|(name, filter)| {
+            let mut filter = filter.box_clone();
+            filter.prepare(&header);
+            let passed = match filter.to_streaming() {
+                Some(mut stream) => {
+                    let mut decided = Vec::new();
+                    for record in &sample {
+                        stream.push(record.clone(), &mut decided);
+                    }
+                    stream.finish(&mut decided);
+                    decided.iter().filter(|(_, keep)| *keep).count()
+                }
+                None => sample.iter().filter(|record| filter.apply_to(record)).count(),
+            };
+            (name.to_string(), passed)
+        })
+        .collect();
+    (sample.len(), passed)
+}
+
+// Add the reads that a stream passed among its latest decisions to the number of filters they passed
+fn count_stream_passes(
+    decided: &mut Vec<(Record, bool)>,
+    stream_decided: &mut u64,
+    pending: &mut VecDeque<usize>,
+    first_pending: u64,
+) {
+    for (_, passed) in decided.drain(..) {
+        if passed {
+            pending[(*stream_decided - first_pending) as usize] += 1;
+        }
+        *stream_decided += 1;
+    }
+}
+
+// The number of reads of the inputs that pass each number of the filters, from none of them to all of them
+fn count_passes(filters: &[Box<dyn Filtering>], args: &ApplyArgs) -> Vec<u64> {
+    let threads = args.threads.unwrap_or_else(|| default_threads(std::thread::available_parallelism().ok()));
+    let mut counts = vec![0; filters.len() + 1];
+    for input_file in utils::expand_input_paths(&args.input) {
+        let (header, reader) = open_input(&input_file, threads);
+        let filters: Vec<Box<dyn Filtering>> = filters
+            .iter()
+            .map(|filter| {
+                let mut filter = filter.box_clone();
+                filter.prepare(&header);
+                filter
+            })
+            .collect();
+        // streams decide on the reads in input order, so the number of reads each has decided on tells which
+        // read a decision belongs to
+        let mut streams: Vec<(Box<dyn StreamingFilter>, u64)> = Vec::new();
+        let mut direct = Vec::new();
+        for filter in &filters {
+            match filter.to_streaming() {
+                Some(stream) => streams.push((stream, 0)),
+                None => direct.push(filter),
+            }
+        }
+        // the number of filters passed by the reads that some stream has not decided on yet, from read `first_pending`
+        let mut pending: VecDeque<usize> = VecDeque::new();
+        let mut first_pending = 0;
+        let mut decided = Vec::new();
+        let records = CheckedRecords {
+            reader,
+            record_number: 0,
+            max_records: args.max_records,
+            skip_errors: args.skip_errors,
+            error_report: None,
+            errors: 0,
+        };
+        utils::with_error_path(&input_file, || {
+            for record in records {
+                pending.push_back(direct.iter().filter(|filter| filter.apply_to(&record)).count());
+                for (stream, stream_decided) in streams.iter_mut() {
+                    stream.push(record.clone(), &mut decided);
+                    count_stream_passes(&mut decided, stream_decided, &mut pending, first_pending);
+                }
+                let all_decided = streams.iter().map(|(_, stream_decided)| *stream_decided).min();
+                while first_pending < all_decided.unwrap_or(first_pending + pending.len() as u64) {
+                    counts[pending.pop_front().unwrap()] += 1;
+                    first_pending += 1;
+                }
+            }
+        });
+        for (stream, stream_decided) in streams.iter_mut() {
+            stream.finish(&mut decided);
+            count_stream_passes(&mut decided, stream_decided, &mut pending, first_pending);
+        }
+        for passed in pending {
+            counts[passed] += 1;
+        }
+    }
+    counts
+}
+
+fn format_summary_line(entry: &ManifestEntry) -> String {
+    format!(
+        "file={} total={} kept={} removed={} filter={}",
+        entry.input.display(),
+        entry.total,
+        entry.kept,
+        entry.removed,
+        entry.filter
+    )
+}
+
+fn format_pass_counts(counts: &[u64]) -> String {
+    let total: u64 = counts.iter().sum();
+    let mut lines = vec![format!("Counted the filters passed by {} records", total)];
+    for (passed, count) in counts.iter().enumerate() {
+        let percent = if total > 0 { 100.0 * *count as f64 / total as f64 } else { 0.0 };
+        lines.push(format!("{}/{} filters: {} ({:.1}%)", passed, counts.len() - 1, count, percent));
+    }
+    lines.join("\n")
+}
+
+fn format_check(sampled: usize, passed: &[(String, usize)]) -> String {
+    let mut lines = vec![format!("Checked {} filters on {} records", passed.len(), sampled)];
+    for (name, count) in passed {
+        let flag = if sampled > 0 && *count == 0 {
+            " (passes none of the sample)"
+        } else if sampled > 0 && *count == sampled {
+            " (passes all of the sample)"
+        } else {
+            ""
+        };
+        lines.push(format!("{}: {}/{}{}", name, count, sampled, flag));
+    }
+    lines.join("\n")
+}
+
+// Problems with the filters of a config, as '<filter name>: <problem>' lines.
+// The filters that combined filters are built from are checked as well, since they may no longer be in the config
+fn verify_config(config: &filters::Config) -> Vec<String> {
+    fn check(filter: &dyn Filtering, problems: &mut Vec<String>) {
+        for problem in filter.problems() {
+            let line = format!("{}: {}", filter.name(), problem);
+            if !problems.contains(&line) {
+                problems.push(line);
+            }
+        }
+        for input in filter.inputs() {
+            check(input, problems);
+        }
+    }
+
+    let mut problems = Vec::new();
+    let mut normalized_names: IndexMap<String, Vec<&str>> = IndexMap::new();
+    for (name, filter) in config.iter() {
+        if filter.name() != name {
+            problems.push(format!("{}: stored under a different name than its own ({})", name, filter.name()));
+        }
+        normalized_names.entry(name.trim().to_lowercase()).or_default().push(name);
+        check(filter.as_ref(), &mut problems);
+    }
+    for names in normalized_names.values().filter(|names| names.len() > 1) {
+        problems.push(format!("{}: names only differ in case or surrounding spaces", names.join(", ")));
+    }
+    problems
+}
+
+fn format_view_summary(config: &filters::Config) -> String {
+    let by_type: Vec<String> = config
+        .count_by_type()
+        .iter()
+        .map(|(filter_type, count)| format!("{} {}", count, filter_type))
+        .collect();
+    if by_type.is_empty() {
+        return "0 filters".to_string();
+    }
+    format!("{} filters ({})", config.count(), by_type.join(", "))
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ConfigDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct FilterType {
+    name: String,
+    description: String,
+    parameters: Vec<FilterParameter>,
+}
+
+// Positional parameters are named by their id, options by their long flag (e.g. '--numeric')
+#[derive(Serialize, Debug)]
+struct FilterParameter {
+    name: String,
+    required: bool,
+    description: String,
+}
+
+// The filter types are read from the 'create' subcommands, so the list always matches the CLI
+fn filter_types() -> Vec<FilterType> {
+    // only the first sentence of a doc comment. Abbreviations like 'e.g.' don't end it
+    fn first_sentence(text: Option<&clap::builder::StyledStr>) -> String {
+        let text = text.map(|text| text.to_string()).unwrap_or_default();
+        let end = text
+            .match_indices(". ")
+            .map(|(index, _)| index)
+            .find(|&index| !text[..index].ends_with("e.g") && !text[..index].ends_with("i.e"));
+        text[..end.unwrap_or(text.len())].to_string()
+    }
+
+    let create = CreateCommands::augment_subcommands(clap::Command::new("create"));
+    create
+        .get_subcommands()
+        .map(|cmd| FilterType {
+            name: cmd.get_name().to_string(),
+            description: first_sentence(cmd.get_about()),
+            parameters: cmd
+                .get_arguments()
+                .map(|arg| FilterParameter {
+                    name: match arg.get_long() {
+                        Some(long) => format!("--{}", long),
+                        None => arg.get_id().to_string(),
+                    },
+                    required: arg.is_required_set(),
+                    description: first_sentence(arg.get_help()),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn format_filter_types(types: &[FilterType]) -> String {
+    let mut lines = Vec::new();
+    for filter_type in types {
+        let usage: Vec<String> = filter_type
+            .parameters
+            .iter()
+            .map(|parameter| match parameter.required {
+                true => format!("<{}>", parameter.name),
+                false => format!("[{}]", parameter.name),
+            })
+            .collect();
+        lines.push(format!("{} {}", filter_type.name, usage.join(" ")).trim_end().to_string());
+        lines.push(format!("    {}", filter_type.description));
+        for parameter in &filter_type.parameters {
+            match parameter.description.is_empty() {
+                true => lines.push(format!("    {}", parameter.name)),
+                false => lines.push(format!("    {}: {}", parameter.name, parameter.description)),
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+fn diff_configs(a_path: &Path, b_path: &Path) -> ConfigDiff {
+    let a = load_config(a_path);
+    let b = load_config(b_path);
+    let mut diff = ConfigDiff {
+        added: Vec::new(),
+        removed: Vec::new(),
+        modified: Vec::new(),
+    };
+    for (name, a_filter) in a.iter() {
+        match b.get(name) {
+            Some(b_filter) => {
+                if serde_json::to_value(a_filter).unwrap() != serde_json::to_value(&b_filter).unwrap() {
+                    diff.modified.push(name.clone());
+                }
+            }
+            None => diff.removed.push(name.clone()),
+        }
+    }
+    for (name, _) in b.iter() {
+        if a.get(name).is_none() {
+            diff.added.push(name.clone());
+        }
+    }
+    diff.added.sort();
+    diff.removed.sort();
+    diff.modified.sort();
+    diff
+}
+
+fn format_config_diff(diff: &ConfigDiff) -> String {
+    let mut lines = Vec::new();
+    for name in &diff.added {
+        lines.push(format!("+ {}", name));
+    }
+    for name in &diff.removed {
+        lines.push(format!("- {}", name));
+    }
+    for name in &diff.modified {
+        lines.push(format!("~ {}", name));
+    }
+    lines.push(format!(
+        "{} added, {} removed, {} modified",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len()
+    ));
+    lines.join("\n")
+}
+
+fn main() {
+    let args = Args::parse();
+    let config_path = match args.bametric_path {
+        Some(s) => s,
+        None => PathBuf::from_str("bametric.json").unwrap(),
+    };
+    // errors raised with `utils::fail` are reported once here, also when raised by the parallel filtering workers.
+    // Any other panic is unexpected (e.g. a bug), and is still printed as usual, with where it was raised
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if !info.payload().is::<utils::CommandError>() {
+            default_hook(info);
+        }
+    }));
+    if let Err(payload) = std::panic::catch_unwind(|| run_command(args.cmd, &config_path)) {
+        let expected = payload.is::<utils::CommandError>();
+        let error = command_error(payload);
+        if args.json_errors {
+            eprintln!("{}", json_error(&error));
+        } else if expected {
+            eprintln!("Error: {}", error.message);
+        }
+        std::process::exit(101);
+    }
+}
+
+// The error that a command stopped with. Panics not raised with `utils::fail` have no kind or file of their own
+fn command_error(payload: Box<dyn std::any::Any + Send>) -> utils::CommandError {
+    match payload.downcast::<utils::CommandError>() {
+        Ok(error) => *error,
+        Err(payload) => utils::CommandError {
+            kind: ErrorKind::Error,
+            message: panic_message(payload.as_ref()),
+            path: None,
+        },
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown error".to_string()
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    error_kind: ErrorKind,
+    message: &'a str,
+    path: Option<String>,
+}
+
+fn json_error(error: &utils::CommandError) -> String {
+    let error = JsonError {
+        error_kind: error.kind,
+        message: &error.message,
+        path: error.path.as_ref().map(|path| path.display().to_string()),
+    };
+    serde_json::to_string(&error).unwrap()
+}
+
+fn run_command(cmd: Commands, config_path: &Path) {
+    match cmd {
+        Commands::Init {} => init(config_path),
+        Commands::Create {
+            name,
+            opposite,
+            cmd,
+        } => create_filter(name, opposite, cmd, config_path),
+        Commands::Combine {
+            first,
+            second,
+            filter2,
+            name,
+            names_file,
+            force,
+        } => {
+            let (filter1, operator) = combine_operands(first, second);
+            let more_filters = with_names_file(Vec::new(), names_file.as_deref());
+            combine_filters(name, &filter1, operator, &filter2, &more_filters, force, config_path)
+        }
+        Commands::Apply {
+            filter_name,
+            names_file,
+            all: _,
+            any,
+            default,
+            filter_file,
+            filter_file_name,
+            mut args,
+        } => {
+            let filters = match filter_file {
+                Some(filter_file) => {
+                    // the first input file was parsed as the filter name
+                    if let Some(input) = filter_name {
+                        args.input.insert(0, PathBuf::from(input));
+                    }
+                    vec![load_filter_file(&filter_file, filter_file_name.as_deref())]
+                }
+                None => {
+                    let filter_names = with_names_file(filter_name.into_iter().collect(), names_file.as_deref());
+                    get_filters(filter_names.iter().map(String::as_str).collect(), config_path)
+                }
+            };
+            if args.count_passes {
+                println!("{}", format_pass_counts(&count_passes(&filters, &args)));
+                return;
+            }
+            let operator = if any { BoolOperator::OR } else { BoolOperator::AND };
+            let filter = chain_applied_filters(filters, &operator);
+            // keeping every read except the matching ones is the same as writing the reads that don't match
+            if default == utils::DefaultAction::Keep {
+                args.invert_output = !args.invert_output;
+            }
+            apply_filter_to_files(filter.as_ref(), &args);
+        }
+        Commands::Rename { name, new_name } => rename_filter(&name, &new_name, config_path),
+        Commands::Delete { names, names_file } => {
+            delete_filters(&with_names_file(names, names_file.as_deref()), config_path)
+        }
+        Commands::Run {
+            args,
+            opposite,
+            cmd,
+        } => run_inline_filter(opposite, cmd, &args, config_path),
+        Commands::Pipeline { filters, input, output, no_clobber, force } => {
+            let stages = run_pipeline(&filters, &input, &output, force, no_clobber, config_path);
+            println!("{}", format_pipeline(&stages))
+        }
+        Commands::Import { import_path } => import_filters(&import_path, config_path),
+        Commands::Export {
+            export_path,
+            as_script,
+            append,
+            pretty,
+        } => {
+            let out = export_filters(export_path.as_deref(), as_script, append, pretty, config_path);
+            if let Some(s) = out {
+                println!("{}", s);
+            }
+        }
+        Commands::View { count_only, format } => view_filters(config_path, count_only, format),
+        Commands::Describe { name, header } => println!("{}", describe_filter(&name, header.as_deref(), config_path)),
+        Commands::Check { input, sample_size } => {
+            let (sampled, passed) = check_filters(&input, sample_size, config_path);
+            println!("{}", format_check(sampled, &passed));
+        }
+        Commands::Verify { path } => {
+            let path = path.as_deref().unwrap_or(config_path);
+            let problems = verify_config(&load_config(path));
+            if problems.is_empty() {
+                println!("No problems found in {}", path.display());
+            } else {
+                for problem in &problems {
+                    println!("{}", problem);
+                }
+                eprintln!("Found {} problems in {}", problems.len(), path.display());
+                std::process::exit(1);
+            }
+        }
+        Commands::Types { json } => {
+            let types = filter_types();
+            if json {
+                println!("{}", serde_json::to_string(&types).unwrap());
+            } else {
+                println!("{}", format_filter_types(&types));
+            }
+        }
+        Commands::Diff { a, b, json } => {
+            let diff = diff_configs(&a, &b);
+            if json {
+                println!("{}", serde_json::to_string(&diff).unwrap());
+            } else {
+                println!("{}", format_config_diff(&diff));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::*;
+
+    #[fixture]
+    fn bam_record() {}
+
+    fn test_header() -> bam::Header {
+        let mut header = bam::Header::new();
+        header
+            .push_entry(bam::header::HeaderEntry::ref_sequence("chr1".to_string(), 1000))
+            .unwrap();
+        header
+            .push_entry(bam::header::HeaderEntry::ref_sequence("chr2".to_string(), 1000))
+            .unwrap();
+        header
+    }
+
+    fn test_record(name: &str, seq: &str) -> Record {
+        let mut record = Record::new();
+        record.set_name(name.bytes());
+        record.set_seq_qual(seq.bytes(), std::iter::repeat_n(30, seq.len())).unwrap();
+        record.set_ref_id(0);
+        record.set_start(100);
+        record.set_cigar(format!("{}M", seq.len()).bytes()).unwrap();
+        record.set_mapq(30);
+        record
+    }
+
+    fn write_sam(path: &Path, records: &[Record]) {
+        let mut writer = bam::SamWriter::from_path(path, test_header()).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn write_bam(path: &Path, records: &[Record]) {
+        let mut writer = bam::BamWriter::from_path(path, test_header()).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn read_records(path: &Path) -> Vec<Record> {
+        let reader: Box<dyn RecordReader<Item=std::io::Result<Record>>> = if path.extension().unwrap() == "bam" {
+            Box::new(bam::BamReader::from_path(path, 0).unwrap())
+        } else {
+            Box::new(bam::SamReader::from_path(path).unwrap())
+        };
+        reader.map(|record| record.unwrap()).collect()
+    }
+
+    fn read_names(path: &Path) -> Vec<String> {
+        read_records(path)
+            .iter()
+            .map(|record| String::from_utf8(record.name().to_vec()).unwrap())
+            .collect()
+    }
+
+    // Known records for end-to-end tests: reads of 4, 12 and 20 bases with mapping qualities 0, 30 and 60,
+    // on both references, and an unmapped read
+    fn fixture_records() -> Vec<Record> {
+        let read = |name: &str, seq: &str, ref_id: i32, mapq: u8| {
+            let mut record = test_record(name, seq);
+            record.set_ref_id(ref_id);
+            record.set_mapq(mapq);
+            record
+        };
+        let mut unmapped = test_record("unmapped", "ACGTACGTACGTACGTACGT");
+        unmapped.set_ref_id(-1);
+        unmapped.set_start(-1);
+        unmapped.set_cigar(std::iter::empty()).unwrap();
+        unmapped.set_mapq(0);
+        unmapped.flag_mut().set_mapped(false);
+        vec![
+            read("short low", "ACGT", 0, 0),
+            read("short high", "ACGT", 1, 60),
+            read("mid low", "ACGTACGTACGT", 0, 0),
+            read("mid high", "ACGTACGTACGT", 0, 30),
+            read("long low", "ACGTACGTACGTACGTACGT", 1, 0),
+            read("long high", "ACGTACGTACGTACGTACGT", 1, 60),
+            unmapped,
+        ]
+    }
+
+    // Write the fixture records to a BAM or SAM file, depending on its extension
+    fn write_fixture(path: &Path) {
+        if path.extension().unwrap() == "bam" {
+            write_bam(path, &fixture_records());
+        } else {
+            write_sam(path, &fixture_records());
+        }
+    }
+
+    // Run a command line as the binary would, with the given session config
+    fn run_cli(config_path: &Path, args: &[&str]) {
+        let mut argv = vec!["BAMetrics".to_string(), "-p".to_string(), config_path.display().to_string()];
+        argv.extend(args.iter().map(|arg| arg.to_string()));
+        run_command(Args::try_parse_from(argv).unwrap().cmd, config_path);
+    }
+
+    fn apply_args(input: Vec<PathBuf>, output: PathBuf) -> ApplyArgs {
+        ApplyArgs {
+            input,
+            output: Some(output),
+            threads: Some(1),
+            verbose: false,
+            parallel_filter: false,
+            keep_pairs: false,
+            pair_buffer_size: 100000,
+            invert_output: false,
+            rejected: None,
+            stats_by_ref: None,
+            stats_json: None,
+            summary_line: false,
+            skip_errors: false,
+            error_report: None,
+            region: None,
+            assert_sort: None,
+            max_records: None,
+            max_records_of: utils::RecordLimit::Output,
+            shuffle: false,
+            profile_filters: false,
+            no_clobber: false,
+            force: false,
+            seed: 0,
+            subsample: None,
+            name_template: None,
+            manifest: None,
+            resume: false,
+            split_by_rg: false,
+            unmapped_fastq: Vec::new(),
+            progress_json: None,
+            progress_interval: 10.0,
+            count_passes: false,
+            transform: false,
+        }
+    }
+
+    #[rstest]
+    fn test_parse_run_command() {
+        let args = Args::try_parse_from([
+            "bametrics", "run", "-o", "out.sam", "a.sam", "b.sam", "length", "18", "24",
+        ])
+        .unwrap();
+        match args.cmd {
+            Commands::Run { args, cmd, .. } => {
+                assert_eq!(args.input, vec![PathBuf::from("a.sam"), PathBuf::from("b.sam")]);
+                assert!(matches!(cmd, CreateCommands::Length { min_len: utils::Limit(Some(18)), max_len: utils::Limit(Some(24)) }));
+            }
+            _ => panic!("Expected the run command"),
+        }
+    }
+
+    #[rstest]
+    fn test_run_inline_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        init(&config_path);
+        create_filter(
+            Some("stored".to_string()),
+            false,
+            CreateCommands::Mapq { min_mapq: 0.into(), max_mapq: 10.into() },
+            &config_path,
+        );
+        let config_before = std::fs::read(&config_path).unwrap();
+
+        let input = dir.path().join("input.sam");
+        let output = dir.path().join("output.sam");
+        write_sam(
+            &input,
+            &[
+                test_record("short", "ACGT"),
+                test_record("long", "ACGTACGTACGTACGTACGT"),
+            ],
+        );
+        run_inline_filter(
+            false,
+            CreateCommands::Length { min_len: 18.into(), max_len: 24.into() },
+            &apply_args(vec![input], output.clone()),
+            &config_path,
+        );
+
+        assert_eq!(read_names(&output), vec!["long"]);
+        assert_eq!(std::fs::read(&config_path).unwrap(), config_before);
+    }
+
+    #[rstest]
+    fn test_parallel_filter_matches_serial() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        // span several batches so that batch boundaries are exercised
+        let records: Vec<Record> = (0..PARALLEL_BATCH_SIZE * 2 + 17)
+            .map(|i| {
+                let seq = "ACGTTGCA".repeat(1 + i % 4);
+                test_record(&format!("read{}", i), &seq)
+            })
+            .collect();
+        write_sam(&input, &records);
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 20, false);
+
+        let serial_output = dir.path().join("serial.bam");
+        apply_filter(&filter, &input, &apply_args(vec![input.clone()], serial_output.clone()));
+        let parallel_output = dir.path().join("parallel.bam");
+        let mut args = apply_args(vec![input.clone()], parallel_output.clone());
+        args.parallel_filter = true;
+        apply_filter(&filter, &input, &args);
+
+        let serial_bytes = std::fs::read(&serial_output).unwrap();
+        assert!(!serial_bytes.is_empty());
+        assert_eq!(serial_bytes, std::fs::read(&parallel_output).unwrap());
+    }
+
+    #[rstest]
+    fn test_diff_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        let a_path = dir.path().join("a.json");
+        let b_path = dir.path().join("b.json");
+        for path in [&a_path, &b_path] {
+            init(path);
+            create_filter(
+                Some("unchanged".to_string()),
+                false,
+                CreateCommands::Flag { remove_flags: 4 },
+                path,
+            );
+        }
+        create_filter(
+            Some("changed".to_string()),
+            false,
+            CreateCommands::Length { min_len: 18.into(), max_len: 24.into() },
+            &a_path,
+        );
+        create_filter(
+            Some("changed".to_string()),
+            false,
+            CreateCommands::Length { min_len: 18.into(), max_len: 30.into() },
+            &b_path,
+        );
+        create_filter(
+            Some("new".to_string()),
+            false,
+            CreateCommands::Mapq { min_mapq: 10.into(), max_mapq: 60.into() },
+            &b_path,
+        );
+
+        let diff = diff_configs(&a_path, &b_path);
+        assert_eq!(
+            diff,
+            ConfigDiff {
+                added: vec!["new".to_string()],
+                removed: vec![],
+                modified: vec!["changed".to_string()],
+            }
+        );
+        assert_eq!(
+            format_config_diff(&diff),
+            "+ new\n~ changed\n1 added, 0 removed, 1 modified"
+        );
+        assert_eq!(
+            serde_json::to_string(&diff).unwrap(),
+            r#"{"added":["new"],"removed":[],"modified":["changed"]}"#
+        );
+        assert_eq!(diff_configs(&b_path, &a_path).removed, vec!["new".to_string()]);
+    }
+
+    #[rstest]
+    fn test_apply_keep_pairs() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let mut records = vec![
+            test_record("pair", "ACGTACGTACGTACGTACGT"),
+            test_record("pair", "ACGT"),
+            test_record("other", "ACGT"),
+            test_record("other", "ACGT"),
+        ];
+        for (i, record) in records.iter_mut().enumerate() {
+            record.flag_mut().set_paired(true);
+            record.flag_mut().set_first_in_pair(i % 2 == 0);
+            record.flag_mut().set_last_in_pair(i % 2 == 1);
+        }
+        write_sam(&input, &records);
+        let filter = filters::LengthFilter::new("len".to_string(), 18, 24, false);
+
+        let output = dir.path().join("output.sam");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        apply_filter(&filter, &input, &args);
+        assert_eq!(read_names(&output), vec!["pair"]);
+
+        args.keep_pairs = true;
+        args.force = true;
+        apply_filter(&filter, &input, &args);
+        assert_eq!(read_names(&output), vec!["pair", "pair"]);
+    }
+
+    #[rstest]
+    fn test_view_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        init(&config_path);
+        assert_eq!(format_view_summary(&load_config(&config_path)), "0 filters");
+        for (name, min_len) in [("len1", 10), ("len2", 20), ("len3", 30)] {
+            create_filter(
+                Some(name.to_string()),
+                false,
+                CreateCommands::Length { min_len: min_len.into(), max_len: 100.into() },
+                &config_path,
+            );
+        }
+        create_filter(
+            Some("flag".to_string()),
+            false,
+            CreateCommands::Flag { remove_flags: 4 },
+            &config_path,
+        );
+        combine_filters(
+            Some("combined".to_string()),
+            "len1",
+            BoolOperator::AND,
+            "flag",
+            &[],
+            false,
+            &config_path,
+        );
+        assert_eq!(
+            format_view_summary(&load_config(&config_path)),
+            "5 filters (3 length, 1 combined, 1 flag)"
+        );
+    }
+
+    #[rstest]
+    fn test_names_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        init(&config_path);
+        create_filter(
+            Some("long".to_string()),
+            false,
+            CreateCommands::Length { min_len: 10.into(), max_len: 100.into() },
+            &config_path,
+        );
+        create_filter(
+            Some("starts with G".to_string()),
+            false,
+            CreateCommands::Nucleotide { position: 1, nucleotide: 'G', n_is_wildcard: false },
+            &config_path,
+        );
+        create_filter(
+            Some("mapq".to_string()),
+            false,
+            CreateCommands::Mapq { min_mapq: 20.into(), max_mapq: 60.into() },
+            &config_path,
+        );
+        let names_file = dir.path().join("names.txt");
+        std::fs::write(&names_file, "# extra filters\nstarts with G\n\nmapq\n").unwrap();
+
+        let filter_names = with_names_file(vec!["long".to_string()], Some(&names_file));
+        assert_eq!(filter_names, vec!["long", "starts with G", "mapq"]);
+        let input = dir.path().join("input.sam");
+        let output = dir.path().join("output.sam");
+        write_sam(
+            &input,
+            &[
+                test_record("short G", "GACT"),
+                test_record("long G", "GACTGACTGACT"),
+                test_record("long A", "AACTGACTGACT"),
+            ],
+        );
+        let filters = get_filters(filter_names.iter().map(String::as_str).collect(), &config_path);
+        let filter = chain_applied_filters(filters, &BoolOperator::AND);
+        apply_filter(filter.as_ref(), &input, &apply_args(vec![input.clone()], output.clone()));
+        assert_eq!(read_names(&output), vec!["long G"]);
+
+        delete_filters(&with_names_file(Vec::new(), Some(&names_file)), &config_path);
+        let config = load_config(&config_path);
+        assert_eq!(config.count(), 1);
+        assert!(config.get("long").is_some());
+    }
+
+    fn config_with_two_filters(dir: &Path) -> PathBuf {
+        let config_path = dir.join("bametric.json");
+        init(&config_path);
+        create_filter(
+            Some("f1".to_string()),
+            false,
+            CreateCommands::Length { min_len: 10.into(), max_len: 100.into() },
+            &config_path,
+        );
+        create_filter(
+            Some("f2".to_string()),
+            false,
+            CreateCommands::Mapq { min_mapq: 20.into(), max_mapq: 60.into() },
+            &config_path,
+        );
+        config_path
+    }
+
+    #[rstest]
+    #[case("f1")]
+    #[case("f2")]
+    #[should_panic(expected = "would replace its input filter")]
+    fn test_combine_name_collision(#[case] name: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        combine_filters(Some(name.to_string()), "f1", BoolOperator::AND, "f2", &[], false, &config_path);
+    }
+
+    #[rstest]
+    fn test_combine_name_collision_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        combine_filters(Some("f1".to_string()), "f1", BoolOperator::AND, "f2", &[], true, &config_path);
+        let config = load_config(&config_path);
+        assert_eq!(config.count(), 2);
+        assert!(config.get("f1").unwrap().repr().starts_with("CombinedFilter(name=f1,"));
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_apply_invert_output(#[case] parallel_filter: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let records: Vec<Record> = (0..20)
+            .map(|i| test_record(&format!("read{}", i), &"ACGTT".repeat(1 + i % 5)))
+            .collect();
+        write_sam(&input, &records);
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 15, false);
+
+        let kept = dir.path().join("kept.sam");
+        let mut args = apply_args(vec![input.clone()], kept.clone());
+        args.parallel_filter = parallel_filter;
+        apply_filter(&filter, &input, &args);
+        let rejected = dir.path().join("rejected.sam");
+        args.output = Some(rejected.clone());
+        args.invert_output = true;
+        apply_filter(&filter, &input, &args);
+
+        let kept = read_names(&kept);
+        let rejected = read_names(&rejected);
+        assert_eq!(kept.len(), 8);
+        assert_eq!(kept.len() + rejected.len(), records.len());
+        assert!(kept.iter().all(|name| !rejected.contains(name)));
+    }
+
+    #[rstest]
+    fn test_apply_rejected_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let records: Vec<Record> = (0..20)
+            .map(|i| test_record(&format!("read{}", i), &"ACGTT".repeat(1 + i % 5)))
+            .collect();
+        write_sam(&input, &records);
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 15, false);
+
+        let kept = dir.path().join("kept.sam");
+        let rejected = dir.path().join("rejected.sam");
+        let mut args = apply_args(vec![input.clone()], kept.clone());
+        args.rejected = Some(rejected.clone());
+        apply_filter(&filter, &input, &args);
+
+        let mut kept = read_names(&kept);
+        let rejected = read_names(&rejected);
+        assert_eq!(kept.len(), 8);
+        assert!(kept.iter().all(|name| !rejected.contains(name)));
+        kept.extend(rejected);
+        kept.sort();
+        let mut all_names = read_names(&input);
+        all_names.sort();
+        assert_eq!(kept, all_names);
+    }
+
+    #[rstest]
+    fn test_apply_stats_by_ref() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let mut records = Vec::new();
+        for i in 0..6 {
+            let mut record = test_record(&format!("chr1_read{}", i), &"ACGTT".repeat(1 + i % 3));
+            record.set_ref_id(0);
+            records.push(record);
+        }
+        for i in 0..4 {
+            let mut record = test_record(&format!("chr2_read{}", i), &"ACGTT".repeat(2 + i % 2));
+            record.set_ref_id(1);
+            records.push(record);
+        }
+        let mut unmapped = test_record("unmapped", "ACGTT");
+        unmapped.set_ref_id(-1);
+        unmapped.set_start(-1);
+        unmapped.set_cigar(std::iter::empty()).unwrap();
+        unmapped.flag_mut().set_mapped(false);
+        records.push(unmapped);
+        write_sam(&input, &records);
+
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 15, false);
+        let stats = dir.path().join("stats.tsv");
+        let mut args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        args.stats_by_ref = Some(stats.clone());
+        apply_filter(&filter, &input, &args);
+
+        assert_eq!(
+            std::fs::read_to_string(&stats).unwrap(),
+            "reference\tkept\tremoved\nchr1\t4\t2\nchr2\t4\t0\n*\t0\t1\n"
+        );
+    }
+
+    fn write_sam_with_bad_lines(path: &Path) {
+        write_sam(
+            path,
+            &[test_record("read1", "ACGTACGTAC"), test_record("read2", "ACGTACGTAC"), test_record("read3", "ACGTACGTAC")],
+        );
+        let mut lines: Vec<String> = std::fs::read_to_string(path).unwrap().lines().map(str::to_string).collect();
+        let first_record = lines.iter().position(|line| !line.starts_with('@')).unwrap();
+        lines.insert(first_record + 1, "bad1\t0\tchr1\tnot_a_position\t30\t4M\t*\t0\t0\tACGT\t????".to_string());
+        lines.insert(first_record + 3, "bad2\t0\tchr9\t101\t30\t4M\t*\t0\t0\tACGT\t????".to_string());
+        std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[rstest]
+    fn test_apply_skip_errors_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam_with_bad_lines(&input);
+        let output = dir.path().join("output.sam");
+        let report = dir.path().join("errors.tsv");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.skip_errors = true;
+        args.error_report = Some(report.clone());
+        let filter = filters::LengthFilter::new("len".to_string(), 0, 100, false);
+        apply_filter(&filter, &input, &args);
+
+        assert_eq!(read_names(&output), vec!["read1", "read2", "read3"]);
+        let report = std::fs::read_to_string(&report).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "record\treason");
+        assert!(lines[1].starts_with("2\t"));
+        assert!(lines[2].starts_with("4\t"));
+    }
+
+    #[rstest]
+    fn test_apply_stops_on_error_without_skip() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam_with_bad_lines(&input);
+        let args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        let filter = filters::LengthFilter::new("len".to_string(), 0, 100, false);
+        let error = error_of(|| {
+            apply_filter(&filter, &input, &args);
+        });
+        assert_eq!(error.kind, ErrorKind::BadInput);
+        assert!(error.message.starts_with("Could not read record 2"), "{}", error.message);
+    }
+
+    #[rstest]
+    fn test_apply_failure_leaves_no_partial_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam_with_bad_lines(&input);
+        let output = dir.path().join("output.sam");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.rejected = Some(dir.path().join("rejected.sam"));
+        let filter = filters::LengthFilter::new("len".to_string(), 0, 100, false);
+        let result = std::panic::catch_unwind(|| apply_filter(&filter, &input, &args));
+
+        assert!(result.is_err());
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, vec!["input.sam"]);
+    }
+
+    #[rstest]
+    fn test_apply_failure_keeps_previous_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam_with_bad_lines(&input);
+        let output = dir.path().join("output.sam");
+        std::fs::write(&output, "previous output").unwrap();
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.force = true;
+        let filter = filters::LengthFilter::new("len".to_string(), 0, 100, false);
+        let result = std::panic::catch_unwind(|| apply_filter(&filter, &input, &args));
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "previous output");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[rstest]
+    fn test_config_preserves_creation_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        init(&config_path);
+        let names = ["zeta", "alpha", "mid", "beta", "omega"];
+        for name in names {
+            create_filter(
+                Some(name.to_string()),
+                false,
+                CreateCommands::Mapq { min_mapq: 10.into(), max_mapq: 60.into() },
+                &config_path,
+            );
+        }
+        delete_filters(&["mid".to_string()], &config_path);
+        let config = load_config(&config_path);
+        let order: Vec<&str> = config.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(order, vec!["zeta", "alpha", "beta", "omega"]);
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        let positions: Vec<usize> = order.iter().map(|name| saved.find(&format!("\"{}\"", name)).unwrap()).collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    // Write a coordinate-sorted BAM file with its BAI index. Each record gets its own BGZF block,
+    // so that the virtual offsets needed by the index are simply the block offsets.
+    fn write_indexed_bam(path: &Path, records: &[Record]) {
+        use bam::bgzip::Block;
+
+        let header = test_header();
+        let mut file = std::fs::File::create(path).unwrap();
+        let mut offset = 0_u64;
+        let mut write_block = |contents: &[u8]| {
+            let mut block = Block::new();
+            block.extend_contents(contents);
+            block.compress(flate2::Compression::default()).unwrap();
+            block.dump(&mut file).unwrap();
+            let start = offset;
+            offset += block.block_size().unwrap() as u64;
+            (start << 16, offset << 16)
+        };
+        let mut contents = Vec::new();
+        header.write_bam(&mut contents).unwrap();
+        write_block(&contents);
+        // for each reference, the chunks of each bin and the offset of its first record
+        let mut bins = vec![std::collections::BTreeMap::<u16, Vec<(u64, u64)>>::new(); header.n_references()];
+        let mut first_offsets = vec![None; header.n_references()];
+        let mut n_unplaced = 0_u64;
+        for record in records {
+            let mut contents = Vec::new();
+            record.write_bam(&mut contents).unwrap();
+            let (start, end) = write_block(&contents);
+            // unplaced unmapped reads at the end of the file are only counted
+            let Ok(ref_id) = usize::try_from(record.ref_id()) else {
+                n_unplaced += 1;
+                continue;
+            };
+            first_offsets[ref_id].get_or_insert(start);
+            let chunks = bins[ref_id].entry(record.calculate_bin()).or_default();
+            match chunks.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => chunks.push((start, end)),
+            }
+        }
+        write_block(&[]);
+
+        let mut index = b"BAI\x01".to_vec();
+        index.extend((bins.len() as i32).to_le_bytes());
+        for (bins, first_offset) in bins.iter().zip(&first_offsets) {
+            index.extend((bins.len() as i32).to_le_bytes());
+            for (bin_id, chunks) in bins {
+                index.extend((*bin_id as u32).to_le_bytes());
+                index.extend((chunks.len() as i32).to_le_bytes());
+                for (start, end) in chunks {
+                    index.extend(start.to_le_bytes());
+                    index.extend(end.to_le_bytes());
+                }
+            }
+            // a single linear index window pointing at the first record of the reference
+            match first_offset {
+                Some(first_offset) => {
+                    index.extend(1_i32.to_le_bytes());
+                    index.extend(first_offset.to_le_bytes());
+                }
+                None => index.extend(0_i32.to_le_bytes()),
+            }
+        }
+        index.extend(n_unplaced.to_le_bytes());
+        std::fs::write(format!("{}.bai", path.display()), index).unwrap();
+    }
+
+    fn positioned_record(name: &str, ref_id: i32, start: i32) -> Record {
+        let mut record = test_record(name, "ACGTACGTACGTACGTACGT");
+        record.set_ref_id(ref_id);
+        record.set_start(start);
+        record
+    }
+
+    #[rstest]
+    fn test_apply_region_matches_full_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let mut records = Vec::new();
+        for ref_id in 0..2 {
+            for start in (0..950).step_by(25) {
+                records.push(positioned_record(&format!("read_{}_{}", ref_id, start), ref_id, start));
+            }
+        }
+        write_indexed_bam(&input, &records);
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 100, false);
+
+        let region_output = dir.path().join("region.sam");
+        let mut args = apply_args(vec![input.clone()], region_output.clone());
+        args.region = Some("chr2:301-500".to_string());
+        apply_filter(&filter, &input, &args);
+
+        // a full scan, keeping the reads that overlap positions 300-499 (0-based) of chr2
+        let full_output = dir.path().join("full.sam");
+        let full_args = apply_args(vec![input.clone()], full_output.clone());
+        let region_filter = filters::ScriptFilter::new("region".to_string(), "ref_id == 1 && pos > 280 && pos < 500", false);
+        apply_filter(&region_filter, &input, &full_args);
+
+        let region_names = read_names(&region_output);
+        assert_eq!(region_names.len(), 8);
+        assert_eq!(region_names, read_names(&full_output));
+    }
+
+    #[rstest]
+    #[should_panic(expected = "requires a BAM index")]
+    fn test_apply_region_requires_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_indexed_bam(&input, &[positioned_record("read", 0, 10)]);
+        std::fs::remove_file(dir.path().join("input.bam.bai")).unwrap();
+        let mut args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        args.region = Some("chr1:1-100".to_string());
+        apply_filter(&filters::LengthFilter::new("len".to_string(), 10, 100, false), &input, &args);
+    }
+
+    #[rstest]
+    fn test_filter_ids_survive_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        let f1_id = load_config(&config_path).id("f1").unwrap();
+        let f2_id = load_config(&config_path).id("f2").unwrap();
+        assert_ne!(f1_id, f2_id);
+
+        rename_filter(&format!("@{}", f1_id), "length", &config_path);
+        let config = load_config(&config_path);
+        assert!(config.get("f1").is_none());
+        assert_eq!(config.id("length"), Some(f1_id));
+        assert_eq!(config.resolve(&format!("@{}", f1_id)), Some("length".to_string()));
+        assert_eq!(config.get(&format!("@{}", f1_id)).unwrap().name(), "length");
+        let order: Vec<&str> = config.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(order, vec!["length", "f2"]);
+
+        combine_filters(
+            Some("both".to_string()),
+            &format!("@{}", f1_id),
+            BoolOperator::AND,
+            &format!("@{}", f2_id),
+            &[],
+            false,
+            &config_path,
+        );
+        let f1_ref = format!("@{}", f1_id);
+        let apply_filter = chain_applied_filters(get_filters(vec![&f1_ref, "both"], &config_path), &BoolOperator::AND);
+        assert_eq!(apply_filter.name(), "length AND both");
+
+        delete_filters(&[format!("@{}", f1_id)], &config_path);
+        let config = load_config(&config_path);
+        assert!(config.get("length").is_none());
+        assert!(config.resolve(&format!("@{}", f1_id)).is_none());
+        // ids are never reused
+        create_filter(Some("new".to_string()), false, CreateCommands::Flag { remove_flags: 4 }, &config_path);
+        assert!(load_config(&config_path).id("new").unwrap() > load_config(&config_path).id("both").unwrap());
+    }
+
+    #[rstest]
+    fn test_stats_json_parallel_matches_serial() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let n_records = PARALLEL_BATCH_SIZE * 12 + 321;
+        let records: Vec<Record> = (0..n_records)
+            .map(|i| test_record(&format!("read{}", i), &"ACGTTGCA".repeat(1 + (i * 7) % 5)))
+            .collect();
+        write_sam(&input, &records);
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 30, false);
+
+        let mut all_stats = Vec::new();
+        for parallel in [false, true] {
+            let stats_file = dir.path().join(format!("stats_{}.json", parallel));
+            let mut args = apply_args(vec![input.clone()], dir.path().join("output.bam"));
+            args.parallel_filter = parallel;
+            args.stats_json = Some(stats_file.clone());
+            args.force = true;
+            apply_filter(&filter, &input, &args);
+            let stats: ApplyStats = serde_json::from_str(&std::fs::read_to_string(&stats_file).unwrap()).unwrap();
+            all_stats.push(stats);
+        }
+        assert_eq!(all_stats[0].records, n_records as u64);
+        assert_eq!(all_stats[0].kept + all_stats[0].removed, n_records as u64);
+        assert!(all_stats[0].kept > 0 && all_stats[0].removed > 0);
+        assert_eq!(all_stats[0], all_stats[1]);
+    }
+
+    // Split a command quoted by utils::shell_quote into its arguments
+    fn shell_split(command: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut current = String::new();
+        let mut in_word = false;
+        let mut in_quotes = false;
+        let mut chars = command.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if !in_quotes => {
+                    current.extend(chars.next());
+                    in_word = true;
+                }
+                '\'' => {
+                    in_quotes = !in_quotes;
+                    in_word = true;
+                }
+                ' ' if !in_quotes => {
+                    if in_word {
+                        args.push(std::mem::take(&mut current));
+                        in_word = false;
+                    }
+                }
+                _ => {
+                    current.push(c);
+                    in_word = true;
+                }
+            }
+        }
+        if in_word {
+            args.push(current);
+        }
+        args
+    }
+
+    fn run_script(script: &str, config_path: &Path) {
+        for line in script.lines() {
+            if let Some(command) = line.strip_prefix("\"$BAMETRICS\" ") {
+                let args = shell_split(command);
+                run_cli(config_path, &args.iter().map(String::as_str).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[rstest]
+    fn test_export_as_script_recreates_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        let creates = [
+            ("it's a tag", true, CreateCommands::Tag { tag_name: "NM".to_string(), tag_type: utils::CliTagType::Int, tag_value: "-2".to_string(), numeric: true }),
+            ("last base", false, CreateCommands::Nucleotide { position: -1, nucleotide: 'G', n_is_wildcard: true }),
+            ("purines", false, CreateCommands::Composition { bases: "AG".to_string(), min_fraction: 0.1, max_fraction: 0.35 }),
+            ("gc", false, CreateCommands::AlignedGc { min_fraction: 0.3, max_fraction: 0.65 }),
+            ("adapter", true, CreateCommands::ClipContent { motif: "AGATCGG".to_string(), side: utils::ClipSide::Trailing, max_mismatch: 1, both_strands: true }),
+            ("expr", false, CreateCommands::Script { expression: "!(tag(\"NM\") > 3) && length >= 18".to_string() }),
+            ("mask", false, CreateCommands::FlagMask { require_mask: 1, exclude_mask: 1024 }),
+            ("unmapped", false, CreateCommands::RefName { ref_id: -1 }),
+            ("minus", true, CreateCommands::Strand { strand: utils::Strand::Forward }),
+            ("tiles", false, CreateCommands::NameField { field: utils::NameField::Tile, pattern: "1101-*".to_string() }),
+            ("low quality", false, CreateCommands::LowQualFraction { quality_threshold: 15, min_fraction: 0.0, max_fraction: 0.25 }),
+            ("ends", false, CreateCommands::ContigMargin { margin: 500 }),
+            ("corrupt", true, CreateCommands::InternalClip {}),
+            ("scored", false, CreateCommands::AsPerBase { min_score: -0.5, max_score: 2.0 }),
+            ("clipped", false, CreateCommands::ClipRatio { min_ratio: 0.0, max_ratio: 0.25 }),
+            ("complex", false, CreateCommands::DistinctBases { min_distinct: 3, max_distinct: 4 }),
+            ("proper", false, CreateCommands::Concordant { min_insert: 100.into(), max_insert: 800.into() }),
+            ("window", false, CreateCommands::RecordIndex { first_index: 5.into(), last_index: utils::Limit(None) }),
+            ("first_segment", false, CreateCommands::LeftmostSplit {}),
+            ("clean_lanes", false, CreateCommands::RgQuality { max_rate: 0.02 }),
+            ("trimmed", false, CreateCommands::Trim { leading: 3, trailing: 0, quality_threshold: Some(20), min_len: 30 }),
+            ("no_cliff", false, CreateCommands::QualityCliff { quality_threshold: 25, window: 5, min_len: 50 }),
+            (
+                "panel_edges",
+                false,
+                CreateCommands::RegionMode { region: "chr1:1-500".to_string(), mode: utils::RegionMode::Spanning },
+            ),
+            ("sv", false, CreateCommands::Breakpoint { reference: "chr1".to_string(), position: 5000, tolerance: 5 }),
+            ("barcoded", true, CreateCommands::TaggedPrimary { tag_name: "CB".to_string() }),
+        ];
+        for (name, opposite, cmd) in creates {
+            create_filter(Some(name.to_string()), opposite, cmd, &config_path);
+        }
+        create_filter(
+            Some("pairs".to_string()),
+            false,
+            CreateCommands::PairConcordant { filter_name: "f2".to_string() },
+            &config_path,
+        );
+        combine_filters(
+            Some("chained".to_string()),
+            "f1",
+            BoolOperator::OR,
+            "it's a tag",
+            &["expr".to_string(), "mask".to_string()],
+            false,
+            &config_path,
+        );
+        combine_filters(Some("adapter".to_string()), "adapter", BoolOperator::NAND, "f2", &[], true, &config_path);
+
+        let script_path = dir.path().join("recreate.sh");
+        assert!(export_filters(Some(&script_path), true, false, false, &config_path).is_none());
+        let script = std::fs::read_to_string(&script_path).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("delete '(f1 OR it'\\''s a tag)'"));
+
+        let recreated_path = dir.path().join("recreated.json");
+        run_script(&script, &recreated_path);
+        let diff = diff_configs(&config_path, &recreated_path);
+        assert_eq!(diff, ConfigDiff { added: vec![], removed: vec![], modified: vec![] });
+        let original_order: Vec<String> = load_config(&config_path).iter().map(|(name, _)| name.clone()).collect();
+        let recreated_order: Vec<String> = load_config(&recreated_path).iter().map(|(name, _)| name.clone()).collect();
+        assert_eq!(original_order, recreated_order);
+    }
+
+    #[rstest]
+    #[case(utils::RecordLimit::Output, 7, 7, 14)]
+    #[case(utils::RecordLimit::Input, 7, 3, 7)]
+    #[case(utils::RecordLimit::Output, 1000, 50, 100)]
+    fn test_apply_max_records(#[case] limit: utils::RecordLimit, #[case] max_records: u64, #[case] expected: usize,
+                              #[case] expected_read: u64, #[values(false, true)] parallel: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        // every other read passes the filter
+        let records: Vec<Record> = (0..100)
+            .map(|i| test_record(&format!("read{}", i), &"ACGTT".repeat(1 + i % 2)))
+            .collect();
+        write_sam(&input, &records);
+        let output = dir.path().join("output.sam");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.max_records = Some(max_records);
+        args.max_records_of = limit;
+        args.parallel_filter = parallel;
+        let stats_file = dir.path().join("stats.json");
+        args.stats_json = Some(stats_file.clone());
+        let summary = apply_filter(&filters::LengthFilter::new("len".to_string(), 10, 10, false), &input, &args);
+
+        let names = read_names(&output);
+        assert_eq!(names.len(), expected);
+        assert_eq!(names[0], "read1");
+        // only the records read until the limit was reached are counted
+        assert_eq!((summary.total, summary.kept), (expected_read, expected as u64));
+        let stats: ApplyStats = serde_json::from_str(&std::fs::read_to_string(&stats_file).unwrap()).unwrap();
+        assert_eq!((stats.records, stats.kept), (expected_read, expected as u64));
+    }
+
+    #[rstest]
+    fn test_describe_ref_name_with_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        init(&config_path);
+        create_filter(Some("on chr2".to_string()), false, CreateCommands::RefName { ref_id: 1 }, &config_path);
+        create_filter(Some("len".to_string()), false, CreateCommands::Length { min_len: 10.into(), max_len: 100.into() }, &config_path);
+        combine_filters(Some("both".to_string()), "on chr2", BoolOperator::AND, "len", &[], false, &config_path);
+        let header_path = dir.path().join("header.sam");
+        write_sam(&header_path, &[]);
+
+        let described = describe_filter("both", Some(&header_path), &config_path);
+        let lines: Vec<&str> = described.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("CombinedFilter(name=both,"));
+        assert_eq!(lines[1], "  RefNameFilter(name=on chr2, ref_id=1 (chr2), opposite=false)");
+        assert!(lines[2].starts_with("  LengthFilter(name=len,"));
+
+        let without_header = describe_filter("on chr2", None, &config_path);
+        assert_eq!(without_header, "RefNameFilter(name=on chr2, ref_id=1, opposite=false)");
+    }
+
+    fn write_sam_sorted_by(path: &Path, sort_order: &str) {
+        let mut header = test_header();
+        header.push_line(&format!("@HD\tVN:1.6\tSO:{}", sort_order)).unwrap();
+        let mut writer = bam::SamWriter::from_path(path, header).unwrap();
+        writer.write(&test_record("read1", "ACGTACGTAC")).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[rstest]
+    fn test_apply_assert_sort() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam_sorted_by(&input, "coordinate");
+        let output = dir.path().join("output.sam");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.assert_sort = Some(utils::SortOrder::Coordinate);
+        apply_filter(&filters::LengthFilter::new("len".to_string(), 10, 100, false), &input, &args);
+        assert_eq!(read_names(&output), vec!["read1"]);
+    }
+
+    #[rstest]
+    #[case(Some("queryname"))]
+    #[case(None)]
+    fn test_apply_assert_sort_mismatch(#[case] sort_order: Option<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        match sort_order {
+            Some(sort_order) => write_sam_sorted_by(&input, sort_order),
+            None => write_sam(&input, &[test_record("read1", "ACGTACGTAC")]),
+        }
+        let output = dir.path().join("output.sam");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.assert_sort = Some(utils::SortOrder::Coordinate);
+        let error = error_of(|| {
+            apply_filter(&filters::LengthFilter::new("len".to_string(), 10, 100, false), &input, &args);
+        });
+        assert_eq!(error.kind, ErrorKind::BadInput);
+        assert!(error.message.contains("not 'coordinate'"), "{}", error.message);
+    }
+
+    // The error that `f` stops with, as reported by main
+    pub(crate) fn error_of(f: impl FnOnce()) -> utils::CommandError {
+        command_error(std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err())
+    }
+
+    fn json_error_of(f: impl FnOnce()) -> serde_json::Value {
+        serde_json::from_str(&json_error(&error_of(f))).unwrap()
+    }
+
+    #[rstest]
+    fn test_json_errors_missing_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        let args = Args::try_parse_from(["BAMetrics", "--json-errors", "delete", "nonexistent"]).unwrap();
+        assert!(args.json_errors);
+        let error = json_error_of(|| run_command(args.cmd, &config_path));
+        assert_eq!(error["error_kind"], "missing_filter");
+        assert_eq!(error["message"], "Filter nonexistent does not exist!");
+        assert_eq!(error["path"], config_path.display().to_string());
+    }
+
+    #[rstest]
+    fn test_json_errors_bad_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam_with_bad_lines(&input);
+        let args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 100, false);
+        let error = json_error_of(|| {
+            apply_filter(&filter, &input, &args);
+        });
+        assert_eq!(error["error_kind"], "bad_input");
+        assert!(error["message"].as_str().unwrap().starts_with("Could not read record"));
+        assert_eq!(error["path"], input.display().to_string());
+    }
+
+    #[rstest]
+    fn test_json_errors_parallel_inputs() {
+        // the inputs are processed on worker threads, and the error names the input it was raised for
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [dir.path().join("a.sam"), dir.path().join("b.sam"), dir.path().join("c.sam")];
+        write_sam(&inputs[0], &fixture_records());
+        write_sam_with_bad_lines(&inputs[1]);
+        write_sam(&inputs[2], &fixture_records());
+        let mut args = apply_args(inputs.to_vec(), dir.path().join("out"));
+        args.name_template = Some("{stem}.filtered.sam".to_string());
+        args.threads = Some(3);
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 100, false);
+        let error = json_error_of(|| apply_filter_to_files(&filter, &args));
+        assert_eq!(error["error_kind"], "bad_input");
+        assert_eq!(error["path"], inputs[1].display().to_string());
+    }
+
+    #[rstest]
+    fn test_json_errors_unexpected_panic() {
+        let error = json_error_of(|| {
+            let reads: Vec<u8> = Vec::new();
+            let _ = reads[std::hint::black_box(3)];
+        });
+        assert_eq!(error["error_kind"], "error");
+        assert_eq!(error["message"], "index out of bounds: the len is 0 but the index is 3");
+        assert!(error["path"].is_null());
+    }
+
+    #[rstest]
+    fn test_apply_filter_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("short", "ACGTA"), test_record("long", "ACGTACGTACGT")]);
+        let filter: Box<dyn Filtering> = Box::new(filters::LengthFilter::new("len".to_string(), 10, 100, false));
+        let filter_file = dir.path().join("filter.json");
+        std::fs::write(&filter_file, serde_json::to_string(&filter).unwrap()).unwrap();
+        let output = dir.path().join("output.sam");
+        let args = Args::try_parse_from([
+            "BAMetrics",
+            "apply",
+            "--filter-file",
+            filter_file.to_str().unwrap(),
+            input.to_str().unwrap(),
+            "-o",
+            output.to_str().unwrap(),
+        ])
+        .unwrap();
+        // the session config isn't needed
+        run_command(args.cmd, &dir.path().join("bametric.json"));
+        assert_eq!(read_names(&output), vec!["long"]);
+    }
+
+    #[rstest]
+    fn test_load_filter_file_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        assert_eq!(load_filter_file(&config_path, Some("f2")).name(), "f2");
+        let result = std::panic::catch_unwind(|| load_filter_file(&config_path, None));
+        assert!(result.is_err());
+    }
+
+    #[rstest]
+    fn test_apply_shuffle_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let records: Vec<Record> = (0..50).map(|i| test_record(&format!("read{}", i), "ACGTACGTAC")).collect();
+        write_sam(&input, &records);
+        let shuffled = |seed: u64| {
+            let output = dir.path().join(format!("output{}.sam", seed));
+            let mut args = apply_args(vec![input.clone()], output.clone());
+            args.shuffle = true;
+            args.seed = seed;
+            args.force = true;
+            apply_filter(&filters::LengthFilter::new("len".to_string(), 10, 100, false), &input, &args);
+            read_names(&output)
+        };
+        let order = shuffled(1);
+        let mut sorted_order = order.clone();
+        sorted_order.sort();
+        let mut input_order = read_names(&input);
+        input_order.sort();
+        assert_eq!(sorted_order, input_order);
+        assert_ne!(order, read_names(&input));
+        assert_eq!(shuffled(1), order);
+        assert_ne!(shuffled(2), order);
+    }
+
+    #[rstest]
+    fn test_apply_subsample() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        // every other read passes the length filter, and each name is shared by the two mates of a pair
+        let records: Vec<Record> = (0..2000)
+            .flat_map(|i| {
+                let seq = if i % 2 == 0 { "ACGTACGTACGT" } else { "ACGT" };
+                let read = test_record(&format!("read{}", i), seq);
+                [read.clone(), read]
+            })
+            .collect();
+        write_sam(&input, &records);
+        let filter = filters::LengthFilter::new("len".to_string(), 10, 100, false);
+        let subsampled = |seed: u64| {
+            let output = dir.path().join(format!("output{}.sam", seed));
+            let mut args = apply_args(vec![input.clone()], output.clone());
+            args.subsample = Some(0.1);
+            args.seed = seed;
+            args.force = true;
+            let summary = apply_filter(&filter, &input, &args);
+            assert_eq!((summary.total, summary.kept + summary.removed), (4000, 4000));
+            read_names(&output)
+        };
+        let kept = subsampled(1);
+        // 10% of the 2000 records that pass the filter
+        assert!((160..=240).contains(&kept.len()), "{}", kept.len());
+        assert!(kept.chunks(2).all(|mates| mates[0] == mates[1]));
+        assert!(kept.iter().all(|name| name[4..].parse::<u32>().unwrap() % 2 == 0));
+        assert_eq!(subsampled(1), kept);
+        assert_ne!(subsampled(2), kept);
+
+        let parse = |extra: &[&str]| {
+            let mut argv = vec!["BAMetrics", "apply", "f1", "in.sam", "-o", "out.sam"];
+            argv.extend_from_slice(extra);
+            Args::try_parse_from(argv)
+        };
+        assert!(parse(&["--subsample", "0.1", "--seed", "3"]).is_ok());
+        assert!(parse(&["--seed", "3"]).is_err());
+        assert!(parse(&["--subsample", "0.1", "--invert-output"]).is_err());
+        // --count-passes takes no --output
+        let count = |extra: &[&str]| {
+            let mut argv = vec!["BAMetrics", "apply", "f1", "in.sam", "--count-passes"];
+            argv.extend_from_slice(extra);
+            Args::try_parse_from(argv)
+        };
+        assert!(count(&[]).is_ok());
+        assert!(count(&["--subsample", "0.1"]).is_err());
+
+        // '--default keep' inverts the output only after parsing
+        let config_path = config_with_two_filters(dir.path());
+        let output = dir.path().join("kept.sam");
+        let argv = [
+            "BAMetrics", "apply", "f1", input.to_str().unwrap(), "-o", output.to_str().unwrap(),
+            "--default", "keep", "--subsample", "0.1",
+        ];
+        let cmd = Args::try_parse_from(argv).unwrap().cmd;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_command(cmd, &config_path)));
+        assert!(result.is_err());
+        assert!(!output.exists());
+    }
+
+    #[rstest]
+    fn test_shuffle_buffer_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.sam");
+        let mut writer: Box<dyn RecordWriter> = open_writer(&output, test_header());
+        let mut shuffle = ShuffleBuffer::new(0, 10);
+        for i in 0..25 {
+            shuffle.push(test_record(&format!("read{:02}", i), "ACGT"), writer.as_mut());
+        }
+        shuffle.flush(writer.as_mut());
+        writer.finish().unwrap();
+        drop(writer);
+        // each chunk of 10 records is shuffled separately
+        let names = read_names(&output);
+        for (chunk, expected) in names.chunks(10).zip(["read0", "read1", "read2"]) {
+            assert!(chunk.iter().all(|name| name.starts_with(expected)));
+        }
+    }
+
+    #[rstest]
+    fn test_profile_lists_leaf_filters() {
+        let mut filter: Box<dyn Filtering> = Box::new(filters::CombinedFilter::new(
+            "combined".to_string(),
+            Box::new(filters::LengthFilter::new("len".to_string(), 10, 100, false)),
+            Box::new(filters::MapqFilter::new("mapq".to_string(), 0, 60, false)),
+            BoolOperator::AND,
+        ));
+        let profiles = filters::profile_leaves(&mut filter);
+        for seq in ["ACGT", "ACGTACGTAC", "ACGTACGTACGT"] {
+            filter.apply_to(&test_record("read", seq));
+        }
+        let lines: Vec<String> = format_profile(&profiles).lines().map(str::to_string).collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("  len: 3 calls, "));
+        assert!(lines[2].starts_with("  mapq: 2 calls, "));
+        assert!(lines[3].starts_with("  total: 5 calls, "));
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[should_panic(expected = "already exists! Use --force")]
+    fn test_apply_no_clobber(#[case] rejected: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("read1", "ACGTACGTAC")]);
+        let output = dir.path().join("output.sam");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        if rejected {
+            args.rejected = Some(dir.path().join("rejected.sam"));
+            std::fs::write(dir.path().join("rejected.sam"), "existing").unwrap();
+        } else {
+            std::fs::write(&output, "existing").unwrap();
+        }
+        args.no_clobber = true;
+        apply_filter(&filters::LengthFilter::new("len".to_string(), 10, 100, false), &input, &args);
+    }
+
+    #[rstest]
+    fn test_apply_force_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("read1", "ACGTACGTAC")]);
+        let output = dir.path().join("output.sam");
+        std::fs::write(&output, "existing").unwrap();
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.force = true;
+        apply_filter(&filters::LengthFilter::new("len".to_string(), 10, 100, false), &input, &args);
+        assert_eq!(read_names(&output), vec!["read1"]);
+    }
+
+    #[rstest]
+    #[case(&["length", "18"], "min_len=18, max_len=*", &[("short", false), ("long", true)])]
+    #[case(&["length", "*", "10"], "min_len=*, max_len=10", &[("short", true), ("long", false)])]
+    #[case(&["length", "2", "10"], "min_len=2, max_len=10", &[("short", true), ("long", false)])]
+    #[case(&["length"], "min_len=*, max_len=*", &[("short", true), ("long", true)])]
+    fn test_open_ended_range_filters(#[case] subcommand: &[&str], #[case] repr: &str,
+                                     #[case] expected: &[(&str, bool)]) {
+        let mut argv = vec!["bametrics", "run", "-o", "out.sam", "a.sam"];
+        argv.extend_from_slice(subcommand);
+        let Commands::Run { cmd, .. } = Args::try_parse_from(argv).unwrap().cmd else {
+            panic!("Expected the run command")
+        };
+        let filter = build_filter("len".to_string(), false, cmd, Path::new("bametric.json"));
+        assert!(filter.repr().contains(repr), "{}", filter.repr());
+        let args = filter.command_args().unwrap();
+        assert_eq!(args[args.len() - 2..].join(" "), repr.replace("min_len=", "").replace(", max_len=", " "));
+        for (name, keep) in expected {
+            let seq = if *name == "short" { "ACGT" } else { "ACGTACGTACGTACGTACGT" };
+            assert_eq!(filter.apply_to(&test_record(name, seq)), *keep, "{}", name);
+        }
+    }
+
+    #[rstest]
+    #[should_panic(expected = "must not be greater than maximum")]
+    fn test_range_filter_min_above_max() {
+        build_filter(
+            "len".to_string(),
+            false,
+            CreateCommands::Length { min_len: 24.into(), max_len: 18.into() },
+            Path::new("bametric.json"),
+        );
+    }
+
+    #[rstest]
+    #[case(1000, 4, vec![("long", 2), ("mapq", 4), ("on chr2", 0)])]
+    #[case(2, 2, vec![("long", 0), ("mapq", 2), ("on chr2", 0)])]
+    fn test_check_filters(#[case] sample_size: usize, #[case] expected_sampled: usize,
+                          #[case] expected: Vec<(&str, usize)>) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        init(&config_path);
+        create_filter(
+            Some("long".to_string()),
+            false,
+            CreateCommands::Length { min_len: 10.into(), max_len: utils::Limit(None) },
+            &config_path,
+        );
+        create_filter(
+            Some("mapq".to_string()),
+            false,
+            CreateCommands::Mapq { min_mapq: 20.into(), max_mapq: 60.into() },
+            &config_path,
+        );
+        create_filter(Some("on chr2".to_string()), false, CreateCommands::RefName { ref_id: 1 }, &config_path);
+        let input = dir.path().join("input.sam");
+        write_sam(
+            &input,
+            &[
+                test_record("short 1", "GACT"),
+                test_record("short 2", "GACT"),
+                test_record("long 1", "GACTGACTGACT"),
+                test_record("long 2", "AACTGACTGACT"),
+            ],
+        );
+
+        let (sampled, passed) = check_filters(&input, sample_size, &config_path);
+        assert_eq!(sampled, expected_sampled);
+        let expected: Vec<(String, usize)> = expected.into_iter().map(|(name, count)| (name.to_string(), count)).collect();
+        assert_eq!(passed, expected);
+        let report = format_check(sampled, &passed);
+        assert!(report.contains(&format!("mapq: {0}/{0} (passes all of the sample)", sampled)));
+        assert!(report.contains(&format!("on chr2: 0/{} (passes none of the sample)", sampled)));
+    }
+
+    #[rstest]
+    #[case(None, 1)]
+    #[case(Some(1), 1)]
+    #[case(Some(8), 8)]
+    #[case(Some(100_000), u16::MAX)]
+    fn test_default_threads(#[case] available: Option<usize>, #[case] expected: u16) {
+        let available = available.map(|cpus| std::num::NonZeroUsize::new(cpus).unwrap());
+        assert_eq!(default_threads(available), expected);
+    }
+
+    #[rstest]
+    #[case(&[], None)]
+    #[case(&["-p", "1"], Some(1))]
+    #[case(&["--threads", "4"], Some(4))]
+    fn test_parse_threads(#[case] flags: &[&str], #[case] expected: Option<u16>) {
+        let mut argv = vec!["bametrics", "apply", "f", "a.bam", "-o", "out.bam"];
+        argv.extend_from_slice(flags);
+        let Commands::Apply { args, .. } = Args::try_parse_from(argv).unwrap().cmd else {
+            panic!("Expected the apply command")
+        };
+        assert_eq!(args.threads, expected);
+    }
+
+    #[rstest]
+    fn test_export_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = config_with_two_filters(dir.path());
+        let second = dir.path().join("second.json");
+        init(&second);
+        create_filter(
+            Some("f2".to_string()),
+            false,
+            CreateCommands::Mapq { min_mapq: 20.into(), max_mapq: 60.into() },
+            &second,
+        );
+        create_filter(Some("f3".to_string()), false, CreateCommands::Flag { remove_flags: 4 }, &second);
+
+        let export_path = dir.path().join("library.json");
+        assert!(export_filters(Some(&export_path), false, true, false, &first).is_none());
+        assert!(export_filters(Some(&export_path), false, true, false, &second).is_none());
+        let exported = load_config(&export_path);
+        let names: Vec<&str> = exported.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["f1", "f2", "f3"]);
+        assert_eq!(exported.id("f3"), Some(3));
+
+        // without --append the file is overwritten
+        export_filters(Some(&export_path), false, false, false, &second);
+        assert_eq!(load_config(&export_path).count(), 2);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Filter f2 already exists with a different definition!")]
+    fn test_export_append_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = config_with_two_filters(dir.path());
+        let second = dir.path().join("second.json");
+        init(&second);
+        create_filter(Some("f2".to_string()), false, CreateCommands::Flag { remove_flags: 4 }, &second);
+
+        let export_path = dir.path().join("library.json");
+        export_filters(Some(&export_path), false, true, false, &first);
+        export_filters(Some(&export_path), false, true, false, &second);
+    }
+
+    #[rstest]
+    fn test_export_pretty() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        let export_path = dir.path().join("pretty.json");
+        run_cli(&config_path, &["export", export_path.to_str().unwrap(), "--pretty"]);
+        let exported = std::fs::read_to_string(&export_path).unwrap();
+        assert!(exported.lines().count() > 1);
+        let exported: serde_json::Value = serde_json::from_str(&exported).unwrap();
+
+        // stdout exports are indented too, while the session file stays compact
+        let printed = export_filters(None, false, false, false, &config_path).unwrap();
+        assert!(printed.lines().count() > 1);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&printed).unwrap(), exported);
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap().lines().count(), 1);
+
+        let imported = dir.path().join("imported.json");
+        import_filters(&export_path, &imported);
+        let imported: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&imported).unwrap()).unwrap();
+        assert_eq!(imported, exported);
+    }
+
+    #[rstest]
+    fn test_apply_name_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.sam");
+        let second = dir.path().join("second.sam");
+        write_sam(&first, &[test_record("short 1", "ACGT"), test_record("long 1", "ACGTACGTACGTACGTACGT")]);
+        write_sam(&second, &[test_record("long 2", "ACGTACGTACGTACGTACGT")]);
+        let output_dir = dir.path().join("filtered");
+        let mut args = apply_args(vec![first, second], output_dir.clone());
+        args.name_template = Some("{stem}.{filter}.filtered.{ext}".to_string());
+        let filter = filters::LengthFilter::new("long".to_string(), 10, 100, false);
+        apply_filter_to_files(&filter, &args);
+
+        assert_eq!(read_names(&output_dir.join("first.long.filtered.sam")), vec!["long 1"]);
+        assert_eq!(read_names(&output_dir.join("second.long.filtered.sam")), vec!["long 2"]);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Invalid name template '{input}.bam'")]
+    fn test_apply_name_template_unknown_placeholder() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = apply_args(vec![dir.path().join("missing.sam")], dir.path().to_path_buf());
+        args.name_template = Some("{input}.bam".to_string());
+        let filter = filters::LengthFilter::new("long".to_string(), 10, 100, false);
+        apply_filter_to_files(&filter, &args);
+    }
+
+    #[rstest]
+    fn test_view_tsv() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        create_filter(
+            Some("edit distance".to_string()),
+            true,
+            CreateCommands::Tag {
+                tag_name: "NM".to_string(),
+                tag_type: utils::CliTagType::Int,
+                tag_value: "2".to_string(),
+                numeric: false,
+            },
+            &config_path,
+        );
+        combine_filters(Some("both".to_string()), "f1", BoolOperator::AND, "f2", &[], false, &config_path);
+
+        let config = load_config(&config_path);
+        let tsv = format_view_tsv(&filter_rows(&config));
+        let rows: Vec<Vec<&str>> = tsv.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["name", "type", "opposite", "params"],
+                vec!["f1", "length", "false", "max_len=100,min_len=10"],
+                vec!["f2", "mapq", "false", "max_mapq=60,min_mapq=20"],
+                vec!["edit distance", "tag", "true", "numeric=false,tag_name=[78,77],tag_value={\"Int\":2}"],
+                vec!["both", "combined", "false", "filter1=f1,filter2=f2,operator=AND"],
+            ]
+        );
+
+        let json = serde_json::to_value(filter_rows(&config)).unwrap();
+        assert_eq!(json[2]["type"], "tag");
+        assert_eq!(json[2]["id"], 3);
+        assert_eq!(json[3]["params"]["operator"], "AND");
+    }
+
+    #[rstest]
+    #[cfg(feature = "remote")]
+    fn test_apply_url_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("short", "ACGT"), test_record("long", "ACGTACGTACGTACGTACGT")]);
+        let (address, requests) = remote::tests::serve("/bucket/input.sam", std::fs::read(&input).unwrap());
+
+        let output = dir.path().join("output.sam");
+        let url = PathBuf::from(format!("http://{}/bucket/input.sam", address));
+        let filter = filters::LengthFilter::new("long".to_string(), 10, 100, false);
+        apply_filter_to_files(&filter, &apply_args(vec![url], output.clone()));
+        assert_eq!(read_names(&output), vec!["long"]);
+        // the header and the records are read from a single request
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[rstest]
+    #[cfg(not(feature = "remote"))]
+    fn test_apply_url_input_without_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = PathBuf::from("http://localhost/input.sam");
+        let filter = filters::LengthFilter::new("long".to_string(), 10, 100, false);
+        let args = apply_args(vec![url.clone()], dir.path().join("output.sam"));
+        let error = error_of(|| apply_filter_to_files(&filter, &args));
+        assert_eq!((error.kind, error.path), (ErrorKind::Io, Some(url)));
+        assert!(error.message.contains("requires building BAMetrics with the 'remote' feature"), "{}", error.message);
+    }
+
+    #[rstest]
+    #[case("bam", "bam")]
+    #[case("bam", "sam")]
+    #[case("sam", "bam")]
+    #[case("sam", "sam")]
+    fn test_cli_pipeline(#[case] input_format: &str, #[case] output_format: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        let input = dir.path().join(format!("input.{}", input_format));
+        let output = dir.path().join(format!("output.{}", output_format));
+        write_fixture(&input);
+
+        run_cli(&config_path, &["init"]);
+        run_cli(&config_path, &["create", "-n", "not short", "length", "10"]);
+        run_cli(&config_path, &["create", "-n", "confident", "mapq", "20"]);
+        run_cli(&config_path, &["combine", "not short", "and", "confident", "-n", "good"]);
+        run_cli(&config_path, &["apply", "good", input.to_str().unwrap(), "-o", output.to_str().unwrap(), "-p", "2"]);
+
+        assert_eq!(read_names(&output), vec!["mid high", "long high"]);
+        // the records and the header are written unchanged
+        let expected: Vec<Record> = fixture_records()
+            .into_iter()
+            .filter(|record| record.name() == b"mid high" || record.name() == b"long high")
+            .collect();
+        let written = read_records(&output);
+        for (written, expected) in written.iter().zip(&expected) {
+            assert_eq!(written.sequence().to_vec(), expected.sequence().to_vec());
+            assert_eq!((written.ref_id(), written.start(), written.mapq()), (expected.ref_id(), expected.start(), expected.mapq()));
+        }
+        assert_eq!(read_header(&output, 1).reference_names(), test_header().reference_names());
+    }
+
+    #[rstest]
+    fn test_cli_pipeline_opposite_and_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        let input = dir.path().join("input.bam");
+        let output = dir.path().join("output.bam");
+        let rejected = dir.path().join("rejected.sam");
+        write_fixture(&input);
+
+        run_cli(&config_path, &["init"]);
+        run_cli(&config_path, &["create", "-n", "off chr2", "--opposite", "ref-name", "1"]);
+        run_cli(&config_path, &[
+            "apply", "off chr2", input.to_str().unwrap(), "-o", output.to_str().unwrap(),
+            "--rejected", rejected.to_str().unwrap(),
+        ]);
+
+        assert_eq!(read_names(&output), vec!["short low", "mid low", "mid high", "unmapped"]);
+        assert_eq!(read_names(&rejected), vec!["short high", "long low", "long high"]);
+    }
+
+    #[rstest]
+    #[case(&[], "drop", vec!["mid low", "mid high", "long low", "long high", "unmapped"])]
+    #[case(&[], "keep", vec!["short low", "short high"])]
+    #[case(&["--all"], "drop", vec!["mid high", "long high"])]
+    #[case(&["--any"], "drop", vec!["short high", "mid low", "mid high", "long low", "long high", "unmapped"])]
+    #[case(&["--all"], "keep", vec!["short low", "short high", "mid low", "long low", "unmapped"])]
+    #[case(&["--any"], "keep", vec!["short low"])]
+    fn test_apply_default_action(#[case] second_filter: &[&str], #[case] default: &str, #[case] expected: Vec<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("bametric.json");
+        let input = dir.path().join("input.bam");
+        let output = dir.path().join("output.sam");
+        write_fixture(&input);
+        run_cli(&config_path, &["init"]);
+        run_cli(&config_path, &["create", "-n", "not short", "length", "10"]);
+        run_cli(&config_path, &["create", "-n", "confident", "mapq", "20"]);
+        let names_file = dir.path().join("names.txt");
+        std::fs::write(&names_file, "confident\n").unwrap();
+
+        let mut argv = vec!["apply", "not short", input.to_str().unwrap(), "-o", output.to_str().unwrap(), "--default", default];
+        if !second_filter.is_empty() {
+            argv.extend_from_slice(second_filter);
+            argv.extend_from_slice(&["--names-file", names_file.to_str().unwrap()]);
+        }
+        run_cli(&config_path, &argv);
+        assert_eq!(read_names(&output), expected);
+    }
+
+    #[rstest]
+    #[case(false, vec!["pair", "single", "pair"])]
+    #[case(true, vec!["orphan"])]
+    fn test_apply_singleton(#[case] opposite: bool, #[case] expected: Vec<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let output = dir.path().join("output.sam");
+        let mate = |name: &str, first: bool| {
+            let mut record = test_record(name, "ACGTACGT");
+            record.flag_mut().set_paired(true);
+            record.flag_mut().set_first_in_pair(first);
+            record.flag_mut().set_last_in_pair(!first);
+            record
+        };
+        write_sam(&input, &[mate("pair", true), mate("orphan", true), test_record("single", "ACGT"), mate("pair", false)]);
+        let filter = filters::SingletonFilter::new("singleton".to_string(), opposite);
+        apply_filter(&filter, &input, &apply_args(vec![input.clone()], output.clone()));
+        assert_eq!(read_names(&output), expected);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Filter single depends on other records and cannot be combined")]
+    fn test_apply_singleton_with_names_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        create_filter(Some("single".to_string()), false, CreateCommands::Singleton {}, &config_path);
+        let names_file = dir.path().join("names.txt");
+        std::fs::write(&names_file, "f1\n").unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("read", "ACGTACGTACGT")]);
+        let output = dir.path().join("output.sam");
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        run_cli(&config_path, &["apply", "single", "--names-file", names_file.to_str().unwrap(), input, "-o", output]);
+    }
+
+    #[rstest]
+    #[case("bam", "sam")]
+    #[case("sam", "bam")]
+    #[case("bam", "txt")]
+    fn test_apply_misnamed_input(#[case] format: &str, #[case] extension: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join(format!("input.{}", extension));
+        let output = dir.path().join("output.sam");
+        if format == "bam" {
+            write_bam(&input, &fixture_records());
+        } else {
+            write_sam(&input, &fixture_records());
+        }
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        apply_filter(&filter, &input, &apply_args(vec![input.clone()], output.clone()));
+        assert_eq!(read_names(&output), vec!["short high", "mid high", "long high"]);
+    }
+
+    fn memory_sink(invert: bool) -> OutputSink {
+        let header = test_header();
+        OutputSink {
+            ref_stats: RefStats::new(header.reference_names()),
+            writer: Box::new(bam::SamWriter::from_stream(Vec::new(), header).unwrap()),
+            rejected_writer: None,
+            unmapped_fastq: None,
+            invert,
+            max_kept: None,
+            kept: 0,
+            shuffle: None,
+            heartbeat: None,
+            transformer: None,
+        }
+    }
+
+    #[rstest]
+    #[case(false, 3, 4, [(1, 2), (2, 1), (0, 1)])]
+    #[case(true, 4, 3, [(2, 1), (1, 2), (1, 0)])]
+    fn test_apply_filter_to_reader_summary(#[case] invert: bool, #[case] kept: u64, #[case] removed: u64,
+                                           #[case] per_ref: [(u64, u64); 3]) {
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        let args = apply_args(vec![], PathBuf::from("unused.sam"));
+        let summary = apply_filter_to_reader(&filter, fixture_records().into_iter(), &mut memory_sink(invert), &args);
+        let expected_per_ref = ["chr1", "chr2", "*"]
+            .into_iter()
+            .zip(per_ref)
+            .map(|(name, (kept, removed))| (name.to_string(), RefSummary { kept, removed }))
+            .collect();
+        assert_eq!(
+            summary,
+            FilterSummary {
+                total: 7,
+                kept,
+                removed,
+                per_ref: expected_per_ref,
+            }
+        );
+        assert_eq!(summary.to_string(), format!("Kept {} of 7 records ({} removed)", kept, removed));
+    }
+
+    #[rstest]
+    fn test_summary_leaves_out_empty_references() {
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        let args = apply_args(vec![], PathBuf::from("unused.sam"));
+        let records = fixture_records().into_iter().filter(|record| record.ref_id() == 1);
+        let summary = apply_filter_to_reader(&filter, records, &mut memory_sink(false), &args);
+        assert_eq!(summary.ref_tsv(), "reference\tkept\tremoved\nchr2\t2\t1\n");
+        assert_eq!(
+            summary.to_stats(false),
+            ApplyStats {
+                records: 3,
+                passed: 2,
+                failed: 1,
+                kept: 2,
+                removed: 1,
+            }
+        );
+    }
+
+    // Yields the given records (or read errors) from memory
+    struct VecReader(std::vec::IntoIter<std::io::Result<Record>>);
+
+    impl Iterator for VecReader {
+        type Item = std::io::Result<Record>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.0.next()
+        }
+    }
+
+    impl RecordReader for VecReader {
+        fn read_into(&mut self, record: &mut Record) -> std::io::Result<bool> {
+            match self.next() {
+                Some(next) => {
+                    *record = next?;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+
+        fn pause(&mut self) {}
+    }
+
+    // Collects the names of the written records, which stay readable after the writer is moved
+    #[derive(Clone, Default)]
+    struct VecWriter(std::rc::Rc<std::cell::RefCell<Vec<String>>>);
+
+    impl RecordWriter for VecWriter {
+        fn write(&mut self, record: &Record) -> std::io::Result<()> {
+            self.0.borrow_mut().push(String::from_utf8(record.name().to_vec()).unwrap());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn vec_reader(records: Vec<Record>) -> VecReader {
+        VecReader(records.into_iter().map(Ok).collect::<Vec<_>>().into_iter())
+    }
+
+    #[rstest]
+    #[case(false, vec!["short high", "mid high", "long high"])]
+    #[case(true, vec!["short low", "mid low", "long low", "unmapped"])]
+    fn test_run_filter(#[case] invert: bool, #[case] expected: Vec<&str>) {
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        let mut args = apply_args(vec![], PathBuf::from("unused.sam"));
+        args.invert_output = invert;
+        let (writer, rejected) = (VecWriter::default(), VecWriter::default());
+        let rejected_outputs = RejectedOutputs {
+            writer: Some(Box::new(rejected.clone())),
+            unmapped_fastq: None,
+        };
+        let summary =
+            run_filter(vec_reader(fixture_records()), writer.clone(), rejected_outputs, &filter, &test_header(), &args);
+        assert_eq!(*writer.0.borrow(), expected);
+        assert_eq!(writer.0.borrow().len() + rejected.0.borrow().len(), 7);
+        assert_eq!(summary.total, 7);
+        assert_eq!(summary.kept, expected.len() as u64);
+    }
+
+    #[rstest]
+    fn test_run_filter_skips_unreadable_records() {
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        let mut args = apply_args(vec![], PathBuf::from("unused.sam"));
+        args.skip_errors = true;
+        let mut records: Vec<std::io::Result<Record>> = fixture_records().into_iter().map(Ok).collect();
+        records.insert(2, Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed record")));
+        let writer = VecWriter::default();
+        let reader = VecReader(records.into_iter());
+        let summary = run_filter(reader, writer.clone(), RejectedOutputs::default(), &filter, &test_header(), &args);
+        assert_eq!(summary.total, 7);
+        assert_eq!(*writer.0.borrow(), ["short high", "mid high", "long high"]);
+    }
+
+    #[rstest]
+    fn test_summary_line_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.sam");
+        write_fixture(&input);
+        let filter = filters::MapqFilter::new("high mapq".to_string(), 20, 255, false);
+        let manifest_file = dir.path().join("manifest.tsv");
+        let mut args = apply_args(vec![input.clone()], dir.path().join("out.sam"));
+        args.summary_line = true;
+        args.manifest = Some(manifest_file.clone());
+        apply_filter_to_files(&filter, &args);
+        let line = format_summary_line(&read_manifest(&manifest_file)[0]);
+        assert_eq!(line, format!("file={} total=7 kept=3 removed=4 filter=high mapq", input.display()));
+        let fields: Vec<&str> = line.splitn(5, ' ').map(|field| field.split_once('=').unwrap().0).collect();
+        assert_eq!(fields, ["file", "total", "kept", "removed", "filter"]);
+    }
+
+    #[rstest]
+    fn test_filter_types_lists_every_filter() {
+        let types = filter_types();
+        let names: Vec<&str> = types.iter().map(|filter_type| filter_type.name.as_str()).collect();
+        let expected = [
+            "length", "trimmed-length", "low-qual-fraction", "tag", "mapq", "ref-name", "nucleotide", "flag", "flag-mask", "strand",
+            "name-field", "strand-assign", "composition", "clip-content", "adapter", "md-mismatch", "sa-count",
+            "hard-clip", "pair-concordant", "singleton", "tagged-primary", "script", "sanity", "special-contig",
+            "contig-margin", "external", "subtract", "ref-homopolymer", "mate-distance", "junctions",
+            "breakpoint", "perfect-match", "leftmost-split", "rg-quality", "region-mode",
+            "quality-cliff", "coverage", "trim", "clip-ratio", "distinct-bases", "record-index", "concordant",
+            "aligned-gc", "as-per-base", "internal-clip",
+        ];
+        for name in expected {
+            assert!(names.contains(&name), "Filter type {} is not listed", name);
+        }
+        assert_eq!(names.len(), expected.len());
+
+        let tag = types.iter().find(|filter_type| filter_type.name == "tag").unwrap();
+        assert_eq!(tag.description, "Create a filter based on a tag:value pair");
+        let parameters: Vec<(&str, bool)> =
+            tag.parameters.iter().map(|parameter| (parameter.name.as_str(), parameter.required)).collect();
+        assert_eq!(
+            parameters,
+            vec![("tag_name", true), ("tag_type", true), ("tag_value", true), ("--numeric", false)]
+        );
+        let human = format_filter_types(&types);
+        assert!(human.contains("tag <tag_name> <tag_type> <tag_value> [--numeric]\n"));
+        assert!(human.contains("length [min_len] [max_len]\n    Create a filter based on read length\n"));
+    }
+
+    // Sorted records on both references, followed by a tail of unplaced unmapped reads
+    fn records_with_unmapped_tail(n_mapped: i32, n_unmapped: usize) -> Vec<Record> {
+        let mut records = Vec::new();
+        for ref_id in 0..2 {
+            for i in 0..n_mapped {
+                let mut record = positioned_record(&format!("read_{}_{}", ref_id, i), ref_id, i * 900 / n_mapped);
+                record.set_mapq((i * 3 % 60) as u8);
+                records.push(record);
+            }
+        }
+        for i in 0..n_unmapped {
+            let mut record = test_record(&format!("unmapped_{}", i), "ACGTACGTACGTACGTACGT");
+            record.set_ref_id(-1);
+            record.set_start(-1);
+            record.set_cigar(std::iter::empty()).unwrap();
+            record.set_mapq(0);
+            record.flag_mut().set_mapped(false);
+            records.push(record);
+        }
+        records
+    }
+
+    // The filter removes unmapped reads, so with an index they are skipped: the output and stats must not change
+    #[rstest]
+    fn test_apply_skips_unmapped_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let indexed = dir.path().join("indexed.bam");
+        let unindexed = dir.path().join("unindexed.bam");
+        let records = records_with_unmapped_tail(20, 15);
+        write_indexed_bam(&indexed, &records);
+        std::fs::copy(&indexed, &unindexed).unwrap();
+
+        let mapped = filters::FlagFilter::new("mapped".to_string(), 4, false);
+        let mapq = filters::MapqFilter::new("mapq".to_string(), 30, 255, false);
+        let filter = filters::CombinedFilter::new("confident".to_string(), Box::new(mapped), Box::new(mapq), BoolOperator::AND);
+        assert_eq!(unmapped_to_skip(&filter, utils::SupportedFormats::BAM, &indexed, &apply_args(vec![], indexed.clone())), Some(15));
+        assert_eq!(unmapped_to_skip(&filter, utils::SupportedFormats::BAM, &unindexed, &apply_args(vec![], unindexed.clone())), None);
+
+        let mut summaries = Vec::new();
+        let mut outputs = Vec::new();
+        for input in [&indexed, &unindexed] {
+            let output = dir.path().join(format!("{}.out.sam", input.file_stem().unwrap().to_string_lossy()));
+            summaries.push(apply_filter(&filter, input, &apply_args(vec![input.clone()], output.clone())));
+            outputs.push(read_names(&output));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0].len(), 20);
+        assert_eq!(summaries[0], summaries[1]);
+        assert_eq!(summaries[0].total, 55);
+        assert_eq!(summaries[0].per_ref["*"], RefSummary { kept: 0, removed: 15 });
+    }
+
+    #[rstest]
+    fn test_unmapped_not_skipped_when_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        write_indexed_bam(&input, &records_with_unmapped_tail(5, 5));
+        let mapped = filters::FlagFilter::new("mapped".to_string(), 4, false);
+        let args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        assert_eq!(unmapped_to_skip(&mapped, utils::SupportedFormats::BAM, &input, &args), Some(5));
+        let mut inverted = args.clone();
+        inverted.invert_output = true;
+        assert_eq!(unmapped_to_skip(&mapped, utils::SupportedFormats::BAM, &input, &inverted), None);
+        let mut rejected = args.clone();
+        rejected.rejected = Some(dir.path().join("rejected.sam"));
+        assert_eq!(unmapped_to_skip(&mapped, utils::SupportedFormats::BAM, &input, &rejected), None);
+        let unmapped = filters::FlagFilter::new("unmapped".to_string(), 4, true);
+        assert_eq!(unmapped_to_skip(&unmapped, utils::SupportedFormats::BAM, &input, &args), None);
+    }
+
+    // Benchmark of skipping a large unmapped tail, compared to reading it.
+    // Run with 'cargo test --release bench_skip_unmapped_tail -- --ignored --nocapture'
+    #[rstest]
+    #[ignore]
+    fn bench_skip_unmapped_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let indexed = dir.path().join("indexed.bam");
+        let unindexed = dir.path().join("unindexed.bam");
+        write_indexed_bam(&indexed, &records_with_unmapped_tail(5_000, 200_000));
+        std::fs::copy(&indexed, &unindexed).unwrap();
+        let filter = filters::FlagFilter::new("mapped".to_string(), 4, false);
+        for input in [&indexed, &unindexed] {
+            let output = dir.path().join(format!("{}.out.bam", input.file_stem().unwrap().to_string_lossy()));
+            let start = std::time::Instant::now();
+            let summary = apply_filter(&filter, input, &apply_args(vec![input.clone()], output));
+            println!("{}: {} in {:?}", input.file_name().unwrap().to_string_lossy(), summary, start.elapsed());
+        }
+    }
+
+    #[rstest]
+    #[case("manifest.tsv")]
+    #[case("manifest.json")]
+    fn test_apply_manifest(#[case] manifest_name: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [dir.path().join("a.sam"), dir.path().join("b.bam")];
+        write_sam(&inputs[0], &fixture_records());
+        write_bam(&inputs[1], &fixture_records()[..3]);
+        let manifest_file = dir.path().join(manifest_name);
+        let mut args = apply_args(inputs.to_vec(), dir.path().join("out"));
+        args.name_template = Some("{stem}.filtered.sam".to_string());
+        args.manifest = Some(manifest_file.clone());
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        apply_filter_to_files(&filter, &args);
+
+        let outputs = [dir.path().join("out").join("a.filtered.sam"), dir.path().join("out").join("b.filtered.sam")];
+        let rows: Vec<(PathBuf, PathBuf, String, u64, u64, u64)> = if manifest_name.ends_with(".json") {
+            let entries: Vec<ManifestEntry> =
+                serde_json::from_str(&std::fs::read_to_string(&manifest_file).unwrap()).unwrap();
+            assert!(entries.iter().all(|entry| entry.timestamp > 0));
+            entries
+                .into_iter()
+                .map(|entry| (entry.input, entry.output, entry.filter, entry.total, entry.kept, entry.removed))
+                .collect()
+        } else {
+            let contents = std::fs::read_to_string(&manifest_file).unwrap();
+            let mut lines = contents.lines();
+            assert_eq!(lines.next(), Some("input\toutput\tfilter\ttimestamp\ttotal\tkept\tremoved"));
+            lines
+                .map(|line| {
+                    let fields: Vec<&str> = line.split('\t').collect();
+                    assert!(fields[3].parse::<u64>().unwrap() > 0);
+                    let count = |i: usize| fields[i].parse().unwrap();
+                    (fields[0].into(), fields[1].into(), fields[2].to_string(), count(4), count(5), count(6))
+                })
+                .collect()
+        };
+        assert_eq!(
+            rows,
+            vec![
+                (inputs[0].clone(), outputs[0].clone(), "confident".to_string(), 7, 3, 4),
+                (inputs[1].clone(), outputs[1].clone(), "confident".to_string(), 3, 1, 2),
+            ]
+        );
+        assert_eq!(read_names(&outputs[1]), vec!["short high"]);
+    }
+
+    #[rstest]
+    #[case("manifest.tsv")]
+    #[case("manifest.json")]
+    fn test_apply_resume_skips_completed(#[case] manifest_name: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [dir.path().join("a.sam"), dir.path().join("b.sam")];
+        write_sam(&inputs[0], &fixture_records());
+        write_sam(&inputs[1], &fixture_records()[..3]);
+        let manifest_file = dir.path().join(manifest_name);
+        let mut args = apply_args(inputs.to_vec(), dir.path().join("out"));
+        args.name_template = Some("{stem}.filtered.sam".to_string());
+        args.manifest = Some(manifest_file.clone());
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        apply_filter_to_files(&filter, &args);
+        let first_manifest = read_manifest(&manifest_file);
+
+        // a skipped output is left untouched, while a missing one is produced again
+        let outputs = [dir.path().join("out").join("a.filtered.sam"), dir.path().join("out").join("b.filtered.sam")];
+        std::fs::write(&outputs[0], "untouched").unwrap();
+        std::fs::remove_file(&outputs[1]).unwrap();
+        args.resume = true;
+        apply_filter_to_files(&filter, &args);
+        assert_eq!(std::fs::read_to_string(&outputs[0]).unwrap(), "untouched");
+        assert_eq!(read_names(&outputs[1]), vec!["short high"]);
+        let second_manifest = read_manifest(&manifest_file);
+        assert_eq!(second_manifest[0], first_manifest[0]);
+        assert_eq!(second_manifest[1].input, inputs[1]);
+        assert_eq!((second_manifest[1].total, second_manifest[1].kept), (3, 1));
+        // the skipped input gets the summary line of the run that produced its output
+        assert_eq!(
+            format_summary_line(&second_manifest[0]),
+            format!("file={} total=7 kept=3 removed=4 filter=confident", inputs[0].display())
+        );
+
+        // inputs processed by another filter are not skipped
+        let other = filters::MapqFilter::new("other".to_string(), 0, 255, false);
+        args.force = true;
+        apply_filter_to_files(&other, &args);
+        assert_eq!(read_names(&outputs[0]).len(), 7);
+    }
+
+    #[rstest]
+    fn test_verify_clean_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        run_cli(&config_path, &["combine", "f1", "and", "f2", "--name", "both"]);
+        assert!(verify_config(&load_config(&config_path)).is_empty());
+    }
+
+    #[rstest]
+    fn test_verify_problems() {
+        let mut config = filters::Config::new();
+        let mut push = |filter: Box<dyn Filtering>| {
+            let name = filter.name().to_string();
+            config.push(&name, filter);
+        };
+        // ranges can only be empty in a hand-edited config, since they are checked on creation
+        let empty: Box<dyn Filtering> = serde_json::from_str(
+            r#"{"type": "LengthFilter", "name": "empty", "min_len": 30, "max_len": 20, "opposite": false}"#,
+        )
+        .unwrap();
+        push(empty.clone());
+        push(serde_json::from_str(
+            r#"{"type": "CompositionFilter", "name": "gc", "bases": "GC", "min_fraction": 0.6, "max_fraction": 0.4,
+                "opposite": false}"#,
+        )
+        .unwrap());
+        push(Box::new(filters::TaggedPrimaryFilter::new("bad tag".to_string(), *b"1X", false)));
+        push(Box::new(filters::CombinedFilter::new("twice".to_string(), empty.clone(), empty, BoolOperator::AND)));
+        push(Box::new(filters::MapqFilter::new("Mapq".to_string(), 20, 255, false)));
+        push(Box::new(filters::MapqFilter::new("mapq".to_string(), 20, 255, false)));
+        let mapq: Box<dyn Filtering> = Box::new(filters::MapqFilter::new("mapq".to_string(), 20, 255, false));
+        let pairs = Box::new(filters::PairConcordantFilter::new("pairs".to_string(), mapq.clone(), false));
+        push(Box::new(filters::CombinedFilter::new("with pairs".to_string(), mapq, pairs, BoolOperator::OR)));
+        assert_eq!(
+            verify_config(&config),
+            vec![
+                "empty: empty range: the minimum (30) is greater than the maximum (20)",
+                "gc: empty range: the minimum fraction (0.6) is greater than the maximum (0.4)",
+                "bad tag: tag name \"1X\" is not a letter followed by a letter or digit",
+                "twice: combines filter empty with itself using AND",
+                "with pairs: input pairs depends on other records and cannot be combined",
+                "Mapq, mapq: names only differ in case or surrounding spaces",
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_apply_split_by_rg() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let reads = [("a", "lane1", 30), ("b", "lane2", 30), ("c", "lane1", 0), ("d", "lane1", 30), ("e", "", 30)];
+        let records: Vec<Record> = reads
+            .iter()
+            .map(|&(name, read_group, mapq)| {
+                let mut record = test_record(name, "ACGTACGTAC");
+                record.set_mapq(mapq);
+                if !read_group.is_empty() {
+                    record.tags_mut().push_string(b"RG", read_group.as_bytes());
+                }
+                record
+            })
+            .collect();
+        write_bam(&input, &records);
+        let output = dir.path().join("by_rg");
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.split_by_rg = true;
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        apply_filter_to_files(&filter, &args);
+
+        assert_eq!(read_names(&output.join("lane1.bam")), vec!["a", "d"]);
+        assert_eq!(read_names(&output.join("lane2.bam")), vec!["b"]);
+        assert_eq!(read_names(&output.join("no_read_group.bam")), vec!["e"]);
+        assert_eq!(std::fs::read_dir(&output).unwrap().count(), 3);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "--split-by-rg requires a single input file!")]
+    fn test_apply_split_by_rg_multiple_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [dir.path().join("a.sam"), dir.path().join("b.sam")];
+        for input in &inputs {
+            write_sam(input, &fixture_records());
+        }
+        let mut args = apply_args(inputs.to_vec(), dir.path().join("by_rg"));
+        args.split_by_rg = true;
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        apply_filter_to_files(&filter, &args);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "more than 256 read groups")]
+    fn test_apply_split_by_rg_too_many_read_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.bam");
+        let records: Vec<Record> = (0..=MAX_READ_GROUP_OUTPUTS)
+            .map(|i| {
+                let mut record = test_record(&format!("read{}", i), "ACGT");
+                record.tags_mut().push_string(b"RG", format!("rg{}", i).as_bytes());
+                record
+            })
+            .collect();
+        write_bam(&input, &records);
+        let mut args = apply_args(vec![input.clone()], dir.path().join("by_rg"));
+        args.split_by_rg = true;
+        let filter = filters::MapqFilter::new("confident".to_string(), 0, 255, false);
+        apply_filter(&filter, &input, &args);
+    }
+
+    fn unmapped_record(name: &str, seq: &str, qualities: &[u8], reverse: bool) -> Record {
+        let mut record = test_record(name, seq);
+        record.set_seq_qual(seq.bytes(), qualities.iter().copied()).unwrap();
+        record.flag_mut().set_mapped(false);
+        record.flag_mut().set_strand(!reverse);
+        record
+    }
+
+    #[rstest]
+    fn test_apply_unmapped_fastq() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let records = vec![
+            test_record("mapped", "ACGTACGTAC"),
+            unmapped_record("forward", "ACGTTT", &[30, 31, 32, 33, 34, 35], false),
+            unmapped_record("reverse", "AACCGT", &[10, 20, 30, 40, 30, 20], true),
+        ];
+        write_sam(&input, &records);
+        let fastq = dir.path().join("unmapped.fq");
+        let mut args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        args.unmapped_fastq = vec![fastq.clone()];
+        let filter = filters::FlagFilter::new("mapped".to_string(), 4, false);
+        apply_filter(&filter, &input, &args);
+
+        assert_eq!(read_names(&dir.path().join("output.sam")), vec!["mapped"]);
+        assert_eq!(
+            std::fs::read_to_string(&fastq).unwrap(),
+            "@forward\nACGTTT\n+\n?@ABCD\n@reverse\nACGGTT\n+\n5?I?5+\n"
+        );
+    }
+
+    #[rstest]
+    fn test_apply_unmapped_fastq_pairs() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let mate = |name: &str, seq: &str, first: bool, mapped: bool| {
+            let mut record = unmapped_record(name, seq, &[30; 4], false);
+            record.flag_mut().set_mapped(mapped);
+            record.flag_mut().set_paired(true);
+            record.flag_mut().set_first_in_pair(first);
+            record.flag_mut().set_last_in_pair(!first);
+            record
+        };
+        let records = vec![
+            mate("pair", "CCCC", false, false),
+            mate("half", "GGGG", true, true),
+            mate("half", "TTTT", false, false),
+            unmapped_record("single", "AAAA", &[30; 4], false),
+            mate("pair", "AAAC", true, false),
+        ];
+        write_sam(&input, &records);
+        let fastq = [dir.path().join("unmapped_1.fq"), dir.path().join("unmapped_2.fq")];
+        let mut args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        args.unmapped_fastq = fastq.to_vec();
+        let filter = filters::FlagFilter::new("mapped".to_string(), 4, false);
+        apply_filter(&filter, &input, &args);
+
+        assert_eq!(std::fs::read_to_string(&fastq[0]).unwrap(), "@pair\nAAAC\n+\n????\n");
+        assert_eq!(std::fs::read_to_string(&fastq[1]).unwrap(), "@pair\nCCCC\n+\n????\n");
+    }
+
+    #[rstest]
+    fn test_heartbeat_updates_during_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress.json");
+        let mut sink = memory_sink(false);
+        sink.heartbeat = Some(Heartbeat::new(&path, 0.0));
+        let read_progress = || serde_json::from_str::<Progress>(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let records = fixture_records();
+
+        sink.handle(&records[0], true);
+        sink.handle(&records[1], false);
+        let progress = read_progress();
+        assert_eq!((progress.processed, progress.kept, progress.removed), (2, 1, 1));
+        sink.handle(&records[2], true);
+        let progress = read_progress();
+        assert_eq!((progress.processed, progress.kept, progress.removed), (3, 2, 1));
+        assert!(progress.elapsed_secs >= 0.0);
+    }
+
+    #[rstest]
+    fn test_heartbeat_waits_for_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress.json");
+        let mut heartbeat = Heartbeat::new(&path, 3600.0);
+        heartbeat.add(true);
+        assert!(!path.exists());
+        heartbeat.write();
+        let progress: Progress = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!((progress.processed, progress.kept, progress.removed), (1, 1, 0));
+    }
+
+    #[rstest]
+    fn test_apply_progress_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &fixture_records());
+        let progress_file = dir.path().join("progress.json");
+        let mut args = apply_args(vec![input.clone()], dir.path().join("output.sam"));
+        args.progress_json = Some(progress_file.clone());
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        let summary = apply_filter(&filter, &input, &args);
+
+        let progress: Progress = serde_json::from_str(&std::fs::read_to_string(&progress_file).unwrap()).unwrap();
+        let counts = (progress.processed, progress.kept, progress.removed);
+        assert_eq!(counts, (summary.total, summary.kept, summary.removed));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[rstest]
+    #[case("f1", "and", ("f1", BoolOperator::AND))]
+    #[case("AND", "f1", ("f1", BoolOperator::AND))]
+    #[case("implies", "f1", ("f1", BoolOperator::IMPLIES))]
+    #[case("xor", "nor", ("xor", BoolOperator::NOR))]
+    fn test_combine_operands(#[case] first: &str, #[case] second: &str, #[case] expected: (&str, BoolOperator)) {
+        assert_eq!(combine_operands(first.to_string(), second.to_string()), (expected.0.to_string(), expected.1));
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Neither 'f1' nor 'f2' is a boolean operator! Expected one of: and, or, xor")]
+    fn test_combine_operands_no_operator() {
+        combine_operands("f1".to_string(), "f2".to_string());
+    }
+
+    #[rstest]
+    fn test_combine_operator_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        run_cli(&config_path, &["combine", "f1", "or", "f2", "--name", "infix"]);
+        run_cli(&config_path, &["combine", "OR", "f1", "f2", "--name", "prefix"]);
+
+        let config = load_config(&config_path);
+        let describe = |name: &str| config.get(name).unwrap().repr().replace(name, "combined");
+        assert_eq!(describe("infix"), describe("prefix"));
+        assert!(describe("prefix").contains("OR"));
+    }
+
+    #[rstest]
+    #[case(8, 1, (1, 8))]
+    #[case(8, 2, (2, 4))]
+    #[case(8, 3, (3, 2))]
+    #[case(8, 20, (8, 1))]
+    #[case(1, 20, (1, 1))]
+    #[case(4, 0, (1, 4))]
+    fn test_thread_allocation(#[case] budget: u16, #[case] parallel_inputs: usize, #[case] expected: (usize, u16)) {
+        assert_eq!(thread_allocation(budget, parallel_inputs), expected);
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(2)]
+    #[case(3)]
+    #[case(16)]
+    fn test_apply_thread_budget_multiple_files(#[case] threads: u16) {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs: Vec<PathBuf> = (0..5).map(|i| dir.path().join(format!("input{}.bam", i))).collect();
+        for (i, input) in inputs.iter().enumerate() {
+            write_bam(input, &fixture_records()[..i + 3]);
+        }
+        let manifest_file = dir.path().join("manifest.json");
+        let mut args = apply_args(inputs.clone(), dir.path().join("out"));
+        args.threads = Some(threads);
+        args.name_template = Some("{stem}.filtered.bam".to_string());
+        args.manifest = Some(manifest_file.clone());
+        let filter = filters::MapqFilter::new("confident".to_string(), 20, 255, false);
+        apply_filter_to_files(&filter, &args);
+
+        let entries: Vec<ManifestEntry> =
+            serde_json::from_str(&std::fs::read_to_string(&manifest_file).unwrap()).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.input.clone()).collect::<Vec<_>>(), inputs);
+        for (i, input) in inputs.iter().enumerate() {
+            let expected_names: Vec<String> = fixture_records()[..i + 3]
+                .iter()
+                .filter(|record| record.mapq() >= 20)
+                .map(|record| String::from_utf8(record.name().to_vec()).unwrap())
+                .collect();
+            let stem = input.file_stem().unwrap().to_string_lossy();
+            let output = dir.path().join("out").join(format!("{}.filtered.bam", stem));
+            assert_eq!(read_names(&output), expected_names);
+            assert_eq!(entries[i].kept, expected_names.len() as u64);
+        }
+    }
+
+    #[rstest]
+    fn test_count_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        init(&config_path);
+        create_filter(
+            Some("min5".to_string()),
+            false,
+            CreateCommands::Length { min_len: 5.into(), max_len: utils::Limit(None) },
+            &config_path,
+        );
+        create_filter(
+            Some("min10".to_string()),
+            false,
+            CreateCommands::Length { min_len: 10.into(), max_len: utils::Limit(None) },
+            &config_path,
+        );
+        create_filter(Some("mated".to_string()), false, CreateCommands::Singleton {}, &config_path);
+        let input = dir.path().join("input.sam");
+        let mut singleton = test_record("singleton", "ACGTACGTACGTACGTACGT");
+        singleton.flag_mut().set_paired(true);
+        let records = [
+            test_record("tiny", "ACGT"),
+            test_record("short", "ACGTACGT"),
+            singleton,
+            test_record("long", "ACGTACGTACGT"),
+        ];
+        write_sam(&input, &records);
+
+        // the paired read without a mate is the only one that fails the singleton filter
+        let args = Args::try_parse_from([
+            "bametrics", "apply", "min5", "--names-file", "names.txt", input.to_str().unwrap(), "--count-passes",
+        ])
+        .unwrap();
+        assert!(matches!(args.cmd, Commands::Apply { args: ApplyArgs { count_passes: true, output: None, .. }, .. }));
+        let filters = get_filters(vec!["min5", "min10", "mated"], &config_path);
+        let mut args = apply_args(vec![input], dir.path().join("unused.sam"));
+        args.count_passes = true;
+        let counts = count_passes(&filters, &args);
+        assert_eq!(counts, vec![0, 1, 2, 1]);
+        assert_eq!(
+            format_pass_counts(&counts).lines().collect::<Vec<_>>(),
+            vec![
+                "Counted the filters passed by 4 records",
+                "0/3 filters: 0 (0.0%)",
+                "1/3 filters: 1 (25.0%)",
+                "2/3 filters: 2 (50.0%)",
+                "3/3 filters: 1 (25.0%)",
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_apply_transform_trims_kept_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        let output = dir.path().join("output.sam");
+        let rejected = dir.path().join("rejected.sam");
+        write_sam(&input, &[test_record("long", "ACGTACGTACGT"), test_record("short", "ACGTAC")]);
+        let filter = filters::TrimFilter::new("trim".to_string(), 2, 1, None, 5, false);
+        let mut args = apply_args(vec![input.clone()], output.clone());
+        args.rejected = Some(rejected.clone());
+        args.transform = true;
+        apply_filter(&filter, &input, &args);
+
+        let sequences = |path: &Path| -> Vec<String> {
+            bam::SamReader::from_path(path)
+                .unwrap()
+                .map(|record| String::from_utf8(record.unwrap().sequence().to_vec()).unwrap())
+                .collect()
+        };
+        assert_eq!(sequences(&output), vec!["GTACGTACG"]);
+        assert_eq!(sequences(&rejected), vec!["ACGTAC"]);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Filter trim modifies the reads it keeps. Use --transform to apply it!")]
+    fn test_apply_transform_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("long", "ACGTACGTACGT")]);
+        let filter = filters::TrimFilter::new("trim".to_string(), 2, 1, None, 5, false);
+        apply_filter(&filter, &input, &apply_args(vec![input.clone()], dir.path().join("output.sam")));
+    }
+
+    // the names of the inputs of a stored combined filter, left to right
+    fn combined_inputs(name: &str, config_path: &Path) -> Vec<String> {
+        let combined = load_config(config_path).get(name).unwrap();
+        combined.inputs().iter().map(|input| input.name().to_string()).collect()
+    }
+
+    #[rstest]
+    #[case(&["combine", "f1", "implies", "f2", "-n", "combined"])]
+    #[case(&["combine", "implies", "f1", "f2", "-n", "combined"])]
+    fn test_combine_keeps_operand_order(#[case] args: &[&str]) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        run_cli(&config_path, args);
+        assert_eq!(combined_inputs("combined", &config_path), vec!["f1", "f2"]);
+
+        // f1 (length 10-100) implies f2 (mapq 20-60): only reads that pass f1 and fail f2 fail
+        let combined = load_config(&config_path).get("combined").unwrap();
+        let mut long_unconfident = test_record("long", "ACGTACGTACGTACGT");
+        long_unconfident.set_mapq(5);
+        let mut short_unconfident = test_record("short", "ACGT");
+        short_unconfident.set_mapq(5);
+        assert!(!combined.apply_to(&long_unconfident));
+        assert!(combined.apply_to(&short_unconfident));
+    }
+
+    #[rstest]
+    fn test_combine_swapped_operands() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        combine_filters(Some("forward".to_string()), "f1", BoolOperator::IMPLIES, "f2", &[], false, &config_path);
+        combine_filters(Some("backward".to_string()), "f2", BoolOperator::IMPLIES, "f1", &[], false, &config_path);
+        assert_eq!(combined_inputs("forward", &config_path), vec!["f1", "f2"]);
+        assert_eq!(combined_inputs("backward", &config_path), vec!["f2", "f1"]);
+        combine_filters(
+            Some("chained".to_string()),
+            "f2",
+            BoolOperator::IMPLIES,
+            "f1",
+            &["forward".to_string()],
+            false,
+            &config_path,
+        );
+        assert_eq!(combined_inputs("chained", &config_path), vec!["(f2 IMPLIES f1)", "forward"]);
+    }
+
+    #[rstest]
+    fn test_save_config_is_deterministic() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        create_filter(Some("f3".to_string()), false, CreateCommands::Flag { remove_flags: 4 }, &config_path);
+        rename_filter("f1", "renamed", &config_path);
+        let saved = std::fs::read_to_string(&config_path).unwrap();
+        for _ in 0..5 {
+            save_config(&load_config(&config_path), &config_path);
+            assert_eq!(std::fs::read_to_string(&config_path).unwrap(), saved);
+        }
+        let config = load_config(&config_path);
+        let ids: Vec<(&str, Option<u64>)> = config.iter().map(|(name, _)| (name.as_str(), config.id(name))).collect();
+        assert_eq!(ids, vec![("renamed", Some(1)), ("f2", Some(2)), ("f3", Some(3))]);
+        assert!(saved.contains(r#""ids":{"renamed":1,"f2":2,"f3":3}"#));
+    }
+
+    #[rstest]
+    fn test_pipeline_stage_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        let input = dir.path().join("input.sam");
+        let output = dir.path().join("output.sam");
+        let mut records = vec![
+            test_record("short", "ACGT"),
+            test_record("confident", "ACGTACGTACGT"),
+            test_record("unconfident", "ACGTACGTACGT"),
+            test_record("very confident", "ACGTACGTACGTACGT"),
+        ];
+        for (record, mapq) in records.iter_mut().zip([30, 30, 5, 40]) {
+            record.set_mapq(mapq);
+        }
+        write_sam(&input, &records);
+
+        let stages = run_pipeline(&["f1".to_string(), "f2".to_string()], &input, &output, false, false, &config_path);
+        assert_eq!(
+            stages,
+            vec![
+                PipelineStage { name: "f1".to_string(), input: 4, kept: 3 },
+                PipelineStage { name: "f2".to_string(), input: 3, kept: 2 },
+            ]
+        );
+        assert_eq!(read_names(&output), vec!["confident", "very confident"]);
+        assert_eq!(
+            format_pipeline(&stages),
+            "Stage 1 (f1): 4 in, 3 kept, 1 removed\nStage 2 (f2): 3 in, 2 kept, 1 removed\nKept 2 of 4 records (50.0%)"
+        );
+
+        // the order of the stages changes the attrition at each stage, but not the kept reads (--force overwrites the
+        // output of the first run)
+        let stages = run_pipeline(&["f2".to_string(), "f1".to_string()], &input, &output, true, false, &config_path);
+        assert_eq!((stages[0].kept, stages[1].input, stages[1].kept), (3, 3, 2));
+    }
+
+    #[rstest]
+    #[case(CreateCommands::PairConcordant { filter_name: "f1".to_string() })]
+    #[case(CreateCommands::RgQuality { max_rate: 0.02 })]
+    #[case(CreateCommands::RecordIndex { first_index: 0.into(), last_index: 10.into() })]
+    #[should_panic(expected = "Filter streaming depends on other records and cannot be combined")]
+    fn test_combine_streaming_filter(#[case] cmd: CreateCommands, #[values(false, true)] applied: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        create_filter(Some("streaming".to_string()), false, cmd, &config_path);
+        match applied {
+            // e.g. 'apply f2 --names-file', which combines the applied filters
+            true => {
+                chain_applied_filters(get_filters(vec!["f2", "streaming"], &config_path), &BoolOperator::AND);
+            }
+            false => {
+                combine_filters(Some("both".to_string()), "f2", BoolOperator::AND, "streaming", &[], false, &config_path)
+            }
+        }
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[should_panic(expected = "already exists! Use --force")]
+    fn test_pipeline_existing_output(#[case] no_clobber: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("read", "ACGTACGTACGT")]);
+        let output = dir.path().join("output.sam");
+        std::fs::write(&output, "existing").unwrap();
+        let result = std::panic::catch_unwind(|| {
+            run_pipeline(&["f1".to_string()], &input, &output, false, no_clobber, &config_path);
+        });
+        // the existing output is left as it was
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "existing");
+        std::panic::resume_unwind(result.unwrap_err());
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Filter pairs depends on other records or modifies reads")]
+    fn test_pipeline_streaming_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = config_with_two_filters(dir.path());
+        create_filter(
+            Some("pairs".to_string()),
+            false,
+            CreateCommands::PairConcordant { filter_name: "f1".to_string() },
+            &config_path,
+        );
+        let input = dir.path().join("input.sam");
+        write_sam(&input, &[test_record("read", "ACGTACGTACGT")]);
+        let output = dir.path().join("output.sam");
+        run_pipeline(&["f1".to_string(), "pairs".to_string()], &input, &output, false, false, &config_path);
+    }
 }
@@ -0,0 +1,135 @@
+extern crate bam;
+
+use std::collections::{HashMap, VecDeque};
+
+use bam::Record;
+
+/// Whether a record is matched with its mate. Unpaired reads, and secondary or supplementary alignments, are not
+pub fn has_mate(record: &Record) -> bool {
+    let flag = record.flag();
+    flag.is_paired() && !flag.is_secondary() && !flag.is_supplementary()
+}
+
+/// Holds an item for each read (e.g. the record itself, or its position in the input) until the mate of the read
+/// arrives, matching mates by read name. Shared by everything that pairs up mates (`MatePairStream`,
+/// and the FASTQ output of unmapped pairs).
+///
+/// On name-sorted (or name-grouped) input mates are adjacent and few reads wait at a time.
+/// On coordinate-sorted input mates can be far apart, and many reads may wait.
+pub struct MateBuffer<T> {
+    waiting: HashMap<Vec<u8>, T>,
+    // names of the waiting reads in arrival order. May include reads that already found their mate
+    arrival_order: VecDeque<Vec<u8>>,
+}
+
+impl<T> MateBuffer<T> {
+    pub fn new() -> MateBuffer<T> {
+        MateBuffer {
+            waiting: HashMap::new(),
+            arrival_order: VecDeque::new(),
+        }
+    }
+
+    /// Number of reads currently waiting for their mate
+    pub fn buffered(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Add the item of a paired read. If its mate was waiting, the items of both mates are returned,
+    /// in the order they were pushed. Otherwise the item waits for its mate
+    pub fn push(&mut self, name: &[u8], item: T) -> Option<(T, T)> {
+        if let Some(mate) = self.waiting.remove(name) {
+            // drop the names of reads that already found their mates once they make up most of the queue
+            if self.arrival_order.len() > 2 * self.waiting.len() + 64 {
+                let waiting = &self.waiting;
+                self.arrival_order.retain(|name| waiting.contains_key(name));
+            }
+            return Some((mate, item));
+        }
+        self.arrival_order.push_back(name.to_vec());
+        self.waiting.insert(name.to_vec(), item);
+        None
+    }
+
+    /// Remove the item that has waited the longest for its mate, e.g. to give up on it once too many reads wait,
+    /// or at the end of the input
+    pub fn pop_oldest(&mut self) -> Option<T> {
+        while let Some(name) = self.arrival_order.pop_front() {
+            if let Some(item) = self.waiting.remove(&name) {
+                return Some(item);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn mate(name: &str, first: bool) -> Record {
+        let mut record = Record::new();
+        record.set_name(name.bytes());
+        record.flag_mut().set_paired(true);
+        record.flag_mut().set_first_in_pair(first);
+        record.flag_mut().set_last_in_pair(!first);
+        record
+    }
+
+    #[rstest]
+    fn test_mate_buffer_in_order_pairs() {
+        let mut mates = MateBuffer::new();
+        assert_eq!(mates.push(b"a", 0), None);
+        assert_eq!(mates.push(b"a", 1), Some((0, 1)));
+        assert_eq!(mates.push(b"b", 2), None);
+        assert_eq!(mates.push(b"b", 3), Some((2, 3)));
+        assert_eq!(mates.buffered(), 0);
+        assert_eq!(mates.pop_oldest(), None);
+    }
+
+    #[rstest]
+    fn test_mate_buffer_interleaved_pairs() {
+        let mut mates = MateBuffer::new();
+        for (index, name) in [b"a", b"b", b"c"].into_iter().enumerate() {
+            assert_eq!(mates.push(name, index), None);
+        }
+        assert_eq!(mates.push(b"b", 3), Some((1, 3)));
+        assert_eq!(mates.buffered(), 2);
+        // 'b' found its mate, so 'a' and then 'c' waited the longest
+        assert_eq!(mates.pop_oldest(), Some(0));
+        assert_eq!(mates.push(b"a", 4), None);
+        assert_eq!(mates.pop_oldest(), Some(2));
+        assert_eq!(mates.pop_oldest(), Some(4));
+        assert_eq!(mates.pop_oldest(), None);
+    }
+
+    #[rstest]
+    fn test_mate_buffer_many_pairs() {
+        // enough pairs for the arrival order to be compacted while 'first' still waits
+        let mut mates = MateBuffer::new();
+        mates.push(b"first", usize::MAX);
+        for index in 0..500 {
+            let name = format!("read{}", index);
+            assert_eq!(mates.push(name.as_bytes(), 2 * index), None);
+            assert_eq!(mates.push(name.as_bytes(), 2 * index + 1), Some((2 * index, 2 * index + 1)));
+        }
+        assert_eq!(mates.pop_oldest(), Some(usize::MAX));
+        assert_eq!(mates.pop_oldest(), None);
+    }
+
+    #[rstest]
+    #[case(true, false, false, true)]
+    #[case(false, false, false, false)]
+    #[case(true, true, false, false)]
+    #[case(true, false, true, false)]
+    fn test_has_mate(#[case] paired: bool, #[case] secondary: bool, #[case] supplementary: bool,
+                     #[case] expected: bool) {
+        let mut record = mate("a", true);
+        record.flag_mut().set_paired(paired);
+        record.flag_mut().set_secondary(secondary);
+        record.flag_mut().set_supplementary(supplementary);
+        assert_eq!(has_mate(&record), expected);
+    }
+}
@@ -0,0 +1,332 @@
+extern crate bam;
+
+use bam::record::tags::{TagName, TagValue};
+use bam::Record;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// A compiled filter expression, e.g. `length >= 18 && (mapq > 20 || tag("NM") < 3)`.
+///
+/// Expressions combine comparisons with `&&`, `||`, `!` and parentheses.
+/// Comparisons (`==`, `!=`, `<`, `<=`, `>`, `>=`) take numbers, record fields
+/// (`length`, `mapq`, `flag`, `pos`, `ref_id`) or numeric tag values (`tag("NM")`).
+/// A comparison involving a tag that is missing or non-numeric is false.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(CompareOp, Operand, Operand),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Number(f64),
+    Length,
+    Mapq,
+    Flag,
+    Pos,
+    RefId,
+    Tag(TagName),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Str(String),
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Compare(CompareOp),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '&' if next == Some('&') => {
+                tokens.push(Token::And);
+                i += 2;
+            }
+            '|' if next == Some('|') => {
+                tokens.push(Token::Or);
+                i += 2;
+            }
+            '=' if next == Some('=') => {
+                tokens.push(Token::Compare(CompareOp::Eq));
+                i += 2;
+            }
+            '!' if next == Some('=') => {
+                tokens.push(Token::Compare(CompareOp::Ne));
+                i += 2;
+            }
+            '!' => {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            '<' | '>' => {
+                let op = match (c, next == Some('=')) {
+                    ('<', false) => CompareOp::Lt,
+                    ('<', true) => CompareOp::Le,
+                    ('>', false) => CompareOp::Gt,
+                    _ => CompareOp::Ge,
+                };
+                tokens.push(Token::Compare(op));
+                i += if next == Some('=') { 2 } else { 1 };
+            }
+            '"' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&c| c == '"')
+                    .ok_or("Unterminated string")?;
+                tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            _ if c.is_ascii_digit() || c == '.' || c == '-' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let number = text.parse::<f64>().map_err(|_| format!("Invalid number '{}'", text))?;
+                tokens.push(Token::Number(number));
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            _ => return Err(format!("Unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("Expected {:?}, found {:?}", expected, token)),
+            None => Err(format!("Expected {:?}, found end of expression", expected)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.next();
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            _ => self.parse_comparison(),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, String> {
+        let left = self.parse_operand()?;
+        let op = match self.next() {
+            Some(Token::Compare(op)) => op,
+            Some(token) => return Err(format!("Expected a comparison, found {:?}", token)),
+            None => return Err("Expected a comparison, found end of expression".to_string()),
+        };
+        let right = self.parse_operand()?;
+        Ok(Expr::Compare(op, left, right))
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Operand::Number(number)),
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "length" => Ok(Operand::Length),
+                "mapq" => Ok(Operand::Mapq),
+                "flag" => Ok(Operand::Flag),
+                "pos" => Ok(Operand::Pos),
+                "ref_id" => Ok(Operand::RefId),
+                "tag" => {
+                    self.expect(Token::LParen)?;
+                    let tag_name = match self.next() {
+                        Some(Token::Str(s)) if s.len() == 2 && s.is_ascii() => {
+                            [s.as_bytes()[0], s.as_bytes()[1]]
+                        }
+                        _ => return Err("tag() expects a 2-character tag name in quotes".to_string()),
+                    };
+                    self.expect(Token::RParen)?;
+                    Ok(Operand::Tag(tag_name))
+                }
+                _ => Err(format!("Unknown field '{}'", ident)),
+            },
+            Some(token) => Err(format!("Expected a value, found {:?}", token)),
+            None => Err("Expected a value, found end of expression".to_string()),
+        }
+    }
+}
+
+/// Compile an expression, returning a description of the problem if it is malformed
+pub fn compile(expression: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+    };
+    let expr = parser.parse_or()?;
+    if let Some(token) = parser.peek() {
+        return Err(format!("Unexpected {:?} after the end of the expression", token));
+    }
+    Ok(expr)
+}
+
+impl CompareOp {
+    pub fn apply(&self, left: f64, right: f64) -> bool {
+        match self {
+            CompareOp::Eq => left == right,
+            CompareOp::Ne => left != right,
+            CompareOp::Lt => left < right,
+            CompareOp::Le => left <= right,
+            CompareOp::Gt => left > right,
+            CompareOp::Ge => left >= right,
+        }
+    }
+}
+
+impl Operand {
+    fn value(&self, record: &Record) -> Option<f64> {
+        match self {
+            Operand::Number(number) => Some(*number),
+            Operand::Length => Some(record.query_len() as f64),
+            Operand::Mapq => Some(record.mapq() as f64),
+            Operand::Flag => Some(record.flag().0 as f64),
+            Operand::Pos => Some(record.start() as f64),
+            Operand::RefId => Some(record.ref_id() as f64),
+            Operand::Tag(tag_name) => match record.tags().get(tag_name) {
+                Some(TagValue::Int(value, _)) => Some(value as f64),
+                Some(TagValue::Float(value)) => Some(value as f64),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Expr {
+    pub fn evaluate(&self, record: &Record) -> bool {
+        match self {
+            Expr::Or(a, b) => a.evaluate(record) || b.evaluate(record),
+            Expr::And(a, b) => a.evaluate(record) && b.evaluate(record),
+            Expr::Not(a) => !a.evaluate(record),
+            Expr::Compare(op, left, right) => match (left.value(record), right.value(record)) {
+                (Some(left), Some(right)) => op.apply(left, right),
+                _ => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn record(len: usize, mapq: u8, nm: Option<u8>) -> Record {
+        let mut record = Record::new();
+        record.set_seq_qual(std::iter::repeat_n(b'A', len), std::iter::repeat_n(30, len)).unwrap();
+        record.set_mapq(mapq);
+        if let Some(nm) = nm {
+            record.tags_mut().push_num(b"NM", nm);
+        }
+        record
+    }
+
+    #[rstest]
+    #[case("length >= 18 && mapq > 20", 20, 30, None, true)]
+    #[case("length >= 18 && mapq > 20", 17, 30, None, false)]
+    #[case("length >= 18 && mapq > 20", 20, 20, None, false)]
+    #[case("tag(\"NM\") < 3", 20, 30, Some(2), true)]
+    #[case("tag(\"NM\") < 3", 20, 30, Some(3), false)]
+    #[case("tag(\"NM\") < 3", 20, 30, None, false)]
+    #[case("!(tag(\"NM\") >= 3) || length == 4", 4, 0, None, true)]
+    #[case("mapq != 0 && (length < 10 || length > 30)", 40, 5, None, true)]
+    fn test_evaluate(#[case] expression: &str, #[case] len: usize, #[case] mapq: u8,
+                     #[case] nm: Option<u8>, #[case] expected: bool) {
+        let expr = compile(expression).unwrap();
+        assert_eq!(expr.evaluate(&record(len, mapq, nm)), expected);
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("length >")]
+    #[case("length >= 18 &&")]
+    #[case("(length >= 18")]
+    #[case("width > 3")]
+    #[case("tag(\"NMX\") > 3")]
+    #[case("length > 3 mapq")]
+    #[case("length $ 3")]
+    fn test_compile_errors(#[case] expression: &str) {
+        assert!(compile(expression).is_err());
+    }
+}
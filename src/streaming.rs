@@ -0,0 +1,379 @@
+extern crate bam;
+
+use std::collections::{HashMap, VecDeque};
+
+use bam::Record;
+
+use crate::filters::Filtering;
+use crate::pairing;
+use crate::pairing::MateBuffer;
+use crate::utils;
+use crate::utils::BoolOperator;
+
+/// A filter whose decision for a record can depend on other records in the input.
+/// Records are pushed in input order, and decisions are released in the same order once they are final.
+pub trait StreamingFilter {
+    /// Push the next input record, appending every record whose decision is now final to `decided`.
+    fn push(&mut self, record: Record, decided: &mut Vec<(Record, bool)>);
+
+    /// Signal the end of the input, appending all remaining records to `decided`.
+    fn finish(&mut self, decided: &mut Vec<(Record, bool)>);
+}
+
+struct PendingRecord {
+    record: Record,
+    passed: bool,
+    decision: Option<bool>,
+}
+
+/// Decides on both mates of a pair together, combining their individual filter results with a boolean operator
+/// (e.g. AND keeps a pair only if both mates pass the filter).
+/// Unpaired, secondary and supplementary records are decided on their own.
+///
+/// A read is held back until its mate arrives, and so are all reads after it, to preserve the input order.
+/// On name-sorted (or name-grouped) input mates are adjacent and the buffer stays small;
+/// on coordinate-sorted input mates can be far apart, and many reads may be buffered.
+/// A read whose mate never appears is combined as if its mate failed the filter.
+/// An optional buffer limit bounds the memory use: once exceeded, the oldest read still waiting for its mate
+/// is decided as if its mate was missing, and a warning is printed since this suggests unsorted input.
+pub struct MatePairStream {
+    filter: Box<dyn Filtering>,
+    operator: BoolOperator,
+    opposite: bool,
+    pending: VecDeque<PendingRecord>,
+    // position in the input of the first pending record
+    first_index: usize,
+    // positions in the input of the pending reads that are waiting for their mates
+    mates: MateBuffer<usize>,
+    max_buffer: Option<usize>,
+    warned: bool,
+}
+
+impl MatePairStream {
+    pub fn new(filter: Box<dyn Filtering>, operator: BoolOperator, opposite: bool) -> MatePairStream {
+        MatePairStream {
+            filter,
+            operator,
+            opposite,
+            pending: VecDeque::new(),
+            first_index: 0,
+            mates: MateBuffer::new(),
+            max_buffer: None,
+            warned: false,
+        }
+    }
+
+    pub fn with_max_buffer(mut self, max_buffer: usize) -> MatePairStream {
+        assert!(max_buffer > 0, "Pair buffer size must be greater than 0!");
+        self.max_buffer = Some(max_buffer);
+        self
+    }
+
+    fn decide_as_orphan(&mut self, index: usize) {
+        let pending = &mut self.pending[index - self.first_index];
+        pending.decision = Some(utils::_opposite(
+            self.operator.apply(pending.passed, false),
+            self.opposite,
+        ));
+    }
+
+    fn release(&mut self, decided: &mut Vec<(Record, bool)>) {
+        while let Some(PendingRecord { decision: Some(_), .. }) = self.pending.front() {
+            let pending = self.pending.pop_front().unwrap();
+            self.first_index += 1;
+            decided.push((pending.record, pending.decision.unwrap()));
+        }
+    }
+}
+
+impl StreamingFilter for MatePairStream {
+    fn push(&mut self, record: Record, decided: &mut Vec<(Record, bool)>) {
+        let passed = self.filter.apply_to(&record);
+        let index = self.first_index + self.pending.len();
+        let decision = if !pairing::has_mate(&record) {
+            Some(utils::_opposite(passed, self.opposite))
+        } else if let Some((mate_index, _)) = self.mates.push(record.name(), index) {
+            let mate = &mut self.pending[mate_index - self.first_index];
+            let pair_decision = utils::_opposite(self.operator.apply(mate.passed, passed), self.opposite);
+            mate.decision = Some(pair_decision);
+            Some(pair_decision)
+        } else {
+            None
+        };
+        self.pending.push_back(PendingRecord {
+            record,
+            passed,
+            decision,
+        });
+        // the buffer limit counts every held back read, including decided reads behind the oldest waiting one,
+        // which is always the first pending read
+        if let Some(max_buffer) = self.max_buffer {
+            if self.pending.len() > max_buffer {
+                if !self.warned {
+                    eprintln!(
+                        "Warning: more than {} reads are waiting for their mates. \
+                        Is the input sorted by read name? Reads whose mate is not found in time are treated as unpaired.",
+                        max_buffer
+                    );
+                    self.warned = true;
+                }
+                if let Some(index) = self.mates.pop_oldest() {
+                    self.decide_as_orphan(index);
+                }
+            }
+        }
+        self.release(decided);
+    }
+
+    fn finish(&mut self, decided: &mut Vec<(Record, bool)>) {
+        while let Some(index) = self.mates.pop_oldest() {
+            self.decide_as_orphan(index);
+        }
+        self.release(decided);
+    }
+}
+
+/// Decides on reads by the mean mismatch rate (NM divided by the read length) of their read group:
+/// reads of groups whose mean rate is below `max_rate` pass. Reads without an RG tag form a group of their own,
+/// and reads without an NM tag don't count towards their group's mean. Groups without any such read fail.
+///
+/// This takes two passes: a group's mean is only known once all of its reads were seen, so every record
+/// is held in memory until the end of the input, and all decisions are released by `finish`.
+pub struct ReadGroupRateStream {
+    max_rate: f64,
+    opposite: bool,
+    records: Vec<Record>,
+    // sum and number of the mismatch rates of each read group
+    rates: HashMap<Vec<u8>, (f64, u64)>,
+}
+
+impl ReadGroupRateStream {
+    pub fn new(max_rate: f64, opposite: bool) -> ReadGroupRateStream {
+        ReadGroupRateStream {
+            max_rate,
+            opposite,
+            records: Vec::new(),
+            rates: HashMap::new(),
+        }
+    }
+}
+
+impl StreamingFilter for ReadGroupRateStream {
+    fn push(&mut self, record: Record, _decided: &mut Vec<(Record, bool)>) {
+        let read_group = utils::read_group(&record).unwrap_or_default().to_vec();
+        let (sum, count) = self.rates.entry(read_group).or_insert((0.0, 0));
+        if let Some(rate) = utils::mismatch_rate(&record) {
+            *sum += rate;
+            *count += 1;
+        }
+        self.records.push(record);
+    }
+
+    fn finish(&mut self, decided: &mut Vec<(Record, bool)>) {
+        for record in self.records.drain(..) {
+            let read_group = utils::read_group(&record).unwrap_or_default();
+            let (sum, count) = self.rates[read_group];
+            let passed = count > 0 && sum / (count as f64) < self.max_rate;
+            decided.push((record, utils::_opposite(passed, self.opposite)));
+        }
+    }
+}
+
+/// Decides on records by their 0-based position in the input: records within `range` pass.
+/// Each record is decided as soon as it is pushed
+pub struct RecordIndexStream {
+    range: utils::RangeBound<u64>,
+    opposite: bool,
+    next_index: u64,
+}
+
+impl RecordIndexStream {
+    pub fn new(range: utils::RangeBound<u64>, opposite: bool) -> RecordIndexStream {
+        RecordIndexStream {
+            range,
+            opposite,
+            next_index: 0,
+        }
+    }
+}
+
+impl StreamingFilter for RecordIndexStream {
+    fn push(&mut self, record: Record, decided: &mut Vec<(Record, bool)>) {
+        let passed = self.range.contains(self.next_index);
+        self.next_index += 1;
+        decided.push((record, utils::_opposite(passed, self.opposite)));
+    }
+
+    fn finish(&mut self, _decided: &mut Vec<(Record, bool)>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::filters::MapqFilter;
+
+    fn mate(name: &str, mapq: u8, first: bool) -> Record {
+        let mut record = Record::new();
+        record.set_name(name.bytes());
+        record.set_mapq(mapq);
+        record.flag_mut().set_paired(true);
+        record.flag_mut().set_first_in_pair(first);
+        record.flag_mut().set_last_in_pair(!first);
+        record
+    }
+
+    fn run_stream(stream: &mut dyn StreamingFilter, records: Vec<Record>) -> Vec<(String, bool)> {
+        let mut decided = Vec::new();
+        for record in records {
+            stream.push(record, &mut decided);
+        }
+        stream.finish(&mut decided);
+        decided
+            .into_iter()
+            .map(|(record, keep)| (String::from_utf8(record.name().to_vec()).unwrap(), keep))
+            .collect()
+    }
+
+    #[rstest]
+    fn test_mate_pair_stream_and_drops_both_mates() {
+        let filter = Box::new(MapqFilter::new("mapq".to_string(), 20, 60, false));
+        let mut stream = MatePairStream::new(filter, BoolOperator::AND, false);
+        let decided = run_stream(
+            &mut stream,
+            vec![
+                mate("pair1", 30, true),
+                mate("pair2", 30, true),
+                mate("pair1", 5, false),
+                mate("pair2", 40, false),
+                mate("orphan", 50, true),
+            ],
+        );
+        assert_eq!(
+            decided,
+            vec![
+                ("pair1".to_string(), false),
+                ("pair2".to_string(), true),
+                ("pair1".to_string(), false),
+                ("pair2".to_string(), true),
+                ("orphan".to_string(), false),
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_mate_pair_stream_unpaired_records() {
+        let filter = Box::new(MapqFilter::new("mapq".to_string(), 20, 60, false));
+        let mut stream = MatePairStream::new(filter, BoolOperator::AND, false);
+        let mut single = mate("single", 30, true);
+        single.flag_mut().set_paired(false);
+        let decided = run_stream(&mut stream, vec![single]);
+        assert_eq!(decided, vec![("single".to_string(), true)]);
+    }
+
+    #[rstest]
+    fn test_mate_pair_stream_or_keeps_both_mates() {
+        let filter = Box::new(MapqFilter::new("mapq".to_string(), 20, 60, false));
+        let mut stream = MatePairStream::new(filter, BoolOperator::OR, false);
+        let decided = run_stream(
+            &mut stream,
+            vec![mate("pair1", 30, true), mate("pair1", 5, false), mate("orphan", 5, true)],
+        );
+        assert_eq!(
+            decided,
+            vec![
+                ("pair1".to_string(), true),
+                ("pair1".to_string(), true),
+                ("orphan".to_string(), false),
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_mate_pair_stream_max_buffer() {
+        let filter = Box::new(MapqFilter::new("mapq".to_string(), 20, 60, false));
+        let mut stream = MatePairStream::new(filter, BoolOperator::OR, false).with_max_buffer(2);
+        // the mates of pair1 and pair2 are too far apart for the buffer, so they are decided separately
+        let decided = run_stream(
+            &mut stream,
+            vec![
+                mate("pair1", 5, true),
+                mate("pair2", 30, true),
+                mate("pair3", 30, true),
+                mate("pair1", 30, false),
+                mate("pair2", 5, false),
+            ],
+        );
+        assert_eq!(
+            decided,
+            vec![
+                ("pair1".to_string(), false),
+                ("pair2".to_string(), true),
+                ("pair3".to_string(), true),
+                ("pair1".to_string(), true),
+                ("pair2".to_string(), false),
+            ]
+        );
+    }
+
+    fn grouped_read(name: &str, read_group: &str, nm: Option<u8>) -> Record {
+        let mut record = Record::new();
+        record.set_name(name.bytes());
+        record.set_seq_qual(std::iter::repeat_n(b'A', 100), std::iter::repeat_n(30, 100)).unwrap();
+        if !read_group.is_empty() {
+            record.tags_mut().push_string(b"RG", read_group.as_bytes());
+        }
+        if let Some(nm) = nm {
+            record.tags_mut().push_num(b"NM", nm);
+        }
+        record
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    fn test_read_group_rate_stream(#[case] opposite: bool) {
+        // the mean rate of 'good' is 0.01 and that of 'bad' is 0.05; 'untagged' has no NM to measure
+        let mut stream = ReadGroupRateStream::new(0.02, opposite);
+        let decided = run_stream(
+            &mut stream,
+            vec![
+                grouped_read("good1", "good", Some(0)),
+                grouped_read("bad1", "bad", Some(1)),
+                grouped_read("good2", "good", Some(2)),
+                grouped_read("bad2", "bad", Some(9)),
+                grouped_read("good3", "good", None),
+                grouped_read("untagged", "", None),
+            ],
+        );
+        let kept = [
+            ("good1", true),
+            ("bad1", false),
+            ("good2", true),
+            ("bad2", false),
+            ("good3", true),
+            ("untagged", false),
+        ];
+        let expected: Vec<(String, bool)> =
+            kept.iter().map(|&(name, keep)| (name.to_string(), keep != opposite)).collect();
+        assert_eq!(decided, expected);
+    }
+
+    #[rstest]
+    #[case(utils::Limit(Some(0)), utils::Limit(Some(9)), (0..10).collect())]
+    #[case(utils::Limit(Some(5)), utils::Limit(Some(5)), vec![5])]
+    #[case(utils::Limit(Some(12)), utils::Limit(None), vec![12, 13, 14])]
+    fn test_record_index_stream(#[case] first: utils::Limit<u64>, #[case] last: utils::Limit<u64>,
+                                #[case] expected: Vec<usize>) {
+        let records: Vec<Record> = (0..15).map(|i| mate(&format!("read{}", i), 30, true)).collect();
+        let mut stream = RecordIndexStream::new(utils::RangeBound::new(first, last), false);
+        let decided = run_stream(&mut stream, records);
+        assert_eq!(decided.len(), 15);
+        let passed: Vec<usize> = decided.iter().enumerate().filter(|(_, (_, keep))| *keep).map(|(i, _)| i).collect();
+        assert_eq!(passed, expected);
+        for (i, (name, _)) in decided.iter().enumerate() {
+            assert_eq!(name, &format!("read{}", i));
+        }
+    }
+}
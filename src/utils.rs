@@ -2,14 +2,22 @@ extern crate bam;
 extern crate clap;
 extern crate strum_macros;
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
 use strum_macros::{Display, EnumString};
 
+use bam::record::cigar::{Cigar, Operation};
 use bam::record::tags::{IntegerType, StringType, TagName, TagValue};
+use bam::Record;
 use clap::ValueEnum;
-use serde::{
-    ser::{SerializeStruct, Serializer},
-    Deserialize, Serialize,
-};
+use serde::{Deserialize, Serialize};
+
+/// Whether a tag name follows the SAM specification: a letter followed by a letter or digit
+pub fn is_valid_tag_name(tag_name: &TagName) -> bool {
+    tag_name[0].is_ascii_alphabetic() && tag_name[1].is_ascii_alphanumeric()
+}
 
 pub fn str_to_tag_name(s: &str) -> TagName {
     if s.len() != 2 {
@@ -18,8 +26,7 @@ pub fn str_to_tag_name(s: &str) -> TagName {
     let mut chars = s.chars();
     let first = chars.next().unwrap();
     let second = chars.next().unwrap();
-    let tag_name = [first as u8, second as u8];
-    return tag_name;
+    [first as u8, second as u8]
 }
 #[derive(Serialize, Deserialize, Clone, Display)]
 pub enum MinimalTagValue {
@@ -36,12 +43,29 @@ pub enum CliTagType {
     Float,
     String,
 }
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, ValueEnum, EnumString)]
 pub enum SupportedFormats {
     BAM,
     SAM,
 }
 
+/// Detect the format of a file from its first bytes: BAM is BGZF-compressed (a gzip member with a 'BC' extra subfield),
+/// and SAM is plain text. Returns None for other contents (e.g. plain gzip), or if the file can't be read
+pub fn sniff_format(path: &Path) -> Option<SupportedFormats> {
+    use std::io::Read;
+
+    let mut start = Vec::with_capacity(16);
+    std::fs::File::open(path).ok()?.take(16).read_to_end(&mut start).ok()?;
+    match start.as_slice() {
+        [0x1f, 0x8b, _, flags, _, _, _, _, _, _, _, _, b'B', b'C', ..] if flags & 0x04 != 0 => Some(SupportedFormats::BAM),
+        [0x1f, 0x8b, ..] => None,
+        _ if start.iter().all(|&c| c.is_ascii_graphic() || c.is_ascii_whitespace()) => Some(SupportedFormats::SAM),
+        _ => None,
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, ValueEnum, EnumString, Serialize, Deserialize)]
 pub enum BoolOperator {
     AND,
@@ -53,8 +77,326 @@ pub enum BoolOperator {
     IMPLIES,
 }
 
+impl BoolOperator {
+    pub fn apply(&self, a: bool, b: bool) -> bool {
+        match self {
+            BoolOperator::AND => a && b,
+            BoolOperator::OR => a || b,
+            BoolOperator::XOR => a ^ b,
+            BoolOperator::XNOR => !(a ^ b),
+            BoolOperator::NAND => !(a && b),
+            BoolOperator::NOR => !(a || b),
+            BoolOperator::IMPLIES => !a || b,
+        }
+    }
+
+    /// The result of the operator if it is already decided by its first operand, without evaluating the second.
+    /// Only AND and OR short-circuit.
+    pub fn short_circuit(&self, a: bool) -> Option<bool> {
+        match (self, a) {
+            (BoolOperator::AND, false) => Some(false),
+            (BoolOperator::OR, true) => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// Which soft-clipped end of a read to examine, relative to the original read orientation (5' to 3')
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+pub enum ClipSide {
+    Leading,
+    Trailing,
+    Either,
+}
+
+/// How the aligned span of a read relates to a region
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+pub enum RegionMode {
+    /// Entirely within the region
+    Contained,
+    /// Overlapping the region and extending past at least one of its ends
+    Spanning,
+    /// Overlapping the region at all (contained or spanning)
+    Overlapping,
+}
+
+impl RegionMode {
+    /// Whether the 0-based half-open span `[start, end)` is in this relation to the region `[region_start, region_end)`
+    pub fn matches(&self, (start, end): (i64, i64), (region_start, region_end): (i64, i64)) -> bool {
+        let overlaps = start < region_end && end > region_start;
+        let contained = start >= region_start && end <= region_end;
+        match self {
+            RegionMode::Contained => contained,
+            RegionMode::Spanning => overlaps && !contained,
+            RegionMode::Overlapping => overlaps,
+        }
+    }
+}
+
+/// The strand of the reference that a read is aligned to
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
+
+/// A field of an Illumina read name (instrument:run:flowcell:lane:tile:x:y).
+/// Fields can also be given by their 0-based index
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+pub enum NameField {
+    #[value(alias = "0")]
+    Instrument,
+    #[value(alias = "1")]
+    Run,
+    #[value(alias = "2")]
+    Flowcell,
+    #[value(alias = "3")]
+    Lane,
+    #[value(alias = "4")]
+    Tile,
+    #[value(alias = "5")]
+    X,
+    #[value(alias = "6")]
+    Y,
+}
+
+impl NameField {
+    /// The value of this field in an Illumina read name, or None if the name has fewer than 7 fields
+    pub fn value_in<'a>(&self, read_name: &'a [u8]) -> Option<&'a [u8]> {
+        let fields: Vec<&[u8]> = read_name.split(|&c| c == b':').collect();
+        if fields.len() < 7 {
+            return None;
+        }
+        Some(fields[*self as usize])
+    }
+}
+
+/// A value a read name field must be equal to, or an inclusive numeric range given as 'min-max' (e.g. '1101-1120'),
+/// either end of which may be '*'
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum NameFieldMatch {
+    Exact(String),
+    Range(u32, u32),
+}
+
+impl NameFieldMatch {
+    pub fn parse(pattern: &str) -> NameFieldMatch {
+        let range = pattern
+            .split_once('-')
+            .and_then(|(min, max)| Some((min.parse::<Limit<u32>>().ok()?, max.parse::<Limit<u32>>().ok()?)));
+        match range {
+            Some((min, max)) => {
+                let range = RangeBound::new(min, max);
+                NameFieldMatch::Range(range.min_value(), range.max_value())
+            }
+            None => NameFieldMatch::Exact(pattern.to_string()),
+        }
+    }
+
+    pub fn matches(&self, value: &[u8]) -> bool {
+        match self {
+            NameFieldMatch::Exact(expected) => value == expected.as_bytes(),
+            NameFieldMatch::Range(min, max) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<u32>().ok())
+                .is_some_and(|value| RangeBound::from_values(*min, *max).contains(value)),
+        }
+    }
+}
+
+impl std::fmt::Display for NameFieldMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameFieldMatch::Exact(expected) => write!(f, "{}", expected),
+            NameFieldMatch::Range(min, max) => {
+                let range = RangeBound::from_values(*min, *max);
+                write!(f, "{}-{}", range.min_str(), range.max_str())
+            }
+        }
+    }
+}
+
+/// Library type of stranded paired-end RNA-seq, which tells how the read strands relate to the transcript strand
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum, Serialize, Deserialize)]
+pub enum LibraryType {
+    /// The first read is aligned to the opposite strand of the transcript (e.g. dUTP protocols)
+    FrFirststrand,
+    /// The first read is aligned to the same strand as the transcript (e.g. ligation protocols)
+    FrSecondstrand,
+}
+
+impl LibraryType {
+    /// Strand of the transcript a mapped read comes from. Unpaired reads are treated as first reads
+    pub fn transcript_strand(&self, record: &Record) -> Strand {
+        let flag = record.flag();
+        let is_first = !flag.is_paired() || flag.first_in_pair();
+        let same_as_read = (*self == LibraryType::FrSecondstrand) == is_first;
+        if same_as_read != flag.is_reverse_strand() {
+            Strand::Forward
+        } else {
+            Strand::Reverse
+        }
+    }
+}
+
+/// Common names of the mitochondrial genome in reference assemblies
+pub const MITO_CONTIGS: &[&str] = &["chrM", "chrMT", "MT", "M"];
+
+/// Common name patterns of unplaced, alternate and decoy contigs in reference assemblies
+pub const DECOY_CONTIGS: &[&str] = &["*_random", "*_alt", "*_decoy", "chrUn_*", "chrEBV", "hs37d5"];
+
+/// Sort orders that can be declared in the `SO` field of a SAM/BAM header
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+pub enum SortOrder {
+    Coordinate,
+    Queryname,
+    Unsorted,
+}
+
+impl SortOrder {
+    pub fn header_value(&self) -> &'static str {
+        match self {
+            SortOrder::Coordinate => "coordinate",
+            SortOrder::Queryname => "queryname",
+            SortOrder::Unsorted => "unsorted",
+        }
+    }
+}
+
+/// The sort order declared in the `@HD` line of a header, if any
+pub fn header_sort_order(header: &bam::Header) -> Option<&str> {
+    header.lines().find_map(|line| match line {
+        bam::header::HeaderLine::Entry(entry) if entry.entry_type() == bam::header::EntryType::HeaderLine => {
+            entry.get(b"SO")
+        }
+        _ => None,
+    })
+}
+
+/// Output format of `view`
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+pub enum ViewFormat {
+    /// A summary line, then the name, id and description of each filter
+    Human,
+    /// A JSON array with the name, id, type, opposite flag and parameters of each filter
+    Json,
+    /// A table with the name, type, opposite flag and parameters of each filter
+    Tsv,
+}
+
+/// What `apply --default` does with the reads that don't match the filters
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+pub enum DefaultAction {
+    Keep,
+    Drop,
+}
+
+/// What `apply --max-records` counts
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+pub enum RecordLimit {
+    /// Stop once this many records were written to the output
+    Output,
+    /// Stop once this many records were read from the input
+    Input,
+}
+
+/// Numeric types whose full range can serve as an open-ended bound
+pub trait Bounded: Copy + PartialOrd + std::fmt::Display {
+    const LOWEST: Self;
+    const HIGHEST: Self;
+}
+
+impl Bounded for u8 {
+    const LOWEST: u8 = u8::MIN;
+    const HIGHEST: u8 = u8::MAX;
+}
+
+impl Bounded for u32 {
+    const LOWEST: u32 = u32::MIN;
+    const HIGHEST: u32 = u32::MAX;
+}
+
+impl Bounded for u64 {
+    const LOWEST: u64 = u64::MIN;
+    const HIGHEST: u64 = u64::MAX;
+}
+
+/// One end of a range given on the command line. '*' leaves that end of the range open
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Limit<T>(pub Option<T>);
+
+impl<T> From<T> for Limit<T> {
+    fn from(value: T) -> Limit<T> {
+        Limit(Some(value))
+    }
+}
+
+impl<T: std::str::FromStr> std::str::FromStr for Limit<T>
+where
+    T::Err: std::fmt::Display,
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Limit<T>, String> {
+        if s == "*" {
+            return Ok(Limit(None));
+        }
+        s.parse().map(|value| Limit(Some(value))).map_err(|e| format!("{} (use '*' for no bound)", e))
+    }
+}
+
+/// An inclusive numeric range, either end of which may be open.
+/// An open end is stored by the filters as the bound of the value type, and shown as '*'
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RangeBound<T> {
+    min: Option<T>,
+    max: Option<T>,
+}
+
+impl<T: Bounded> RangeBound<T> {
+    pub fn new(min: Limit<T>, max: Limit<T>) -> RangeBound<T> {
+        if let (Some(min), Some(max)) = (min.0, max.0) {
+            assert!(min <= max, "Minimum ({}) must not be greater than maximum ({})!", min, max);
+        }
+        RangeBound { min: min.0, max: max.0 }
+    }
+
+    /// A range from the values stored in a filter, where the bounds of the type stand for open ends
+    pub fn from_values(min: T, max: T) -> RangeBound<T> {
+        RangeBound {
+            min: if min == T::LOWEST { None } else { Some(min) },
+            max: if max == T::HIGHEST { None } else { Some(max) },
+        }
+    }
+
+    pub fn min_value(&self) -> T {
+        self.min.unwrap_or(T::LOWEST)
+    }
+
+    pub fn max_value(&self) -> T {
+        self.max.unwrap_or(T::HIGHEST)
+    }
+
+    pub fn contains(&self, value: T) -> bool {
+        value >= self.min_value() && value <= self.max_value()
+    }
+
+    /// Whether no value is in the range, which can only happen in a hand-edited config
+    pub fn is_empty(&self) -> bool {
+        self.min_value() > self.max_value()
+    }
+
+    pub fn min_str(&self) -> String {
+        self.min.map_or("*".to_string(), |min| min.to_string())
+    }
+
+    pub fn max_str(&self) -> String {
+        self.max.map_or("*".to_string(), |max| max.to_string())
+    }
+}
+
 pub fn _opposite(boolean: bool, opposite: bool) -> bool {
-    return if opposite { !boolean } else { boolean };
+    if opposite { !boolean } else { boolean }
 }
 
 pub fn convert_to_minimal_tag_value(
@@ -77,17 +419,10 @@ pub fn convert_to_minimal_tag_value(
         CliTagType::String => Ok(MinimalTagValue::String(data.to_string())),
     }
 }
-pub fn _minimal_tag_to_tag(tag: &MinimalTagValue) -> TagValue {
+pub fn _minimal_tag_to_tag(tag: &MinimalTagValue) -> TagValue<'_> {
     match tag {
         MinimalTagValue::Char(c) => TagValue::Char(*c),
-        MinimalTagValue::Int(i) => {
-            let int_type = if *i < 0 {
-                IntegerType::I32
-            } else {
-                IntegerType::U32
-            };
-            TagValue::Int(*i, IntegerType::I32)
-        }
+        MinimalTagValue::Int(i) => TagValue::Int(*i, IntegerType::I32),
         MinimalTagValue::Float(f) => TagValue::Float(*f),
         MinimalTagValue::String(s) => TagValue::String(s.as_bytes(), StringType::String),
     }
@@ -95,7 +430,8 @@ pub fn _minimal_tag_to_tag(tag: &MinimalTagValue) -> TagValue {
 pub fn _are_tag_values_equal(a: &TagValue, b: &TagValue) -> bool {
     match (a, b) {
         (TagValue::Char(a), TagValue::Char(b)) => a == b,
-        (TagValue::Int(a, a_type), TagValue::Int(b, b_type)) => a == b && a_type == b_type,
+        // BAM files may store an integer tag with any integer subtype (e.g. NM as U8), so only the values are compared
+        (TagValue::Int(a, _), TagValue::Int(b, _)) => a == b,
         (TagValue::Float(a), TagValue::Float(b)) => a == b,
         (TagValue::String(a, a_type), TagValue::String(b, b_type)) => a == b && a_type == b_type,
         (TagValue::IntArray(a), TagValue::IntArray(b)) => a.raw() == b.raw(),
@@ -104,6 +440,649 @@ pub fn _are_tag_values_equal(a: &TagValue, b: &TagValue) -> bool {
     }
 }
 
+/// Like `_are_tag_values_equal`, but integer and float values are also compared numerically (e.g. 3 equals 3.0)
+pub fn _are_tag_values_numerically_equal(a: &TagValue, b: &TagValue) -> bool {
+    match (a, b) {
+        (TagValue::Int(a, _), TagValue::Float(b)) | (TagValue::Float(b), TagValue::Int(a, _)) => *a as f64 == *b as f64,
+        _ => _are_tag_values_equal(a, b),
+    }
+}
+
+/// Count the mismatched bases described by an MD tag (e.g. `10A5^AC6` has one mismatch).
+/// Deleted reference bases (following a `^`) are not counted. Returns None if the tag is malformed.
+pub fn count_md_mismatches(md: &str) -> Option<u32> {
+    let mut mismatches = 0;
+    let mut in_deletion = false;
+    let mut expect_number = true;
+    for c in md.chars() {
+        if c.is_ascii_digit() {
+            in_deletion = false;
+            expect_number = false;
+        } else if c == '^' {
+            if in_deletion || expect_number {
+                return None;
+            }
+            in_deletion = true;
+        } else if c.is_ascii_uppercase() {
+            if !in_deletion {
+                if expect_number {
+                    return None;
+                }
+                mismatches += 1;
+                expect_number = true;
+            }
+        } else {
+            return None;
+        }
+    }
+    if expect_number || in_deletion {
+        return None;
+    }
+    Some(mismatches)
+}
+
+/// Whether `motif` occurs in `seq` with at most `max_mismatch` mismatched bases (Hamming distance, no indels).
+/// Each window is abandoned as soon as it has too many mismatches.
+pub fn contains_with_mismatches(seq: &[u8], motif: &[u8], max_mismatch: u32) -> bool {
+    seq.windows(motif.len()).any(|window| {
+        let mut mismatches = 0;
+        for (a, b) in window.iter().zip(motif) {
+            if a != b {
+                mismatches += 1;
+                if mismatches > max_mismatch {
+                    return false;
+                }
+            }
+        }
+        true
+    })
+}
+
+/// The read group ID of a record (its `RG` tag), if it has one
+pub fn read_group(record: &Record) -> Option<&[u8]> {
+    match record.tags().get(b"RG") {
+        Some(TagValue::String(read_group, _)) => Some(read_group),
+        _ => None,
+    }
+}
+
+/// The number of mismatches of a read (its `NM` tag) divided by its length.
+/// None for reads without a numeric `NM` tag or without a sequence
+pub fn mismatch_rate(record: &Record) -> Option<f64> {
+    let mismatches = match record.tags().get(b"NM") {
+        Some(TagValue::Int(mismatches, _)) => mismatches as f64,
+        _ => return None,
+    };
+    let len = record.query_len();
+    (len > 0).then(|| mismatches / len as f64)
+}
+
+/// The reverse complement of a nucleotide sequence. Bases other than A, C, G and T are kept as they are
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|&base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            other => other,
+        })
+        .collect()
+}
+
+/// Expand input paths containing wildcards (`*`, `?`, `[...]`) into the files they match.
+/// Paths without wildcards are passed through unchanged. Patterns that match nothing are reported.
+pub fn expand_input_paths(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut expanded = Vec::new();
+    for path in paths {
+        let pattern = path.to_string_lossy();
+        if !pattern.contains(['*', '?', '[']) || is_url(path) {
+            expanded.push(path.clone());
+            continue;
+        }
+        let matches: Vec<PathBuf> = glob::glob(&pattern)
+            .unwrap_or_else(|e| panic!("Invalid input pattern '{}': {}", pattern, e))
+            .filter_map(Result::ok)
+            .collect();
+        if matches.is_empty() {
+            eprintln!("Warning: input pattern '{}' did not match any files", pattern);
+        }
+        expanded.extend(matches);
+    }
+    expanded
+}
+
+/// Whether an input is given as an 'http://', 'https://' or 's3://' URL rather than a local path
+pub fn is_url(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    ["http://", "https://", "s3://"].iter().any(|scheme| path.starts_with(scheme))
+}
+
+/// A deterministic subsample of the reads, chosen by hashing their names with a seed, so that both mates of a pair
+/// are either in it or left out together. The hash does not depend on the platform or the Rust version
+#[derive(Clone, Copy, Debug)]
+pub struct Subsample {
+    fraction: f64,
+    seed: u64,
+}
+
+impl Subsample {
+    pub fn new(fraction: f64, seed: u64) -> Subsample {
+        assert!((0.0..=1.0).contains(&fraction), "Subsample fraction must be between 0 and 1!");
+        Subsample { fraction, seed }
+    }
+
+    pub fn contains(&self, name: &[u8]) -> bool {
+        // FNV-1a over the seed and the name, followed by the SplitMix64 finalizer to spread the bits
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &byte in self.seed.to_le_bytes().iter().chain(name) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^= hash >> 31;
+        ((hash >> 11) as f64 / (1u64 << 53) as f64) < self.fraction
+    }
+}
+
+/// The kinds of errors told apart by --json-errors
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    MissingFilter,
+    BadInput,
+    Io,
+    // any other error, including the unexpected ones
+    Error,
+}
+
+/// An error raised as the payload of a panic (see `fail`), so that its kind and the file it concerns
+/// can be reported without parsing its message
+#[derive(Debug)]
+pub struct CommandError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub path: Option<PathBuf>,
+}
+
+/// Raise an error of the given kind, concerning the given file
+pub fn fail(kind: ErrorKind, path: &Path, message: String) -> ! {
+    std::panic::panic_any(CommandError { kind, message, path: Some(path.to_path_buf()) })
+}
+
+/// Run `f`, adding `path` to the errors raised within it that don't tell which file they concern yet
+/// (e.g. a record that can't be read). Other panics are passed on as they are
+pub fn with_error_path<T>(path: &Path, f: impl FnOnce() -> T) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        match payload.downcast::<CommandError>() {
+            Ok(mut error) => {
+                error.path.get_or_insert_with(|| path.to_path_buf());
+                std::panic::resume_unwind(error)
+            }
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    })
+}
+
+/// Raise an I/O error, reported as '<action> <path>: <error>' (e.g. 'Could not read names file names.txt: ...')
+pub fn fail_io(path: &Path, action: &str, error: impl std::fmt::Display) -> ! {
+    fail(ErrorKind::Io, path, format!("{} {}: {}", action, path.display(), error))
+}
+
+/// Read a text file, decompressing it if it is gzip-compressed (also if compressed with bgzip).
+/// `kind` describes the file in error messages (e.g. 'BED')
+pub fn read_text_file(path: &Path, kind: &str) -> String {
+    use std::io::Read;
+
+    let contents = std::fs::read(path)
+        .unwrap_or_else(|e| fail_io(path, &format!("Could not read {} file", kind), e));
+    if !contents.starts_with(&[0x1f, 0x8b]) {
+        return String::from_utf8(contents)
+            .unwrap_or_else(|e| fail_io(path, &format!("Could not read {} file", kind), e));
+    }
+    let mut decompressed = String::new();
+    flate2::read::MultiGzDecoder::new(contents.as_slice())
+        .read_to_string(&mut decompressed)
+        .unwrap_or_else(|e| fail_io(path, &format!("Could not decompress {} file", kind), e));
+    decompressed
+}
+
+/// Read newline-separated filter names from a file, ignoring blank lines and '#' comments
+pub fn read_names_file(path: &Path) -> Vec<String> {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| fail_io(path, "Could not read names file", e));
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The real CIGAR of a record. CIGARs with more than 65535 operations don't fit in the CIGAR field of a BAM record,
+/// and are stored in the `CG` tag instead, with a `<query length>S<reference length>N` placeholder in the CIGAR field.
+pub fn effective_cigar(record: &Record) -> Cow<'_, Cigar> {
+    let cigar = record.cigar();
+    let is_placeholder = cigar.len() == 2
+        && cigar.at(0) == (record.query_len(), Operation::Soft)
+        && cigar.at(1).1 == Operation::Skip;
+    if is_placeholder {
+        if let Some(TagValue::IntArray(array)) = record.tags().get(b"CG") {
+            return Cow::Owned(Cigar::from_raw(&array.iter().map(|value| value as u32).collect::<Vec<u32>>()));
+        }
+    }
+    Cow::Borrowed(cigar)
+}
+
+/// Reference positions skipped by the `N` operations of a record's CIGAR (i.e. its introns),
+/// as 0-based inclusive (first, last) pairs
+pub fn intron_spans(record: &Record) -> Vec<(u32, u32)> {
+    let mut spans = Vec::new();
+    let mut position = record.start().max(0) as u32;
+    for (len, operation) in effective_cigar(record).iter() {
+        if operation == Operation::Skip && len > 0 {
+            spans.push((position, position + len - 1));
+        }
+        if operation.consumes_ref() {
+            position += len;
+        }
+    }
+    spans
+}
+
+/// The reference name and 0-based start position of each alignment listed in an `SA` tag, whose
+/// 'rname,pos,strand,CIGAR,mapQ,NM;' entries have 1-based positions. Malformed entries are skipped
+pub fn sa_positions(sa: &[u8]) -> Vec<(String, i32)> {
+    String::from_utf8_lossy(sa)
+        .split(';')
+        .filter_map(|entry| {
+            let mut fields = entry.split(',');
+            let ref_name = fields.next().filter(|ref_name| !ref_name.is_empty())?;
+            let position = fields.next()?.parse::<i32>().ok().filter(|&position| position > 0)?;
+            Some((ref_name.to_string(), position - 1))
+        })
+        .collect()
+}
+
+/// Read splice junctions from a tab-separated file with a reference name and the first and last intron base
+/// (1-based, inclusive) on each line, ignoring blank lines and '#' comments. The file may be gzip-compressed.
+/// Returns the introns of each reference as 0-based inclusive (first, last) pairs.
+pub fn read_junctions_file(path: &Path) -> BTreeMap<String, Vec<(u32, u32)>> {
+    let contents = read_text_file(path, "junctions");
+    let mut junctions: BTreeMap<String, Vec<(u32, u32)>> = BTreeMap::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let parsed = match fields[..] {
+            [ref_name, first, last, ..] => match (first.parse::<u32>(), last.parse::<u32>()) {
+                (Ok(first), Ok(last)) if !ref_name.is_empty() && first >= 1 && first <= last => {
+                    Some((ref_name, first, last))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let (ref_name, first, last) = parsed.unwrap_or_else(|| {
+            panic!(
+                "Invalid junction on line {} of {}: expected 'ref_name<TAB>intron_start<TAB>intron_end'",
+                line_number + 1,
+                path.display()
+            )
+        });
+        junctions.entry(ref_name.to_string()).or_default().push((first - 1, last - 1));
+    }
+    junctions
+}
+
+/// Read one numeric column of a tab-separated file with a header line, keyed by the read name in the first column.
+/// Blank lines and '#' comments are ignored.
+pub fn read_annotation_column(path: &Path, column: &str) -> HashMap<Vec<u8>, f64> {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| fail_io(path, "Could not read annotation file", e));
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    let (_, header) = lines
+        .next()
+        .unwrap_or_else(|| panic!("Annotation file {} is empty!", path.display()));
+    let index = header
+        .split('\t')
+        .position(|name| name == column)
+        .unwrap_or_else(|| panic!("Column '{}' is not in the header of annotation file {}!", column, path.display()));
+    assert!(index > 0, "Column '{}' holds the read names, not values!", column);
+    let mut values = HashMap::new();
+    for (line_number, line) in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let value = fields
+            .get(index)
+            .and_then(|value| value.trim().parse::<f64>().ok())
+            .unwrap_or_else(|| panic!("Invalid value of column '{}' on line {}: {}", column, line_number + 1, line));
+        values.insert(fields[0].as_bytes().to_vec(), value);
+    }
+    values
+}
+
+/// Read intervals as (reference name, start, end) with 0-based half-open coordinates, either from a BED file
+/// (which may be gzip-compressed, e.g. 'panel.bed.gz') or from the alignments of the mapped reads in a BAM/SAM file
+pub fn read_intervals(path: &Path) -> Vec<(String, u32, u32)> {
+    let extension_of =
+        |path: &Path| path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_string();
+    let mut extension = extension_of(path);
+    if extension == "gz" {
+        extension = match extension_of(&path.with_extension("")).as_str() {
+            "bed" => "bed".to_string(),
+            _ => "gz".to_string(),
+        };
+    }
+    match extension.as_str() {
+        "bam" | "sam" => {
+            let reader: Box<dyn bam::RecordReader<Item=std::io::Result<Record>>> = if extension == "bam" {
+                Box::new(bam::BamReader::from_path(path, 0).unwrap())
+            } else {
+                Box::new(bam::SamReader::from_path(path).unwrap())
+            };
+            let header = if extension == "bam" {
+                bam::BamReader::from_path(path, 0).unwrap().header().clone()
+            } else {
+                bam::SamReader::from_path(path).unwrap().header().clone()
+            };
+            reader
+                .map(|record| record.unwrap())
+                .filter(|record| record.flag().is_mapped() && record.ref_id() >= 0)
+                .map(|record| {
+                    let ref_name = header.reference_name(record.ref_id() as u32).unwrap().to_string();
+                    (ref_name, record.start() as u32, record.calculate_end() as u32)
+                })
+                .collect()
+        }
+        "bed" => {
+            let contents = read_text_file(path, "BED");
+            contents
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| {
+                    let line = line.trim();
+                    !(line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser"))
+                })
+                .map(|(line_number, line)| {
+                    let fields: Vec<&str> = line.split('\t').collect();
+                    let parsed = match fields[..] {
+                        [ref_name, start, end, ..] => match (start.parse::<u32>(), end.parse::<u32>()) {
+                            (Ok(start), Ok(end)) if start <= end => Some((ref_name.to_string(), start, end)),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    parsed.unwrap_or_else(|| {
+                        panic!("Invalid BED interval on line {} of {}", line_number + 1, path.display())
+                    })
+                })
+                .collect()
+        }
+        _ => panic!("Interval file {} must be a BED (optionally gzipped), BAM or SAM file!", path.display()),
+    }
+}
+
+/// Read the regions of a BedGraph file as (reference name, start, end, value) with 0-based half-open coordinates,
+/// ignoring blank lines, '#' comments and 'track'/'browser' lines. The file may be gzip-compressed.
+pub fn read_bedgraph(path: &Path) -> Vec<(String, u32, u32, f64)> {
+    let contents = read_text_file(path, "BedGraph");
+    let mut regions = Vec::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || ["#", "track", "browser"].iter().any(|prefix| line.starts_with(prefix)) {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields[..] {
+            [ref_name, start, end, value, ..] => {
+                match (start.parse::<u32>(), end.parse::<u32>(), value.parse::<f64>()) {
+                    (Ok(start), Ok(end), Ok(value)) if start <= end => Some((ref_name.to_string(), start, end, value)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        regions.push(parsed.unwrap_or_else(|| {
+            panic!(
+                "Invalid BedGraph region on line {} of {}: expected 'chrom<TAB>start<TAB>end<TAB>value'",
+                line_number + 1,
+                path.display()
+            )
+        }));
+    }
+    regions
+}
+
+/// Read the sequences of a FASTA file as (name, sequence). The name is the first word of the '>' line
+pub fn read_fasta(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let contents = std::fs::read(path)
+        .unwrap_or_else(|e| fail_io(path, "Could not read FASTA file", e));
+    let mut sequences: Vec<(String, Vec<u8>)> = Vec::new();
+    for line in contents.split(|&c| c == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if let Some(description) = line.strip_prefix(b">") {
+            let name = String::from_utf8_lossy(description).split_whitespace().next().unwrap_or("").to_string();
+            sequences.push((name, Vec::new()));
+        } else if !line.is_empty() {
+            let (_, sequence) = sequences
+                .last_mut()
+                .unwrap_or_else(|| panic!("FASTA file {} must start with a '>' line!", path.display()));
+            sequence.extend_from_slice(line);
+        }
+    }
+    sequences
+}
+
+/// Runs of a single base (ignoring case, and excluding 'N') at least `min_length` long,
+/// as 0-based half-open (start, end) positions
+pub fn homopolymer_tracts(sequence: &[u8], min_length: u32) -> Vec<(u32, u32)> {
+    let mut tracts = Vec::new();
+    let mut start = 0;
+    for end in 1..=sequence.len() {
+        if end == sequence.len() || !sequence[end].eq_ignore_ascii_case(&sequence[start]) {
+            if (end - start) as u32 >= min_length && !sequence[start].eq_ignore_ascii_case(&b'N') {
+                tracts.push((start as u32, end as u32));
+            }
+            start = end;
+        }
+    }
+    tracts
+}
+
+/// Substitute the {stem}, {filter} and {ext} placeholders of an output file name template.
+/// Returns a description of the problem if the template has an unknown or unclosed placeholder
+pub fn render_name_template(template: &str, stem: &str, filter: &str, ext: &str) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed placeholder '{}'", &rest[open..]))?;
+        let placeholder = &rest[open + 1..open + close];
+        rendered.push_str(match placeholder {
+            "stem" => stem,
+            "filter" => filter,
+            "ext" => ext,
+            _ => return Err(format!("unknown placeholder '{{{}}}' (use {{stem}}, {{filter}} or {{ext}})", placeholder)),
+        });
+        rest = &rest[open + close + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+// Number of bases that BWA-style quality trimming removes from the start of `qualities`:
+// the prefix that maximizes the running sum of (threshold - quality), stopping once the sum drops below zero
+fn quality_trim_count<'a>(qualities: impl Iterator<Item = &'a u8>, threshold: u8) -> usize {
+    let (mut sum, mut max_sum, mut trimmed) = (0i64, 0i64, 0);
+    for (i, &quality) in qualities.enumerate() {
+        sum += threshold as i64 - quality as i64;
+        if sum < 0 {
+            break;
+        }
+        if sum > max_sum {
+            max_sum = sum;
+            trimmed = i + 1;
+        }
+    }
+    trimmed
+}
+
+/// Number of bases that BWA-style quality trimming with a Phred quality threshold removes from the start and
+/// from the end of the given base qualities. The end is trimmed first
+pub fn quality_trim_counts(qualities: &[u8], threshold: u8) -> (u32, u32) {
+    let end_trimmed = quality_trim_count(qualities.iter().rev(), threshold);
+    let start_trimmed = quality_trim_count(qualities[..qualities.len() - end_trimmed].iter(), threshold);
+    (start_trimmed as u32, end_trimmed as u32)
+}
+
+/// Length of a read after BWA-style quality trimming from both ends with a Phred quality threshold.
+/// Reads without base qualities are not trimmed
+pub fn quality_trimmed_len(record: &Record, threshold: u8) -> u32 {
+    let qualities = record.qualities();
+    if !qualities.available() {
+        return record.query_len();
+    }
+    let (start_trimmed, end_trimmed) = quality_trim_counts(qualities.raw(), threshold);
+    qualities.raw().len() as u32 - start_trimmed - end_trimmed
+}
+
+// Remove `bases` query bases from the start of a CIGAR, replacing them with a hard clip. Deletions and skips that
+// would start the trimmed alignment are dropped too, and insertions there become soft clips.
+// Returns the trimmed CIGAR (None if no aligned bases are left) and the number of reference bases removed
+fn trim_cigar_start(ops: &[(u32, Operation)], bases: u32) -> (Option<Vec<(u32, Operation)>>, u32) {
+    let (mut hard, mut ref_removed, mut remaining) = (0, 0, bases);
+    let mut trimmed = Vec::new();
+    let mut aligned = false;
+    for &(len, operation) in ops {
+        if aligned {
+            trimmed.push((len, operation));
+            continue;
+        }
+        match operation {
+            Operation::Hard => hard += len,
+            Operation::Padding => {}
+            Operation::Deletion | Operation::Skip => ref_removed += len,
+            Operation::Soft | Operation::Insertion => {
+                let cut = len.min(remaining);
+                remaining -= cut;
+                hard += cut;
+                if len > cut {
+                    trimmed.push((len - cut, Operation::Soft));
+                }
+            }
+            Operation::AlnMatch | Operation::SeqMatch | Operation::SeqMismatch => {
+                let cut = len.min(remaining);
+                remaining -= cut;
+                hard += cut;
+                ref_removed += cut;
+                if len > cut {
+                    trimmed.push((len - cut, operation));
+                    aligned = true;
+                }
+            }
+        }
+    }
+    if !aligned {
+        return (None, ref_removed);
+    }
+    if hard > 0 {
+        trimmed.insert(0, (hard, Operation::Hard));
+    }
+    (Some(trimmed), ref_removed)
+}
+
+/// Remove `start` bases from the start and `end` bases from the end of a record's stored sequence and qualities
+/// (i.e. the left and right ends on the reference for mapped reads, whatever their strand).
+/// The CIGAR of a mapped read is hard-clipped to match, and its start position moved past the trimmed reference
+/// bases; a mapped read left without aligned bases becomes unmapped, at the same position.
+/// The MD and NM tags no longer describe a trimmed alignment, so they are removed
+pub fn trim_record(record: &mut Record, start: u32, end: u32) {
+    let len = record.query_len();
+    let start = start.min(len);
+    let end = end.min(len - start);
+    if start == 0 && end == 0 {
+        return;
+    }
+    let kept = start as usize..(len - end) as usize;
+    let sequence = record.sequence().to_vec()[kept.clone()].to_vec();
+    let qualities = record.qualities();
+    let qualities = if qualities.available() { qualities.raw()[kept].to_vec() } else { Vec::new() };
+    record.set_seq_qual(sequence, qualities).unwrap();
+    if !record.flag().is_mapped() || record.cigar().is_empty() {
+        return;
+    }
+    let ops: Vec<(u32, Operation)> = record.cigar().iter().collect();
+    let (ops, ref_removed) = trim_cigar_start(&ops, start);
+    let ops = ops.and_then(|mut ops| {
+        ops.reverse();
+        let (ops, _) = trim_cigar_start(&ops, end);
+        ops.map(|ops| ops.into_iter().rev().collect::<Vec<_>>())
+    });
+    match ops {
+        Some(ops) => {
+            record.set_start(record.start() + ref_removed as i32);
+            record.set_raw_cigar(ops.into_iter().map(|(len, operation)| len << 4 | operation as u32));
+        }
+        None => {
+            record.flag_mut().set_mapped(false);
+            record.set_raw_cigar(std::iter::empty());
+            record.set_mapq(0);
+        }
+    }
+    record.tags_mut().remove(b"MD");
+    record.tags_mut().remove(b"NM");
+}
+
+/// Number of bases before the quality cliff of a read, given its base qualities in 5' to 3' order:
+/// the start of the first window of `window` bases whose mean quality is below `threshold`,
+/// or the whole read if there is none. Reads shorter than the window are a single window
+pub fn pre_cliff_len(qualities: &[u8], window: usize, threshold: u8) -> u32 {
+    let window = window.clamp(1, qualities.len().max(1));
+    qualities
+        .windows(window)
+        .position(|bases| bases.iter().map(|&quality| quality as u32).sum::<u32>() < threshold as u32 * window as u32)
+        .unwrap_or(qualities.len()) as u32
+}
+
+/// Quote a string for a POSIX shell, leaving simple words (e.g. names, numbers and paths) as they are
+pub fn shell_quote(arg: &str) -> String {
+    let is_simple = |c: char| c.is_ascii_alphanumeric() || "_-+=.,/:@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_simple) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Parse a region string such as `chr1` or `chr1:1,000-2,000` into the reference name and,
+/// if given, the 1-based inclusive start and end positions
+pub fn parse_region(region: &str) -> (String, Option<(u32, u32)>) {
+    let (ref_name, range) = match region.rsplit_once(':') {
+        Some((ref_name, range)) => (ref_name, Some(range)),
+        None => (region, None),
+    };
+    assert!(!ref_name.is_empty(), "Invalid region '{}': missing reference name", region);
+    let range = range.map(|range| {
+        let positions = range
+            .split_once('-')
+            .and_then(|(start, end)| Some((start.replace(',', "").parse::<u32>().ok()?, end.replace(',', "").parse::<u32>().ok()?)));
+        match positions {
+            Some((start, end)) if start >= 1 && start <= end => (start, end),
+            _ => panic!("Invalid region '{}': expected 'name:start-end' with 1 <= start <= end", region),
+        }
+    });
+    (ref_name.to_string(), range)
+}
+
 #[cfg(test)]
 mod tests {
     use rstest::rstest;
@@ -120,4 +1099,506 @@ mod tests {
         let result = _opposite(boolean, opposite);
         assert_eq!(result, expected);
     }
+
+    #[rstest]
+    #[case("50", Some(0))]
+    #[case("10A39", Some(1))]
+    #[case("0C10A0T5", Some(3))]
+    #[case("5^AC10G2", Some(1))]
+    #[case("5^AC0G2", Some(1))]
+    #[case("", None)]
+    #[case("10A", None)]
+    #[case("10^", None)]
+    #[case("10AB5", None)]
+    #[case("1x0", None)]
+    fn test_count_md_mismatches(#[case] md: &str, #[case] expected: Option<u32>) {
+        assert_eq!(count_md_mismatches(md), expected);
+    }
+
+    #[rstest]
+    fn test_expand_input_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.bam", "a.bam", "c.sam"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let literal = dir.path().join("literal.bam");
+        let expanded = expand_input_paths(&[
+            dir.path().join("*.bam"),
+            literal.clone(),
+            dir.path().join("*.cram"),
+        ]);
+        assert_eq!(
+            expanded,
+            vec![dir.path().join("a.bam"), dir.path().join("b.bam"), literal]
+        );
+    }
+
+    #[rstest]
+    fn test_read_names_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("names.txt");
+        std::fs::write(&path, "# filters for run 1\nlength\n\n  mapq filter  \n#flag\n").unwrap();
+        assert_eq!(read_names_file(&path), vec!["length", "mapq filter"]);
+    }
+
+    #[rstest]
+    #[case(10, "50M", vec![])]
+    #[case(10, "20M100N30M", vec![(30, 129)])]
+    #[case(10, "5S20M2D10M50N10M3I5M200N5M", vec![(42, 91), (107, 306)])]
+    fn test_intron_spans(#[case] start: i32, #[case] cigar: &str, #[case] expected: Vec<(u32, u32)>) {
+        let mut record = Record::new();
+        record.set_start(start);
+        record.set_cigar(cigar.bytes()).unwrap();
+        assert_eq!(intron_spans(&record), expected);
+    }
+
+    #[rstest]
+    #[case(TagValue::Int(3, IntegerType::U8), true, true)]
+    #[case(TagValue::Int(3, IntegerType::I16), true, true)]
+    #[case(TagValue::Int(3, IntegerType::U32), true, true)]
+    #[case(TagValue::Int(4, IntegerType::U8), false, false)]
+    #[case(TagValue::Float(3.0), false, true)]
+    #[case(TagValue::Float(3.5), false, false)]
+    #[case(TagValue::Char(b'3'), false, false)]
+    fn test_tag_value_coercion(#[case] value: TagValue, #[case] expected: bool, #[case] expected_numeric: bool) {
+        let filter_value = _minimal_tag_to_tag(&MinimalTagValue::Int(3));
+        assert_eq!(_are_tag_values_equal(&value, &filter_value), expected);
+        assert_eq!(_are_tag_values_numerically_equal(&value, &filter_value), expected_numeric);
+        assert_eq!(_are_tag_values_numerically_equal(&filter_value, &value), expected_numeric);
+    }
+
+    #[rstest]
+    #[case("@HD\tVN:1.6\tSO:coordinate", Some("coordinate"))]
+    #[case("@HD\tVN:1.6", None)]
+    #[case("@CO\tSO:queryname", None)]
+    fn test_header_sort_order(#[case] line: &str, #[case] expected: Option<&str>) {
+        let mut header = bam::Header::new();
+        header.push_line(line).unwrap();
+        assert_eq!(header_sort_order(&header), expected);
+    }
+
+    // a record with a placeholder CIGAR and the real CIGAR in the CG tag
+    fn long_cigar_record(start: i32, placeholder: &str, cigar: &str) -> Record {
+        let mut record = Record::new();
+        record.set_start(start);
+        record.set_seq_qual(b"ACGTACGTAC".iter().copied(), std::iter::empty()).unwrap();
+        record.set_cigar(placeholder.bytes()).unwrap();
+        let mut real_cigar = Cigar::new();
+        real_cigar.extend_from_text(cigar.bytes()).unwrap();
+        record.tags_mut().push_array(b"CG", real_cigar.raw());
+        record
+    }
+
+    #[rstest]
+    #[case("10S30N", "3S2M20N5M", "3S2M20N5M")]
+    #[case("4M6S", "3S2M20N5M", "4M6S")]
+    #[case("10S30M", "3S2M20N5M", "10S30M")]
+    #[case("9S30N", "3S2M20N5M", "9S30N")]
+    fn test_effective_cigar(#[case] placeholder: &str, #[case] cigar: &str, #[case] expected: &str) {
+        let record = long_cigar_record(10, placeholder, cigar);
+        assert_eq!(effective_cigar(&record).to_string(), expected);
+    }
+
+    #[rstest]
+    fn test_intron_spans_long_cigar() {
+        let record = long_cigar_record(10, "10S27N", "2M20N5M3S");
+        assert_eq!(intron_spans(&record), vec![(12, 31)]);
+    }
+
+    #[rstest]
+    #[case(b"chr1,1001,+,50S50M,60,0;", vec![("chr1", 1000)])]
+    #[case(b"chr2,51,-,30M70S,60,1;chr1,7,+,60M40S,20,2;", vec![("chr2", 50), ("chr1", 6)])]
+    #[case(b"chr1,abc,+,50M,60,0;chr1,0,+,50M,60,0;,5,+,50M,60,0;chr3,9", vec![("chr3", 8)])]
+    #[case(b"", vec![])]
+    fn test_sa_positions(#[case] sa: &[u8], #[case] expected: Vec<(&str, i32)>) {
+        let expected: Vec<(String, i32)> = expected.into_iter().map(|(name, pos)| (name.to_string(), pos)).collect();
+        assert_eq!(sa_positions(sa), expected);
+    }
+
+    #[rstest]
+    fn test_read_junctions_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("junctions.tsv");
+        std::fs::write(&path, "# ref\tstart\tend\nchr1\t101\t200\t+\n\nchr2\t51\t60\nchr1\t11\t20\n").unwrap();
+        let junctions = read_junctions_file(&path);
+        assert_eq!(junctions.len(), 2);
+        assert_eq!(junctions["chr1"], vec![(100, 199), (10, 19)]);
+        assert_eq!(junctions["chr2"], vec![(50, 59)]);
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Invalid junction on line 2")]
+    fn test_read_junctions_file_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("junctions.tsv");
+        std::fs::write(&path, "chr1\t101\t200\nchr1\t300\n").unwrap();
+        read_junctions_file(&path);
+    }
+
+    #[rstest]
+    #[case("chr1", "chr1", None)]
+    #[case("chr1:1000-2000", "chr1", Some((1000, 2000)))]
+    #[case("chrUn:KI270302v1:1,000-2,000", "chrUn:KI270302v1", Some((1000, 2000)))]
+    #[case("chr2:5-5", "chr2", Some((5, 5)))]
+    fn test_parse_region(#[case] region: &str, #[case] ref_name: &str, #[case] range: Option<(u32, u32)>) {
+        assert_eq!(parse_region(region), (ref_name.to_string(), range));
+    }
+
+    #[rstest]
+    #[case("chr1:2000-1000")]
+    #[case("chr1:0-10")]
+    #[case("chr1:abc")]
+    #[case(":1-10")]
+    #[should_panic(expected = "Invalid region")]
+    fn test_parse_region_invalid(#[case] region: &str) {
+        parse_region(region);
+    }
+
+    #[rstest]
+    fn test_read_annotation_column() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scores.tsv");
+        std::fs::write(&path, "read\tscore\tqc\n# comment\nread1\t0.5\tpass\n\nread2\t-3\tfail\n").unwrap();
+        let values = read_annotation_column(&path, "score");
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[b"read1".as_slice()], 0.5);
+        assert_eq!(values[b"read2".as_slice()], -3.0);
+    }
+
+    #[rstest]
+    #[case("qc", "Invalid value of column 'qc' on line 3")]
+    #[case("missing", "Column 'missing' is not in the header")]
+    fn test_read_annotation_column_errors(#[case] column: &str, #[case] expected: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scores.tsv");
+        std::fs::write(&path, "read\tscore\tqc\n# comment\nread1\t0.5\tpass\n").unwrap();
+        let message = *std::panic::catch_unwind(|| read_annotation_column(&path, column))
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert!(message.starts_with(expected), "{}", message);
+    }
+
+    #[rstest]
+    fn test_read_intervals_bed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intervals.bed");
+        std::fs::write(&path, "track name=test\n# comment\nchr1\t100\t200\tname\n\nchr2\t0\t10\n").unwrap();
+        assert_eq!(
+            read_intervals(&path),
+            vec![("chr1".to_string(), 100, 200), ("chr2".to_string(), 0, 10)]
+        );
+    }
+
+    #[rstest]
+    #[case("panel.bed.gz")]
+    #[case("panel.bed")]
+    fn test_read_intervals_gzipped_bed(#[case] file_name: &str) {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(file_name);
+        let file = std::fs::File::create(&path).unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder.write_all(b"# panel\nchr1\t100\t200\tEGFR\nchr2\t0\t10\n").unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(
+            read_intervals(&path),
+            vec![("chr1".to_string(), 100, 200), ("chr2".to_string(), 0, 10)]
+        );
+    }
+
+    #[rstest]
+    #[should_panic(expected = "must be a BED (optionally gzipped), BAM or SAM file")]
+    fn test_read_intervals_gzipped_sam() {
+        read_intervals(Path::new("intervals.sam.gz"));
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Invalid BED interval on line 2")]
+    fn test_read_intervals_invalid_bed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intervals.bed");
+        std::fs::write(&path, "chr1\t100\t200\nchr1\t300\n").unwrap();
+        read_intervals(&path);
+    }
+
+    #[rstest]
+    #[case("length", "length")]
+    #[case("-0.5", "-0.5")]
+    #[case("", "''")]
+    #[case("my filter", "'my filter'")]
+    #[case("(a AND b)", "'(a AND b)'")]
+    #[case("it's", "'it'\\''s'")]
+    #[case("tag(\"NM\") < 3", "'tag(\"NM\") < 3'")]
+    fn test_shell_quote(#[case] arg: &str, #[case] expected: &str) {
+        assert_eq!(shell_quote(arg), expected);
+    }
+
+    #[rstest]
+    #[case("18", "24", 18, 24, "18", "24")]
+    #[case("18", "*", 18, u32::MAX, "18", "*")]
+    #[case("*", "24", 0, 24, "*", "24")]
+    #[case("*", "*", 0, u32::MAX, "*", "*")]
+    fn test_range_bound(#[case] min: &str, #[case] max: &str, #[case] min_value: u32, #[case] max_value: u32,
+                        #[case] min_str: &str, #[case] max_str: &str) {
+        let range: RangeBound<u32> = RangeBound::new(min.parse().unwrap(), max.parse().unwrap());
+        assert_eq!((range.min_value(), range.max_value()), (min_value, max_value));
+        assert_eq!(RangeBound::from_values(min_value, max_value), range);
+        assert_eq!((range.min_str().as_str(), range.max_str().as_str()), (min_str, max_str));
+        assert!(range.contains(min_value) && range.contains(max_value));
+    }
+
+    #[rstest]
+    #[case("x")]
+    #[case("-1")]
+    #[case("")]
+    fn test_limit_parse_errors(#[case] value: &str) {
+        assert!(value.parse::<Limit<u32>>().is_err());
+    }
+
+    #[rstest]
+    #[should_panic]
+    fn test_range_bound_min_above_max() {
+        RangeBound::new(Limit(Some(24u32)), Limit(Some(18)));
+    }
+
+    #[rstest]
+    #[case("ACGTTTTAC", 3, vec![(3, 7)])]
+    #[case("AAAcccGNNNNN", 3, vec![(0, 3), (3, 6)])]
+    #[case("ACGT", 1, vec![(0, 1), (1, 2), (2, 3), (3, 4)])]
+    #[case("GGGG", 5, vec![])]
+    #[case("", 2, vec![])]
+    fn test_homopolymer_tracts(#[case] sequence: &str, #[case] min_length: u32, #[case] expected: Vec<(u32, u32)>) {
+        assert_eq!(homopolymer_tracts(sequence.as_bytes(), min_length), expected);
+    }
+
+    #[rstest]
+    fn test_read_fasta() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ref.fa");
+        std::fs::write(&path, ">chr1 first contig\nACGT\r\nTT\n\n>chr2\nGG\n").unwrap();
+        assert_eq!(
+            read_fasta(&path),
+            vec![("chr1".to_string(), b"ACGTTT".to_vec()), ("chr2".to_string(), b"GG".to_vec())]
+        );
+    }
+
+    #[rstest]
+    #[case("1101", NameFieldMatch::Exact("1101".to_string()), "1101")]
+    #[case("1101-1120", NameFieldMatch::Range(1101, 1120), "1101-1120")]
+    #[case("2-*", NameFieldMatch::Range(2, u32::MAX), "2-*")]
+    #[case("*-3", NameFieldMatch::Range(0, 3), "*-3")]
+    #[case("000000000-A1B2C", NameFieldMatch::Exact("000000000-A1B2C".to_string()), "000000000-A1B2C")]
+    fn test_name_field_match_parse(#[case] pattern: &str, #[case] expected: NameFieldMatch, #[case] display: &str) {
+        let parsed = NameFieldMatch::parse(pattern);
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.to_string(), display);
+    }
+
+    #[rstest]
+    #[case(NameField::Instrument, Some("A00123"))]
+    #[case(NameField::Lane, Some("2"))]
+    #[case(NameField::Tile, Some("1101"))]
+    #[case(NameField::Y, Some("1000"))]
+    fn test_name_field_value(#[case] field: NameField, #[case] expected: Option<&str>) {
+        let name = b"A00123:8:H7KJ2DSXY:2:1101:15474:1000";
+        assert_eq!(field.value_in(name), expected.map(str::as_bytes));
+        assert_eq!(field.value_in(b"read1"), None);
+    }
+
+    #[rstest]
+    #[case("{stem}.{filter}.filtered.bam", "sample1", "mapq", "bam", "sample1.mapq.filtered.bam")]
+    #[case("{stem}.{filter}.filtered.bam", "run.2", "long reads", "sam", "run.2.long reads.filtered.bam")]
+    #[case("{stem}_{filter}.{ext}", "sample2", "f1", "sam", "sample2_f1.sam")]
+    #[case("out.bam", "sample3", "f1", "bam", "out.bam")]
+    fn test_render_name_template(#[case] template: &str, #[case] stem: &str, #[case] filter: &str, #[case] ext: &str,
+                                 #[case] expected: &str) {
+        assert_eq!(render_name_template(template, stem, filter, ext).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("{sample}.bam", "unknown placeholder '{sample}'")]
+    #[case("{stem.bam", "unclosed placeholder '{stem.bam'")]
+    fn test_render_name_template_errors(#[case] template: &str, #[case] expected: &str) {
+        let error = render_name_template(template, "a", "b", "bam").unwrap_err();
+        assert!(error.starts_with(expected), "{}", error);
+    }
+
+    #[rstest]
+    #[case("http://localhost:8000/a.bam", true)]
+    #[case("https://example.com/data/*.bam", true)]
+    #[case("s3://bucket/a.bam", true)]
+    #[case("data/http/a.bam", false)]
+    fn test_is_url(#[case] path: &str, #[case] expected: bool) {
+        assert_eq!(is_url(Path::new(path)), expected);
+        if expected {
+            assert_eq!(expand_input_paths(&[PathBuf::from(path)]), vec![PathBuf::from(path)]);
+        }
+    }
+
+    #[rstest]
+    #[case(0.1)]
+    #[case(0.5)]
+    fn test_subsample(#[case] fraction: f64) {
+        let names: Vec<String> = (0..10000).map(|i| format!("read{}", i)).collect();
+        let selected = |seed: u64| -> Vec<&String> {
+            let subsample = Subsample::new(fraction, seed);
+            names.iter().filter(|name| subsample.contains(name.as_bytes())).collect()
+        };
+        let expected = fraction * names.len() as f64;
+        assert!((selected(0).len() as f64 - expected).abs() < expected * 0.1, "{}", selected(0).len());
+        assert_eq!(selected(0), selected(0));
+        assert_ne!(selected(0), selected(1));
+        assert!(Subsample::new(1.0, 0).contains(b"read"));
+        assert!(!Subsample::new(0.0, 0).contains(b"read"));
+    }
+
+    #[rstest]
+    #[case(&[30, 30, 30, 30], 20, 4)]
+    #[case(&[30, 30, 30, 2, 2], 20, 3)]
+    #[case(&[2, 30, 30, 30, 2], 20, 3)]
+    // a single good base doesn't stop the trimming of a low-quality tail
+    #[case(&[30, 30, 30, 30, 2, 2, 25, 2], 20, 4)]
+    #[case(&[2, 2, 2, 2], 20, 0)]
+    #[case(&[15, 15, 15], 10, 3)]
+    fn test_quality_trimmed_len(#[case] qualities: &[u8], #[case] threshold: u8, #[case] expected: u32) {
+        let mut record = Record::new();
+        record.set_seq_qual(std::iter::repeat_n(b'A', qualities.len()), qualities.iter().copied()).unwrap();
+        assert_eq!(quality_trimmed_len(&record, threshold), expected);
+    }
+
+    #[rstest]
+    #[case(&[30, 30, 30, 30, 30, 30], 2, 20, 6)]
+    #[case(&[30, 30, 30, 30, 5, 5, 5], 2, 20, 3)]
+    #[case(&[30, 30, 30, 30, 5, 5, 5], 1, 20, 4)]
+    // the mean of a window is compared, so a single bad base doesn't make a cliff
+    #[case(&[30, 30, 5, 30, 30, 30], 3, 20, 6)]
+    #[case(&[30, 30, 5, 5, 30, 30], 3, 20, 1)]
+    #[case(&[5, 30, 30], 1, 20, 0)]
+    #[case(&[15, 15], 4, 20, 0)]
+    #[case(&[], 4, 20, 0)]
+    fn test_pre_cliff_len(#[case] qualities: &[u8], #[case] window: usize, #[case] threshold: u8,
+                          #[case] expected: u32) {
+        assert_eq!(pre_cliff_len(qualities, window, threshold), expected);
+    }
+
+    #[rstest]
+    fn test_sniff_format() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let bam_path = dir.path().join("records.sam");
+        let mut writer = bam::BamWriter::from_path(&bam_path, bam::Header::new()).unwrap();
+        bam::RecordWriter::finish(&mut writer).unwrap();
+        drop(writer);
+        assert_eq!(sniff_format(&bam_path), Some(SupportedFormats::BAM));
+
+        let sam_path = dir.path().join("records.bam");
+        std::fs::write(&sam_path, "@HD\tVN:1.6\tSO:unsorted\n").unwrap();
+        assert_eq!(sniff_format(&sam_path), Some(SupportedFormats::SAM));
+        let empty_path = dir.path().join("empty.sam");
+        std::fs::write(&empty_path, "").unwrap();
+        assert_eq!(sniff_format(&empty_path), Some(SupportedFormats::SAM));
+
+        let gzip_path = dir.path().join("records.sam.gz");
+        let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(&gzip_path).unwrap(), flate2::Compression::default());
+        encoder.write_all(b"@HD\tVN:1.6\n").unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(sniff_format(&gzip_path), None);
+        assert_eq!(sniff_format(&dir.path().join("missing.bam")), None);
+    }
+
+    #[rstest]
+    #[case(b"TTAGATCGGTT", b"AGATCGG", 0, true)]
+    #[case(b"TTAGTTCGGTT", b"AGATCGG", 0, false)]
+    #[case(b"TTAGTTCGGTT", b"AGATCGG", 1, true)]
+    #[case(b"TTAGTTCGCTT", b"AGATCGG", 1, false)]
+    #[case(b"TTAGTTCGCTT", b"AGATCGG", 2, true)]
+    #[case(b"AGATCG", b"AGATCGG", 3, false)]
+    fn test_contains_with_mismatches(#[case] seq: &[u8], #[case] motif: &[u8], #[case] max_mismatch: u32,
+                                     #[case] expected: bool) {
+        assert_eq!(contains_with_mismatches(seq, motif, max_mismatch), expected);
+    }
+
+    #[rstest]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"AGATCGGN"), b"NCCGATCT".to_vec());
+    }
+
+    #[rstest]
+    #[case(Some(5), 50, Some(0.1))]
+    #[case(Some(0), 50, Some(0.0))]
+    #[case(None, 50, None)]
+    #[case(Some(5), 0, None)]
+    fn test_mismatch_rate(#[case] nm: Option<u8>, #[case] len: usize, #[case] expected: Option<f64>) {
+        let mut record = Record::new();
+        record.set_seq_qual(std::iter::repeat_n(b'A', len), std::iter::repeat_n(30, len)).unwrap();
+        if let Some(nm) = nm {
+            record.tags_mut().push_num(b"NM", nm);
+        }
+        assert_eq!(mismatch_rate(&record), expected);
+    }
+
+    #[rstest]
+    fn test_read_bedgraph() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coverage.bedgraph");
+        std::fs::write(&path, "track type=bedGraph\n# comment\nchr1\t0\t100\t12\n\nchr1\t100\t150\t2500.5\n").unwrap();
+        assert_eq!(
+            read_bedgraph(&path),
+            vec![("chr1".to_string(), 0, 100, 12.0), ("chr1".to_string(), 100, 150, 2500.5)]
+        );
+    }
+
+    #[rstest]
+    #[should_panic(expected = "Invalid BedGraph region on line 2")]
+    fn test_read_bedgraph_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coverage.bedgraph");
+        std::fs::write(&path, "chr1\t0\t100\t12\nchr1\t100\t150\n").unwrap();
+        read_bedgraph(&path);
+    }
+
+    #[rstest]
+    // (start position, CIGAR) after trimming 3 bases from the start and 2 from the end of a read at position 100
+    #[case("10M", 100, 3, 2, Some((103, "3H5M2H")))]
+    #[case("2S8M", 100, 3, 0, Some((101, "3H7M")))]
+    #[case("5H2S8M", 100, 1, 0, Some((100, "6H1S8M")))]
+    #[case("3M2D5M2S", 100, 3, 2, Some((105, "3H5M2H")))]
+    #[case("2M2I6M", 100, 3, 0, Some((102, "3H1S6M")))]
+    #[case("3M100N7M", 100, 3, 3, Some((203, "3H4M3H")))]
+    #[case("4M1I4M1D1M", 100, 0, 2, Some((100, "4M1I3M2H")))]
+    #[case("3M7S", 100, 3, 0, None)]
+    fn test_trim_record(#[case] cigar: &str, #[case] start: i32, #[case] trim_start: u32, #[case] trim_end: u32,
+                        #[case] expected: Option<(i32, &str)>) {
+        let mut record = Record::new();
+        record.set_name(b"read".iter().copied());
+        let qualities: Vec<u8> = (0..10).collect();
+        record.set_seq_qual(b"ACGTACGTAC".iter().copied(), qualities.iter().copied()).unwrap();
+        record.set_cigar(cigar.bytes()).unwrap();
+        record.set_ref_id(0);
+        record.set_start(start);
+        record.tags_mut().push_num(b"NM", 1);
+        record.tags_mut().push_string(b"MD", b"10");
+        record.tags_mut().push_string(b"RG", b"lane1");
+        trim_record(&mut record, trim_start, trim_end);
+
+        let kept = trim_start as usize..10 - trim_end as usize;
+        assert_eq!(record.sequence().to_vec(), b"ACGTACGTAC"[kept.clone()].to_vec());
+        assert_eq!(record.qualities().raw(), &qualities[kept]);
+        match expected {
+            Some((expected_start, expected_cigar)) => {
+                assert!(record.flag().is_mapped());
+                assert_eq!(record.start(), expected_start);
+                assert_eq!(record.cigar().to_string(), expected_cigar);
+            }
+            None => {
+                assert!(!record.flag().is_mapped());
+                assert_eq!(record.start(), start);
+            }
+        }
+        assert!(record.tags().get(b"NM").is_none() && record.tags().get(b"MD").is_none());
+        assert!(record.tags().get(b"RG").is_some());
+    }
 }